@@ -0,0 +1,1581 @@
+// Copyright (C) 2024 Laurynas Biveinis
+
+// Adaptive radix tree operations over the nodes in the buffer manager. A tree
+// is identified by its ART descriptor node ID.
+//
+// The reads see the tree as of a snapshot timestamp, and the writes keep the
+// versions for the older snapshots, unless done at the timestamp zero. The
+// keys deleted for some snapshots only stay in the tree until purged.
+
+use std::cmp::Ordering;
+use std::ops::Bound;
+
+use crate::buffer_manager::{BufferManager, NodeMut, NodeRef};
+use crate::node::{self, Node};
+use crate::DbError;
+
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+#[inline]
+fn descriptor(
+    buffer_manager: &BufferManager,
+    tree: node::Id,
+) -> Result<NodeRef<'_, node::ArtDescriptor>, DbError> {
+    buffer_manager
+        .get(tree)?
+        .and_then(|node| node.try_map(Node::as_art_descriptor))
+        .ok_or(DbError::NotArtDescriptor { node_id: tree })
+}
+
+// The root of the tree, unless the snapshot sees it dropped
+#[inline]
+fn root_at(
+    buffer_manager: &BufferManager,
+    tree: node::Id,
+    snapshot_ts: u64,
+) -> Result<node::Id, DbError> {
+    let descriptor = descriptor(buffer_manager, tree)?;
+    if descriptor.is_visible_at(snapshot_ts) {
+        Ok(descriptor.root())
+    } else {
+        Err(DbError::NotArtDescriptor { node_id: tree })
+    }
+}
+
+#[inline]
+fn descriptor_mut(
+    buffer_manager: &BufferManager,
+    tree: node::Id,
+) -> Result<NodeMut<'_, node::ArtDescriptor>, DbError> {
+    buffer_manager
+        .get_mut(tree)?
+        .and_then(|node| node.try_map(Node::as_art_descriptor, Node::as_art_descriptor_mut))
+        .ok_or(DbError::NotArtDescriptor { node_id: tree })
+}
+
+#[inline]
+fn tree_node(buffer_manager: &BufferManager, node_id: node::Id) -> Result<NodeRef<'_>, DbError> {
+    match buffer_manager.get(node_id)? {
+        Some(node) if matches!(*node, Node::Inner(_) | Node::Leaf(_)) => Ok(node),
+        _ => Err(DbError::UnexpectedNodeType { node_id }),
+    }
+}
+
+// Follows the child reference of an inner node through the buffer manager
+// pointer swizzling
+#[inline]
+fn child<'a>(
+    buffer_manager: &'a BufferManager,
+    parent: node::Id,
+    inner: &node::Inner,
+    key_byte: u8,
+) -> Result<Option<(node::Id, NodeRef<'a>)>, DbError> {
+    let Some(swip) = inner.child_swip(key_byte) else {
+        return Ok(None);
+    };
+    let node_id = swip.id();
+    match buffer_manager.get_child(parent, swip, key_byte)? {
+        Some(node) if matches!(*node, Node::Inner(_) | Node::Leaf(_)) => Ok(Some((node_id, node))),
+        _ => Err(DbError::UnexpectedNodeType { node_id }),
+    }
+}
+
+#[inline]
+fn inner(
+    buffer_manager: &BufferManager,
+    node_id: node::Id,
+) -> Result<NodeRef<'_, node::Inner>, DbError> {
+    buffer_manager
+        .get(node_id)?
+        .and_then(|node| node.try_map(Node::as_inner))
+        .ok_or(DbError::UnexpectedNodeType { node_id })
+}
+
+#[inline]
+fn inner_mut(
+    buffer_manager: &BufferManager,
+    node_id: node::Id,
+) -> Result<NodeMut<'_, node::Inner>, DbError> {
+    buffer_manager
+        .get_mut(node_id)?
+        .and_then(|node| node.try_map(Node::as_inner, Node::as_inner_mut))
+        .ok_or(DbError::UnexpectedNodeType { node_id })
+}
+
+#[inline]
+fn leaf(
+    buffer_manager: &BufferManager,
+    node_id: node::Id,
+) -> Result<NodeRef<'_, node::Leaf>, DbError> {
+    buffer_manager
+        .get(node_id)?
+        .and_then(|node| node.try_map(Node::as_leaf))
+        .ok_or(DbError::UnexpectedNodeType { node_id })
+}
+
+#[inline]
+fn leaf_mut(
+    buffer_manager: &BufferManager,
+    node_id: node::Id,
+) -> Result<NodeMut<'_, node::Leaf>, DbError> {
+    buffer_manager
+        .get_mut(node_id)?
+        .and_then(|node| node.try_map(Node::as_leaf, Node::as_leaf_mut))
+        .ok_or(DbError::UnexpectedNodeType { node_id })
+}
+
+// Where a node is referenced from
+#[derive(Clone, Copy)]
+enum Slot {
+    Root,
+    Child(node::Id, u8),
+    Terminal(node::Id),
+}
+
+fn set_slot(
+    buffer_manager: &BufferManager,
+    tree: node::Id,
+    slot: Slot,
+    node_id: node::Id,
+) -> Result<(), DbError> {
+    match slot {
+        Slot::Root => descriptor_mut(buffer_manager, tree)?.set_root(node_id),
+        Slot::Child(parent, key_byte) => {
+            inner_mut(buffer_manager, parent)?.replace_child(key_byte, node_id);
+        }
+        Slot::Terminal(parent) => inner_mut(buffer_manager, parent)?.set_terminal_leaf(node_id),
+    }
+    Ok(())
+}
+
+#[inline]
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+/// # Errors
+/// Will return `DbError` on a failure to make room for the descriptor node.
+pub fn create(buffer_manager: &BufferManager, tree: node::Id) -> Result<(), DbError> {
+    buffer_manager.insert_node(tree, Node::ArtDescriptor(node::ArtDescriptor::new()))
+}
+
+// Whether the snapshot sees the tree
+#[must_use]
+pub fn exists(buffer_manager: &BufferManager, tree: node::Id, snapshot_ts: u64) -> bool {
+    root_at(buffer_manager, tree, snapshot_ts).is_ok()
+}
+
+/// Marks the tree dropped for the snapshots at or after the commit timestamp.
+/// The older snapshots keep reading it until it is destroyed.
+/// # Errors
+/// Will return `DbError` if `tree` is not an ART.
+pub fn mark_dropped(
+    buffer_manager: &BufferManager,
+    tree: node::Id,
+    commit_ts: u64,
+) -> Result<(), DbError> {
+    descriptor_mut(buffer_manager, tree)?.set_dropped(commit_ts);
+    Ok(())
+}
+
+fn min_leaf(buffer_manager: &BufferManager, node_id: node::Id) -> Result<node::Id, DbError> {
+    let mut node_id = node_id;
+    let mut node = tree_node(buffer_manager, node_id)?;
+    loop {
+        let inner = match &*node {
+            Node::Leaf(_) => return Ok(node_id),
+            Node::Inner(inner) => inner,
+            Node::ArtDescriptor(_) => unreachable!(),
+        };
+        if !inner.terminal_leaf().is_null() {
+            return Ok(inner.terminal_leaf());
+        }
+        let next = match inner.first_child() {
+            Some((key_byte, _)) => child(buffer_manager, node_id, inner, key_byte)?,
+            None => None,
+        };
+        let Some(next) = next else {
+            return Err(DbError::UnexpectedNodeType { node_id });
+        };
+        (node_id, node) = next;
+    }
+}
+
+fn max_leaf(buffer_manager: &BufferManager, node_id: node::Id) -> Result<node::Id, DbError> {
+    let mut node_id = node_id;
+    let mut node = tree_node(buffer_manager, node_id)?;
+    loop {
+        let inner = match &*node {
+            Node::Leaf(_) => return Ok(node_id),
+            Node::Inner(inner) => inner,
+            Node::ArtDescriptor(_) => unreachable!(),
+        };
+        let next = match inner.last_child() {
+            Some((key_byte, _)) => child(buffer_manager, node_id, inner, key_byte)?,
+            None if !inner.terminal_leaf().is_null() => return Ok(inner.terminal_leaf()),
+            None => None,
+        };
+        let Some(next) = next else {
+            return Err(DbError::UnexpectedNodeType { node_id });
+        };
+        (node_id, node) = next;
+    }
+}
+
+// The full compressed path of an inner node at the given depth, taken from a
+// leaf if it is longer than what is stored in the node.
+fn full_prefix(
+    buffer_manager: &BufferManager,
+    node_id: node::Id,
+    depth: usize,
+) -> Result<Vec<u8>, DbError> {
+    let inner = inner(buffer_manager, node_id)?;
+    let prefix_len = inner.prefix_len();
+    if prefix_len <= node::Inner::MAX_STORED_PREFIX_LEN {
+        return Ok(inner.stored_prefix().to_vec());
+    }
+    let leaf_id = min_leaf(buffer_manager, node_id)?;
+    let leaf = leaf(buffer_manager, leaf_id)?;
+    leaf.key()
+        .get(depth..depth + prefix_len)
+        .map(<[u8]>::to_vec)
+        .ok_or(DbError::UnexpectedNodeType { node_id: leaf_id })
+}
+
+// The leaf of the key under the root, deleted or not
+fn find_leaf(
+    buffer_manager: &BufferManager,
+    root: node::Id,
+    key: &[u8],
+) -> Result<Option<node::Id>, DbError> {
+    let mut node_id = root;
+    if node_id.is_null() {
+        return Ok(None);
+    }
+    let mut node = tree_node(buffer_manager, node_id)?;
+    let mut depth = 0;
+    loop {
+        let inner = match &*node {
+            Node::Leaf(leaf) => return Ok((leaf.key() == key).then_some(node_id)),
+            Node::Inner(inner) => inner,
+            Node::ArtDescriptor(_) => unreachable!(),
+        };
+        // Check only the stored part of the prefix, the final leaf key
+        // comparison will catch any mismatch in the rest.
+        let stored_prefix = inner.stored_prefix();
+        if key.get(depth..depth + stored_prefix.len()) != Some(stored_prefix) {
+            return Ok(None);
+        }
+        depth += inner.prefix_len();
+        let next = match key.get(depth) {
+            None if depth == key.len() && !inner.terminal_leaf().is_null() => {
+                let terminal_leaf = inner.terminal_leaf();
+                Some((terminal_leaf, tree_node(buffer_manager, terminal_leaf)?))
+            }
+            None => None,
+            Some(key_byte) => child(buffer_manager, node_id, inner, *key_byte)?,
+        };
+        let Some(next) = next else {
+            return Ok(None);
+        };
+        (node_id, node) = next;
+        depth += 1;
+    }
+}
+
+/// # Errors
+/// Will return `DbError` if `tree` is not an ART, or on a corrupted tree.
+pub fn get(
+    buffer_manager: &BufferManager,
+    tree: node::Id,
+    key: &[u8],
+    snapshot_ts: u64,
+) -> Result<Option<Vec<u8>>, DbError> {
+    let root = root_at(buffer_manager, tree, snapshot_ts)?;
+    let Some(leaf_id) = find_leaf(buffer_manager, root, key)? else {
+        return Ok(None);
+    };
+    let leaf = leaf(buffer_manager, leaf_id)?;
+    Ok(leaf.value_at(snapshot_ts).map(<[u8]>::to_vec))
+}
+
+/// The commit timestamp of the newest version of the key, if it is in the
+/// tree.
+/// # Errors
+/// Will return `DbError` if `tree` is not an ART, or on a corrupted tree.
+pub fn commit_ts(
+    buffer_manager: &BufferManager,
+    tree: node::Id,
+    key: &[u8],
+) -> Result<Option<u64>, DbError> {
+    let root = descriptor(buffer_manager, tree)?.root();
+    let Some(leaf_id) = find_leaf(buffer_manager, root, key)? else {
+        return Ok(None);
+    };
+    Ok(Some(leaf(buffer_manager, leaf_id)?.commit_ts()))
+}
+
+// The first leaf with the key greater than (or equal to, if inclusive) the
+// given one, in the subtree whose keys all start with key[..depth].
+fn seek_forward(
+    buffer_manager: &BufferManager,
+    node_id: node::Id,
+    key: &[u8],
+    depth: usize,
+    inclusive: bool,
+) -> Result<Option<node::Id>, DbError> {
+    let node = tree_node(buffer_manager, node_id)?;
+    let inner = match &*node {
+        Node::Leaf(leaf) => {
+            let found = match leaf.key().cmp(key) {
+                Ordering::Greater => true,
+                Ordering::Equal => inclusive,
+                Ordering::Less => false,
+            };
+            return Ok(found.then_some(node_id));
+        }
+        Node::Inner(inner) => inner,
+        Node::ArtDescriptor(_) => unreachable!(),
+    };
+    let prefix = full_prefix(buffer_manager, node_id, depth)?;
+    let key_rest = &key[depth..];
+    let common_len = common_prefix_len(&prefix, key_rest);
+    if common_len < prefix.len() {
+        // Either the key ends within the prefix, and then the whole subtree is
+        // above it, or they differ at common_len.
+        return if common_len == key_rest.len() || prefix[common_len] > key_rest[common_len] {
+            Ok(Some(min_leaf(buffer_manager, node_id)?))
+        } else {
+            Ok(None)
+        };
+    }
+    let depth = depth + prefix.len();
+    let Some(key_byte) = key.get(depth).copied() else {
+        if inclusive && !inner.terminal_leaf().is_null() {
+            return Ok(Some(inner.terminal_leaf()));
+        }
+        return inner
+            .first_child()
+            .map(|(_, child)| min_leaf(buffer_manager, child))
+            .transpose();
+    };
+    if let Some(child) = inner.find_child(key_byte) {
+        let result = seek_forward(buffer_manager, child, key, depth + 1, inclusive)?;
+        if result.is_some() {
+            return Ok(result);
+        }
+    }
+    inner
+        .child_after(key_byte)
+        .map(|(_, child)| min_leaf(buffer_manager, child))
+        .transpose()
+}
+
+// The last leaf with the key less than (or equal to, if inclusive) the given
+// one, in the subtree whose keys all start with key[..depth].
+fn seek_backward(
+    buffer_manager: &BufferManager,
+    node_id: node::Id,
+    key: &[u8],
+    depth: usize,
+    inclusive: bool,
+) -> Result<Option<node::Id>, DbError> {
+    let node = tree_node(buffer_manager, node_id)?;
+    let inner = match &*node {
+        Node::Leaf(leaf) => {
+            let found = match leaf.key().cmp(key) {
+                Ordering::Less => true,
+                Ordering::Equal => inclusive,
+                Ordering::Greater => false,
+            };
+            return Ok(found.then_some(node_id));
+        }
+        Node::Inner(inner) => inner,
+        Node::ArtDescriptor(_) => unreachable!(),
+    };
+    let prefix = full_prefix(buffer_manager, node_id, depth)?;
+    let key_rest = &key[depth..];
+    let common_len = common_prefix_len(&prefix, key_rest);
+    if common_len < prefix.len() {
+        // Either the key ends within the prefix, and then the whole subtree is
+        // above it, or they differ at common_len.
+        return if common_len < key_rest.len() && prefix[common_len] < key_rest[common_len] {
+            Ok(Some(max_leaf(buffer_manager, node_id)?))
+        } else {
+            Ok(None)
+        };
+    }
+    let depth = depth + prefix.len();
+    let terminal_leaf = (!inner.terminal_leaf().is_null()).then_some(inner.terminal_leaf());
+    let Some(key_byte) = key.get(depth).copied() else {
+        return Ok(terminal_leaf.filter(|_| inclusive));
+    };
+    if let Some(child) = inner.find_child(key_byte) {
+        let result = seek_backward(buffer_manager, child, key, depth + 1, inclusive)?;
+        if result.is_some() {
+            return Ok(result);
+        }
+    }
+    match inner.child_before(key_byte) {
+        Some((_, child)) => Ok(Some(max_leaf(buffer_manager, child)?)),
+        None => Ok(terminal_leaf),
+    }
+}
+
+#[inline]
+fn is_above_lower(key: &[u8], lower: Bound<&[u8]>) -> bool {
+    match lower {
+        Bound::Included(lower) => key >= lower,
+        Bound::Excluded(lower) => key > lower,
+        Bound::Unbounded => true,
+    }
+}
+
+#[inline]
+fn is_below_upper(key: &[u8], upper: Bound<&[u8]>) -> bool {
+    match upper {
+        Bound::Included(upper) => key <= upper,
+        Bound::Excluded(upper) => key < upper,
+        Bound::Unbounded => true,
+    }
+}
+
+/// A node with all the keys under it starting with its path, to seek from
+/// instead of the root.
+#[derive(Debug, Clone, PartialEq, Eq)] // COV_EXCL_LINE
+#[must_use]
+pub struct Subtree {
+    node_id: node::Id,
+    path: Vec<u8>,
+}
+
+impl Subtree {
+    // The bound on the keys under the node, None if it is above all of them
+    fn lower(&self, lower: Bound<&[u8]>) -> Option<Bound<Vec<u8>>> {
+        match lower {
+            Bound::Included(key) | Bound::Excluded(key) if !key.starts_with(&self.path) => {
+                (key < self.path.as_slice()).then_some(Bound::Unbounded)
+            }
+            _ => Some(lower.map(<[u8]>::to_vec)),
+        }
+    }
+
+    // The bound on the keys under the node, None if it is below all of them
+    fn upper(&self, upper: Bound<&[u8]>) -> Option<Bound<Vec<u8>>> {
+        match upper {
+            Bound::Included(key) | Bound::Excluded(key) if !key.starts_with(&self.path) => {
+                (key > self.path.as_slice()).then_some(Bound::Unbounded)
+            }
+            _ => Some(upper.map(<[u8]>::to_vec)),
+        }
+    }
+}
+
+/// The subtree of all the keys starting with `prefix`, or None if there are no
+/// such keys.
+/// # Errors
+/// Will return `DbError` if `tree` is not an ART, or on a corrupted tree.
+pub fn prefix_subtree(
+    buffer_manager: &BufferManager,
+    tree: node::Id,
+    prefix: &[u8],
+    snapshot_ts: u64,
+) -> Result<Option<Subtree>, DbError> {
+    let mut node_id = root_at(buffer_manager, tree, snapshot_ts)?;
+    let mut depth = 0;
+    let subtree = |node_id, depth| Subtree {
+        node_id,
+        path: prefix[..depth].to_vec(),
+    };
+    while !node_id.is_null() {
+        let node = tree_node(buffer_manager, node_id)?;
+        let inner = match &*node {
+            Node::Leaf(leaf) => {
+                return Ok(leaf
+                    .key()
+                    .starts_with(prefix)
+                    .then(|| subtree(node_id, depth)));
+            }
+            Node::Inner(inner) => inner,
+            Node::ArtDescriptor(_) => unreachable!(),
+        };
+        let node_prefix = full_prefix(buffer_manager, node_id, depth)?;
+        let prefix_rest = &prefix[depth..];
+        if prefix_rest.len() <= node_prefix.len() {
+            // The prefix ends within this node
+            return Ok(node_prefix
+                .starts_with(prefix_rest)
+                .then(|| subtree(node_id, depth)));
+        }
+        if !prefix_rest.starts_with(&node_prefix) {
+            return Ok(None);
+        }
+        depth += node_prefix.len();
+        match inner.find_child(prefix[depth]) {
+            Some(child) => node_id = child,
+            None => return Ok(None),
+        }
+        depth += 1;
+    }
+    Ok(None)
+}
+
+/// The entry with the smallest key within the bounds.
+/// # Errors
+/// Will return `DbError` if `tree` is not an ART, or on a corrupted tree.
+pub fn first_in_range(
+    buffer_manager: &BufferManager,
+    tree: node::Id,
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+    snapshot_ts: u64,
+) -> Result<Option<Entry>, DbError> {
+    let root = root_at(buffer_manager, tree, snapshot_ts)?;
+    if root.is_null() {
+        return Ok(None);
+    }
+    let subtree = Subtree {
+        node_id: root,
+        path: Vec::new(),
+    };
+    first_in_subtree(buffer_manager, &subtree, lower, upper, snapshot_ts)
+}
+
+/// The entry with the smallest key within the bounds under the subtree.
+/// # Errors
+/// Will return `DbError` on a corrupted tree.
+pub fn first_in_subtree(
+    buffer_manager: &BufferManager,
+    subtree: &Subtree,
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+    snapshot_ts: u64,
+) -> Result<Option<Entry>, DbError> {
+    let (node_id, depth) = (subtree.node_id, subtree.path.len());
+    let Some(mut lower) = subtree.lower(lower) else {
+        return Ok(None);
+    };
+    // Skip over the keys not seen by the snapshot
+    loop {
+        let leaf_id = match &lower {
+            Bound::Included(key) => seek_forward(buffer_manager, node_id, key, depth, true)?,
+            Bound::Excluded(key) => seek_forward(buffer_manager, node_id, key, depth, false)?,
+            Bound::Unbounded => Some(min_leaf(buffer_manager, node_id)?),
+        };
+        let Some(leaf_id) = leaf_id else {
+            return Ok(None);
+        };
+        let leaf = leaf(buffer_manager, leaf_id)?;
+        if !is_below_upper(leaf.key(), upper) {
+            return Ok(None);
+        }
+        if let Some(value) = leaf.value_at(snapshot_ts) {
+            return Ok(Some((leaf.key().to_vec(), value.to_vec())));
+        }
+        lower = Bound::Excluded(leaf.key().to_vec());
+    }
+}
+
+/// The entry with the largest key within the bounds.
+/// # Errors
+/// Will return `DbError` if `tree` is not an ART, or on a corrupted tree.
+pub fn last_in_range(
+    buffer_manager: &BufferManager,
+    tree: node::Id,
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+    snapshot_ts: u64,
+) -> Result<Option<Entry>, DbError> {
+    let root = root_at(buffer_manager, tree, snapshot_ts)?;
+    if root.is_null() {
+        return Ok(None);
+    }
+    let subtree = Subtree {
+        node_id: root,
+        path: Vec::new(),
+    };
+    last_in_subtree(buffer_manager, &subtree, lower, upper, snapshot_ts)
+}
+
+/// The entry with the largest key within the bounds under the subtree.
+/// # Errors
+/// Will return `DbError` on a corrupted tree.
+pub fn last_in_subtree(
+    buffer_manager: &BufferManager,
+    subtree: &Subtree,
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+    snapshot_ts: u64,
+) -> Result<Option<Entry>, DbError> {
+    let (node_id, depth) = (subtree.node_id, subtree.path.len());
+    let Some(mut upper) = subtree.upper(upper) else {
+        return Ok(None);
+    };
+    loop {
+        let leaf_id = match &upper {
+            Bound::Included(key) => seek_backward(buffer_manager, node_id, key, depth, true)?,
+            Bound::Excluded(key) => seek_backward(buffer_manager, node_id, key, depth, false)?,
+            Bound::Unbounded => Some(max_leaf(buffer_manager, node_id)?),
+        };
+        let Some(leaf_id) = leaf_id else {
+            return Ok(None);
+        };
+        let leaf = leaf(buffer_manager, leaf_id)?;
+        if !is_above_lower(leaf.key(), lower) {
+            return Ok(None);
+        }
+        if let Some(value) = leaf.value_at(snapshot_ts) {
+            return Ok(Some((leaf.key().to_vec(), value.to_vec())));
+        }
+        upper = Bound::Excluded(leaf.key().to_vec());
+    }
+}
+
+fn count_leaves(
+    buffer_manager: &BufferManager,
+    node_id: node::Id,
+    snapshot_ts: u64,
+) -> Result<usize, DbError> {
+    let mut result = 0;
+    let mut stack = vec![node_id];
+    while let Some(node_id) = stack.pop() {
+        let node = tree_node(buffer_manager, node_id)?;
+        let inner = match &*node {
+            Node::Leaf(leaf) => {
+                result += usize::from(leaf.value_at(snapshot_ts).is_some());
+                continue;
+            }
+            Node::Inner(inner) => inner,
+            Node::ArtDescriptor(_) => unreachable!(),
+        };
+        if !inner.terminal_leaf().is_null() {
+            stack.push(inner.terminal_leaf());
+        }
+        let mut child = inner.first_child();
+        while let Some((key_byte, child_id)) = child {
+            stack.push(child_id);
+            child = inner.child_after(key_byte);
+        }
+    }
+    Ok(result)
+}
+
+/// The number of the entries whose keys start with `prefix`, found by walking
+/// the subtree of the prefix without copying any values.
+/// # Errors
+/// Will return `DbError` if `tree` is not an ART, or on a corrupted tree.
+pub fn count_prefix(
+    buffer_manager: &BufferManager,
+    tree: node::Id,
+    prefix: &[u8],
+    snapshot_ts: u64,
+) -> Result<usize, DbError> {
+    match prefix_subtree(buffer_manager, tree, prefix, snapshot_ts)? {
+        Some(subtree) => count_leaves(buffer_manager, subtree.node_id, snapshot_ts),
+        None => Ok(0),
+    }
+}
+
+/// The smallest key above all the keys starting with `prefix`, if any.
+#[must_use]
+pub fn prefix_upper_bound(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut result = prefix.to_vec();
+    while let Some(last_byte) = result.pop() {
+        if last_byte < u8::MAX {
+            result.push(last_byte + 1);
+            return Bound::Excluded(result);
+        }
+    }
+    Bound::Unbounded
+}
+
+// Puts a leaf with the given key under a fresh inner node at depth.
+fn add_leaf(
+    buffer_manager: &BufferManager,
+    inner_id: node::Id,
+    leaf_id: node::Id,
+    key: &[u8],
+    depth: usize,
+) -> Result<(), DbError> {
+    let mut inner = inner_mut(buffer_manager, inner_id)?;
+    match key.get(depth) {
+        Some(key_byte) => inner.add_child(*key_byte, leaf_id),
+        None => inner.set_terminal_leaf(leaf_id),
+    }
+    Ok(())
+}
+
+/// Sets the value committed at the timestamp. Returns whether a new key was
+/// inserted, as opposed to updating an existing one.
+/// # Errors
+/// Will return `DbError` if `tree` is not an ART, or on a corrupted tree.
+pub fn upsert(
+    buffer_manager: &BufferManager,
+    tree: node::Id,
+    key: &[u8],
+    value: &[u8],
+    commit_ts: u64,
+) -> Result<bool, DbError> {
+    let new_leaf = || Node::Leaf(node::Leaf::new_at(key, value, commit_ts));
+    let mut node_id = descriptor(buffer_manager, tree)?.root();
+    let mut slot = Slot::Root;
+    let mut depth = 0;
+    loop {
+        if node_id.is_null() {
+            let leaf_id = buffer_manager.new_node(new_leaf())?;
+            set_slot(buffer_manager, tree, slot, leaf_id)?;
+            return Ok(true);
+        }
+        let leaf_key = match &*tree_node(buffer_manager, node_id)? {
+            Node::Leaf(leaf) => Some(leaf.key().to_vec()),
+            _ => None,
+        };
+        if let Some(leaf_key) = leaf_key {
+            if leaf_key == key {
+                let mut leaf = leaf_mut(buffer_manager, node_id)?;
+                let is_new = leaf.value().is_none();
+                leaf.update(Some(value), commit_ts);
+                return Ok(is_new);
+            }
+            // Expand the leaf into an inner node with the two leaves
+            let common_len = common_prefix_len(&leaf_key[depth..], &key[depth..]);
+            let new_inner = node::Inner::with_prefix(&key[depth..depth + common_len]);
+            let new_inner_id = buffer_manager.new_node(Node::Inner(new_inner))?;
+            let new_leaf_id = buffer_manager.new_node(new_leaf())?;
+            let inner_depth = depth + common_len;
+            add_leaf(
+                buffer_manager,
+                new_inner_id,
+                node_id,
+                &leaf_key,
+                inner_depth,
+            )?;
+            add_leaf(buffer_manager, new_inner_id, new_leaf_id, key, inner_depth)?;
+            set_slot(buffer_manager, tree, slot, new_inner_id)?;
+            return Ok(true);
+        }
+        let prefix = full_prefix(buffer_manager, node_id, depth)?;
+        let common_len = common_prefix_len(&prefix, &key[depth..]);
+        if common_len < prefix.len() {
+            // Split the prefix: a new inner node takes its common part, and the
+            // old node keeps what remains after the distinguishing byte.
+            let new_inner_id = buffer_manager
+                .new_node(Node::Inner(node::Inner::with_prefix(&prefix[..common_len])))?;
+            let remaining_prefix = &prefix[common_len + 1..];
+            inner_mut(buffer_manager, node_id)?
+                .set_prefix(remaining_prefix, remaining_prefix.len());
+            inner_mut(buffer_manager, new_inner_id)?.add_child(prefix[common_len], node_id);
+            let new_leaf_id = buffer_manager.new_node(new_leaf())?;
+            add_leaf(
+                buffer_manager,
+                new_inner_id,
+                new_leaf_id,
+                key,
+                depth + common_len,
+            )?;
+            set_slot(buffer_manager, tree, slot, new_inner_id)?;
+            return Ok(true);
+        }
+        depth += prefix.len();
+        let Some(key_byte) = key.get(depth).copied() else {
+            slot = Slot::Terminal(node_id);
+            node_id = inner(buffer_manager, node_id)?.terminal_leaf();
+            continue;
+        };
+        let child = inner(buffer_manager, node_id)?.find_child(key_byte);
+        let Some(child) = child else {
+            let leaf_id = buffer_manager.new_node(new_leaf())?;
+            inner_mut(buffer_manager, node_id)?.add_child(key_byte, leaf_id);
+            return Ok(true);
+        };
+        slot = Slot::Child(node_id, key_byte);
+        node_id = child;
+        depth += 1;
+    }
+}
+
+/// Adds the key committed at the timestamp, unless it is present in the
+/// newest version of the tree.
+/// # Errors
+/// Will return `DbError::KeyExists` if the key is present, without changing
+/// the tree, or another `DbError` if `tree` is not an ART, or on a corrupted
+/// tree.
+pub fn insert(
+    buffer_manager: &BufferManager,
+    tree: node::Id,
+    key: &[u8],
+    value: &[u8],
+    commit_ts: u64,
+) -> Result<(), DbError> {
+    if get(buffer_manager, tree, key, u64::MAX)?.is_some() {
+        return Err(DbError::KeyExists);
+    }
+    upsert(buffer_manager, tree, key, value, commit_ts)?;
+    Ok(())
+}
+
+// Replaces an inner node that is left with a single entry by that entry.
+fn compress(
+    buffer_manager: &BufferManager,
+    tree: node::Id,
+    slot: Slot,
+    node_id: node::Id,
+) -> Result<(), DbError> {
+    let inner = inner(buffer_manager, node_id)?;
+    let (replacement, replacement_prefix) = match (inner.len(), inner.terminal_leaf().is_null()) {
+        (0, false) => (inner.terminal_leaf(), None),
+        (1, true) => {
+            let Some((key_byte, child)) = inner.first_child() else {
+                unreachable!("No child in an inner node with one child");
+            };
+            let child_node = tree_node(buffer_manager, child)?;
+            let child_prefix = child_node.as_inner().map(|child_inner| {
+                // Concatenate the prefixes. Whatever is not stored in the
+                // parent prefix is not stored in the concatenation either.
+                let mut prefix = inner.stored_prefix().to_vec();
+                if inner.prefix_len() < node::Inner::MAX_STORED_PREFIX_LEN {
+                    prefix.push(key_byte);
+                    prefix.extend_from_slice(child_inner.stored_prefix());
+                }
+                let prefix_len = inner.prefix_len() + 1 + child_inner.prefix_len();
+                (prefix, prefix_len)
+            });
+            (child, child_prefix)
+        }
+        _ => return Ok(()),
+    };
+    drop(inner);
+    if let Some((prefix, prefix_len)) = replacement_prefix {
+        inner_mut(buffer_manager, replacement)?.set_prefix(&prefix, prefix_len);
+    }
+    set_slot(buffer_manager, tree, slot, replacement)?;
+    buffer_manager.remove(node_id)?;
+    Ok(())
+}
+
+/// Deletes the key at the commit timestamp. Returns whether the key was found.
+/// # Errors
+/// Will return `DbError` if `tree` is not an ART, or on a corrupted tree.
+pub fn delete(
+    buffer_manager: &BufferManager,
+    tree: node::Id,
+    key: &[u8],
+    commit_ts: u64,
+) -> Result<bool, DbError> {
+    let root = descriptor(buffer_manager, tree)?.root();
+    let Some(leaf_id) = find_leaf(buffer_manager, root, key)? else {
+        return Ok(false);
+    };
+    if leaf(buffer_manager, leaf_id)?.value().is_none() {
+        return Ok(false);
+    }
+    if commit_ts == 0 {
+        remove_leaf(buffer_manager, tree, key)?;
+    } else {
+        leaf_mut(buffer_manager, leaf_id)?.update(None, commit_ts);
+    }
+    Ok(true)
+}
+
+/// Drops the versions of the key not read by any snapshot at or after the
+/// timestamp, removing the key if it is deleted for all of them.
+/// # Errors
+/// Will return `DbError` if `tree` is not an ART, or on a corrupted tree.
+pub fn purge(
+    buffer_manager: &BufferManager,
+    tree: node::Id,
+    key: &[u8],
+    oldest_snapshot_ts: u64,
+) -> Result<(), DbError> {
+    let root = descriptor(buffer_manager, tree)?.root();
+    let Some(leaf_id) = find_leaf(buffer_manager, root, key)? else {
+        return Ok(());
+    };
+    if leaf_mut(buffer_manager, leaf_id)?.purge(oldest_snapshot_ts) {
+        remove_leaf(buffer_manager, tree, key)?;
+    }
+    Ok(())
+}
+
+// Removes the leaf of the key from the tree, returning whether it was found
+fn remove_leaf(
+    buffer_manager: &BufferManager,
+    tree: node::Id,
+    key: &[u8],
+) -> Result<bool, DbError> {
+    let mut node_id = descriptor(buffer_manager, tree)?.root();
+    let mut slot = Slot::Root;
+    // The parent of the current node and where the parent is referenced from
+    let mut parent = None;
+    let mut depth = 0;
+    loop {
+        if node_id.is_null() {
+            return Ok(false);
+        }
+        let node = tree_node(buffer_manager, node_id)?;
+        let inner = match &*node {
+            Node::Leaf(leaf) => {
+                if leaf.key() != key {
+                    return Ok(false);
+                }
+                break;
+            }
+            Node::Inner(inner) => inner,
+            Node::ArtDescriptor(_) => unreachable!(),
+        };
+        let stored_prefix = inner.stored_prefix();
+        if key.get(depth..depth + stored_prefix.len()) != Some(stored_prefix) {
+            return Ok(false);
+        }
+        depth += inner.prefix_len();
+        let child_slot = match key.get(depth) {
+            None if depth == key.len() => Slot::Terminal(node_id),
+            None => return Ok(false),
+            Some(key_byte) => Slot::Child(node_id, *key_byte),
+        };
+        let child = match child_slot {
+            Slot::Terminal(_) => inner.terminal_leaf(),
+            Slot::Child(_, key_byte) => inner.find_child(key_byte).unwrap_or(node::Id::NULL),
+            Slot::Root => unreachable!(),
+        };
+        parent = Some((slot, node_id));
+        slot = child_slot;
+        node_id = child;
+        depth += 1;
+    }
+    match slot {
+        Slot::Root => descriptor_mut(buffer_manager, tree)?.set_root(node::Id::NULL),
+        Slot::Child(parent_id, key_byte) => {
+            let removed = inner_mut(buffer_manager, parent_id)?.remove_child(key_byte);
+            debug_assert_eq!(removed, node_id);
+        }
+        Slot::Terminal(parent_id) => {
+            inner_mut(buffer_manager, parent_id)?.set_terminal_leaf(node::Id::NULL);
+        }
+    }
+    buffer_manager.remove(node_id)?;
+    if let Some((parent_slot, parent_id)) = parent {
+        compress(buffer_manager, tree, parent_slot, parent_id)?;
+    }
+    Ok(true)
+}
+
+/// Removes all the nodes of the tree, including its descriptor.
+/// # Errors
+/// Will return `DbError` if `tree` is not an ART, or on a corrupted tree.
+pub fn destroy(buffer_manager: &BufferManager, tree: node::Id) -> Result<(), DbError> {
+    let root = descriptor(buffer_manager, tree)?.root();
+    buffer_manager.remove(tree)?;
+    let mut stack = vec![root];
+    while let Some(node_id) = stack.pop() {
+        if node_id.is_null() {
+            continue;
+        }
+        if let Node::Inner(inner) = &*tree_node(buffer_manager, node_id)? {
+            stack.push(inner.terminal_leaf());
+            let mut child = inner.first_child();
+            while let Some((key_byte, child_id)) = child {
+                stack.push(child_id);
+                child = inner.child_after(key_byte);
+            }
+        }
+        buffer_manager.remove(node_id)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        commit_ts, count_prefix, create, delete, destroy, exists, first_in_range, first_in_subtree,
+        get, insert, last_in_range, last_in_subtree, prefix_subtree, prefix_upper_bound, purge,
+        upsert, Entry,
+    };
+    use crate::buffer_manager::tests::{checkpoint, new_buffer_manager};
+    use crate::buffer_manager::BufferManager;
+    use crate::node;
+    use crate::DbError;
+    use std::collections::BTreeMap;
+    use std::ops::Bound;
+
+    const LATEST: u64 = u64::MAX;
+    const UNVERSIONED: u64 = 0;
+
+    fn new_tree() -> (BufferManager, node::Id) {
+        let buffer_manager = new_buffer_manager(node::Id::from(2));
+        let tree = node::Id::from(1);
+        create(&buffer_manager, tree).unwrap();
+        (buffer_manager, tree)
+    }
+
+    // A small deterministic generator, good enough for shuffling test keys
+    fn xorshift(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn empty_tree() {
+        let (buffer_manager, tree) = new_tree();
+        assert_eq!(get(&buffer_manager, tree, b"", LATEST).unwrap(), None);
+        assert_eq!(get(&buffer_manager, tree, b"a", LATEST).unwrap(), None);
+        assert!(!delete(&buffer_manager, tree, b"a", UNVERSIONED).unwrap());
+    }
+
+    #[test]
+    fn not_a_tree() {
+        let (buffer_manager, tree) = new_tree();
+        let wrong_tree = tree.next();
+        assert!(matches!(
+            get(&buffer_manager, wrong_tree, b"a", LATEST),
+            Err(DbError::NotArtDescriptor { node_id }) if node_id == wrong_tree
+        ));
+        assert!(upsert(&buffer_manager, wrong_tree, b"a", b"b", UNVERSIONED).is_err());
+        assert!(delete(&buffer_manager, wrong_tree, b"a", UNVERSIONED).is_err());
+    }
+
+    #[test]
+    fn keys_prefixes_of_each_other() {
+        let (buffer_manager, tree) = new_tree();
+        for key in [&b"abc"[..], b"", b"a", b"ab", b"abd"] {
+            assert!(upsert(&buffer_manager, tree, key, key, UNVERSIONED).unwrap());
+        }
+        for key in [&b"abc"[..], b"", b"a", b"ab", b"abd"] {
+            assert_eq!(
+                get(&buffer_manager, tree, key, LATEST).unwrap().unwrap(),
+                key
+            );
+        }
+        assert!(delete(&buffer_manager, tree, b"ab", UNVERSIONED).unwrap());
+        assert_eq!(get(&buffer_manager, tree, b"ab", LATEST).unwrap(), None);
+        assert_eq!(
+            get(&buffer_manager, tree, b"abc", LATEST).unwrap().unwrap(),
+            b"abc"
+        );
+        assert_eq!(
+            get(&buffer_manager, tree, b"a", LATEST).unwrap().unwrap(),
+            b"a"
+        );
+    }
+
+    #[test]
+    fn update_existing() {
+        let (buffer_manager, tree) = new_tree();
+        assert!(upsert(&buffer_manager, tree, b"key", b"1", UNVERSIONED).unwrap());
+        assert!(!upsert(&buffer_manager, tree, b"key", b"2", UNVERSIONED).unwrap());
+        assert_eq!(
+            get(&buffer_manager, tree, b"key", LATEST).unwrap().unwrap(),
+            b"2"
+        );
+    }
+
+    #[test]
+    fn insert_existing() {
+        let (buffer_manager, tree) = new_tree();
+        insert(&buffer_manager, tree, b"key", b"1", 1).unwrap();
+        assert!(matches!(
+            insert(&buffer_manager, tree, b"key", b"2", 2),
+            Err(DbError::KeyExists)
+        ));
+        assert_eq!(commit_ts(&buffer_manager, tree, b"key").unwrap(), Some(1));
+        // A deleted key can be inserted again
+        assert!(delete(&buffer_manager, tree, b"key", 3).unwrap());
+        insert(&buffer_manager, tree, b"key", b"4", 4).unwrap();
+        assert_eq!(
+            get(&buffer_manager, tree, b"key", LATEST).unwrap().unwrap(),
+            b"4"
+        );
+    }
+
+    #[test]
+    fn delete_all_frees_nodes() {
+        let (buffer_manager, tree) = new_tree();
+        assert!(upsert(&buffer_manager, tree, b"long key", b"1", UNVERSIONED).unwrap());
+        assert!(upsert(&buffer_manager, tree, b"long kez", b"1", UNVERSIONED).unwrap());
+        assert!(delete(&buffer_manager, tree, b"long key", UNVERSIONED).unwrap());
+        assert!(!delete(&buffer_manager, tree, b"long key", UNVERSIONED).unwrap());
+        assert!(delete(&buffer_manager, tree, b"long kez", UNVERSIONED).unwrap());
+        assert_eq!(buffer_manager.node_count(), 1);
+    }
+
+    #[test]
+    fn destroy_frees_all_nodes() {
+        let (buffer_manager, tree) = new_tree();
+        for key in [&b""[..], b"a", b"ab", b"long key", b"long kez", b"b"] {
+            assert!(upsert(&buffer_manager, tree, key, key, UNVERSIONED).unwrap());
+        }
+        destroy(&buffer_manager, tree).unwrap();
+        assert!(!exists(&buffer_manager, tree, LATEST));
+        assert_eq!(buffer_manager.node_count(), 0);
+        assert!(destroy(&buffer_manager, tree).is_err());
+    }
+
+    #[test]
+    fn churn_reuses_space() {
+        let (buffer_manager, tree) = new_tree();
+        let keys: Vec<_> = (0..500_u32).map(u32::to_be_bytes).collect();
+        let mut max_pages = 0;
+        for round in 0..5 {
+            for key in &keys {
+                assert!(upsert(&buffer_manager, tree, key, key, UNVERSIONED).unwrap());
+            }
+            buffer_manager.flush().unwrap();
+            // Give or take the page of the descriptor record left in between
+            if round == 0 {
+                max_pages = buffer_manager.data_file_page_count() + 1;
+            }
+            assert!(buffer_manager.data_file_page_count() <= max_pages);
+            for key in &keys {
+                assert!(delete(&buffer_manager, tree, key, UNVERSIONED).unwrap());
+            }
+            buffer_manager.flush().unwrap();
+            assert_eq!(buffer_manager.node_count(), 1);
+        }
+        // Only the descriptor is left, and all the other IDs are free. The
+        // checkpoint moves it off its sparse page.
+        assert!(buffer_manager.free_node_ids().len() >= keys.len());
+        let image = checkpoint(&buffer_manager, 0);
+        assert_eq!(image.page_count(), 1);
+    }
+
+    #[test]
+    fn random_operations_match_btree_map() {
+        let (buffer_manager, tree) = new_tree();
+        let mut model = BTreeMap::new();
+        let mut state = 0x2545_F491_4F6C_DD1D;
+        for _ in 0..5000 {
+            let r = xorshift(&mut state);
+            let key_len = usize::try_from(r % 4).unwrap();
+            let key: Vec<u8> = (0..key_len)
+                .map(|i| u8::try_from((r >> (8 + i * 8)) % 8).unwrap() * 31)
+                .collect();
+            let value = r.to_ne_bytes();
+            if r.is_multiple_of(3) {
+                let deleted = delete(&buffer_manager, tree, &key, UNVERSIONED).unwrap();
+                assert_eq!(deleted, model.remove(&key).is_some());
+            } else {
+                let inserted = upsert(&buffer_manager, tree, &key, &value, UNVERSIONED).unwrap();
+                assert_eq!(
+                    inserted,
+                    model.insert(key.clone(), value.to_vec()).is_none()
+                );
+            }
+            assert_eq!(
+                get(&buffer_manager, tree, &key, LATEST).unwrap(),
+                model.get(&key).cloned()
+            );
+        }
+        for (key, value) in &model {
+            assert_eq!(
+                get(&buffer_manager, tree, key, LATEST).unwrap().as_ref(),
+                Some(value)
+            );
+        }
+    }
+
+    fn random_bound(state: &mut u64) -> Bound<Vec<u8>> {
+        let r = xorshift(state);
+        let key_len = usize::try_from(r % 4).unwrap();
+        let key = (0..key_len)
+            .map(|i| u8::try_from((r >> (8 + i * 8)) % 8).unwrap() * 31)
+            .collect();
+        match r % 5 {
+            0 => Bound::Unbounded,
+            1 | 2 => Bound::Included(key),
+            _ => Bound::Excluded(key),
+        }
+    }
+
+    #[test]
+    fn random_range_queries_match_btree_map() {
+        let (buffer_manager, tree) = new_tree();
+        let mut model = BTreeMap::new();
+        let mut state = 0x9E37_79B9_7F4A_7C15;
+        for i in 0..300_u32 {
+            let key: Vec<u8> = (0..(i % 4))
+                .map(|j| u8::try_from((xorshift(&mut state) >> j) % 8).unwrap() * 31)
+                .collect();
+            upsert(&buffer_manager, tree, &key, &i.to_ne_bytes(), UNVERSIONED).unwrap();
+            model.insert(key, i.to_ne_bytes().to_vec());
+        }
+        for _ in 0..2000 {
+            let lower = random_bound(&mut state);
+            let upper = random_bound(&mut state);
+            let lower = lower.as_ref().map(Vec::as_slice);
+            let upper = upper.as_ref().map(Vec::as_slice);
+            let first = first_in_range(&buffer_manager, tree, lower, upper, LATEST).unwrap();
+            let last = last_in_range(&buffer_manager, tree, lower, upper, LATEST).unwrap();
+            let valid_range = match (lower, upper) {
+                (Bound::Included(l), Bound::Included(u)) => l <= u,
+                (Bound::Included(l) | Bound::Excluded(l), Bound::Excluded(u))
+                | (Bound::Excluded(l), Bound::Included(u)) => l < u,
+                _ => true,
+            };
+            if valid_range {
+                let mut expected = model.range::<[u8], _>((lower, upper));
+                let expected_first = expected.next().map(|(k, v)| (k.clone(), v.clone()));
+                let expected_last = expected
+                    .next_back()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .or_else(|| expected_first.clone());
+                assert_eq!(first, expected_first);
+                assert_eq!(last, expected_last);
+            } else {
+                assert_eq!(first, None);
+                assert_eq!(last, None);
+            }
+        }
+    }
+
+    #[test]
+    fn count_by_prefix() {
+        let (buffer_manager, tree) = new_tree();
+        assert_eq!(count_prefix(&buffer_manager, tree, b"", LATEST).unwrap(), 0);
+        for key in [&b"t1|a|1"[..], b"t1|a|2", b"t1|b|1", b"t2|a|1", b"t1"] {
+            upsert(&buffer_manager, tree, key, b"", UNVERSIONED).unwrap();
+        }
+        assert_eq!(count_prefix(&buffer_manager, tree, b"", LATEST).unwrap(), 5);
+        assert_eq!(
+            count_prefix(&buffer_manager, tree, b"t1", LATEST).unwrap(),
+            4
+        );
+        assert_eq!(
+            count_prefix(&buffer_manager, tree, b"t1|", LATEST).unwrap(),
+            3
+        );
+        assert_eq!(
+            count_prefix(&buffer_manager, tree, b"t1|a|", LATEST).unwrap(),
+            2
+        );
+        assert_eq!(
+            count_prefix(&buffer_manager, tree, b"t1|a|2", LATEST).unwrap(),
+            1
+        );
+        assert_eq!(
+            count_prefix(&buffer_manager, tree, b"t3", LATEST).unwrap(),
+            0
+        );
+        assert_eq!(
+            count_prefix(&buffer_manager, tree, b"t1|a|22", LATEST).unwrap(),
+            0
+        );
+        // The bounds outside the subtree
+        let subtree = prefix_subtree(&buffer_manager, tree, b"t1|", LATEST)
+            .unwrap()
+            .unwrap();
+        let key = |entry: Option<Entry>| entry.map(|(key, _)| key);
+        let first = |lower, upper| {
+            key(first_in_subtree(&buffer_manager, &subtree, lower, upper, LATEST).unwrap())
+        };
+        let last = |lower, upper| {
+            key(last_in_subtree(&buffer_manager, &subtree, lower, upper, LATEST).unwrap())
+        };
+        let below = Bound::Included(&b"t"[..]);
+        let above = Bound::Excluded(&b"t2"[..]);
+        assert_eq!(first(below, above), Some(b"t1|a|1".to_vec()));
+        assert_eq!(last(below, above), Some(b"t1|b|1".to_vec()));
+        assert_eq!(first(above, Bound::Unbounded), None);
+        assert_eq!(last(Bound::Unbounded, below), None);
+    }
+
+    #[test]
+    fn upper_bound_of_prefix() {
+        assert_eq!(prefix_upper_bound(b""), Bound::Unbounded);
+        assert_eq!(prefix_upper_bound(b"ab"), Bound::Excluded(b"ac".to_vec()));
+        assert_eq!(
+            prefix_upper_bound(&[1, 0xFF, 0xFF]),
+            Bound::Excluded(vec![2])
+        );
+        assert_eq!(prefix_upper_bound(&[0xFF]), Bound::Unbounded);
+    }
+
+    #[test]
+    fn single_key_is_single_leaf() {
+        let (buffer_manager, tree) = new_tree();
+        let key = [7; 200];
+        assert!(upsert(&buffer_manager, tree, &key, b"v", UNVERSIONED).unwrap());
+        // The descriptor and the leaf
+        assert_eq!(buffer_manager.node_count(), 2);
+        assert_eq!(
+            get(&buffer_manager, tree, &key, LATEST).unwrap().unwrap(),
+            b"v"
+        );
+        assert_eq!(
+            get(&buffer_manager, tree, &key[..199], LATEST).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn long_common_prefix_compressed() {
+        let (buffer_manager, tree) = new_tree();
+        let mut keys = Vec::new();
+        for i in 0..=u8::MAX {
+            let mut key = vec![b'x'; 150];
+            key.push(i);
+            key.extend_from_slice(&[b'y'; 40]);
+            keys.push(key);
+        }
+        for key in &keys {
+            assert!(upsert(&buffer_manager, tree, key, key, UNVERSIONED).unwrap());
+        }
+        // The descriptor, a single inner node and the leaves
+        assert_eq!(buffer_manager.node_count(), 2 + keys.len());
+        for key in &keys {
+            assert_eq!(
+                get(&buffer_manager, tree, key, LATEST).unwrap().as_ref(),
+                Some(key)
+            );
+            let mut mismatch = key.clone();
+            mismatch[100] = b'z';
+            assert_eq!(get(&buffer_manager, tree, &mismatch, LATEST).unwrap(), None);
+        }
+        assert_eq!(
+            count_prefix(&buffer_manager, tree, &[b'x'; 150], LATEST).unwrap(),
+            256
+        );
+        assert_eq!(
+            count_prefix(&buffer_manager, tree, &[b'x'; 151], LATEST).unwrap(),
+            1
+        );
+        assert_eq!(
+            count_prefix(&buffer_manager, tree, &keys[5][..160], LATEST).unwrap(),
+            1
+        );
+        assert_eq!(
+            count_prefix(&buffer_manager, tree, &[b'y'; 10], LATEST).unwrap(),
+            0
+        );
+        for key in &keys[1..] {
+            assert!(delete(&buffer_manager, tree, key, UNVERSIONED).unwrap());
+        }
+        // Collapsed back to a single leaf
+        assert_eq!(buffer_manager.node_count(), 2);
+        assert_eq!(
+            get(&buffer_manager, tree, &keys[0], LATEST)
+                .unwrap()
+                .as_ref(),
+            Some(&keys[0])
+        );
+    }
+
+    #[test]
+    fn split_long_prefix() {
+        let (buffer_manager, tree) = new_tree();
+        let a = [&[1; 30][..], b"a"].concat();
+        let b = [&[1; 30][..], b"b"].concat();
+        let c = [&[1; 20][..], &[2; 10], b"c"].concat();
+        let d = [&[1; 5][..]].concat();
+        for key in [&a, &b, &c, &d] {
+            assert!(upsert(&buffer_manager, tree, key, key, UNVERSIONED).unwrap());
+        }
+        for key in [&a, &b, &c, &d] {
+            assert_eq!(
+                get(&buffer_manager, tree, key, LATEST).unwrap().as_ref(),
+                Some(key)
+            );
+        }
+        let first = first_in_range(
+            &buffer_manager,
+            tree,
+            Bound::Excluded(&d),
+            Bound::Unbounded,
+            LATEST,
+        );
+        assert_eq!(first.unwrap().unwrap().0, a);
+        let last = last_in_range(
+            &buffer_manager,
+            tree,
+            Bound::Unbounded,
+            Bound::Excluded(&c),
+            LATEST,
+        );
+        assert_eq!(last.unwrap().unwrap().0, b);
+        assert!(delete(&buffer_manager, tree, &c, UNVERSIONED).unwrap());
+        assert!(delete(&buffer_manager, tree, &d, UNVERSIONED).unwrap());
+        for key in [&a, &b] {
+            assert_eq!(
+                get(&buffer_manager, tree, key, LATEST).unwrap().as_ref(),
+                Some(key)
+            );
+        }
+        assert_eq!(
+            count_prefix(&buffer_manager, tree, &[1; 30], LATEST).unwrap(),
+            2
+        );
+    }
+
+    fn random_long_key(state: &mut u64) -> Vec<u8> {
+        let r = xorshift(state);
+        // Long shared prefixes, differing at various depths
+        let mut key = vec![b'p'; usize::try_from(r % 3).unwrap() * 12];
+        for i in 0..usize::try_from((r >> 8) % 4).unwrap() {
+            key.push(u8::try_from((r >> (16 + i * 4)) % 3).unwrap());
+            key.extend_from_slice(&[b'q'; 9]);
+        }
+        key
+    }
+
+    #[test]
+    fn random_long_keys_match_btree_map() {
+        let (buffer_manager, tree) = new_tree();
+        let mut model = BTreeMap::new();
+        let mut state = 0xD1B5_4A32_D192_ED03;
+        for i in 0..4000_u32 {
+            let key = random_long_key(&mut state);
+            if xorshift(&mut state).is_multiple_of(3) {
+                let deleted = delete(&buffer_manager, tree, &key, UNVERSIONED).unwrap();
+                assert_eq!(deleted, model.remove(&key).is_some());
+            } else {
+                let inserted =
+                    upsert(&buffer_manager, tree, &key, &i.to_ne_bytes(), UNVERSIONED).unwrap();
+                assert_eq!(
+                    inserted,
+                    model.insert(key, i.to_ne_bytes().to_vec()).is_none()
+                );
+            }
+            let probe = random_long_key(&mut state);
+            assert_eq!(
+                get(&buffer_manager, tree, &probe, LATEST).unwrap(),
+                model.get(&probe).cloned()
+            );
+            let first = first_in_range(
+                &buffer_manager,
+                tree,
+                Bound::Included(&probe),
+                Bound::Unbounded,
+                LATEST,
+            )
+            .unwrap();
+            let expected = model
+                .range::<[u8], _>((Bound::Included(&probe[..]), Bound::Unbounded))
+                .next()
+                .map(|(k, v)| (k.clone(), v.clone()));
+            assert_eq!(first, expected);
+            let last = last_in_range(
+                &buffer_manager,
+                tree,
+                Bound::Unbounded,
+                Bound::Excluded(&probe),
+                LATEST,
+            )
+            .unwrap();
+            let expected = model
+                .range::<[u8], _>((Bound::Unbounded, Bound::Excluded(&probe[..])))
+                .next_back()
+                .map(|(k, v)| (k.clone(), v.clone()));
+            assert_eq!(last, expected);
+            let prefix = &probe[..probe.len() / 2];
+            let expected_count = model.keys().filter(|k| k.starts_with(prefix)).count();
+            assert_eq!(
+                count_prefix(&buffer_manager, tree, prefix, LATEST).unwrap(),
+                expected_count
+            );
+            let subtree = prefix_subtree(&buffer_manager, tree, prefix, LATEST).unwrap();
+            assert_eq!(subtree.is_some(), expected_count > 0);
+            if let Some(subtree) = subtree {
+                let upper = prefix_upper_bound(prefix);
+                let upper = upper.as_ref().map(Vec::as_slice);
+                let mut expected = model
+                    .range::<[u8], _>((Bound::Included(prefix), upper))
+                    .map(|(k, v)| (k.clone(), v.clone()));
+                let lower = Bound::Included(prefix);
+                let first =
+                    first_in_subtree(&buffer_manager, &subtree, lower, upper, LATEST).unwrap();
+                assert_eq!(first, expected.next());
+                let last =
+                    last_in_subtree(&buffer_manager, &subtree, lower, upper, LATEST).unwrap();
+                assert_eq!(last, expected.next_back().or_else(|| first.clone()));
+            }
+        }
+        // All the inner nodes have at least two entries
+        assert!(buffer_manager.node_count() < 1 + 2 * model.len());
+    }
+
+    #[test]
+    fn operations_across_evictions() {
+        let (buffer_manager, tree) = new_tree();
+        let mut model = BTreeMap::new();
+        let mut state = 0x9E37_79B9_7F4A_7C15;
+        for i in 0..2000_u32 {
+            let key = random_long_key(&mut state);
+            if xorshift(&mut state).is_multiple_of(4) {
+                let deleted = delete(&buffer_manager, tree, &key, UNVERSIONED).unwrap();
+                assert_eq!(deleted, model.remove(&key).is_some());
+            } else {
+                let value = vec![u8::try_from(i % 256).unwrap(); usize::try_from(i).unwrap()];
+                upsert(&buffer_manager, tree, &key, &value, UNVERSIONED).unwrap();
+                model.insert(key, value);
+            }
+            if i % 100 == 0 {
+                for id in buffer_manager.resident_node_ids() {
+                    assert!(buffer_manager.evict(id).unwrap());
+                }
+            }
+        }
+        buffer_manager.flush().unwrap();
+        for id in buffer_manager.resident_node_ids() {
+            assert!(buffer_manager.evict(id).unwrap());
+        }
+        assert_eq!(
+            count_prefix(&buffer_manager, tree, b"", LATEST).unwrap(),
+            model.len()
+        );
+        for (key, value) in &model {
+            assert_eq!(
+                get(&buffer_manager, tree, key, LATEST).unwrap().as_ref(),
+                Some(value)
+            );
+        }
+    }
+
+    #[test]
+    fn snapshot_reads() {
+        let (buffer_manager, tree) = new_tree();
+        upsert(&buffer_manager, tree, b"a", b"0", UNVERSIONED).unwrap();
+        assert!(upsert(&buffer_manager, tree, b"b", b"1", 1).unwrap());
+        assert!(!upsert(&buffer_manager, tree, b"a", b"2", 2).unwrap());
+        assert!(delete(&buffer_manager, tree, b"b", 3).unwrap());
+        assert!(!delete(&buffer_manager, tree, b"b", 4).unwrap());
+        assert_eq!(commit_ts(&buffer_manager, tree, b"b").unwrap(), Some(3));
+        assert_eq!(commit_ts(&buffer_manager, tree, b"c").unwrap(), None);
+        let all = (Bound::Unbounded, Bound::Unbounded);
+        for (snapshot_ts, a, b) in [
+            (0, Some(&b"0"[..]), None),
+            (1, Some(b"0"), Some(&b"1"[..])),
+            (2, Some(b"2"), Some(b"1")),
+            (3, Some(b"2"), None),
+        ] {
+            let a = a.map(<[u8]>::to_vec);
+            let b = b.map(<[u8]>::to_vec);
+            assert_eq!(get(&buffer_manager, tree, b"a", snapshot_ts).unwrap(), a);
+            assert_eq!(get(&buffer_manager, tree, b"b", snapshot_ts).unwrap(), b);
+            let count = usize::from(b.is_some()) + 1;
+            assert_eq!(
+                count_prefix(&buffer_manager, tree, b"", snapshot_ts).unwrap(),
+                count
+            );
+            let first = first_in_range(&buffer_manager, tree, all.0, all.1, snapshot_ts).unwrap();
+            assert_eq!(first, Some((b"a".to_vec(), a.clone().unwrap())));
+            let last = last_in_range(&buffer_manager, tree, all.0, all.1, snapshot_ts).unwrap();
+            let expected_last = match b {
+                Some(b) => (b"b".to_vec(), b),
+                None => (b"a".to_vec(), a.unwrap()),
+            };
+            assert_eq!(last, Some(expected_last));
+        }
+        // The deleted key stays until no snapshot reads it
+        purge(&buffer_manager, tree, b"b", 2).unwrap();
+        assert_eq!(
+            get(&buffer_manager, tree, b"b", 2).unwrap(),
+            Some(b"1".to_vec())
+        );
+        purge(&buffer_manager, tree, b"b", 3).unwrap();
+        assert_eq!(commit_ts(&buffer_manager, tree, b"b").unwrap(), None);
+        purge(&buffer_manager, tree, b"a", 3).unwrap();
+        assert_eq!(commit_ts(&buffer_manager, tree, b"a").unwrap(), Some(0));
+        assert_eq!(buffer_manager.node_count(), 2);
+    }
+}
@@ -1,34 +1,1352 @@
-// Copyright (C) 2022-2023 Laurynas Biveinis
+// Copyright (C) 2022-2024 Laurynas Biveinis
 
-use crate::node;
+// The nodes are stored in the data file, and are read into memory on demand
+// through shared references. The modified nodes are written back on flush or
+// eviction. The memory taken by the nodes is bounded by evicting the nodes
+// chosen by the eviction policy once it is exceeded.
+//
+// The nodes in memory are held in frames, found by the node ID through the
+// translation table. The inner node child references are swizzled to the child
+// frames once followed, so that the hot paths skip the table, and unswizzled
+// through the parent pointers of the frames when the children are evicted.
+// The swizzled hops skip the eviction policy too, only setting the reference
+// bit of the frame, which the policy learns about when it picks the frame as a
+// victim, giving it a second chance instead.
+//
+// The pages of the nodes in the last checkpoint image are never overwritten:
+// such nodes are moved to new pages when written back, and their old pages are
+// freed only after the next checkpoint. The small nodes are appended as
+// records to the slotted pages whenever written back, and the live ones left
+// in the sparse pages are moved at the checkpoints, so that the pages are
+// freed.
+//
+// All the operations may run concurrently, but the caller must not read a node
+// while it is being changed or removed. A node is modified through a copy,
+// which replaces the node in its frame once done with, thus the shared
+// references taken before keep seeing the node as it was. The translation
+// table, the eviction policy and the memory accounting are under the catalog
+// lock, while each frame has its own latch, which is all that a swizzled hop
+// takes. A node missing from memory is read without holding the catalog lock,
+// marked as being loaded, so that the other readers of it wait instead of
+// reading it again. The data file and the node locations have their own lock,
+// which is held while a node is written back, so that its writes are ordered
+// as its versions. The locks are taken in the order of the catalog, the data
+// file, the frames and a frame latch, skipping any.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{
+    Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+
+use crate::checkpoint::Checkpoint;
+use crate::data_file::{DataFile, Extent, Location};
+use crate::eviction::{self, Policy};
+use crate::node::{self, Node};
+use crate::transaction_manager;
+use crate::{lock, BufferPoolStats, DbError, DbOptions};
+
+#[derive(Debug)] // COV_EXCL_LINE
+struct Frame {
+    id: node::Id,
+    node: Arc<Node>,
+    dirty: bool,
+    // The memory size of the node, as accounted in the pool
+    size: usize,
+    // The inner node and its key byte whose child reference is swizzled to
+    // this frame
+    parent: Option<(node::Id, u8)>,
+}
+
+impl Frame {
+    fn new(id: node::Id, node: Arc<Node>, dirty: bool) -> Self {
+        let size = node.memory_size();
+        Self {
+            id,
+            node,
+            dirty,
+            size,
+            parent: None,
+        }
+    }
+
+    // A node referenced by any NodeRef or NodeMut cannot be evicted
+    #[inline]
+    fn is_pinned(&self) -> bool {
+        Arc::strong_count(&self.node) > 1
+    }
+}
+
+#[derive(Debug)] // COV_EXCL_LINE
+struct FrameSlot {
+    // The frame latch
+    frame: Mutex<Option<Frame>>,
+    // Whether the node has been used through a swizzled reference since the
+    // policy was last told
+    referenced: AtomicBool,
+}
+
+impl FrameSlot {
+    #[inline]
+    fn latch(&self) -> MutexGuard<'_, Option<Frame>> {
+        lock(&self.frame)
+    }
+}
+
+#[derive(Debug)] // COV_EXCL_LINE
+struct Catalog {
+    // The translation table from the node IDs to their frames
+    frame_of: HashMap<node::Id, usize>,
+    free_frames: Vec<usize>,
+    policy: Box<dyn Policy>,
+    used: usize,
+    // The nodes being read by some reader
+    loading: HashSet<node::Id>,
+}
+
+#[derive(Debug)] // COV_EXCL_LINE
+struct Storage {
+    data_file: DataFile,
+    // The data file locations of the nodes that have been written there
+    locations: HashMap<node::Id, Location>,
+    // The nodes whose locations belong to the last checkpoint image
+    checkpointed: HashSet<node::Id>,
+}
+
+#[derive(Debug, Default)] // COV_EXCL_LINE
+struct NodeIds {
+    // The IDs of the removed nodes, reused the lowest first
+    free: BTreeSet<node::Id>,
+    // The IDs that must not be reused yet, because the log being redone
+    // allocates them later
+    reserved: HashSet<node::Id>,
+}
+
+#[derive(Debug, Default)] // COV_EXCL_LINE
+struct Stats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    write_backs: AtomicU64,
+}
+
+impl Stats {
+    #[inline]
+    fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// A shared reference to a node in memory, pinning it there while alive. It
+// keeps seeing the node as it was when taken.
+#[derive(Debug)] // COV_EXCL_LINE
+#[must_use]
+pub struct NodeRef<'a, T: ?Sized = Node> {
+    node: Arc<Node>,
+    project: fn(&Node) -> Option<&T>,
+    _buffer_manager: PhantomData<&'a BufferManager>,
+}
+
+impl<'a> NodeRef<'a> {
+    // Narrows the reference to the node variant, if it is of that variant
+    pub fn try_map<U: ?Sized>(self, project: fn(&Node) -> Option<&U>) -> Option<NodeRef<'a, U>> {
+        project(&self.node)?;
+        Some(NodeRef {
+            node: self.node,
+            project,
+            _buffer_manager: PhantomData,
+        })
+    }
+}
+
+impl<T: ?Sized> Deref for NodeRef<'_, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        let Some(result) = (self.project)(&self.node) else {
+            unreachable!("Node variant checked on creation");
+        };
+        result
+    }
+}
+
+#[allow(clippy::unnecessary_wraps)]
+#[inline]
+fn whole_node(node: &Node) -> Option<&Node> {
+    Some(node)
+}
+
+#[allow(clippy::unnecessary_wraps)]
+#[inline]
+fn whole_node_mut(node: &mut Node) -> Option<&mut Node> {
+    Some(node)
+}
+
+// The node to modify, copied on the first write. It replaces the node in its
+// frame when dropped, marking it modified.
+#[derive(Debug)] // COV_EXCL_LINE
+struct NodeCopy<'a> {
+    buffer_manager: &'a BufferManager,
+    id: node::Id,
+    // Pins the frame meanwhile
+    _original: Arc<Node>,
+    node: Arc<Node>,
+}
+
+impl Drop for NodeCopy<'_> {
+    fn drop(&mut self) {
+        self.buffer_manager.install(self.id, &self.node);
+    }
+}
+
+// An exclusive reference to a node in memory, pinning it there while alive.
+// The changes through it are seen by the references taken after it is
+// dropped.
+#[derive(Debug)] // COV_EXCL_LINE
+#[must_use]
+pub struct NodeMut<'a, T: ?Sized = Node> {
+    copy: NodeCopy<'a>,
+    project: fn(&Node) -> Option<&T>,
+    project_mut: fn(&mut Node) -> Option<&mut T>,
+}
+
+impl<'a> NodeMut<'a> {
+    // Narrows the reference to the node variant, if it is of that variant
+    pub fn try_map<U: ?Sized>(
+        self,
+        project: fn(&Node) -> Option<&U>,
+        project_mut: fn(&mut Node) -> Option<&mut U>,
+    ) -> Option<NodeMut<'a, U>> {
+        project(&self.copy.node)?;
+        Some(NodeMut {
+            copy: self.copy,
+            project,
+            project_mut,
+        })
+    }
+}
+
+impl<T: ?Sized> Deref for NodeMut<'_, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        let Some(result) = (self.project)(&self.copy.node) else {
+            unreachable!("Node variant checked on creation");
+        };
+        result
+    }
+}
+
+impl<T: ?Sized> DerefMut for NodeMut<'_, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        let Some(result) = (self.project_mut)(Arc::make_mut(&mut self.copy.node)) else {
+            unreachable!("Node variant checked on creation");
+        };
+        result
+    }
+}
+
+// The exclusive access needs no locking, and no panic can leave the locked
+// state half-updated
+#[inline]
+fn get_mut<T>(lock: &mut RwLock<T>) -> &mut T {
+    lock.get_mut().unwrap_or_else(PoisonError::into_inner)
+}
 
 #[derive(Debug)] // COV_EXCL_LINE
 pub struct BufferManager {
     next_node_id: node::AtomicId,
+    node_ids: Mutex<NodeIds>,
+    catalog: Mutex<Catalog>,
+    // Notified whenever a node has been loaded
+    loaded: Condvar,
+    frames: RwLock<Vec<FrameSlot>>,
+    storage: RwLock<Storage>,
+    capacity: usize,
+    stats: Stats,
 }
 
 impl BufferManager {
-    #[must_use]
-    pub fn new(first_free_node_id: node::Id) -> Self {
+    pub fn new(first_free_node_id: node::Id, data_file: DataFile, options: &DbOptions) -> Self {
         Self {
             next_node_id: node::AtomicId::new(first_free_node_id),
+            node_ids: Mutex::new(NodeIds::default()),
+            catalog: Mutex::new(Catalog {
+                frame_of: HashMap::new(),
+                free_frames: Vec::new(),
+                policy: eviction::new_policy(options.eviction_policy),
+                used: 0,
+                loading: HashSet::new(),
+            }),
+            loaded: Condvar::new(),
+            frames: RwLock::new(Vec::new()),
+            storage: RwLock::new(Storage {
+                data_file,
+                locations: HashMap::new(),
+                checkpointed: HashSet::new(),
+            }),
+            capacity: options.buffer_pool_size,
+            stats: Stats::default(),
         }
     }
 
-    #[inline]
-    pub fn allocate_new_node_id(&mut self) -> node::Id {
+    // Reuses the lowest free ID that is not reserved, if any
+    pub fn allocate_new_node_id(&self) -> node::Id {
+        let mut node_ids = lock(&self.node_ids);
+        let reusable = node_ids
+            .free
+            .iter()
+            .find(|id| !node_ids.reserved.contains(id))
+            .copied();
+        if let Some(id) = reusable {
+            node_ids.free.remove(&id);
+            return id;
+        }
         self.next_node_id.get_and_advance()
     }
+
+    // Returns an allocated ID of a node that has not been created
+    pub fn free_node_id(&self, id: node::Id) {
+        debug_assert!(!self.catalog().frame_of.contains_key(&id));
+        debug_assert!(!self.storage().locations.contains_key(&id));
+        lock(&self.node_ids).free.insert(id);
+    }
+
+    // Frees the IDs below the next one that no node has. Such IDs were
+    // allocated by the transactions that did not commit, and are not in the
+    // free list of the checkpoint if they were active at it.
+    pub fn reclaim_unused_node_ids(&self) {
+        let catalog = self.catalog();
+        let storage = self.storage();
+        let free = &mut lock(&self.node_ids).free;
+        let mut id = node::Id::CATALOG.next();
+        while id < self.next_node_id.get() {
+            if !catalog.frame_of.contains_key(&id) && !storage.locations.contains_key(&id) {
+                free.insert(id);
+            }
+            id = id.next();
+        }
+    }
+
+    pub fn reserve_node_ids(&self, ids: impl IntoIterator<Item = node::Id>) {
+        lock(&self.node_ids).reserved.extend(ids);
+    }
+
+    pub fn release_reserved_node_ids(&self) {
+        lock(&self.node_ids).reserved.clear();
+    }
+
+    #[must_use]
+    pub fn free_node_ids(&self) -> BTreeSet<node::Id> {
+        lock(&self.node_ids).free.clone()
+    }
+
+    /// # Errors
+    /// Will return `DbError` on a failure to write back the evicted nodes.
+    pub fn new_node(&self, node: Node) -> Result<node::Id, DbError> {
+        let id = self.allocate_new_node_id();
+        self.insert_node(id, node)?;
+        Ok(id)
+    }
+
+    /// Add a node with an already-allocated ID, which may have been free when
+    /// redoing the log
+    /// # Errors
+    /// Will return `DbError` on a failure to write back the evicted nodes.
+    pub fn insert_node(&self, id: node::Id, node: Node) -> Result<(), DbError> {
+        debug_assert!(!self.storage().locations.contains_key(&id));
+        lock(&self.node_ids).free.remove(&id);
+        let node = Arc::new(node);
+        // Pin the new node while making room for it
+        self.admit(&mut self.catalog(), Frame::new(id, node.clone(), true))?;
+        drop(node);
+        Ok(())
+    }
+
+    /// # Errors
+    /// Will return `DbError` on a failure to read the node or to write back the
+    /// evicted nodes.
+    pub fn get(&self, id: node::Id) -> Result<Option<NodeRef<'_>>, DbError> {
+        self.get_with_frame(id)
+            .map(|node| node.map(|(node, _)| node))
+    }
+
+    /// Follows the child reference of the inner node through the swizzled
+    /// frame, if any, otherwise resolves it and swizzles it.
+    /// # Errors
+    /// Will return `DbError` on a failure to read the node or to write back the
+    /// evicted nodes.
+    pub fn get_child(
+        &self,
+        parent: node::Id,
+        swip: &node::Swip,
+        key_byte: u8,
+    ) -> Result<Option<NodeRef<'_>>, DbError> {
+        let id = swip.id();
+        if let Some(index) = swip.frame() {
+            // The frame may have been reused by another node since
+            let frames = self.frames();
+            let node = frames[index]
+                .latch()
+                .as_ref()
+                .filter(|frame| frame.id == id)
+                .map(|frame| frame.node.clone());
+            if let Some(node) = node {
+                frames[index].referenced.store(true, Ordering::Relaxed);
+                Stats::count(&self.stats.hits);
+                return Ok(Some(self.node_ref(node)));
+            }
+        }
+        let Some((result, index)) = self.get_with_frame(id)? else {
+            return Ok(None);
+        };
+        // The frame cannot be reused while the result pins it
+        swip.swizzle(index);
+        if let Some(frame) = self.frames()[index].latch().as_mut() {
+            frame.parent = Some((parent, key_byte));
+        }
+        Ok(Some(result))
+    }
+
+    /// # Errors
+    /// Will return `DbError` on a failure to read the node or to write back the
+    /// evicted nodes.
+    pub fn get_mut(&self, id: node::Id) -> Result<Option<NodeMut<'_>>, DbError> {
+        let Some((node_ref, _)) = self.get_with_frame(id)? else {
+            return Ok(None);
+        };
+        let node = node_ref.node;
+        Ok(Some(NodeMut {
+            copy: NodeCopy {
+                buffer_manager: self,
+                id,
+                _original: node.clone(),
+                node,
+            },
+            project: whole_node,
+            project_mut: whole_node_mut,
+        }))
+    }
+
+    /// Frees the node ID and its data file pages for reuse. Returns whether the
+    /// node existed.
+    /// # Errors
+    /// Will return `DbError` on a failure to shrink the data file.
+    pub fn remove(&self, id: node::Id) -> Result<bool, DbError> {
+        let mut catalog = self.catalog();
+        let was_resident = if let Some(index) = catalog.frame_of.get(&id).copied() {
+            self.release(&mut catalog, index);
+            true
+        } else {
+            false
+        };
+        let mut storage = self.storage_mut();
+        let location = storage.locations.remove(&id);
+        let was_written = location.is_some();
+        if let Some(location) = location {
+            let in_image = storage.checkpointed.remove(&id);
+            storage.data_file.free_location(location, in_image)?;
+        }
+        drop(storage);
+        drop(catalog);
+        let existed = was_resident || was_written;
+        if existed {
+            lock(&self.node_ids).free.insert(id);
+        }
+        Ok(existed)
+    }
+
+    #[must_use]
+    pub fn node_count(&self) -> usize {
+        let catalog = self.catalog();
+        let not_resident = self
+            .storage()
+            .locations
+            .keys()
+            .filter(|id| !catalog.frame_of.contains_key(id))
+            .count();
+        catalog.frame_of.len() + not_resident
+    }
+
+    /// Writes back all the modified nodes.
+    /// # Errors
+    /// Will return `DbError` on a failure to write.
+    pub fn flush(&self) -> Result<(), DbError> {
+        let dirty: Vec<_> = self
+            .frames()
+            .iter()
+            .enumerate()
+            .filter_map(|(index, slot)| slot.latch().as_ref()?.dirty.then_some(index))
+            .collect();
+        for index in dirty {
+            self.write_back(index)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the node from memory, writing it back first if it is modified.
+    /// Returns whether the node was in memory.
+    /// # Errors
+    /// Will return `DbError` on a failure to write.
+    pub fn evict(&self, id: node::Id) -> Result<bool, DbError> {
+        let mut catalog = self.catalog();
+        let Some(index) = catalog.frame_of.get(&id).copied() else {
+            return Ok(false);
+        };
+        self.evict_frame(&mut catalog, index)?;
+        Ok(true)
+    }
+
+    #[cfg(test)]
+    #[must_use]
+    pub(crate) fn dirty_node_ids(&self) -> Vec<node::Id> {
+        self.frames()
+            .iter()
+            .filter_map(|slot| {
+                slot.latch()
+                    .as_ref()
+                    .filter(|frame| frame.dirty)
+                    .map(|frame| frame.id)
+            })
+            .collect()
+    }
+
+    /// Writes back all the modified nodes, together with the live records of
+    /// the sparse pages, and passes the resulting data file image to `write`
+    /// for a checkpoint at the log position, when the transaction IDs below the
+    /// given one have been used. Once it is written, frees the pages of the
+    /// previous image not in the new one. No node may be changed meanwhile,
+    /// while the evicted nodes are written back only after it.
+    /// # Errors
+    /// Will return `DbError` on a failure to read or write, or if `write`
+    /// fails.
+    pub fn checkpoint(
+        &self,
+        lsn: u64,
+        next_transaction_id: transaction_manager::Id,
+        write: impl FnOnce(Checkpoint) -> Result<(), DbError>,
+    ) -> Result<(), DbError> {
+        let moved: Vec<_> = {
+            let mut storage = self.storage_mut();
+            storage.data_file.seal_open_page()?;
+            let sparse_pages = storage.data_file.sparse_record_pages();
+            storage
+                .locations
+                .iter()
+                .filter_map(|(id, location)| match location {
+                    Location::Record { page, .. } if sparse_pages.contains(page) => Some(*id),
+                    _ => None,
+                })
+                .collect()
+        };
+        for id in moved {
+            drop(self.get_mut(id)?);
+        }
+        self.flush()?;
+        let mut storage = self.storage_mut();
+        storage.data_file.seal_open_page()?;
+        storage.data_file.sync()?;
+        let (page_count, free_extents) = storage.data_file.space_after_checkpoint();
+        let record_pages = storage.data_file.record_pages();
+        let mut locations: Vec<_> = storage
+            .locations
+            .iter()
+            .map(|(id, location)| (*id, *location))
+            .collect();
+        locations.sort_unstable_by_key(|(id, _)| *id);
+        write(
+            Checkpoint::new(
+                lsn,
+                self.next_node_id.get(),
+                next_transaction_id,
+                self.free_node_ids().into_iter().collect(),
+                locations,
+                page_count,
+                free_extents,
+            )
+            .with_record_pages(record_pages),
+        )?;
+        storage.data_file.finish_checkpoint()?;
+        storage.checkpointed = storage.locations.keys().copied().collect();
+        Ok(())
+    }
+
+    /// Sets the nodes to those of the checkpoint image, or to none without a
+    /// checkpoint, discarding anything written to the data file after it.
+    /// # Errors
+    /// Will return `DbError` on a failure to truncate the data file.
+    pub fn restore(&mut self, checkpoint: Option<&Checkpoint>) -> Result<(), DbError> {
+        debug_assert_eq!(self.node_count(), 0);
+        let storage = get_mut(&mut self.storage);
+        let Some(checkpoint) = checkpoint else {
+            storage.data_file.restore(0, &[], &[], std::iter::empty())?;
+            return Ok(());
+        };
+        storage.data_file.restore(
+            checkpoint.page_count(),
+            checkpoint.free_extents(),
+            checkpoint.record_pages(),
+            checkpoint.locations().iter().map(|(_, location)| *location),
+        )?;
+        storage.locations = checkpoint.locations().iter().copied().collect();
+        storage.checkpointed = checkpoint.locations().iter().map(|(id, _)| *id).collect();
+        lock(&self.node_ids).free = checkpoint.free_node_ids().iter().copied().collect();
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn resident_node_ids(&self) -> Vec<node::Id> {
+        self.catalog().frame_of.keys().copied().collect()
+    }
+
+    // The memory taken by the nodes in memory
+    #[must_use]
+    pub fn used_memory(&self) -> usize {
+        self.catalog().used
+    }
+
+    #[must_use]
+    pub fn data_file_page_count(&self) -> u64 {
+        self.storage().data_file.page_count()
+    }
+
+    #[inline]
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            hits: self.stats.hits.load(Ordering::Relaxed),
+            misses: self.stats.misses.load(Ordering::Relaxed),
+            evictions: self.stats.evictions.load(Ordering::Relaxed),
+            write_backs: self.stats.write_backs.load(Ordering::Relaxed),
+        }
+    }
+
+    // The reference borrows the buffer manager, not the node
+    #[allow(clippy::unused_self)]
+    fn node_ref(&self, node: Arc<Node>) -> NodeRef<'_> {
+        NodeRef {
+            node,
+            project: whole_node,
+            _buffer_manager: PhantomData,
+        }
+    }
+
+    #[inline]
+    fn catalog(&self) -> MutexGuard<'_, Catalog> {
+        lock(&self.catalog)
+    }
+
+    #[inline]
+    fn frames(&self) -> RwLockReadGuard<'_, Vec<FrameSlot>> {
+        self.frames.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[inline]
+    fn storage(&self) -> RwLockReadGuard<'_, Storage> {
+        self.storage.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[inline]
+    fn storage_mut(&self) -> RwLockWriteGuard<'_, Storage> {
+        self.storage.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn get_with_frame(&self, id: node::Id) -> Result<Option<(NodeRef<'_>, usize)>, DbError> {
+        let mut catalog = self.catalog();
+        loop {
+            if let Some(index) = catalog.frame_of.get(&id).copied() {
+                let node = self.frames()[index]
+                    .latch()
+                    .as_ref()
+                    .map(|frame| frame.node.clone());
+                let Some(node) = node else {
+                    unreachable!("Translated node {id} to an empty frame");
+                };
+                self.hit(&mut catalog, id);
+                return Ok(Some((self.node_ref(node), index)));
+            }
+            if !catalog.loading.contains(&id) {
+                break;
+            }
+            catalog = self
+                .loaded
+                .wait(catalog)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        catalog.loading.insert(id);
+        drop(catalog);
+        let node = self.read_node(id);
+        let mut catalog = self.catalog();
+        catalog.loading.remove(&id);
+        self.loaded.notify_all();
+        let Some(node) = node? else {
+            return Ok(None);
+        };
+        let result = self.node_ref(node.clone());
+        let index = self.admit(&mut catalog, Frame::new(id, node, false))?;
+        Ok(Some((result, index)))
+    }
+
+    fn hit(&self, catalog: &mut Catalog, id: node::Id) {
+        catalog.policy.touch(id);
+        Stats::count(&self.stats.hits);
+    }
+
+    // Adds the frame to the pool, evicting other nodes if it is over capacity.
+    // Returns the frame index.
+    fn admit(&self, catalog: &mut Catalog, frame: Frame) -> Result<usize, DbError> {
+        let index = self.place(catalog, frame);
+        self.shrink_to_capacity(catalog)?;
+        Ok(index)
+    }
+
+    fn place(&self, catalog: &mut Catalog, frame: Frame) -> usize {
+        let id = frame.id;
+        catalog.used += frame.size;
+        let index = if let Some(index) = catalog.free_frames.pop() {
+            let frames = self.frames();
+            frames[index].referenced.store(false, Ordering::Relaxed);
+            *frames[index].latch() = Some(frame);
+            index
+        } else {
+            let mut frames = self.frames.write().unwrap_or_else(PoisonError::into_inner);
+            frames.push(FrameSlot {
+                frame: Mutex::new(Some(frame)),
+                referenced: AtomicBool::new(false),
+            });
+            frames.len() - 1
+        };
+        let old_index = catalog.frame_of.insert(id, index);
+        debug_assert!(old_index.is_none());
+        catalog.policy.admit(id);
+        index
+    }
+
+    // Removes the frame from the pool, unswizzling the references to it from
+    // its parent, and from it to its children
+    fn release(&self, catalog: &mut Catalog, index: usize) {
+        let frames = self.frames();
+        let Some(frame) = frames[index].latch().take() else {
+            unreachable!("Releasing an empty frame");
+        };
+        catalog.free_frames.push(index);
+        catalog.frame_of.remove(&frame.id);
+        catalog.used -= frame.size;
+        catalog.policy.forget(frame.id);
+        if let Some((parent, key_byte)) = frame.parent {
+            if let Some(parent_index) = catalog.frame_of.get(&parent) {
+                if let Some(swip) = frames[*parent_index]
+                    .latch()
+                    .as_ref()
+                    .and_then(|parent| parent.node.as_inner())
+                    .and_then(|inner| inner.child_swip(key_byte))
+                    .filter(|swip| swip.frame() == Some(index))
+                {
+                    swip.unswizzle();
+                }
+            }
+        }
+        if let Node::Inner(inner) = &*frame.node {
+            let mut child = inner.first_child();
+            while let Some((key_byte, _)) = child {
+                if let Some(child_index) = inner.child_swip(key_byte).and_then(node::Swip::frame) {
+                    if let Some(child_frame) = frames[child_index]
+                        .latch()
+                        .as_mut()
+                        .filter(|child_frame| child_frame.parent == Some((frame.id, key_byte)))
+                    {
+                        child_frame.parent = None;
+                    }
+                }
+                child = inner.child_after(key_byte);
+            }
+        }
+    }
+
+    // Replaces the node in its frame by its modified copy, or puts the copy in
+    // a new frame if the node has been evicted meanwhile. The pool may go over
+    // its capacity until the next node is admitted.
+    fn install(&self, id: node::Id, node: &Arc<Node>) {
+        let mut catalog = self.catalog();
+        if let Some(index) = catalog.frame_of.get(&id).copied() {
+            let frames = self.frames();
+            let mut frame = frames[index].latch();
+            let Some(frame) = frame.as_mut() else {
+                unreachable!("Translated node {id} to an empty frame");
+            };
+            frame.dirty = true;
+            if !Arc::ptr_eq(&frame.node, node) {
+                let size = node.memory_size();
+                catalog.used = catalog.used - frame.size + size;
+                frame.node = node.clone();
+                frame.size = size;
+            }
+            return;
+        }
+        self.place(&mut catalog, Frame::new(id, node.clone(), true));
+    }
+
+    // Evicts the unpinned nodes chosen by the policy until the pool is within
+    // its capacity or all the nodes in it are pinned
+    fn shrink_to_capacity(&self, catalog: &mut Catalog) -> Result<(), DbError> {
+        while catalog.used > self.capacity {
+            let victim = {
+                let frames = self.frames();
+                let frame_of = &catalog.frame_of;
+                catalog.policy.victim(&|id| {
+                    frame_of.get(&id).is_some_and(|index| {
+                        frames[*index]
+                            .latch()
+                            .as_ref()
+                            .is_some_and(|frame| !frame.is_pinned())
+                    })
+                })
+            };
+            let Some((id, index)) =
+                victim.and_then(|id| Some((id, catalog.frame_of.get(&id).copied()?)))
+            else {
+                break;
+            };
+            if self.frames()[index]
+                .referenced
+                .swap(false, Ordering::Relaxed)
+            {
+                catalog.policy.touch(id);
+                continue;
+            }
+            self.evict_frame(catalog, index)?;
+        }
+        Ok(())
+    }
+
+    fn evict_frame(&self, catalog: &mut Catalog, index: usize) -> Result<(), DbError> {
+        self.write_back(index)?;
+        self.release(catalog, index);
+        Stats::count(&self.stats.evictions);
+        Ok(())
+    }
+
+    // The data file is read without holding the catalog lock
+    fn read_node(&self, id: node::Id) -> Result<Option<Arc<Node>>, DbError> {
+        let bytes = {
+            let storage = self.storage();
+            let Some(location) = storage.locations.get(&id).copied() else {
+                return Ok(None);
+            };
+            storage.data_file.read(location)?
+        };
+        let node = Node::deserialize(&bytes).ok_or(DbError::BadNode { node_id: id })?;
+        Stats::count(&self.stats.misses);
+        Ok(Some(Arc::new(node)))
+    }
+
+    // Writes the node if it is modified. A small node is appended as a new
+    // record. A large one is rewritten in place if it still fits and is not in
+    // the checkpoint image, otherwise moved to new pages.
+    fn write_back(&self, index: usize) -> Result<(), DbError> {
+        let mut storage = self.storage_mut();
+        let (id, node) = {
+            let frames = self.frames();
+            let frame = frames[index].latch();
+            let Some(frame) = frame.as_ref().filter(|frame| frame.dirty) else {
+                return Ok(());
+            };
+            (frame.id, Arc::clone(&frame.node))
+        };
+        let bytes = node.serialize();
+        let is_record = DataFile::fits_in_record(bytes.len());
+        let page_count = Extent::pages_for(bytes.len());
+        let Storage {
+            data_file,
+            locations,
+            checkpointed,
+        } = &mut *storage;
+        match locations.get(&id).copied() {
+            Some(Location::Extent(extent))
+                if !is_record
+                    && extent.page_count() >= page_count
+                    && !checkpointed.contains(&id) =>
+            {
+                data_file.write(extent, &bytes)?;
+            }
+            _ => {
+                // The old location is freed only once the node is in the new
+                // one
+                let location = if is_record {
+                    data_file.append_record(&bytes)?
+                } else {
+                    let extent = data_file.allocate(page_count);
+                    if let Err(error) = data_file.write(extent, &bytes) {
+                        data_file.free(extent)?;
+                        return Err(error.into());
+                    }
+                    Location::Extent(extent)
+                };
+                if let Some(old_location) = locations.insert(id, location) {
+                    let in_image = checkpointed.remove(&id);
+                    data_file.free_location(old_location, in_image)?;
+                }
+            }
+        }
+        // Unless changed meanwhile
+        if let Some(frame) = self.frames()[index]
+            .latch()
+            .as_mut()
+            .filter(|frame| frame.id == id && Arc::ptr_eq(&frame.node, &node))
+        {
+            frame.dirty = false;
+        }
+        Stats::count(&self.stats.write_backs);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::BufferManager;
+    use crate::checkpoint::Checkpoint;
+    use crate::data_file::{DataFile, Extent};
+    use crate::node;
+    use crate::transaction_manager;
+    use crate::{DbOptions, EvictionPolicy};
+    use cap_std::fs::File;
+
+    pub(crate) fn new_buffer_manager_with_options(
+        first_free_node_id: node::Id,
+        options: &DbOptions,
+    ) -> BufferManager {
+        let data_file = DataFile::new(File::from_std(tempfile::tempfile().unwrap()));
+        BufferManager::new(first_free_node_id, data_file, options)
+    }
+
+    pub(crate) fn new_buffer_manager(first_free_node_id: node::Id) -> BufferManager {
+        new_buffer_manager_with_options(first_free_node_id, &DbOptions::default())
+    }
+
+    // The image of a checkpoint at the log position
+    pub(crate) fn checkpoint(buffer_manager: &BufferManager, lsn: u64) -> Checkpoint {
+        let mut image = None;
+        buffer_manager
+            .checkpoint(lsn, transaction_manager::Id::from(0), |checkpoint| {
+                image = Some(checkpoint);
+                Ok(())
+            })
+            .unwrap();
+        image.unwrap()
+    }
 
     #[test]
     fn node_id_sequence() {
-        let mut buffer_manager = BufferManager::new(crate::node::Id::from(14));
+        let buffer_manager = new_buffer_manager(crate::node::Id::from(14));
         assert_eq!(14, buffer_manager.allocate_new_node_id().as_u64());
         assert_eq!(15, buffer_manager.allocate_new_node_id().as_u64());
     }
+
+    #[test]
+    fn nodes_addressable_by_id() {
+        let buffer_manager = new_buffer_manager(crate::node::Id::from(1));
+        let leaf_id = buffer_manager
+            .new_node(node::Node::Leaf(node::Leaf::new(b"k", b"v")))
+            .unwrap();
+        let descriptor_id = buffer_manager
+            .new_node(node::Node::ArtDescriptor(node::ArtDescriptor::new()))
+            .unwrap();
+        assert_ne!(leaf_id, descriptor_id);
+        let mut node = buffer_manager.get_mut(descriptor_id).unwrap().unwrap();
+        let node::Node::ArtDescriptor(descriptor) = &mut *node else {
+            panic!("Expected an ART descriptor node");
+        };
+        descriptor.set_root(leaf_id);
+        drop(node);
+        let descriptor = buffer_manager.get(descriptor_id).unwrap().unwrap();
+        let descriptor = descriptor
+            .try_map(node::Node::as_art_descriptor)
+            .expect("Expected an ART descriptor node");
+        let leaf = buffer_manager.get(descriptor.root()).unwrap().unwrap();
+        let leaf = leaf
+            .try_map(node::Node::as_leaf)
+            .expect("Expected a leaf node");
+        assert_eq!(leaf.key(), b"k");
+        assert_eq!(leaf.value(), Some(&b"v"[..]));
+        let descriptor = buffer_manager.get(descriptor_id).unwrap().unwrap();
+        assert!(descriptor.try_map(node::Node::as_leaf).is_none());
+    }
+
+    #[test]
+    fn remove_nodes() {
+        let buffer_manager = new_buffer_manager(crate::node::Id::from(1));
+        let resident = buffer_manager
+            .new_node(node::Node::Leaf(node::Leaf::new(b"a", b"")))
+            .unwrap();
+        let written = buffer_manager
+            .new_node(node::Node::Leaf(node::Leaf::new(b"b", b"")))
+            .unwrap();
+        assert!(buffer_manager.evict(written).unwrap());
+        assert_eq!(buffer_manager.node_count(), 2);
+        assert!(buffer_manager.remove(resident).unwrap());
+        assert!(buffer_manager.remove(written).unwrap());
+        assert!(!buffer_manager.remove(written).unwrap());
+        assert!(buffer_manager.get(resident).unwrap().is_none());
+        assert!(buffer_manager.get(written).unwrap().is_none());
+        assert_eq!(buffer_manager.node_count(), 0);
+    }
+
+    #[test]
+    fn evicted_nodes_read_back() {
+        let buffer_manager = new_buffer_manager(crate::node::Id::from(1));
+        let small = buffer_manager
+            .new_node(node::Node::Leaf(node::Leaf::new(b"k", b"v")))
+            .unwrap();
+        let large_value = vec![7; 10_000];
+        let large = buffer_manager
+            .new_node(node::Node::Leaf(node::Leaf::new(b"l", &large_value)))
+            .unwrap();
+        buffer_manager.flush().unwrap();
+        assert!(buffer_manager.evict(small).unwrap());
+        assert!(buffer_manager.evict(large).unwrap());
+        assert!(!buffer_manager.evict(large).unwrap());
+        assert!(buffer_manager.resident_node_ids().is_empty());
+        assert_eq!(buffer_manager.node_count(), 2);
+        // Growing the small node moves it to an extent
+        let mut node = buffer_manager.get_mut(small).unwrap().unwrap();
+        let node::Node::Leaf(leaf) = &mut *node else {
+            panic!("Expected a leaf node");
+        };
+        leaf.set_value(&large_value);
+        drop(node);
+        assert!(buffer_manager.evict(small).unwrap());
+        for id in [small, large] {
+            let leaf = buffer_manager.get(id).unwrap().unwrap();
+            let leaf = leaf.try_map(node::Node::as_leaf).unwrap();
+            assert_eq!(leaf.value(), Some(large_value.as_slice()));
+        }
+        assert_eq!(buffer_manager.resident_node_ids().len(), 2);
+    }
+
+    fn leaf(key: u8) -> node::Node {
+        node::Node::Leaf(node::Leaf::new(&[key], b"value"))
+    }
+
+    #[test]
+    fn failed_write_back_keeps_node_dirty() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("DATA");
+        std::fs::File::create(&path).unwrap();
+        let read_only = std::fs::File::open(&path).unwrap();
+        let data_file = DataFile::new(File::from_std(read_only));
+        let buffer_manager =
+            BufferManager::new(node::Id::from(1), data_file, &DbOptions::default());
+        let large_value = vec![b'v'; 10_000];
+        let large = node::Node::Leaf(node::Leaf::new(b"k", &large_value));
+        let ids = [
+            buffer_manager.new_node(leaf(1)).unwrap(),
+            buffer_manager.new_node(large).unwrap(),
+        ];
+        for id in ids {
+            assert!(buffer_manager.evict(id).is_err());
+            assert!(buffer_manager.flush().is_err());
+            assert!(buffer_manager.dirty_node_ids().contains(&id));
+            assert!(!buffer_manager.storage().locations.contains_key(&id));
+            assert!(buffer_manager.get(id).unwrap().is_some());
+        }
+        // Only the record page being filled, while the extent has been freed
+        assert_eq!(buffer_manager.data_file_page_count(), 1);
+    }
+
+    #[test]
+    fn bounded_pool() {
+        let leaf_size = leaf(0).memory_size();
+        for eviction_policy in [
+            EvictionPolicy::Lru,
+            EvictionPolicy::Clock,
+            EvictionPolicy::TwoQueue,
+        ] {
+            let options = DbOptions {
+                buffer_pool_size: 4 * leaf_size,
+                eviction_policy,
+                ..DbOptions::default()
+            };
+            let buffer_manager = new_buffer_manager_with_options(node::Id::from(1), &options);
+            let ids: Vec<_> = (0..20)
+                .map(|key| buffer_manager.new_node(leaf(key)).unwrap())
+                .collect();
+            assert_eq!(buffer_manager.resident_node_ids().len(), 4);
+            assert_eq!(buffer_manager.used_memory(), 4 * leaf_size);
+            for (key, id) in (0..20).zip(&ids) {
+                let node = buffer_manager.get(*id).unwrap().unwrap();
+                assert_eq!(node.try_map(node::Node::as_leaf).unwrap().key(), [key]);
+                assert!(buffer_manager.used_memory() <= options.buffer_pool_size);
+            }
+            let stats = buffer_manager.stats();
+            assert!(stats.misses >= 16);
+            assert_eq!(stats.hits + stats.misses, 20);
+            assert_eq!(stats.evictions, 16 + stats.misses);
+            assert_eq!(buffer_manager.node_count(), 20);
+        }
+    }
+
+    #[test]
+    fn pinned_nodes_stay_in_memory() {
+        let options = DbOptions {
+            buffer_pool_size: 0,
+            ..DbOptions::default()
+        };
+        let buffer_manager = new_buffer_manager_with_options(node::Id::from(1), &options);
+        let a = buffer_manager.new_node(leaf(b'a')).unwrap();
+        let b = buffer_manager.new_node(leaf(b'b')).unwrap();
+        // A new node is pinned only while making room for it
+        assert_eq!(buffer_manager.resident_node_ids(), [b]);
+        {
+            let a_ref = buffer_manager.get(a).unwrap().unwrap();
+            let b_ref = buffer_manager.get(b).unwrap().unwrap();
+            let mut resident = buffer_manager.resident_node_ids();
+            resident.sort_by_key(|id| id.as_u64());
+            assert_eq!(resident, [a, b]);
+            assert!(buffer_manager.used_memory() > options.buffer_pool_size);
+            drop(a_ref);
+            let _a_ref = buffer_manager.get(a).unwrap().unwrap();
+            drop(b_ref);
+        }
+        // The unpinned nodes are evicted on the next admission
+        let c = buffer_manager.new_node(leaf(b'c')).unwrap();
+        assert_eq!(buffer_manager.resident_node_ids(), [c]);
+    }
+
+    #[test]
+    fn concurrent_readers() {
+        let options = DbOptions {
+            buffer_pool_size: 4 * leaf(0).memory_size(),
+            ..DbOptions::default()
+        };
+        let buffer_manager = new_buffer_manager_with_options(node::Id::from(1), &options);
+        let ids: Vec<_> = (0..32)
+            .map(|key| buffer_manager.new_node(leaf(key)).unwrap())
+            .collect();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..8 {
+                        for (key, id) in (0..32).zip(&ids) {
+                            let node = buffer_manager.get(*id).unwrap().unwrap();
+                            assert_eq!(node.try_map(node::Node::as_leaf).unwrap().key(), [key]);
+                        }
+                    }
+                });
+            }
+        });
+        let stats = buffer_manager.stats();
+        assert_eq!(stats.hits + stats.misses, 4 * 8 * 32);
+        assert_eq!(buffer_manager.node_count(), 32);
+    }
+
+    #[test]
+    fn modified_node_size_accounted() {
+        let buffer_manager = new_buffer_manager(node::Id::from(1));
+        let id = buffer_manager.new_node(leaf(b'a')).unwrap();
+        let used = buffer_manager.used_memory();
+        let mut node = buffer_manager.get_mut(id).unwrap().unwrap();
+        let node::Node::Leaf(leaf) = &mut *node else {
+            panic!("Expected a leaf node");
+        };
+        leaf.set_value(&[0; 1000]);
+        drop(node);
+        assert!(buffer_manager.used_memory() > used + 900);
+        assert!(buffer_manager.remove(id).unwrap());
+        assert_eq!(buffer_manager.used_memory(), 0);
+    }
+
+    #[test]
+    fn swizzled_child_references() {
+        let buffer_manager = new_buffer_manager(node::Id::from(1));
+        let child = buffer_manager.new_node(leaf(b'c')).unwrap();
+        let mut inner = node::Inner::new();
+        inner.add_child(b'c', child);
+        let parent = buffer_manager.new_node(node::Node::Inner(inner)).unwrap();
+        let follow = |buffer_manager: &BufferManager| {
+            let parent_node = buffer_manager.get(parent).unwrap().unwrap();
+            let parent_inner = parent_node.try_map(node::Node::as_inner).unwrap();
+            let swip = parent_inner.child_swip(b'c').unwrap();
+            let child_node = buffer_manager.get_child(parent, swip, b'c').unwrap();
+            assert_eq!(
+                child_node
+                    .unwrap()
+                    .try_map(node::Node::as_leaf)
+                    .unwrap()
+                    .key(),
+                b"c"
+            );
+            swip.frame()
+        };
+        let frame = follow(&buffer_manager);
+        assert!(frame.is_some());
+        assert_eq!(follow(&buffer_manager), frame);
+        // Evicting the child unswizzles its reference
+        assert!(buffer_manager.evict(child).unwrap());
+        let parent_node = buffer_manager.get(parent).unwrap().unwrap();
+        let parent_inner = parent_node.try_map(node::Node::as_inner).unwrap();
+        assert_eq!(parent_inner.child_swip(b'c').unwrap().frame(), None);
+        drop(parent_inner);
+        assert!(follow(&buffer_manager).is_some());
+        // Evicting the parent first leaves no reference to unswizzle
+        assert!(buffer_manager.evict(parent).unwrap());
+        assert!(buffer_manager.evict(child).unwrap());
+        assert!(follow(&buffer_manager).is_some());
+        assert_eq!(buffer_manager.stats().misses, 3);
+    }
+
+    #[test]
+    fn swizzled_hop_gives_second_chance() {
+        let mut inner = node::Inner::new();
+        inner.add_child(b'c', node::Id::from(1));
+        let options = DbOptions {
+            buffer_pool_size: node::Node::Inner(inner.clone()).memory_size()
+                + 2 * leaf(0).memory_size(),
+            eviction_policy: EvictionPolicy::Lru,
+            ..DbOptions::default()
+        };
+        let buffer_manager = new_buffer_manager_with_options(node::Id::from(1), &options);
+        let child = buffer_manager.new_node(leaf(b'c')).unwrap();
+        let parent = buffer_manager.new_node(node::Node::Inner(inner)).unwrap();
+        for _ in 0..2 {
+            let parent_node = buffer_manager.get(parent).unwrap().unwrap();
+            let parent_inner = parent_node.try_map(node::Node::as_inner).unwrap();
+            let swip = parent_inner.child_swip(b'c').unwrap();
+            assert!(buffer_manager
+                .get_child(parent, swip, b'c')
+                .unwrap()
+                .is_some());
+        }
+        // The child was last touched before its parent, but used since
+        let x = buffer_manager.new_node(leaf(b'x')).unwrap();
+        assert!(buffer_manager.resident_node_ids().contains(&x));
+        // So the parent is evicted instead, and then x, which is older than the
+        // second chance of the child
+        let y = buffer_manager.new_node(leaf(b'y')).unwrap();
+        let mut resident = buffer_manager.resident_node_ids();
+        resident.sort_by_key(|id| id.as_u64());
+        assert_eq!(resident, [child, y]);
+        assert!(!resident.contains(&parent));
+    }
+
+    #[test]
+    fn node_ids_reused() {
+        let buffer_manager = new_buffer_manager(node::Id::from(1));
+        let ids: Vec<_> = (0..4)
+            .map(|key| buffer_manager.new_node(leaf(key)).unwrap())
+            .collect();
+        buffer_manager.flush().unwrap();
+        assert!(buffer_manager.evict(ids[2]).unwrap());
+        assert!(buffer_manager.remove(ids[2]).unwrap());
+        assert!(buffer_manager.remove(ids[1]).unwrap());
+        assert_eq!(buffer_manager.free_node_ids().len(), 2);
+        // The lowest free ID not reserved is reused first
+        buffer_manager.reserve_node_ids([ids[1]]);
+        assert_eq!(buffer_manager.allocate_new_node_id(), ids[2]);
+        assert_eq!(buffer_manager.allocate_new_node_id(), node::Id::from(5));
+        buffer_manager.release_reserved_node_ids();
+        assert_eq!(buffer_manager.new_node(leaf(1)).unwrap(), ids[1]);
+        assert!(buffer_manager.free_node_ids().is_empty());
+        // Inserting a free ID takes it off the free list
+        assert!(buffer_manager.remove(ids[3]).unwrap());
+        buffer_manager.insert_node(ids[3], leaf(3)).unwrap();
+        assert!(buffer_manager.free_node_ids().is_empty());
+    }
+
+    #[test]
+    fn removed_node_pages_reused() {
+        let buffer_manager = new_buffer_manager(node::Id::from(1));
+        let ids: Vec<_> = (0..4)
+            .map(|key| buffer_manager.new_node(leaf(key)).unwrap())
+            .collect();
+        buffer_manager.flush().unwrap();
+        // The small nodes share a page
+        assert_eq!(buffer_manager.data_file_page_count(), 1);
+        assert!(buffer_manager.remove(ids[1]).unwrap());
+        let id = buffer_manager.new_node(leaf(1)).unwrap();
+        buffer_manager.flush().unwrap();
+        assert_eq!(buffer_manager.data_file_page_count(), 1);
+        // A node grown out of a record moves to an extent
+        let mut node = buffer_manager.get_mut(ids[0]).unwrap().unwrap();
+        let node::Node::Leaf(grown) = &mut *node else {
+            panic!("Expected a leaf node");
+        };
+        grown.set_value(&[0; 5000]);
+        drop(node);
+        buffer_manager.flush().unwrap();
+        assert_eq!(buffer_manager.data_file_page_count(), 3);
+        for id in [ids[0], ids[2], ids[3]] {
+            assert!(buffer_manager.remove(id).unwrap());
+        }
+        assert_eq!(buffer_manager.data_file_page_count(), 1);
+        // The checkpoint frees the page once its last record is gone
+        assert!(buffer_manager.remove(id).unwrap());
+        let image = checkpoint(&buffer_manager, 0);
+        assert!(image.record_pages().is_empty());
+        assert_eq!(buffer_manager.data_file_page_count(), 0);
+    }
+
+    #[test]
+    fn sparse_record_pages_moved() {
+        let buffer_manager = new_buffer_manager(node::Id::from(1));
+        let ids: Vec<_> = (0..8)
+            .map(|key| buffer_manager.new_node(leaf(key)).unwrap())
+            .collect();
+        let image = checkpoint(&buffer_manager, 100);
+        assert_eq!(image.record_pages(), [(0, 8)]);
+        for id in &ids[1..] {
+            assert!(buffer_manager.remove(*id).unwrap());
+        }
+        // The live record of the sparse page moves to a new page
+        let next_image = checkpoint(&buffer_manager, 200);
+        assert_eq!(next_image.record_pages(), [(1, 1)]);
+        assert_eq!(next_image.free_extents(), [Extent::new(0, 1)]);
+        let node = buffer_manager.get(ids[0]).unwrap().unwrap();
+        assert_eq!(node.try_map(node::Node::as_leaf).unwrap().key(), [0]);
+    }
+
+    #[test]
+    fn checkpoint_image_kept_intact() {
+        let file = tempfile::tempfile().unwrap();
+        let new_over_file = || {
+            let data_file = DataFile::new(File::from_std(file.try_clone().unwrap()));
+            BufferManager::new(node::Id::from(1), data_file, &DbOptions::default())
+        };
+        let mut buffer_manager = new_over_file();
+        buffer_manager.restore(None).unwrap();
+        let ids: Vec<_> = (0..3)
+            .map(|key| buffer_manager.new_node(leaf(key)).unwrap())
+            .collect();
+        assert_eq!(buffer_manager.dirty_node_ids().len(), 3);
+        let image = checkpoint(&buffer_manager, 100);
+        assert!(buffer_manager.dirty_node_ids().is_empty());
+        assert_eq!(image.lsn(), 100);
+        assert_eq!(image.next_node_id(), node::Id::from(4));
+        assert_eq!(image.locations().len(), 3);
+        assert_eq!(image.page_count(), 1);
+        // Neither the changed nor the removed nodes reuse the image pages
+        *buffer_manager.get_mut(ids[0]).unwrap().unwrap() = leaf(b'x');
+        *buffer_manager.get_mut(ids[2]).unwrap().unwrap() = leaf(b'z');
+        assert!(buffer_manager.remove(ids[1]).unwrap());
+        let id = buffer_manager.new_node(leaf(b'y')).unwrap();
+        assert_eq!(id, ids[1]);
+        assert_eq!(buffer_manager.dirty_node_ids().len(), 3);
+        buffer_manager.flush().unwrap();
+        assert_eq!(buffer_manager.data_file_page_count(), 2);
+        // The next checkpoint frees the pages of the previous image
+        let next_image = checkpoint(&buffer_manager, 200);
+        assert_eq!(next_image.page_count(), 2);
+        assert_eq!(next_image.free_extents(), [Extent::new(0, 1)]);
+        // Which are intact until reused
+        let mut restored = new_over_file();
+        restored.restore(Some(&image)).unwrap();
+        assert_eq!(restored.data_file_page_count(), 1);
+        for (key, id) in (0..3).zip(&ids) {
+            let node = restored.get(*id).unwrap().unwrap();
+            assert_eq!(node.try_map(node::Node::as_leaf).unwrap().key(), [key]);
+        }
+    }
 }
@@ -0,0 +1,149 @@
+// Copyright (C) 2024 Laurynas Biveinis
+
+// The system catalog is an ART at a well-known descriptor node, mapping the
+// keyspace names to their definitions. It is created without being logged at
+// every open that does not start from a checkpoint, and its contents are
+// logged and recovered as any other tree.
+
+use crate::art;
+use crate::buffer_manager::BufferManager;
+use crate::key::KeySchema;
+use crate::log::{read_bytes, read_u64, write_bytes};
+use crate::node;
+use crate::DbError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[must_use]
+pub struct Keyspace {
+    name: String,
+    tree: node::Id,
+    key_schema: KeySchema,
+    creation_lsn: u64,
+    options: Vec<u8>,
+}
+
+impl Keyspace {
+    pub(crate) fn new(
+        name: String,
+        tree: node::Id,
+        key_schema: KeySchema,
+        creation_lsn: u64,
+        options: Vec<u8>,
+    ) -> Self {
+        Self {
+            name,
+            tree,
+            key_schema,
+            creation_lsn,
+            options,
+        }
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub fn tree(&self) -> node::Id {
+        self.tree
+    }
+
+    /// A schema without any columns leaves the keys uninterpreted.
+    #[inline]
+    pub fn key_schema(&self) -> &KeySchema {
+        &self.key_schema
+    }
+
+    /// The log end at the keyspace creation: all the changes to the keyspace
+    /// are logged after it.
+    #[must_use]
+    #[inline]
+    pub fn creation_lsn(&self) -> u64 {
+        self.creation_lsn
+    }
+
+    /// Stored verbatim for the client.
+    #[must_use]
+    #[inline]
+    pub fn options(&self) -> &[u8] {
+        &self.options
+    }
+
+    // The catalog value: the tree, the creation LSN, the key schema, and the
+    // options
+    pub(crate) fn to_value(&self) -> Result<Vec<u8>, DbError> {
+        let mut result = Vec::new();
+        result.extend_from_slice(&self.tree.to_le_bytes());
+        result.extend_from_slice(&self.creation_lsn.to_le_bytes());
+        write_bytes(&mut result, &self.key_schema.to_bytes())?;
+        write_bytes(&mut result, &self.options)?;
+        Ok(result)
+    }
+
+    pub(crate) fn from_entry(name: Vec<u8>, value: &[u8]) -> Result<Self, DbError> {
+        let name = String::from_utf8(name).map_err(|error| DbError::BadCatalogEntry {
+            name: String::from_utf8_lossy(error.as_bytes()).into_owned(),
+        })?;
+        let mut reader = value;
+        let fields = (|| {
+            let tree = node::Id::from(read_u64(&mut reader).ok()?);
+            let creation_lsn = read_u64(&mut reader).ok()?;
+            let key_schema = KeySchema::from_bytes(&read_bytes(&mut reader).ok()?)?;
+            let options = read_bytes(&mut reader).ok()?;
+            reader
+                .is_empty()
+                .then_some((tree, key_schema, creation_lsn, options))
+        })();
+        let Some((tree, key_schema, creation_lsn, options)) = fields else {
+            return Err(DbError::BadCatalogEntry { name });
+        };
+        Ok(Self::new(name, tree, key_schema, creation_lsn, options))
+    }
+}
+
+pub(crate) fn bootstrap(buffer_manager: &BufferManager) -> Result<(), DbError> {
+    art::create(buffer_manager, node::Id::CATALOG)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Keyspace;
+    use crate::key::{Collation, Column, ColumnType, KeySchema, Order};
+    use crate::node;
+    use crate::DbError;
+
+    #[test]
+    fn value_round_trip() {
+        let keyspace = Keyspace::new(
+            "db/t".to_owned(),
+            node::Id::from(7),
+            KeySchema::new(vec![
+                Column::new(ColumnType::I32, Order::Ascending, false),
+                Column::new(
+                    ColumnType::String(Collation::Binary),
+                    Order::Descending,
+                    true,
+                ),
+            ]),
+            1234,
+            b"options".to_vec(),
+        );
+        let value = keyspace.to_value().unwrap();
+        assert_eq!(
+            Keyspace::from_entry(b"db/t".to_vec(), &value).unwrap(),
+            keyspace
+        );
+        for bad_len in [0, 8, 16, value.len() - 1] {
+            assert!(matches!(
+                Keyspace::from_entry(b"db/t".to_vec(), &value[..bad_len]),
+                Err(DbError::BadCatalogEntry { name }) if name == "db/t"
+            ));
+        }
+        let mut trailing = value.clone();
+        trailing.push(0);
+        assert!(Keyspace::from_entry(b"db/t".to_vec(), &trailing).is_err());
+        assert!(Keyspace::from_entry(vec![0xFF], &value).is_err());
+    }
+}
@@ -0,0 +1,393 @@
+// Copyright (C) 2024 Laurynas Biveinis
+
+// A checkpoint is an image of all the nodes in the data file, together with the
+// log position from which the log must be redone on top of it. The image pages
+// are never overwritten until the next checkpoint replaces it, thus a crash at
+// any point leaves either the old or the new checkpoint intact. The checkpoint
+// file is replaced atomically by renaming.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::Path;
+
+use cap_std::fs::{Dir, OpenOptions};
+
+use crate::data_file::{Extent, Location};
+use crate::log::{read_bytes, read_u64, sync_dir, write_bytes};
+use crate::node;
+use crate::transaction_manager;
+use crate::DbError;
+
+const CHECKPOINT_FILE_NAME: &str = "CHECKPOINT";
+const NEW_CHECKPOINT_FILE_NAME: &str = "CHECKPOINT.new";
+
+#[derive(Debug, Clone, PartialEq, Eq)] // COV_EXCL_LINE
+#[must_use]
+pub struct Checkpoint {
+    lsn: u64,
+    next_node_id: node::Id,
+    next_transaction_id: transaction_manager::Id,
+    free_node_ids: Vec<node::Id>,
+    locations: Vec<(node::Id, Location)>,
+    page_count: u64,
+    free_extents: Vec<Extent>,
+    // The slotted pages of the records and their slot counts
+    record_pages: Vec<(u64, u16)>,
+    // The keys with the versions kept for the snapshots. The deleted ones are
+    // still in the image, and are to be removed at open.
+    unpurged_keys: Vec<(node::Id, Vec<u8>)>,
+    // The trees dropped but kept for the snapshots, to be destroyed at open
+    dropped_trees: Vec<node::Id>,
+}
+
+fn write_count(writer: &mut impl Write, count: usize) -> Result<(), io::Error> {
+    let count = u64::try_from(count).map_err(io::Error::other)?;
+    writer.write_all(&count.to_le_bytes())
+}
+
+fn read_extent(reader: &mut &[u8]) -> Result<Extent, io::Error> {
+    let first_page = read_u64(reader)?;
+    let page_count = read_u64(reader)?;
+    if page_count == 0 {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+    Ok(Extent::new(first_page, page_count))
+}
+
+const RECORD_LOCATION: u64 = 0;
+const EXTENT_LOCATION: u64 = 1;
+
+fn write_location(writer: &mut Vec<u8>, location: Location) {
+    let (kind, first, second) = match location {
+        Location::Record { page, slot } => (RECORD_LOCATION, page, u64::from(slot)),
+        Location::Extent(extent) => (EXTENT_LOCATION, extent.first_page(), extent.page_count()),
+    };
+    writer.extend_from_slice(&kind.to_le_bytes());
+    writer.extend_from_slice(&first.to_le_bytes());
+    writer.extend_from_slice(&second.to_le_bytes());
+}
+
+fn read_location(reader: &mut &[u8]) -> Result<Location, io::Error> {
+    match read_u64(reader)? {
+        RECORD_LOCATION => {
+            let page = read_u64(reader)?;
+            let slot = u16::try_from(read_u64(reader)?).map_err(io::Error::other)?;
+            Ok(Location::Record { page, slot })
+        }
+        EXTENT_LOCATION => Ok(Location::Extent(read_extent(reader)?)),
+        _ => Err(io::Error::from(io::ErrorKind::InvalidData)),
+    }
+}
+
+// Reads the count-prefixed items, not trusting the count to preallocate
+fn read_items<T>(
+    reader: &mut &[u8],
+    read_item: impl Fn(&mut &[u8]) -> Result<T, io::Error>,
+) -> Result<Vec<T>, io::Error> {
+    let count = read_u64(reader)?;
+    let mut result = Vec::new();
+    for _ in 0..count {
+        result.push(read_item(reader)?);
+    }
+    Ok(result)
+}
+
+impl Checkpoint {
+    pub fn new(
+        lsn: u64,
+        next_node_id: node::Id,
+        next_transaction_id: transaction_manager::Id,
+        free_node_ids: Vec<node::Id>,
+        locations: Vec<(node::Id, Location)>,
+        page_count: u64,
+        free_extents: Vec<Extent>,
+    ) -> Self {
+        Self {
+            lsn,
+            next_node_id,
+            next_transaction_id,
+            free_node_ids,
+            locations,
+            page_count,
+            free_extents,
+            record_pages: Vec::new(),
+            unpurged_keys: Vec::new(),
+            dropped_trees: Vec::new(),
+        }
+    }
+
+    #[inline]
+    pub fn with_record_pages(mut self, record_pages: Vec<(u64, u16)>) -> Self {
+        self.record_pages = record_pages;
+        self
+    }
+
+    #[inline]
+    pub fn with_unpurged_keys(mut self, unpurged_keys: Vec<(node::Id, Vec<u8>)>) -> Self {
+        self.unpurged_keys = unpurged_keys;
+        self
+    }
+
+    #[inline]
+    pub fn with_dropped_trees(mut self, dropped_trees: Vec<node::Id>) -> Self {
+        self.dropped_trees = dropped_trees;
+        self
+    }
+
+    // The log position to redo from
+    #[must_use]
+    #[inline]
+    pub fn lsn(&self) -> u64 {
+        self.lsn
+    }
+
+    #[inline]
+    pub fn next_node_id(&self) -> node::Id {
+        self.next_node_id
+    }
+
+    // All the transaction IDs below it have been used
+    #[inline]
+    pub fn next_transaction_id(&self) -> transaction_manager::Id {
+        self.next_transaction_id
+    }
+
+    #[inline]
+    pub fn free_node_ids(&self) -> &[node::Id] {
+        &self.free_node_ids
+    }
+
+    #[inline]
+    pub fn locations(&self) -> &[(node::Id, Location)] {
+        &self.locations
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn page_count(&self) -> u64 {
+        self.page_count
+    }
+
+    #[inline]
+    pub fn free_extents(&self) -> &[Extent] {
+        &self.free_extents
+    }
+
+    #[inline]
+    pub fn record_pages(&self) -> &[(u64, u16)] {
+        &self.record_pages
+    }
+
+    #[inline]
+    pub fn unpurged_keys(&self) -> &[(node::Id, Vec<u8>)] {
+        &self.unpurged_keys
+    }
+
+    #[inline]
+    pub fn dropped_trees(&self) -> &[node::Id] {
+        &self.dropped_trees
+    }
+
+    // Returns None if no checkpoint has been written yet
+    pub fn read(dir_handle: &Dir) -> Result<Option<Self>, DbError> {
+        let bytes = match dir_handle.read(CHECKPOINT_FILE_NAME) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(DbError::Io(error)),
+        };
+        Self::from_bytes(&bytes)
+            .map(Some)
+            .ok_or(DbError::BadCheckpoint)
+    }
+
+    // Replaces the current checkpoint, if any
+    pub fn write(&self, dir_handle: &Dir) -> Result<(), DbError> {
+        let bytes = self.to_bytes()?;
+        let mut file = dir_handle.open_with(
+            NEW_CHECKPOINT_FILE_NAME,
+            OpenOptions::new().write(true).create(true).truncate(true),
+        )?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        drop(file);
+        dir_handle.rename(
+            Path::new(NEW_CHECKPOINT_FILE_NAME),
+            dir_handle,
+            Path::new(CHECKPOINT_FILE_NAME),
+        )?;
+        sync_dir(dir_handle)?;
+        Ok(())
+    }
+
+    // The LSN, the next node and transaction IDs, the page count, and the lists
+    // of the free node IDs, the node locations, the free extents, the record
+    // pages, the unpurged tree keys, and the dropped trees, each prefixed by
+    // its length
+    fn to_bytes(&self) -> Result<Vec<u8>, io::Error> {
+        let mut result = Vec::new();
+        result.extend_from_slice(&self.lsn.to_le_bytes());
+        result.extend_from_slice(&self.next_node_id.to_le_bytes());
+        result.extend_from_slice(&self.next_transaction_id.as_u64().to_le_bytes());
+        result.extend_from_slice(&self.page_count.to_le_bytes());
+        write_count(&mut result, self.free_node_ids.len())?;
+        for id in &self.free_node_ids {
+            result.extend_from_slice(&id.to_le_bytes());
+        }
+        write_count(&mut result, self.locations.len())?;
+        for (id, location) in &self.locations {
+            result.extend_from_slice(&id.to_le_bytes());
+            write_location(&mut result, *location);
+        }
+        write_count(&mut result, self.free_extents.len())?;
+        for extent in &self.free_extents {
+            result.extend_from_slice(&extent.first_page().to_le_bytes());
+            result.extend_from_slice(&extent.page_count().to_le_bytes());
+        }
+        write_count(&mut result, self.record_pages.len())?;
+        for (page, slot_count) in &self.record_pages {
+            result.extend_from_slice(&page.to_le_bytes());
+            result.extend_from_slice(&u64::from(*slot_count).to_le_bytes());
+        }
+        write_count(&mut result, self.unpurged_keys.len())?;
+        for (tree, key) in &self.unpurged_keys {
+            result.extend_from_slice(&tree.to_le_bytes());
+            write_bytes(&mut result, key)?;
+        }
+        write_count(&mut result, self.dropped_trees.len())?;
+        for tree in &self.dropped_trees {
+            result.extend_from_slice(&tree.to_le_bytes());
+        }
+        Ok(result)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut reader = bytes;
+        let lsn = read_u64(&mut reader).ok()?;
+        let next_node_id = node::Id::from(read_u64(&mut reader).ok()?);
+        let next_transaction_id = transaction_manager::Id::from(read_u64(&mut reader).ok()?);
+        let page_count = read_u64(&mut reader).ok()?;
+        let free_node_ids =
+            read_items(&mut reader, |reader| Ok(node::Id::from(read_u64(reader)?))).ok()?;
+        let locations = read_items(&mut reader, |reader| {
+            let id = node::Id::from(read_u64(reader)?);
+            Ok((id, read_location(reader)?))
+        })
+        .ok()?;
+        let free_extents = read_items(&mut reader, read_extent).ok()?;
+        let record_pages = read_items(&mut reader, |reader| {
+            let page = read_u64(reader)?;
+            let slot_count = u16::try_from(read_u64(reader)?).map_err(io::Error::other)?;
+            Ok((page, slot_count))
+        })
+        .ok()?;
+        let unpurged_keys = read_items(&mut reader, |reader| {
+            let tree = node::Id::from(read_u64(reader)?);
+            Ok((tree, read_bytes(reader)?))
+        })
+        .ok()?;
+        let dropped_trees =
+            read_items(&mut reader, |reader| Ok(node::Id::from(read_u64(reader)?))).ok()?;
+        let is_in_file = |extent: &Extent| extent.first_page() + extent.page_count() <= page_count;
+        let slot_counts: BTreeMap<_, _> = record_pages.iter().copied().collect();
+        let is_valid_location = |location: &Location| match location {
+            Location::Record { page, slot } => slot_counts
+                .get(page)
+                .is_some_and(|slot_count| slot < slot_count),
+            Location::Extent(extent) => is_in_file(extent),
+        };
+        let is_valid = reader.is_empty()
+            && locations
+                .iter()
+                .all(|(_, location)| is_valid_location(location))
+            && free_extents.iter().all(is_in_file)
+            && record_pages.iter().all(|(page, _)| *page < page_count)
+            && free_node_ids.iter().all(|id| *id < next_node_id);
+        is_valid.then(|| {
+            Self::new(
+                lsn,
+                next_node_id,
+                next_transaction_id,
+                free_node_ids,
+                locations,
+                page_count,
+                free_extents,
+            )
+            .with_record_pages(record_pages)
+            .with_unpurged_keys(unpurged_keys)
+            .with_dropped_trees(dropped_trees)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Checkpoint, CHECKPOINT_FILE_NAME};
+    use crate::data_file::{Extent, Location};
+    use crate::node;
+    use crate::transaction_manager;
+    use crate::DbError;
+    use cap_std::fs::Dir;
+
+    fn checkpoint() -> Checkpoint {
+        Checkpoint::new(
+            1234,
+            node::Id::from(10),
+            transaction_manager::Id::from(20),
+            vec![node::Id::from(3), node::Id::from(5)],
+            vec![
+                (node::Id::CATALOG, Location::Record { page: 0, slot: 1 }),
+                (node::Id::from(7), Location::Extent(Extent::new(3, 2))),
+            ],
+            5,
+            vec![Extent::new(1, 2)],
+        )
+        .with_record_pages(vec![(0, 2)])
+        .with_unpurged_keys(vec![(node::Id::from(7), b"key".to_vec())])
+        .with_dropped_trees(vec![node::Id::from(8)])
+    }
+
+    #[test]
+    fn round_trip() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir_handle =
+            Dir::open_ambient_dir(temp_dir.path(), cap_std::ambient_authority()).unwrap();
+        assert!(Checkpoint::read(&dir_handle).unwrap().is_none());
+        checkpoint().write(&dir_handle).unwrap();
+        assert_eq!(Checkpoint::read(&dir_handle).unwrap(), Some(checkpoint()));
+        let newer = Checkpoint::new(
+            5678,
+            node::Id::from(2),
+            transaction_manager::Id::from(0),
+            vec![],
+            vec![],
+            0,
+            vec![],
+        );
+        newer.write(&dir_handle).unwrap();
+        assert_eq!(Checkpoint::read(&dir_handle).unwrap(), Some(newer));
+    }
+
+    #[test]
+    fn corrupted() {
+        let bytes = checkpoint().to_bytes().unwrap();
+        assert_eq!(Checkpoint::from_bytes(&bytes), Some(checkpoint()));
+        for bad_len in [0, 8, 24, 40, bytes.len() - 1] {
+            assert!(Checkpoint::from_bytes(&bytes[..bad_len]).is_none());
+        }
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(Checkpoint::from_bytes(&trailing).is_none());
+        // The page count below the end of an extent
+        let mut short_file = bytes.clone();
+        short_file[24..32].copy_from_slice(&4_u64.to_le_bytes());
+        assert!(Checkpoint::from_bytes(&short_file).is_none());
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir_handle =
+            Dir::open_ambient_dir(temp_dir.path(), cap_std::ambient_authority()).unwrap();
+        dir_handle.write(CHECKPOINT_FILE_NAME, &bytes[..8]).unwrap();
+        assert!(matches!(
+            Checkpoint::read(&dir_handle),
+            Err(DbError::BadCheckpoint)
+        ));
+    }
+}
@@ -0,0 +1,351 @@
+// Copyright (C) 2024 Laurynas Biveinis
+
+// Ordered access to a single tree through a transaction. Neither the cursors
+// nor the range hold any nodes between the calls: every step is a fresh seek
+// from the last returned key. A prefix scan seeks under the subtree of the
+// prefix, found again only once the tree may have been changed. A cursor
+// borrows its transaction, thus the transaction cannot be written while it
+// exists. A detached cursor is given the transaction at every call instead,
+// and stays valid across the writes of the transaction between the calls.
+
+use std::ops::Bound;
+
+use crate::art;
+use crate::art::Entry;
+use crate::node;
+use crate::transaction_manager::{PrefixSubtree, Transaction};
+use crate::DbError;
+
+#[derive(Debug)] // COV_EXCL_LINE
+#[must_use]
+enum Position {
+    Unpositioned,
+    At(Entry),
+    Exhausted,
+}
+
+#[derive(Debug)] // COV_EXCL_LINE
+#[must_use]
+pub struct DetachedCursor {
+    tree: node::Id,
+    position: Position,
+}
+
+impl DetachedCursor {
+    pub fn new(tree: node::Id) -> Self {
+        Self {
+            tree,
+            position: Position::Unpositioned,
+        }
+    }
+
+    fn set_position(&mut self, entry: Option<Entry>) -> bool {
+        if let Some(entry) = entry {
+            self.position = Position::At(entry);
+            true
+        } else {
+            self.position = Position::Exhausted;
+            false
+        }
+    }
+
+    /// Positions at the first key greater than or equal to `key`. Returns
+    /// whether there is such key.
+    /// # Errors
+    /// Will return `DbError` if it encounters any.
+    pub fn seek(&mut self, transaction: &Transaction, key: &[u8]) -> Result<bool, DbError> {
+        let entry =
+            transaction.first_in_range(self.tree, Bound::Included(key), Bound::Unbounded)?;
+        Ok(self.set_position(entry))
+    }
+
+    /// Positions at the last key less than or equal to `key`. Returns whether
+    /// there is such key.
+    /// # Errors
+    /// Will return `DbError` if it encounters any.
+    pub fn seek_for_prev(
+        &mut self,
+        transaction: &Transaction,
+        key: &[u8],
+    ) -> Result<bool, DbError> {
+        let entry = transaction.last_in_range(self.tree, Bound::Unbounded, Bound::Included(key))?;
+        Ok(self.set_position(entry))
+    }
+
+    /// # Errors
+    /// Will return `DbError` if it encounters any.
+    pub fn seek_to_first(&mut self, transaction: &Transaction) -> Result<bool, DbError> {
+        let entry = transaction.first_in_range(self.tree, Bound::Unbounded, Bound::Unbounded)?;
+        Ok(self.set_position(entry))
+    }
+
+    /// # Errors
+    /// Will return `DbError` if it encounters any.
+    pub fn seek_to_last(&mut self, transaction: &Transaction) -> Result<bool, DbError> {
+        let entry = transaction.last_in_range(self.tree, Bound::Unbounded, Bound::Unbounded)?;
+        Ok(self.set_position(entry))
+    }
+
+    /// Moves to the next key. Returns false if there is none or the cursor is
+    /// not positioned.
+    /// # Errors
+    /// Will return `DbError` if it encounters any.
+    pub fn move_next(&mut self, transaction: &Transaction) -> Result<bool, DbError> {
+        let Position::At((key, _)) = &self.position else {
+            return Ok(false);
+        };
+        let entry = transaction.first_in_range(
+            self.tree,
+            Bound::Excluded(key.as_slice()),
+            Bound::Unbounded,
+        )?;
+        Ok(self.set_position(entry))
+    }
+
+    /// Moves to the previous key. Returns false if there is none or the cursor
+    /// is not positioned.
+    /// # Errors
+    /// Will return `DbError` if it encounters any.
+    pub fn move_prev(&mut self, transaction: &Transaction) -> Result<bool, DbError> {
+        let Position::At((key, _)) = &self.position else {
+            return Ok(false);
+        };
+        let entry = transaction.last_in_range(
+            self.tree,
+            Bound::Unbounded,
+            Bound::Excluded(key.as_slice()),
+        )?;
+        Ok(self.set_position(entry))
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn is_valid(&self) -> bool {
+        matches!(self.position, Position::At(_))
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn key(&self) -> Option<&[u8]> {
+        match &self.position {
+            Position::At((key, _)) => Some(key),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn value(&self) -> Option<&[u8]> {
+        match &self.position {
+            Position::At((_, value)) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)] // COV_EXCL_LINE
+#[must_use]
+pub struct Cursor<'t> {
+    transaction: &'t Transaction,
+    detached: DetachedCursor,
+    // Whether the current entry has already been returned by the iterator
+    yielded: bool,
+}
+
+impl<'t> Cursor<'t> {
+    pub(crate) fn new(transaction: &'t Transaction, tree: node::Id) -> Self {
+        Self {
+            transaction,
+            detached: DetachedCursor::new(tree),
+            yielded: false,
+        }
+    }
+
+    /// Positions at the first key greater than or equal to `key`. Returns
+    /// whether there is such key.
+    /// # Errors
+    /// Will return `DbError` if it encounters any.
+    pub fn seek(&mut self, key: &[u8]) -> Result<bool, DbError> {
+        self.yielded = false;
+        self.detached.seek(self.transaction, key)
+    }
+
+    /// Positions at the last key less than or equal to `key`. Returns whether
+    /// there is such key.
+    /// # Errors
+    /// Will return `DbError` if it encounters any.
+    pub fn seek_for_prev(&mut self, key: &[u8]) -> Result<bool, DbError> {
+        self.yielded = false;
+        self.detached.seek_for_prev(self.transaction, key)
+    }
+
+    /// # Errors
+    /// Will return `DbError` if it encounters any.
+    pub fn seek_to_first(&mut self) -> Result<bool, DbError> {
+        self.yielded = false;
+        self.detached.seek_to_first(self.transaction)
+    }
+
+    /// # Errors
+    /// Will return `DbError` if it encounters any.
+    pub fn seek_to_last(&mut self) -> Result<bool, DbError> {
+        self.yielded = false;
+        self.detached.seek_to_last(self.transaction)
+    }
+
+    /// Moves to the next key. Returns false if there is none or the cursor is
+    /// not positioned.
+    /// # Errors
+    /// Will return `DbError` if it encounters any.
+    pub fn move_next(&mut self) -> Result<bool, DbError> {
+        self.yielded = false;
+        self.detached.move_next(self.transaction)
+    }
+
+    /// Moves to the previous key. Returns false if there is none or the cursor
+    /// is not positioned.
+    /// # Errors
+    /// Will return `DbError` if it encounters any.
+    pub fn move_prev(&mut self) -> Result<bool, DbError> {
+        self.yielded = false;
+        self.detached.move_prev(self.transaction)
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn is_valid(&self) -> bool {
+        self.detached.is_valid()
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn key(&self) -> Option<&[u8]> {
+        self.detached.key()
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn value(&self) -> Option<&[u8]> {
+        self.detached.value()
+    }
+}
+
+// Returns the entry at the cursor and then advances, starting from the first
+// key if the cursor has not been positioned yet.
+impl Iterator for Cursor<'_> {
+    type Item = Result<Entry, DbError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let step = match self.detached.position {
+            Position::Unpositioned => self.seek_to_first(),
+            Position::At(_) if self.yielded => self.move_next(),
+            Position::At(_) => Ok(true),
+            Position::Exhausted => Ok(false),
+        };
+        match step {
+            Ok(true) => {
+                self.yielded = true;
+                let Position::At(entry) = &self.detached.position else {
+                    unreachable!("Cursor not positioned after a successful step");
+                };
+                Some(Ok(entry.clone()))
+            }
+            Ok(false) => None,
+            Err(error) => {
+                self.detached.position = Position::Exhausted;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+// A bounded double-ended iterator over the entries of a tree.
+#[derive(Debug)] // COV_EXCL_LINE
+#[must_use]
+pub struct Range<'t> {
+    transaction: &'t Transaction,
+    tree: node::Id,
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+    // The steps of a prefix scan seek under the subtree of the prefix
+    prefix_subtree: Option<PrefixSubtree>,
+    done: bool,
+}
+
+impl<'t> Range<'t> {
+    pub(crate) fn new(
+        transaction: &'t Transaction,
+        tree: node::Id,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Self {
+        Self {
+            transaction,
+            tree,
+            lower: lower.map(<[u8]>::to_vec),
+            upper: upper.map(<[u8]>::to_vec),
+            prefix_subtree: None,
+            done: false,
+        }
+    }
+
+    pub(crate) fn new_prefix(transaction: &'t Transaction, tree: node::Id, prefix: &[u8]) -> Self {
+        Self {
+            transaction,
+            tree,
+            lower: Bound::Included(prefix.to_vec()),
+            upper: art::prefix_upper_bound(prefix),
+            prefix_subtree: Some(PrefixSubtree::new(prefix)),
+            done: false,
+        }
+    }
+
+    fn step(&mut self, forward: bool) -> Option<Result<Entry, DbError>> {
+        if self.done {
+            return None;
+        }
+        let lower = self.lower.as_ref().map(Vec::as_slice);
+        let upper = self.upper.as_ref().map(Vec::as_slice);
+        let result = self.transaction.seek_in_range(
+            self.tree,
+            lower,
+            upper,
+            forward,
+            self.prefix_subtree.as_mut(),
+        );
+        match result {
+            Ok(Some(entry)) => {
+                if forward {
+                    self.lower = Bound::Excluded(entry.0.clone());
+                } else {
+                    self.upper = Bound::Excluded(entry.0.clone());
+                }
+                Some(Ok(entry))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(error) => {
+                self.done = true;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+impl Iterator for Range<'_> {
+    type Item = Result<Entry, DbError>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.step(true)
+    }
+}
+
+impl DoubleEndedIterator for Range<'_> {
+    #[inline]
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.step(false)
+    }
+}
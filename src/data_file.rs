@@ -0,0 +1,688 @@
+// Copyright (C) 2024 Laurynas Biveinis
+
+// The data file is split into fixed-size pages. A node that fits into a page
+// is stored as a record of a slotted page, shared with other nodes, and a
+// larger one in an extent of contiguous pages, starting with its serialized
+// length. The freed extents are coalesced and reused best-fit, and the file is
+// shrunk when its tail gets freed.
+//
+// A slotted page starts with its slot count and the directory of the record
+// offsets and lengths, and its records are packed from the page end. The
+// records are only appended to the page being filled, and a page is freed once
+// none of its records are live. The extents and the pages of the last
+// checkpoint image are never written, and are freed only after the next
+// checkpoint, so that the image stays intact until then.
+//
+// The nodes are read at their positions without seeking, so that the reads can
+// run concurrently through shared references.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use cap_std::fs::{Dir, File, OpenOptions};
+
+pub const PAGE_SIZE: u64 = PAGE_LEN as u64;
+const PAGE_LEN: usize = 4096;
+const LEN_SIZE: u64 = 8;
+const SLOT_COUNT_SIZE: usize = 2;
+const SLOT_SIZE: usize = 4;
+// The longest node stored as a record
+const MAX_RECORD_LEN: usize = PAGE_LEN - SLOT_COUNT_SIZE - SLOT_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use]
+pub struct Extent {
+    first_page: u64,
+    page_count: u64,
+}
+
+impl Extent {
+    pub fn new(first_page: u64, page_count: u64) -> Self {
+        debug_assert!(page_count > 0);
+        Self {
+            first_page,
+            page_count,
+        }
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn first_page(self) -> u64 {
+        self.first_page
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn page_count(self) -> u64 {
+        self.page_count
+    }
+
+    // The number of pages needed to store the bytes
+    #[must_use]
+    pub fn pages_for(len: usize) -> u64 {
+        (len as u64 + LEN_SIZE).div_ceil(PAGE_SIZE)
+    }
+
+    #[inline]
+    fn offset(self) -> u64 {
+        self.first_page * PAGE_SIZE
+    }
+}
+
+// Where a node is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use]
+pub enum Location {
+    Record { page: u64, slot: u16 },
+    Extent(Extent),
+}
+
+// The slotted page accessors over its bytes
+#[inline]
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let bytes = bytes.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+#[inline]
+fn slot_count(page_bytes: &[u8]) -> Option<u16> {
+    read_u16(page_bytes, 0)
+}
+
+fn record(page_bytes: &[u8], slot: u16) -> Option<&[u8]> {
+    if slot >= slot_count(page_bytes)? {
+        return None;
+    }
+    let slot_offset = SLOT_COUNT_SIZE + usize::from(slot) * SLOT_SIZE;
+    let offset = usize::from(read_u16(page_bytes, slot_offset)?);
+    let len = usize::from(read_u16(page_bytes, slot_offset + 2)?);
+    page_bytes.get(offset..offset + len)
+}
+
+// The page being filled with records, as it is written to the file
+#[derive(Debug)] // COV_EXCL_LINE
+struct OpenPage {
+    page: u64,
+    bytes: Vec<u8>,
+    slot_count: u16,
+    // The offset of the last appended record
+    records_start: usize,
+}
+
+impl OpenPage {
+    fn new(page: u64) -> Self {
+        Self {
+            page,
+            bytes: vec![0; PAGE_LEN],
+            slot_count: 0,
+            records_start: PAGE_LEN,
+        }
+    }
+
+    #[inline]
+    fn fits(&self, len: usize) -> bool {
+        let directory_end = SLOT_COUNT_SIZE + (usize::from(self.slot_count) + 1) * SLOT_SIZE;
+        directory_end + len <= self.records_start
+    }
+
+    // Returns the slot of the record
+    #[allow(clippy::cast_possible_truncation)]
+    fn append(&mut self, record: &[u8]) -> u16 {
+        debug_assert!(self.fits(record.len()));
+        let slot = self.slot_count;
+        self.records_start -= record.len();
+        self.bytes[self.records_start..self.records_start + record.len()].copy_from_slice(record);
+        // Both fit, being within the page
+        let slot_offset = SLOT_COUNT_SIZE + usize::from(slot) * SLOT_SIZE;
+        self.bytes[slot_offset..slot_offset + 2]
+            .copy_from_slice(&(self.records_start as u16).to_le_bytes());
+        self.bytes[slot_offset + 2..slot_offset + 4]
+            .copy_from_slice(&(record.len() as u16).to_le_bytes());
+        self.slot_count += 1;
+        self.bytes[..SLOT_COUNT_SIZE].copy_from_slice(&self.slot_count.to_le_bytes());
+        slot
+    }
+}
+
+#[derive(Debug, Clone, Copy)] // COV_EXCL_LINE
+struct RecordPage {
+    slot_count: u16,
+    live_count: u16,
+    // Whether the page belongs to the last checkpoint image
+    in_image: bool,
+}
+
+// The allocated page count, and the free extents within it
+#[derive(Debug, Clone, Default)] // COV_EXCL_LINE
+struct FreeSpace {
+    page_count: u64,
+    // The free extents as the first page to page count, and ordered by the
+    // page count for the best fit
+    free_extents: BTreeMap<u64, u64>,
+    free_by_size: BTreeSet<(u64, u64)>,
+}
+
+impl FreeSpace {
+    // Takes the smallest free extent that fits, or allocates the pages at the
+    // end
+    fn allocate(&mut self, page_count: u64) -> Extent {
+        if let Some(&(free_count, first_page)) = self.free_by_size.range((page_count, 0)..).next() {
+            self.remove_free(first_page, free_count);
+            if free_count > page_count {
+                self.insert_free(first_page + page_count, free_count - page_count);
+            }
+            return Extent::new(first_page, page_count);
+        }
+        let result = Extent::new(self.page_count, page_count);
+        self.page_count += page_count;
+        result
+    }
+
+    // Coalesces the extent with the adjacent free ones, dropping it from the
+    // end if it is the last one
+    fn free(&mut self, extent: Extent) {
+        let mut first_page = extent.first_page;
+        let mut page_count = extent.page_count;
+        debug_assert!(first_page + page_count <= self.page_count);
+        if let Some((&prev_first, &prev_count)) = self.free_extents.range(..first_page).next_back()
+        {
+            debug_assert!(prev_first + prev_count <= first_page);
+            if prev_first + prev_count == first_page {
+                self.remove_free(prev_first, prev_count);
+                first_page = prev_first;
+                page_count += prev_count;
+            }
+        }
+        let next_first = first_page + page_count;
+        if let Some(&next_count) = self.free_extents.get(&next_first) {
+            self.remove_free(next_first, next_count);
+            page_count += next_count;
+        }
+        if first_page + page_count == self.page_count {
+            self.page_count = first_page;
+        } else {
+            self.insert_free(first_page, page_count);
+        }
+    }
+
+    fn free_extents(&self) -> Vec<Extent> {
+        self.free_extents
+            .iter()
+            .map(|(first_page, page_count)| Extent::new(*first_page, *page_count))
+            .collect()
+    }
+
+    fn insert_free(&mut self, first_page: u64, page_count: u64) {
+        self.free_extents.insert(first_page, page_count);
+        self.free_by_size.insert((page_count, first_page));
+    }
+
+    fn remove_free(&mut self, first_page: u64, page_count: u64) {
+        self.free_extents.remove(&first_page);
+        self.free_by_size.remove(&(page_count, first_page));
+    }
+}
+
+#[derive(Debug)] // COV_EXCL_LINE
+#[must_use]
+pub struct DataFile {
+    // For the positional reads
+    file: fs::File,
+    space: FreeSpace,
+    // The freed extents of the last checkpoint image
+    deferred_free: Vec<Extent>,
+    record_pages: BTreeMap<u64, RecordPage>,
+    open_page: Option<OpenPage>,
+}
+
+impl DataFile {
+    // The contents are to be set by restore
+    pub fn open(dir_handle: &Dir, file_name: &Path, create: bool) -> Result<Self, io::Error> {
+        let file = if create {
+            dir_handle.open_with(
+                file_name,
+                OpenOptions::new().read(true).write(true).create_new(true),
+            )
+        } else {
+            dir_handle.open_with(file_name, OpenOptions::new().read(true).write(true))
+        }?;
+        Ok(Self::new(file))
+    }
+
+    // The file must be empty
+    pub fn new(file: File) -> Self {
+        Self {
+            file: file.into_std(),
+            space: FreeSpace::default(),
+            deferred_free: Vec::new(),
+            record_pages: BTreeMap::new(),
+            open_page: None,
+        }
+    }
+
+    // Sets the allocated pages, the free extents, and the record pages with
+    // their slot counts as of a checkpoint, discarding any pages written after
+    // it. The records at the locations are live.
+    pub fn restore(
+        &mut self,
+        page_count: u64,
+        free_extents: &[Extent],
+        record_pages: &[(u64, u16)],
+        locations: impl Iterator<Item = Location>,
+    ) -> Result<(), io::Error> {
+        self.file.set_len(page_count * PAGE_SIZE)?;
+        self.space = FreeSpace {
+            page_count,
+            ..FreeSpace::default()
+        };
+        for extent in free_extents {
+            self.space.insert_free(extent.first_page, extent.page_count);
+        }
+        self.deferred_free.clear();
+        self.open_page = None;
+        self.record_pages = record_pages
+            .iter()
+            .map(|(page, slot_count)| {
+                let record_page = RecordPage {
+                    slot_count: *slot_count,
+                    live_count: 0,
+                    in_image: true,
+                };
+                (*page, record_page)
+            })
+            .collect();
+        for location in locations {
+            if let Location::Record { page, .. } = location {
+                if let Some(record_page) = self.record_pages.get_mut(&page) {
+                    record_page.live_count += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn fits_in_record(len: usize) -> bool {
+        len <= MAX_RECORD_LEN
+    }
+
+    // Appends the record to the page being filled, starting a new page if it
+    // does not fit there
+    pub fn append_record(&mut self, bytes: &[u8]) -> Result<Location, io::Error> {
+        debug_assert!(Self::fits_in_record(bytes.len()));
+        if self
+            .open_page
+            .as_ref()
+            .is_some_and(|open_page| !open_page.fits(bytes.len()))
+        {
+            self.seal_open_page()?;
+        }
+        let open_page = if let Some(open_page) = &mut self.open_page {
+            open_page
+        } else {
+            let page = self.space.allocate(1).first_page();
+            let record_page = RecordPage {
+                slot_count: 0,
+                live_count: 0,
+                in_image: false,
+            };
+            self.record_pages.insert(page, record_page);
+            self.open_page.insert(OpenPage::new(page))
+        };
+        let page = open_page.page;
+        let slot = open_page.append(bytes);
+        if let Some(record_page) = self.record_pages.get_mut(&page) {
+            record_page.slot_count += 1;
+            record_page.live_count += 1;
+        }
+        let written = self
+            .file
+            .seek(SeekFrom::Start(page * PAGE_SIZE))
+            .and_then(|_| self.file.write_all(&open_page.bytes));
+        if let Err(error) = written {
+            // The record is left dead in the page
+            if let Some(record_page) = self.record_pages.get_mut(&page) {
+                record_page.live_count -= 1;
+            }
+            return Err(error);
+        }
+        Ok(Location::Record { page, slot })
+    }
+
+    // Stops appending to the page being filled, which is freed if none of
+    // its records are live anymore
+    pub fn seal_open_page(&mut self) -> Result<(), io::Error> {
+        let Some(open_page) = self.open_page.take() else {
+            return Ok(());
+        };
+        if self
+            .record_pages
+            .get(&open_page.page)
+            .is_some_and(|record_page| record_page.live_count == 0)
+        {
+            self.free_record_page(open_page.page)?;
+        }
+        Ok(())
+    }
+
+    fn free_record_page(&mut self, page: u64) -> Result<(), io::Error> {
+        let Some(record_page) = self.record_pages.remove(&page) else {
+            return Ok(());
+        };
+        let extent = Extent::new(page, 1);
+        if record_page.in_image {
+            self.deferred_free.push(extent);
+            Ok(())
+        } else {
+            self.free(extent)
+        }
+    }
+
+    // Frees the location of a node, which may belong to the last checkpoint
+    // image. The pages of the records are freed once none of their records are
+    // live.
+    pub fn free_location(&mut self, location: Location, in_image: bool) -> Result<(), io::Error> {
+        match location {
+            Location::Record { page, .. } => {
+                let Some(record_page) = self.record_pages.get_mut(&page) else {
+                    return Ok(());
+                };
+                record_page.live_count -= 1;
+                let is_open = self
+                    .open_page
+                    .as_ref()
+                    .is_some_and(|open_page| open_page.page == page);
+                if record_page.live_count == 0 && !is_open {
+                    self.free_record_page(page)?;
+                }
+                Ok(())
+            }
+            Location::Extent(extent) if in_image => {
+                self.free_after_checkpoint(extent);
+                Ok(())
+            }
+            Location::Extent(extent) => self.free(extent),
+        }
+    }
+
+    // The record pages other than the one being filled with less than a
+    // quarter of their records live
+    #[must_use]
+    pub fn sparse_record_pages(&self) -> HashSet<u64> {
+        let open_page = self.open_page.as_ref().map(|open_page| open_page.page);
+        self.record_pages
+            .iter()
+            .filter(|(page, record_page)| {
+                Some(**page) != open_page && record_page.live_count * 4 < record_page.slot_count
+            })
+            .map(|(page, _)| *page)
+            .collect()
+    }
+
+    // The record pages and their slot counts
+    #[must_use]
+    pub fn record_pages(&self) -> Vec<(u64, u16)> {
+        self.record_pages
+            .iter()
+            .map(|(page, record_page)| (*page, record_page.slot_count))
+            .collect()
+    }
+
+    #[inline]
+    pub fn allocate(&mut self, page_count: u64) -> Extent {
+        self.space.allocate(page_count)
+    }
+
+    pub fn free(&mut self, extent: Extent) -> Result<(), io::Error> {
+        let old_page_count = self.space.page_count;
+        self.space.free(extent);
+        if self.space.page_count < old_page_count {
+            self.file.set_len(self.space.page_count * PAGE_SIZE)?;
+        }
+        Ok(())
+    }
+
+    // Frees the extent of the last checkpoint image once the next checkpoint
+    // is written
+    pub fn free_after_checkpoint(&mut self, extent: Extent) {
+        self.deferred_free.push(extent);
+    }
+
+    // The allocated page count and the free extents once the deferred frees
+    // are done
+    pub fn space_after_checkpoint(&self) -> (u64, Vec<Extent>) {
+        let mut space = self.space.clone();
+        for extent in &self.deferred_free {
+            space.free(*extent);
+        }
+        (space.page_count, space.free_extents())
+    }
+
+    // The record pages written so far belong to the new checkpoint image
+    pub fn finish_checkpoint(&mut self) -> Result<(), io::Error> {
+        debug_assert!(self.open_page.is_none());
+        for extent in std::mem::take(&mut self.deferred_free) {
+            self.free(extent)?;
+        }
+        for record_page in self.record_pages.values_mut() {
+            record_page.in_image = true;
+        }
+        Ok(())
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn page_count(&self) -> u64 {
+        self.space.page_count
+    }
+
+    pub fn sync(&self) -> Result<(), io::Error> {
+        self.file.sync_data()
+    }
+
+    pub fn read(&self, location: Location) -> Result<Vec<u8>, io::Error> {
+        match location {
+            Location::Record { page, slot } => self.read_record(page, slot),
+            Location::Extent(extent) => self.read_extent(extent),
+        }
+    }
+
+    fn read_record(&self, page: u64, slot: u16) -> Result<Vec<u8>, io::Error> {
+        let mut page_bytes = vec![0; PAGE_LEN];
+        self.file.read_exact_at(&mut page_bytes, page * PAGE_SIZE)?;
+        record(&page_bytes, slot)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))
+    }
+
+    fn read_extent(&self, extent: Extent) -> Result<Vec<u8>, io::Error> {
+        let mut len_buf = [0; 8];
+        self.file.read_exact_at(&mut len_buf, extent.offset())?;
+        let len = u64::from_le_bytes(len_buf);
+        if len + LEN_SIZE > extent.page_count() * PAGE_SIZE {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+        let mut result = vec![0; usize::try_from(len).map_err(io::Error::other)?];
+        self.file
+            .read_exact_at(&mut result, extent.offset() + LEN_SIZE)?;
+        Ok(result)
+    }
+
+    pub fn write(&mut self, extent: Extent, bytes: &[u8]) -> Result<(), io::Error> {
+        debug_assert!(Extent::pages_for(bytes.len()) <= extent.page_count());
+        self.file.seek(SeekFrom::Start(extent.offset()))?;
+        self.file.write_all(&(bytes.len() as u64).to_le_bytes())?;
+        self.file.write_all(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DataFile, Extent, Location, MAX_RECORD_LEN, PAGE_SIZE};
+    use cap_std::fs::File;
+
+    #[test]
+    fn pages_for() {
+        assert_eq!(Extent::pages_for(0), 1);
+        assert_eq!(Extent::pages_for(4088), 1);
+        assert_eq!(Extent::pages_for(4089), 2);
+        assert_eq!(Extent::pages_for(3 * 4096), 4);
+    }
+
+    #[test]
+    fn write_and_read_extents() {
+        let mut data_file = DataFile::new(File::from_std(tempfile::tempfile().unwrap()));
+        let small = data_file.allocate(1);
+        let large = data_file.allocate(3);
+        assert_eq!(small.first_page(), 0);
+        assert_eq!(large.first_page(), 1);
+        let large_bytes: Vec<u8> = (0..2 * PAGE_SIZE).map(|i| (i % 251) as u8).collect();
+        data_file.write(large, &large_bytes).unwrap();
+        data_file.write(small, b"small").unwrap();
+        assert_eq!(data_file.read(Location::Extent(small)).unwrap(), b"small");
+        assert_eq!(
+            data_file.read(Location::Extent(large)).unwrap(),
+            large_bytes
+        );
+        data_file.write(small, b"").unwrap();
+        assert!(data_file.read(Location::Extent(small)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn free_extents_reused() {
+        let mut data_file = DataFile::new(File::from_std(tempfile::tempfile().unwrap()));
+        let extents: Vec<_> = (1..=4).map(|count| data_file.allocate(count)).collect();
+        let last = data_file.allocate(1);
+        assert_eq!(data_file.page_count(), 11);
+        for extent in &extents {
+            data_file.write(*extent, b"x").unwrap();
+        }
+        data_file.write(last, b"x").unwrap();
+        data_file.free(extents[1]).unwrap();
+        data_file.free(extents[3]).unwrap();
+        // The best fit is taken
+        let reused = data_file.allocate(2);
+        assert_eq!(reused, extents[1]);
+        // The rest of a larger free extent stays free
+        let split = data_file.allocate(3);
+        assert_eq!(split.first_page(), extents[3].first_page());
+        assert_eq!(
+            data_file.allocate(1).first_page(),
+            extents[3].first_page() + 3
+        );
+        assert_eq!(data_file.page_count(), 11);
+        // The adjacent free extents are coalesced
+        data_file.free(extents[0]).unwrap();
+        data_file.free(extents[2]).unwrap();
+        data_file.free(reused).unwrap();
+        assert_eq!(data_file.allocate(6).first_page(), 0);
+        assert_eq!(data_file.page_count(), 11);
+    }
+
+    #[test]
+    fn checkpoint_extents_freed_later() {
+        let mut data_file = DataFile::new(File::from_std(tempfile::tempfile().unwrap()));
+        let first = data_file.allocate(1);
+        let second = data_file.allocate(1);
+        let third = data_file.allocate(1);
+        data_file.free_after_checkpoint(first);
+        data_file.free_after_checkpoint(third);
+        assert_eq!(data_file.allocate(1).first_page(), 3);
+        assert_eq!(data_file.space_after_checkpoint(), (4, vec![first, third]));
+        data_file.finish_checkpoint().unwrap();
+        assert_eq!(data_file.allocate(1), first);
+        // The restored space discards the pages written after the checkpoint
+        data_file.write(second, b"x").unwrap();
+        data_file
+            .restore(2, &[first], &[], std::iter::empty())
+            .unwrap();
+        assert_eq!(data_file.page_count(), 2);
+        assert_eq!(data_file.read(Location::Extent(second)).unwrap(), b"x");
+        assert_eq!(data_file.allocate(1), first);
+        assert_eq!(data_file.allocate(1), third);
+    }
+
+    #[test]
+    fn freed_tail_shrinks_file() {
+        let mut data_file = DataFile::new(File::from_std(tempfile::tempfile().unwrap()));
+        let first = data_file.allocate(1);
+        let second = data_file.allocate(2);
+        let third = data_file.allocate(1);
+        data_file.write(third, b"x").unwrap();
+        data_file.free(second).unwrap();
+        assert_eq!(data_file.page_count(), 4);
+        data_file.free(third).unwrap();
+        assert_eq!(data_file.page_count(), 1);
+        assert_eq!(data_file.file.metadata().unwrap().len(), PAGE_SIZE);
+        data_file.free(first).unwrap();
+        assert_eq!(data_file.page_count(), 0);
+        assert_eq!(data_file.allocate(1), first);
+    }
+
+    #[test]
+    fn records_share_pages() {
+        let mut data_file = DataFile::new(File::from_std(tempfile::tempfile().unwrap()));
+        assert!(DataFile::fits_in_record(MAX_RECORD_LEN));
+        assert!(!DataFile::fits_in_record(MAX_RECORD_LEN + 1));
+        let records: Vec<_> = (0..4u8)
+            .map(|i| data_file.append_record(&[i; 1300]).unwrap())
+            .collect();
+        assert_eq!(records[0], Location::Record { page: 0, slot: 0 });
+        assert_eq!(records[2], Location::Record { page: 0, slot: 2 });
+        // The fourth one does not fit anymore
+        assert_eq!(records[3], Location::Record { page: 1, slot: 0 });
+        assert_eq!(data_file.page_count(), 2);
+        for (i, location) in (0..4u8).zip(&records) {
+            assert_eq!(data_file.read(*location).unwrap(), [i; 1300]);
+        }
+        let empty = data_file.append_record(b"").unwrap();
+        assert!(data_file.read(empty).unwrap().is_empty());
+        // The page being filled is kept even if none of its records are live
+        data_file.free_location(records[3], false).unwrap();
+        data_file.free_location(empty, false).unwrap();
+        assert_eq!(data_file.page_count(), 2);
+        data_file.seal_open_page().unwrap();
+        assert_eq!(data_file.page_count(), 1);
+        data_file.free_location(records[0], false).unwrap();
+        data_file.free_location(records[1], false).unwrap();
+        // A third of its records are still live
+        assert!(data_file.sparse_record_pages().is_empty());
+        assert_eq!(data_file.record_pages(), [(0, 3)]);
+        data_file.free_location(records[2], false).unwrap();
+        assert_eq!(data_file.page_count(), 0);
+        assert!(data_file.record_pages().is_empty());
+    }
+
+    #[test]
+    fn checkpoint_record_pages_freed_later() {
+        let mut data_file = DataFile::new(File::from_std(tempfile::tempfile().unwrap()));
+        let first = data_file.append_record(b"first").unwrap();
+        let second = data_file.append_record(b"second").unwrap();
+        data_file.seal_open_page().unwrap();
+        data_file.finish_checkpoint().unwrap();
+        data_file.free_location(first, true).unwrap();
+        data_file.free_location(second, true).unwrap();
+        // The image page is not reused before the next checkpoint
+        assert_eq!(
+            data_file.append_record(b"third").unwrap(),
+            Location::Record { page: 1, slot: 0 }
+        );
+        assert_eq!(data_file.read(first).unwrap(), b"first");
+        data_file.seal_open_page().unwrap();
+        assert_eq!(data_file.space_after_checkpoint().1, [Extent::new(0, 1)]);
+        // The restored pages count the live records of the image
+        data_file
+            .restore(1, &[], &[(0, 2)], [second].into_iter())
+            .unwrap();
+        assert_eq!(data_file.read(second).unwrap(), b"second");
+        assert!(!data_file.sparse_record_pages().contains(&0));
+        // The freed tail page shrinks the file after the checkpoint
+        data_file.free_location(second, true).unwrap();
+        assert_eq!(data_file.page_count(), 1);
+        assert_eq!(data_file.space_after_checkpoint(), (0, vec![]));
+    }
+}
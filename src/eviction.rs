@@ -0,0 +1,331 @@
+// Copyright (C) 2024 Laurynas Biveinis
+
+// The buffer manager policies for choosing which node to evict from memory.
+// The policy only tracks the node IDs, the buffer manager decides when to
+// evict and which nodes may be evicted at all.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::node;
+use crate::EvictionPolicy;
+
+pub(crate) trait Policy: fmt::Debug + Send {
+    // A node has been brought into memory
+    fn admit(&mut self, id: node::Id);
+
+    // A node in memory has been accessed again
+    fn touch(&mut self, id: node::Id);
+
+    // A node has left memory
+    fn forget(&mut self, id: node::Id);
+
+    // Chooses the node to evict among those for which the predicate holds,
+    // without forgetting it yet
+    fn victim(&mut self, is_evictable: &dyn Fn(node::Id) -> bool) -> Option<node::Id>;
+}
+
+pub(crate) fn new_policy(kind: EvictionPolicy) -> Box<dyn Policy> {
+    match kind {
+        EvictionPolicy::Lru => Box::<Lru>::default(),
+        EvictionPolicy::Clock => Box::<Clock>::default(),
+        EvictionPolicy::TwoQueue => Box::<TwoQueue>::default(),
+    }
+}
+
+// Node IDs ordered by their last push, the oldest first
+#[derive(Debug, Default)] // COV_EXCL_LINE
+struct RecencyList {
+    next_tick: u64,
+    ticks: HashMap<node::Id, u64>,
+    order: BTreeMap<u64, node::Id>,
+}
+
+impl RecencyList {
+    // Adds the node as the most recent one, moving it if already present
+    fn push(&mut self, id: node::Id) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        if let Some(old_tick) = self.ticks.insert(id, tick) {
+            self.order.remove(&old_tick);
+        }
+        self.order.insert(tick, id);
+    }
+
+    fn remove(&mut self, id: node::Id) -> bool {
+        let Some(tick) = self.ticks.remove(&id) else {
+            return false;
+        };
+        self.order.remove(&tick);
+        true
+    }
+
+    fn pop_oldest(&mut self) -> Option<node::Id> {
+        let (_, id) = self.order.pop_first()?;
+        self.ticks.remove(&id);
+        Some(id)
+    }
+
+    #[inline]
+    fn contains(&self, id: node::Id) -> bool {
+        self.ticks.contains_key(&id)
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        self.ticks.len()
+    }
+
+    fn oldest(&self, is_evictable: &dyn Fn(node::Id) -> bool) -> Option<node::Id> {
+        self.order.values().copied().find(|id| is_evictable(*id))
+    }
+}
+
+// Evicts the least recently used node
+#[derive(Debug, Default)] // COV_EXCL_LINE
+struct Lru {
+    nodes: RecencyList,
+}
+
+impl Policy for Lru {
+    fn admit(&mut self, id: node::Id) {
+        self.nodes.push(id);
+    }
+
+    fn touch(&mut self, id: node::Id) {
+        self.nodes.push(id);
+    }
+
+    fn forget(&mut self, id: node::Id) {
+        self.nodes.remove(id);
+    }
+
+    fn victim(&mut self, is_evictable: &dyn Fn(node::Id) -> bool) -> Option<node::Id> {
+        self.nodes.oldest(is_evictable)
+    }
+}
+
+#[derive(Debug)] // COV_EXCL_LINE
+struct ClockSlot {
+    id: node::Id,
+    referenced: bool,
+}
+
+// Sweeps the nodes in a circle, giving a second chance to those referenced
+// since the last sweep
+#[derive(Debug, Default)] // COV_EXCL_LINE
+struct Clock {
+    slots: Vec<Option<ClockSlot>>,
+    slot_of: HashMap<node::Id, usize>,
+    free_slots: Vec<usize>,
+    hand: usize,
+}
+
+impl Policy for Clock {
+    fn admit(&mut self, id: node::Id) {
+        let slot = ClockSlot {
+            id,
+            referenced: true,
+        };
+        let index = if let Some(index) = self.free_slots.pop() {
+            self.slots[index] = Some(slot);
+            index
+        } else {
+            self.slots.push(Some(slot));
+            self.slots.len() - 1
+        };
+        self.slot_of.insert(id, index);
+    }
+
+    fn touch(&mut self, id: node::Id) {
+        if let Some(Some(slot)) = self.slot_of.get(&id).map(|index| &mut self.slots[*index]) {
+            slot.referenced = true;
+        }
+    }
+
+    fn forget(&mut self, id: node::Id) {
+        if let Some(index) = self.slot_of.remove(&id) {
+            self.slots[index] = None;
+            self.free_slots.push(index);
+        }
+    }
+
+    fn victim(&mut self, is_evictable: &dyn Fn(node::Id) -> bool) -> Option<node::Id> {
+        // The first sweep clears all the reference bits, thus the second one
+        // finds a victim if there is any
+        for _ in 0..2 * self.slots.len() {
+            let index = self.hand;
+            self.hand = (self.hand + 1) % self.slots.len();
+            let Some(slot) = &mut self.slots[index] else {
+                continue;
+            };
+            if !is_evictable(slot.id) {
+                continue;
+            }
+            if slot.referenced {
+                slot.referenced = false;
+            } else {
+                return Some(slot.id);
+            }
+        }
+        None
+    }
+}
+
+// The simplified 2Q: the nodes are admitted to a FIFO queue, and only those
+// accessed again soon after being evicted from it enter the main LRU queue.
+// A scan touching each node once thus does not flush the main queue.
+#[derive(Debug, Default)] // COV_EXCL_LINE
+struct TwoQueue {
+    // The recently admitted nodes, in FIFO order
+    admitted: RecencyList,
+    // The nodes that have been evicted from the admitted queue and accessed
+    // again, in LRU order
+    main: RecencyList,
+    // The IDs of the nodes recently evicted from the admitted queue
+    ghosts: RecencyList,
+}
+
+impl TwoQueue {
+    // The share of the resident nodes kept in the admitted queue
+    const ADMITTED_SHARE: usize = 4;
+    // The ghost queue is bounded by half of the resident nodes
+    const GHOST_SHARE: usize = 2;
+
+    #[inline]
+    fn resident_count(&self) -> usize {
+        self.admitted.len() + self.main.len()
+    }
+}
+
+impl Policy for TwoQueue {
+    fn admit(&mut self, id: node::Id) {
+        if self.ghosts.remove(id) {
+            self.main.push(id);
+        } else {
+            self.admitted.push(id);
+        }
+    }
+
+    fn touch(&mut self, id: node::Id) {
+        // The accesses to the admitted queue are assumed to be correlated
+        if self.main.contains(id) {
+            self.main.push(id);
+        }
+    }
+
+    fn forget(&mut self, id: node::Id) {
+        if self.admitted.remove(id) {
+            self.ghosts.push(id);
+            let max_ghosts = (self.resident_count() / Self::GHOST_SHARE).max(1);
+            while self.ghosts.len() > max_ghosts {
+                let _oldest = self.ghosts.pop_oldest();
+            }
+        } else {
+            self.main.remove(id);
+        }
+    }
+
+    fn victim(&mut self, is_evictable: &dyn Fn(node::Id) -> bool) -> Option<node::Id> {
+        let admitted_over_share =
+            self.admitted.len() * Self::ADMITTED_SHARE > self.resident_count();
+        let (first, second) = if admitted_over_share {
+            (&self.admitted, &self.main)
+        } else {
+            (&self.main, &self.admitted)
+        };
+        first
+            .oldest(is_evictable)
+            .or_else(|| second.oldest(is_evictable))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{new_policy, Policy};
+    use crate::node;
+    use crate::EvictionPolicy;
+
+    fn ids(range: std::ops::Range<u64>) -> impl Iterator<Item = node::Id> {
+        range.map(node::Id::from)
+    }
+
+    fn evict(policy: &mut dyn Policy, is_evictable: &dyn Fn(node::Id) -> bool) -> node::Id {
+        let victim = policy.victim(is_evictable).unwrap();
+        policy.forget(victim);
+        victim
+    }
+
+    #[test]
+    fn all_policies_evict_only_evictable() {
+        for kind in [
+            EvictionPolicy::Lru,
+            EvictionPolicy::Clock,
+            EvictionPolicy::TwoQueue,
+        ] {
+            let mut policy = new_policy(kind);
+            assert!(policy.victim(&|_| true).is_none());
+            for id in ids(1..5) {
+                policy.admit(id);
+            }
+            let pinned = node::Id::from(2);
+            let mut evicted: Vec<_> = (0..3)
+                .map(|_| evict(policy.as_mut(), &|id| id != pinned))
+                .collect();
+            evicted.sort_by_key(|id| id.as_u64());
+            assert_eq!(
+                evicted,
+                ids(1..5).filter(|id| *id != pinned).collect::<Vec<_>>()
+            );
+            assert!(policy.victim(&|id| id != pinned).is_none());
+            assert_eq!(policy.victim(&|_| true), Some(pinned));
+        }
+    }
+
+    #[test]
+    fn lru() {
+        let mut policy = new_policy(EvictionPolicy::Lru);
+        for id in ids(1..4) {
+            policy.admit(id);
+        }
+        policy.touch(node::Id::from(1));
+        assert_eq!(evict(policy.as_mut(), &|_| true), node::Id::from(2));
+        assert_eq!(evict(policy.as_mut(), &|_| true), node::Id::from(3));
+        assert_eq!(evict(policy.as_mut(), &|_| true), node::Id::from(1));
+    }
+
+    #[test]
+    fn clock() {
+        let mut policy = new_policy(EvictionPolicy::Clock);
+        for id in ids(1..4) {
+            policy.admit(id);
+        }
+        // All the reference bits are cleared in the first sweep
+        assert_eq!(evict(policy.as_mut(), &|_| true), node::Id::from(1));
+        policy.touch(node::Id::from(2));
+        assert_eq!(evict(policy.as_mut(), &|_| true), node::Id::from(3));
+        // The freed slot is reused
+        policy.admit(node::Id::from(4));
+        assert_eq!(evict(policy.as_mut(), &|_| true), node::Id::from(2));
+        assert_eq!(evict(policy.as_mut(), &|_| true), node::Id::from(4));
+    }
+
+    #[test]
+    fn two_queue_resists_scans() {
+        let mut policy = new_policy(EvictionPolicy::TwoQueue);
+        let hot = node::Id::from(1);
+        policy.admit(hot);
+        assert_eq!(evict(policy.as_mut(), &|_| true), hot);
+        // Accessed again soon after eviction, thus hot
+        policy.admit(hot);
+        for id in ids(2..10) {
+            policy.admit(id);
+        }
+        // The scanned nodes are evicted first, in FIFO order
+        for id in ids(2..10) {
+            policy.touch(id);
+            assert_eq!(evict(policy.as_mut(), &|_| true), id);
+        }
+        assert_eq!(evict(policy.as_mut(), &|_| true), hot);
+    }
+}
@@ -1,7 +1,10 @@
 // Copyright (C) 2022-2023 Laurynas Biveinis
-use crate::transaction_manager::Transaction;
-use crate::{Db, DbError};
+use crate::cursor::DetachedCursor as Cursor;
+use crate::key::{Collation, Column, ColumnType, KeySchema, Order};
+use crate::transaction_manager::{Savepoint, Transaction};
+use crate::{node, Db, DbError};
 use std::path::Path;
+use std::time::Duration;
 
 #[cxx::bridge(namespace = "kirunadb")]
 #[allow(
@@ -12,6 +15,49 @@ use std::path::Path;
     let_underscore_drop
 )]
 pub mod interface {
+    // The modes of crate::Durability, with the periodic sync interval passed
+    // separately. They map onto innodb_flush_log_at_trx_commit values 1, 2
+    // and 0, and innodb_flush_log_at_timeout.
+    enum Durability {
+        Fsync,
+        Fdatasync,
+        Periodic,
+        NoSync,
+    }
+
+    // A write conflict is an expected outcome of the optimistic concurrency,
+    // thus not an exception. The changes are kept until rolled back.
+    enum CommitResult {
+        Committed,
+        WriteConflict,
+    }
+
+    // The types of crate::key::ColumnType, with the string collations as
+    // separate types
+    enum KeyColumnType {
+        U8,
+        U16,
+        U32,
+        U64,
+        U128,
+        I8,
+        I16,
+        I32,
+        I64,
+        I128,
+        F32,
+        F64,
+        BinaryString,
+        CaseInsensitiveString,
+        Bytes,
+    }
+
+    struct KeyColumn {
+        column_type: KeyColumnType,
+        descending: bool,
+        nullable: bool,
+    }
+
     // If cxx.rs starts supporting tuple structs, bridge node::Id and transaction::Id directly.
     extern "Rust" {
         type Transaction;
@@ -20,12 +66,112 @@ pub mod interface {
 
         pub fn new_art_descriptor_node(transaction: &mut Transaction) -> u64;
 
-        // Assuming pessimistic locking so that a failure to commit is
-        // exceptional
-        pub fn commit(self: &mut Transaction) -> Result<()>;
+        // The key columns of a keyspace to create, added in the key order
+        type KeyColumns;
+
+        #[allow(clippy::unnecessary_box_returns)]
+        fn new_key_columns() -> Box<KeyColumns>;
+
+        pub fn add_key_column(key_columns: &mut KeyColumns, key_column: &KeyColumn) -> Result<()>;
+
+        // The keys are not typed if there are no key columns
+        pub fn create_keyspace(
+            transaction: &mut Transaction,
+            name: &str,
+            key_columns: &KeyColumns,
+            options: &[u8],
+        ) -> Result<u64>;
+
+        pub fn open_keyspace(transaction: &Transaction, name: &str) -> Result<u64>;
+
+        pub fn drop_keyspace(transaction: &mut Transaction, name: &str) -> Result<()>;
+
+        pub fn list_keyspaces(transaction: &Transaction) -> Result<Vec<String>>;
 
+        // Returns whether the key was found
+        pub fn get(
+            transaction: &Transaction,
+            tree: u64,
+            key: &[u8],
+            value: &mut Vec<u8>,
+        ) -> Result<bool>;
+
+        pub fn insert(
+            transaction: &mut Transaction,
+            tree: u64,
+            key: &[u8],
+            value: &[u8],
+        ) -> Result<()>;
+
+        pub fn upsert(
+            transaction: &mut Transaction,
+            tree: u64,
+            key: &[u8],
+            value: &[u8],
+        ) -> Result<()>;
+
+        // Returns whether the key was found
+        pub fn delete_key(transaction: &mut Transaction, tree: u64, key: &[u8]) -> Result<bool>;
+
+        pub fn commit(transaction: &mut Transaction) -> Result<CommitResult>;
+
+        pub fn rollback(self: &mut Transaction);
+
+        pub fn savepoint(transaction: &mut Transaction) -> u64;
+
+        pub fn rollback_to_savepoint(transaction: &mut Transaction, savepoint: u64) -> Result<()>;
+
+        pub fn release_savepoint(transaction: &mut Transaction, savepoint: u64) -> Result<()>;
+
+        // The interval is used by the periodic sync only
+        pub fn commit_with_durability(
+            transaction: &mut Transaction,
+            durability: Durability,
+            sync_interval_ms: u64,
+        ) -> Result<CommitResult>;
+
+        // Rolls back the changes since the last commit, if any
         pub fn drop_transaction(transaction: Box<Transaction>);
 
+        // Takes the transaction at every call, and stays valid across its
+        // writes between the calls
+        type Cursor;
+
+        #[allow(clippy::unnecessary_box_returns)]
+        fn new_cursor(tree: u64) -> Box<Cursor>;
+
+        // The positioning functions return whether the cursor is at a key
+        pub fn cursor_seek(
+            cursor: &mut Cursor,
+            transaction: &Transaction,
+            key: &[u8],
+        ) -> Result<bool>;
+
+        pub fn cursor_seek_for_prev(
+            cursor: &mut Cursor,
+            transaction: &Transaction,
+            key: &[u8],
+        ) -> Result<bool>;
+
+        pub fn cursor_seek_to_first(cursor: &mut Cursor, transaction: &Transaction)
+            -> Result<bool>;
+
+        pub fn cursor_seek_to_last(cursor: &mut Cursor, transaction: &Transaction) -> Result<bool>;
+
+        pub fn cursor_next(cursor: &mut Cursor, transaction: &Transaction) -> Result<bool>;
+
+        pub fn cursor_prev(cursor: &mut Cursor, transaction: &Transaction) -> Result<bool>;
+
+        pub fn cursor_is_valid(cursor: &Cursor) -> bool;
+
+        // Empty if the cursor is not at a key
+        pub fn cursor_key(cursor: &Cursor) -> &[u8];
+
+        // Empty if the cursor is not at a key
+        pub fn cursor_value(cursor: &Cursor) -> &[u8];
+
+        pub fn drop_cursor(cursor: Box<Cursor>);
+
         type Db;
 
         pub fn open(path: &str) -> Result<Box<Db>>;
@@ -33,7 +179,7 @@ pub mod interface {
         pub fn close(db: Box<Db>);
 
         #[allow(clippy::unnecessary_box_returns)]
-        fn begin_transaction(db: &mut Db) -> Box<Transaction>;
+        fn begin_transaction(db: &Db) -> Box<Transaction>;
     }
 }
 
@@ -47,9 +193,179 @@ pub fn new_art_descriptor_node(transaction: &mut Transaction) -> u64 {
     transaction.new_art_descriptor_node().as_u64()
 }
 
+#[derive(Debug, Default)] // COV_EXCL_LINE
+#[must_use]
+pub struct KeyColumns(Vec<Column>);
+
 #[allow(clippy::unnecessary_box_returns)]
 #[inline]
-pub fn begin_transaction(db: &mut Db) -> Box<Transaction> {
+pub fn new_key_columns() -> Box<KeyColumns> {
+    Box::default()
+}
+
+pub fn add_key_column(
+    key_columns: &mut KeyColumns,
+    key_column: &interface::KeyColumn,
+) -> Result<(), DbError> {
+    let value_type = match key_column.column_type {
+        interface::KeyColumnType::U8 => ColumnType::U8,
+        interface::KeyColumnType::U16 => ColumnType::U16,
+        interface::KeyColumnType::U32 => ColumnType::U32,
+        interface::KeyColumnType::U64 => ColumnType::U64,
+        interface::KeyColumnType::U128 => ColumnType::U128,
+        interface::KeyColumnType::I8 => ColumnType::I8,
+        interface::KeyColumnType::I16 => ColumnType::I16,
+        interface::KeyColumnType::I32 => ColumnType::I32,
+        interface::KeyColumnType::I64 => ColumnType::I64,
+        interface::KeyColumnType::I128 => ColumnType::I128,
+        interface::KeyColumnType::F32 => ColumnType::F32,
+        interface::KeyColumnType::F64 => ColumnType::F64,
+        interface::KeyColumnType::BinaryString => ColumnType::String(Collation::Binary),
+        interface::KeyColumnType::CaseInsensitiveString => {
+            ColumnType::String(Collation::CaseInsensitive)
+        }
+        interface::KeyColumnType::Bytes => ColumnType::Bytes,
+        _ => {
+            return Err(DbError::BadColumnType {
+                column_type: key_column.column_type.repr,
+            })
+        }
+    };
+    let order = if key_column.descending {
+        Order::Descending
+    } else {
+        Order::Ascending
+    };
+    key_columns
+        .0
+        .push(Column::new(value_type, order, key_column.nullable));
+    Ok(())
+}
+
+pub fn create_keyspace(
+    transaction: &mut Transaction,
+    name: &str,
+    key_columns: &KeyColumns,
+    options: &[u8],
+) -> Result<u64, DbError> {
+    let key_schema = KeySchema::new(key_columns.0.clone());
+    Ok(transaction
+        .create_keyspace(name, key_schema, options)?
+        .as_u64())
+}
+
+#[inline]
+pub fn open_keyspace(transaction: &Transaction, name: &str) -> Result<u64, DbError> {
+    Ok(transaction.open_keyspace(name)?.tree().as_u64())
+}
+
+#[inline]
+pub fn drop_keyspace(transaction: &mut Transaction, name: &str) -> Result<(), DbError> {
+    transaction.drop_keyspace(name)
+}
+
+#[inline]
+pub fn list_keyspaces(transaction: &Transaction) -> Result<Vec<String>, DbError> {
+    let keyspaces = transaction.list_keyspaces()?;
+    Ok(keyspaces
+        .iter()
+        .map(|keyspace| keyspace.name().to_owned())
+        .collect())
+}
+
+pub fn get(
+    transaction: &Transaction,
+    tree: u64,
+    key: &[u8],
+    value: &mut Vec<u8>,
+) -> Result<bool, DbError> {
+    match transaction.get(node::Id::from(tree), key)? {
+        Some(found_value) => {
+            *value = found_value;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+#[inline]
+pub fn insert(
+    transaction: &mut Transaction,
+    tree: u64,
+    key: &[u8],
+    value: &[u8],
+) -> Result<(), DbError> {
+    transaction.insert(node::Id::from(tree), key, value)
+}
+
+#[inline]
+pub fn upsert(
+    transaction: &mut Transaction,
+    tree: u64,
+    key: &[u8],
+    value: &[u8],
+) -> Result<(), DbError> {
+    transaction.upsert(node::Id::from(tree), key, value)
+}
+
+#[inline]
+pub fn delete_key(transaction: &mut Transaction, tree: u64, key: &[u8]) -> Result<bool, DbError> {
+    transaction.delete(node::Id::from(tree), key)
+}
+
+// The other errors are exceptions
+fn commit_result(result: Result<(), DbError>) -> Result<interface::CommitResult, DbError> {
+    match result {
+        Ok(()) => Ok(interface::CommitResult::Committed),
+        Err(DbError::WriteConflict) => Ok(interface::CommitResult::WriteConflict),
+        Err(error) => Err(error),
+    }
+}
+
+#[inline]
+pub fn commit(transaction: &mut Transaction) -> Result<interface::CommitResult, DbError> {
+    commit_result(transaction.commit())
+}
+
+pub fn commit_with_durability(
+    transaction: &mut Transaction,
+    durability: interface::Durability,
+    sync_interval_ms: u64,
+) -> Result<interface::CommitResult, DbError> {
+    let durability = match durability {
+        interface::Durability::Fsync => crate::Durability::Fsync,
+        interface::Durability::Fdatasync => crate::Durability::Fdatasync,
+        interface::Durability::Periodic => crate::Durability::Periodic {
+            interval: Duration::from_millis(sync_interval_ms),
+        },
+        interface::Durability::NoSync => crate::Durability::NoSync,
+        _ => {
+            return Err(DbError::BadDurability {
+                mode: durability.repr,
+            })
+        }
+    };
+    commit_result(transaction.commit_with_durability(durability))
+}
+
+#[inline]
+pub fn savepoint(transaction: &mut Transaction) -> u64 {
+    transaction.savepoint().as_u64()
+}
+
+#[inline]
+pub fn rollback_to_savepoint(transaction: &mut Transaction, savepoint: u64) -> Result<(), DbError> {
+    transaction.rollback_to_savepoint(Savepoint::from(savepoint))
+}
+
+#[inline]
+pub fn release_savepoint(transaction: &mut Transaction, savepoint: u64) -> Result<(), DbError> {
+    transaction.release_savepoint(Savepoint::from(savepoint))
+}
+
+#[allow(clippy::unnecessary_box_returns)]
+#[inline]
+pub fn begin_transaction(db: &Db) -> Box<Transaction> {
     let transaction = db.begin_transaction();
     Box::new(transaction)
 }
@@ -59,6 +375,76 @@ pub fn drop_transaction(transaction: Box<Transaction>) {
     std::mem::drop(transaction);
 }
 
+#[allow(clippy::unnecessary_box_returns)]
+#[inline]
+pub fn new_cursor(tree: u64) -> Box<Cursor> {
+    Box::new(Cursor::new(node::Id::from(tree)))
+}
+
+#[inline]
+pub fn cursor_seek(
+    cursor: &mut Cursor,
+    transaction: &Transaction,
+    key: &[u8],
+) -> Result<bool, DbError> {
+    cursor.seek(transaction, key)
+}
+
+#[inline]
+pub fn cursor_seek_for_prev(
+    cursor: &mut Cursor,
+    transaction: &Transaction,
+    key: &[u8],
+) -> Result<bool, DbError> {
+    cursor.seek_for_prev(transaction, key)
+}
+
+#[inline]
+pub fn cursor_seek_to_first(
+    cursor: &mut Cursor,
+    transaction: &Transaction,
+) -> Result<bool, DbError> {
+    cursor.seek_to_first(transaction)
+}
+
+#[inline]
+pub fn cursor_seek_to_last(
+    cursor: &mut Cursor,
+    transaction: &Transaction,
+) -> Result<bool, DbError> {
+    cursor.seek_to_last(transaction)
+}
+
+#[inline]
+pub fn cursor_next(cursor: &mut Cursor, transaction: &Transaction) -> Result<bool, DbError> {
+    cursor.move_next(transaction)
+}
+
+#[inline]
+pub fn cursor_prev(cursor: &mut Cursor, transaction: &Transaction) -> Result<bool, DbError> {
+    cursor.move_prev(transaction)
+}
+
+#[inline]
+pub fn cursor_is_valid(cursor: &Cursor) -> bool {
+    cursor.is_valid()
+}
+
+#[inline]
+pub fn cursor_key(cursor: &Cursor) -> &[u8] {
+    cursor.key().unwrap_or_default()
+}
+
+#[inline]
+pub fn cursor_value(cursor: &Cursor) -> &[u8] {
+    cursor.value().unwrap_or_default()
+}
+
+#[inline]
+pub fn drop_cursor(cursor: Box<Cursor>) {
+    std::mem::drop(cursor);
+}
+
 pub fn open(path: &str) -> Result<Box<Db>, DbError> {
     let path = Path::new(path);
     let db = Db::open(path)?;
@@ -0,0 +1,627 @@
+// Copyright (C) 2024 Laurynas Biveinis
+
+// Order-preserving encoding of typed key tuples into byte strings, so that
+// comparing the encoded keys byte-wise, as the ART does, gives the same order
+// as comparing the tuples column by column.
+//
+// Every column encoding is self-delimiting, thus the encoding of a tuple prefix
+// is a byte prefix of the full tuple encoding, and can be used for prefix
+// scans. The encoding of collated strings is lossy and cannot be decoded back.
+
+use std::borrow::Cow;
+
+use crate::DbError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use]
+pub enum Order {
+    Ascending,
+    Descending,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use]
+pub enum Collation {
+    // Code point order, which is also the byte order of UTF-8
+    Binary,
+    // Code point order after the Unicode lowercase mapping
+    CaseInsensitive,
+}
+
+impl Collation {
+    fn to_byte(self) -> u8 {
+        match self {
+            Self::Binary => 0,
+            Self::CaseInsensitive => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::Binary),
+            1 => Some(Self::CaseInsensitive),
+            _ => None,
+        }
+    }
+
+    fn sort_key(self, s: &str) -> Cow<'_, [u8]> {
+        match self {
+            Self::Binary => Cow::Borrowed(s.as_bytes()),
+            Self::CaseInsensitive => Cow::Owned(s.to_lowercase().into_bytes()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use]
+pub enum ColumnType {
+    U8,
+    U16,
+    U32,
+    U64,
+    U128,
+    I8,
+    I16,
+    I32,
+    I64,
+    I128,
+    F32,
+    F64,
+    String(Collation),
+    Bytes,
+}
+
+impl ColumnType {
+    // The type byte, and the collation byte for strings
+    fn to_bytes(self) -> [u8; 2] {
+        match self {
+            Self::U8 => [0, 0],
+            Self::U16 => [1, 0],
+            Self::U32 => [2, 0],
+            Self::U64 => [3, 0],
+            Self::U128 => [4, 0],
+            Self::I8 => [5, 0],
+            Self::I16 => [6, 0],
+            Self::I32 => [7, 0],
+            Self::I64 => [8, 0],
+            Self::I128 => [9, 0],
+            Self::F32 => [10, 0],
+            Self::F64 => [11, 0],
+            Self::String(collation) => [12, collation.to_byte()],
+            Self::Bytes => [13, 0],
+        }
+    }
+
+    fn from_bytes(bytes: [u8; 2]) -> Option<Self> {
+        let result = match bytes {
+            [0, 0] => Self::U8,
+            [1, 0] => Self::U16,
+            [2, 0] => Self::U32,
+            [3, 0] => Self::U64,
+            [4, 0] => Self::U128,
+            [5, 0] => Self::I8,
+            [6, 0] => Self::I16,
+            [7, 0] => Self::I32,
+            [8, 0] => Self::I64,
+            [9, 0] => Self::I128,
+            [10, 0] => Self::F32,
+            [11, 0] => Self::F64,
+            [12, collation] => Self::String(Collation::from_byte(collation)?),
+            [13, 0] => Self::Bytes,
+            _ => return None,
+        };
+        Some(result)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use]
+pub struct Column {
+    value_type: ColumnType,
+    order: Order,
+    nullable: bool,
+}
+
+impl Column {
+    #[inline]
+    pub fn new(value_type: ColumnType, order: Order, nullable: bool) -> Self {
+        Self {
+            value_type,
+            order,
+            nullable,
+        }
+    }
+
+    #[inline]
+    pub fn value_type(&self) -> ColumnType {
+        self.value_type
+    }
+
+    #[inline]
+    pub fn order(&self) -> Order {
+        self.order
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn nullable(&self) -> bool {
+        self.nullable
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[must_use]
+pub enum Value<'a> {
+    Null,
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    U128(u128),
+    I8(i8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    I128(i128),
+    F32(f32),
+    F64(f64),
+    Str(&'a str),
+    Bytes(&'a [u8]),
+}
+
+// Signed integers are made unsigned by flipping the sign bit
+macro_rules! flip_sign {
+    ($value:expr, $unsigned:ty) => {
+        <$unsigned>::from_ne_bytes($value.to_ne_bytes()) ^ (1 << (<$unsigned>::BITS - 1))
+    };
+}
+
+// Positive floats get the sign bit set, negative ones get all the bits
+// flipped, so that larger negative numbers sort lower. Negative zero is
+// normalized to zero, and all NaNs to a single NaN, which sorts above the
+// infinity.
+macro_rules! float_bits {
+    ($value:expr, $float:ty, $unsigned:ty) => {{
+        let value = if $value.is_nan() {
+            <$float>::NAN
+        } else if $value == 0.0 {
+            0.0
+        } else {
+            $value
+        };
+        let bits = value.to_bits();
+        if value.is_sign_negative() {
+            !bits
+        } else {
+            bits | (1 << (<$unsigned>::BITS - 1))
+        }
+    }};
+}
+
+const NULL_TAG: u8 = 0;
+const NOT_NULL_TAG: u8 = 1;
+// Variable-length values escape their zero bytes and end with a terminator
+// that sorts below any escaped byte, so that a shorter value sorts first.
+const ESCAPE: u8 = 0;
+const ESCAPED_ZERO: u8 = 0xFF;
+const TERMINATOR: u8 = 1;
+
+fn encode_variable_length(bytes: &[u8], buf: &mut Vec<u8>) {
+    for byte in bytes {
+        buf.push(*byte);
+        if *byte == ESCAPE {
+            buf.push(ESCAPED_ZERO);
+        }
+    }
+    buf.extend_from_slice(&[ESCAPE, TERMINATOR]);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[must_use]
+pub struct KeySchema {
+    columns: Vec<Column>,
+}
+
+impl KeySchema {
+    #[inline]
+    pub fn new(columns: Vec<Column>) -> Self {
+        Self { columns }
+    }
+
+    #[inline]
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    // Four bytes per column: the type, the collation, the order, and whether
+    // it is nullable.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(self.columns.len() * 4);
+        for column in &self.columns {
+            result.extend_from_slice(&column.value_type().to_bytes());
+            result.push(u8::from(column.order() == Order::Descending));
+            result.push(u8::from(column.nullable()));
+        }
+        result
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let column_bytes = bytes.chunks_exact(4);
+        if !column_bytes.remainder().is_empty() {
+            return None;
+        }
+        let mut columns = Vec::with_capacity(column_bytes.len());
+        for column in column_bytes {
+            let value_type = ColumnType::from_bytes([column[0], column[1]])?;
+            let order = match column[2] {
+                0 => Order::Ascending,
+                1 => Order::Descending,
+                _ => return None,
+            };
+            let nullable = match column[3] {
+                0 => false,
+                1 => true,
+                _ => return None,
+            };
+            columns.push(Column::new(value_type, order, nullable));
+        }
+        Some(Self::new(columns))
+    }
+
+    /// Encodes the values for the leading columns. Fewer values than columns
+    /// give a key prefix.
+    /// # Errors
+    /// Will return `DbError::KeyValueCount` if there are more values than
+    /// columns, and `DbError::KeyTypeMismatch` if a value does not match its
+    /// column.
+    pub fn encode(&self, values: &[Value<'_>]) -> Result<Vec<u8>, DbError> {
+        if values.len() > self.columns.len() {
+            return Err(DbError::KeyValueCount {
+                columns: self.columns.len(),
+                values: values.len(),
+            });
+        }
+        let mut result = Vec::new();
+        for (i, (column, value)) in self.columns.iter().zip(values).enumerate() {
+            let column_start = result.len();
+            Self::encode_column(*column, value, &mut result)
+                .ok_or(DbError::KeyTypeMismatch { column: i })?;
+            if column.order() == Order::Descending {
+                for byte in &mut result[column_start..] {
+                    *byte = !*byte;
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    fn encode_column(column: Column, value: &Value<'_>, buf: &mut Vec<u8>) -> Option<()> {
+        if column.nullable() {
+            if *value == Value::Null {
+                buf.push(NULL_TAG);
+                return Some(());
+            }
+            buf.push(NOT_NULL_TAG);
+        }
+        match (column.value_type(), value) {
+            (ColumnType::U8, Value::U8(v)) => buf.extend_from_slice(&v.to_be_bytes()),
+            (ColumnType::U16, Value::U16(v)) => buf.extend_from_slice(&v.to_be_bytes()),
+            (ColumnType::U32, Value::U32(v)) => buf.extend_from_slice(&v.to_be_bytes()),
+            (ColumnType::U64, Value::U64(v)) => buf.extend_from_slice(&v.to_be_bytes()),
+            (ColumnType::U128, Value::U128(v)) => buf.extend_from_slice(&v.to_be_bytes()),
+            (ColumnType::I8, Value::I8(v)) => {
+                buf.extend_from_slice(&flip_sign!(v, u8).to_be_bytes());
+            }
+            (ColumnType::I16, Value::I16(v)) => {
+                buf.extend_from_slice(&flip_sign!(v, u16).to_be_bytes());
+            }
+            (ColumnType::I32, Value::I32(v)) => {
+                buf.extend_from_slice(&flip_sign!(v, u32).to_be_bytes());
+            }
+            (ColumnType::I64, Value::I64(v)) => {
+                buf.extend_from_slice(&flip_sign!(v, u64).to_be_bytes());
+            }
+            (ColumnType::I128, Value::I128(v)) => {
+                buf.extend_from_slice(&flip_sign!(v, u128).to_be_bytes());
+            }
+            (ColumnType::F32, Value::F32(v)) => {
+                buf.extend_from_slice(&float_bits!(*v, f32, u32).to_be_bytes());
+            }
+            (ColumnType::F64, Value::F64(v)) => {
+                buf.extend_from_slice(&float_bits!(*v, f64, u64).to_be_bytes());
+            }
+            (ColumnType::String(collation), Value::Str(v)) => {
+                encode_variable_length(&collation.sort_key(v), buf);
+            }
+            (ColumnType::Bytes, Value::Bytes(v)) => encode_variable_length(v, buf),
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Collation, Column, ColumnType, KeySchema, Order, Value};
+    use crate::DbError;
+
+    fn schema(value_type: ColumnType, order: Order, nullable: bool) -> KeySchema {
+        KeySchema::new(vec![Column::new(value_type, order, nullable)])
+    }
+
+    // Checks that the values, given in their ascending order, encode into
+    // ascending keys, and descending keys for a descending column.
+    fn assert_order(value_type: ColumnType, values: &[Value<'_>]) {
+        for order in [Order::Ascending, Order::Descending] {
+            for nullable in [false, true] {
+                let schema = schema(value_type, order, nullable);
+                let keys: Vec<_> = values
+                    .iter()
+                    .map(|v| schema.encode(&[*v]).unwrap())
+                    .collect();
+                for pair in keys.windows(2) {
+                    match order {
+                        Order::Ascending => assert!(pair[0] < pair[1]),
+                        Order::Descending => assert!(pair[0] > pair[1]),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unsigned_integers() {
+        assert_order(
+            ColumnType::U8,
+            &[Value::U8(0), Value::U8(1), Value::U8(u8::MAX)],
+        );
+        assert_order(
+            ColumnType::U16,
+            &[
+                Value::U16(0),
+                Value::U16(255),
+                Value::U16(256),
+                Value::U16(u16::MAX),
+            ],
+        );
+        assert_order(
+            ColumnType::U32,
+            &[
+                Value::U32(0),
+                Value::U32(0x100),
+                Value::U32(0x1_0000),
+                Value::U32(u32::MAX),
+            ],
+        );
+        assert_order(
+            ColumnType::U64,
+            &[Value::U64(0), Value::U64(1 << 32), Value::U64(u64::MAX)],
+        );
+        assert_order(
+            ColumnType::U128,
+            &[Value::U128(0), Value::U128(1 << 64), Value::U128(u128::MAX)],
+        );
+    }
+
+    #[test]
+    fn signed_integers() {
+        assert_order(
+            ColumnType::I8,
+            &[
+                Value::I8(i8::MIN),
+                Value::I8(-1),
+                Value::I8(0),
+                Value::I8(1),
+                Value::I8(i8::MAX),
+            ],
+        );
+        assert_order(
+            ColumnType::I16,
+            &[
+                Value::I16(i16::MIN),
+                Value::I16(-256),
+                Value::I16(0),
+                Value::I16(i16::MAX),
+            ],
+        );
+        assert_order(
+            ColumnType::I32,
+            &[
+                Value::I32(i32::MIN),
+                Value::I32(-1),
+                Value::I32(0),
+                Value::I32(i32::MAX),
+            ],
+        );
+        assert_order(
+            ColumnType::I64,
+            &[
+                Value::I64(i64::MIN),
+                Value::I64(-1),
+                Value::I64(0),
+                Value::I64(i64::MAX),
+            ],
+        );
+        assert_order(
+            ColumnType::I128,
+            &[
+                Value::I128(i128::MIN),
+                Value::I128(-1),
+                Value::I128(0),
+                Value::I128(i128::MAX),
+            ],
+        );
+    }
+
+    #[test]
+    fn floats() {
+        assert_order(
+            ColumnType::F32,
+            &[
+                Value::F32(f32::NEG_INFINITY),
+                Value::F32(f32::MIN),
+                Value::F32(-1.5),
+                Value::F32(-f32::MIN_POSITIVE),
+                Value::F32(0.0),
+                Value::F32(f32::MIN_POSITIVE),
+                Value::F32(1.5),
+                Value::F32(f32::MAX),
+                Value::F32(f32::INFINITY),
+                Value::F32(f32::NAN),
+            ],
+        );
+        assert_order(
+            ColumnType::F64,
+            &[
+                Value::F64(f64::NEG_INFINITY),
+                Value::F64(-2.0),
+                Value::F64(-1.0),
+                Value::F64(0.0),
+                Value::F64(1e-300),
+                Value::F64(1.0),
+                Value::F64(f64::INFINITY),
+                Value::F64(f64::NAN),
+            ],
+        );
+        let schema = schema(ColumnType::F64, Order::Ascending, false);
+        assert_eq!(
+            schema.encode(&[Value::F64(-0.0)]).unwrap(),
+            schema.encode(&[Value::F64(0.0)]).unwrap()
+        );
+        assert_eq!(
+            schema.encode(&[Value::F64(-f64::NAN)]).unwrap(),
+            schema.encode(&[Value::F64(f64::NAN)]).unwrap()
+        );
+    }
+
+    #[test]
+    fn strings_and_bytes() {
+        assert_order(
+            ColumnType::String(Collation::Binary),
+            &[
+                Value::Str(""),
+                Value::Str("\0"),
+                Value::Str("\0\0"),
+                Value::Str("\u{1}"),
+                Value::Str("A"),
+                Value::Str("a"),
+                Value::Str("a\0"),
+                Value::Str("ab"),
+                Value::Str("\u{e9}"),
+            ],
+        );
+        assert_order(
+            ColumnType::String(Collation::CaseInsensitive),
+            &[Value::Str("a"), Value::Str("AB"), Value::Str("b")],
+        );
+        assert_order(
+            ColumnType::Bytes,
+            &[
+                Value::Bytes(b""),
+                Value::Bytes(&[0]),
+                Value::Bytes(&[0, 0xFF]),
+                Value::Bytes(&[1]),
+                Value::Bytes(&[0xFF, 0]),
+                Value::Bytes(&[0xFF, 0xFF]),
+            ],
+        );
+        let schema = schema(
+            ColumnType::String(Collation::CaseInsensitive),
+            Order::Ascending,
+            false,
+        );
+        assert_eq!(
+            schema.encode(&[Value::Str("Straße")]).unwrap(),
+            schema.encode(&[Value::Str("STRAßE")]).unwrap()
+        );
+    }
+
+    #[test]
+    fn nulls() {
+        let ascending = schema(ColumnType::I32, Order::Ascending, true);
+        assert!(
+            ascending.encode(&[Value::Null]).unwrap()
+                < ascending.encode(&[Value::I32(i32::MIN)]).unwrap()
+        );
+        let descending = schema(ColumnType::I32, Order::Descending, true);
+        assert!(
+            descending.encode(&[Value::Null]).unwrap()
+                > descending.encode(&[Value::I32(i32::MIN)]).unwrap()
+        );
+        let not_nullable = schema(ColumnType::I32, Order::Ascending, false);
+        assert!(matches!(
+            not_nullable.encode(&[Value::Null]),
+            Err(DbError::KeyTypeMismatch { column: 0 })
+        ));
+    }
+
+    #[test]
+    fn tuples() {
+        let schema = KeySchema::new(vec![
+            Column::new(
+                ColumnType::String(Collation::Binary),
+                Order::Ascending,
+                false,
+            ),
+            Column::new(ColumnType::I64, Order::Descending, true),
+            Column::new(ColumnType::Bytes, Order::Ascending, false),
+        ]);
+        let tuples = [
+            [Value::Str("a"), Value::I64(5), Value::Bytes(b"z")],
+            [Value::Str("a"), Value::I64(-5), Value::Bytes(b"")],
+            [Value::Str("a"), Value::I64(-5), Value::Bytes(b"a")],
+            [Value::Str("a"), Value::Null, Value::Bytes(b"")],
+            [Value::Str("a\0"), Value::I64(100), Value::Bytes(b"")],
+            [Value::Str("b"), Value::I64(0), Value::Bytes(b"")],
+        ];
+        let keys: Vec<_> = tuples.iter().map(|t| schema.encode(t).unwrap()).collect();
+        for pair in keys.windows(2) {
+            assert!(pair[0] < pair[1]);
+        }
+        let prefix = schema.encode(&tuples[1][..2]).unwrap();
+        assert!(keys[1].starts_with(&prefix));
+        assert!(keys[2].starts_with(&prefix));
+        assert!(!keys[0].starts_with(&prefix));
+        assert!(!keys[3].starts_with(&prefix));
+    }
+
+    #[test]
+    fn schema_serialization() {
+        let schema = KeySchema::new(vec![
+            Column::new(ColumnType::U8, Order::Ascending, false),
+            Column::new(ColumnType::I128, Order::Descending, true),
+            Column::new(ColumnType::F32, Order::Ascending, true),
+            Column::new(
+                ColumnType::String(Collation::CaseInsensitive),
+                Order::Descending,
+                false,
+            ),
+            Column::new(ColumnType::Bytes, Order::Ascending, false),
+        ]);
+        let bytes = schema.to_bytes();
+        assert_eq!(KeySchema::from_bytes(&bytes).unwrap(), schema);
+        assert_eq!(KeySchema::from_bytes(&[]).unwrap(), KeySchema::new(vec![]));
+        assert_eq!(KeySchema::from_bytes(&bytes[..3]), None);
+        assert_eq!(KeySchema::from_bytes(&[14, 0, 0, 0]), None);
+        assert_eq!(KeySchema::from_bytes(&[12, 2, 0, 0]), None);
+        assert_eq!(KeySchema::from_bytes(&[0, 0, 2, 0]), None);
+        assert_eq!(KeySchema::from_bytes(&[0, 0, 0, 2]), None);
+    }
+
+    #[test]
+    fn wrong_values() {
+        let schema = schema(ColumnType::U32, Order::Ascending, false);
+        assert!(matches!(
+            schema.encode(&[Value::U64(1)]),
+            Err(DbError::KeyTypeMismatch { column: 0 })
+        ));
+        assert!(matches!(
+            schema.encode(&[Value::U32(1), Value::U32(2)]),
+            Err(DbError::KeyValueCount {
+                columns: 1,
+                values: 2
+            })
+        ));
+        assert!(schema.encode(&[]).unwrap().is_empty());
+    }
+}
@@ -1,8 +1,15 @@
 // Copyright (C) 2022-2024 Laurynas Biveinis
 #![deny(clippy::pedantic)]
 
+mod art;
 mod buffer_manager;
+pub mod catalog;
+mod checkpoint;
+pub mod cursor;
+mod data_file;
+mod eviction;
 mod ffi_cxx;
+pub mod key;
 mod log;
 mod node;
 pub mod transaction_manager;
@@ -11,13 +18,16 @@ use crate::log::Log;
 use buffer_manager::BufferManager;
 use cap_std::fs::Dir;
 use cap_std::fs::OpenOptions;
-use std::cell::RefCell;
+use checkpoint::Checkpoint;
+use data_file::DataFile;
 use std::env;
 use std::io;
 use std::io::ErrorKind;
 use std::path::Path;
 use std::path::PathBuf;
-use std::rc::Rc;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 use thiserror::Error;
 use transaction_manager::Transaction;
 use transaction_manager::TransactionManager;
@@ -29,26 +39,154 @@ pub enum DbError {
     Io(#[from] io::Error),
     #[error("Corruption: incorrect log record type {bad_type}")]
     BadLogRecordType { bad_type: u8 },
+    #[error("Corruption: bad log record at LSN {lsn}")]
+    BadLogRecord { lsn: u64 },
     #[error("Corruption: logged multiple allocations for the same node ID {node_id}")]
     LoggedMultipleNodeIdAllocations { node_id: node::Id },
+    #[error("Corruption: bad node ID {node_id} in the data file")]
+    BadNode { node_id: node::Id },
+    #[error("Corruption: unexpected node type for node ID {node_id}")]
+    UnexpectedNodeType { node_id: node::Id },
+    #[error("Node ID {node_id} is not an ART descriptor")]
+    NotArtDescriptor { node_id: node::Id },
+    #[error("Key already exists")]
+    KeyExists,
+    #[error("Key has {values} values for {columns} columns")]
+    KeyValueCount { columns: usize, values: usize },
+    #[error("Key value for column {column} does not match the column type")]
+    KeyTypeMismatch { column: usize },
+    #[error("Keyspace {name} already exists")]
+    KeyspaceExists { name: String },
+    #[error("Keyspace {name} does not exist")]
+    NoSuchKeyspace { name: String },
+    #[error("Corruption: bad catalog entry for keyspace {name}")]
+    BadCatalogEntry { name: String },
+    #[error("The catalog cannot be written directly")]
+    CatalogWrite,
+    #[error("Corruption: bad checkpoint")]
+    BadCheckpoint,
+    #[error("Corruption: missing the log segment at LSN {lsn}")]
+    MissingLogSegment { lsn: u64 },
+    #[error("Unknown durability mode {mode}")]
+    BadDurability { mode: u8 },
+    #[error("Unknown key column type {column_type}")]
+    BadColumnType { column_type: u8 },
+    #[error("No such savepoint")]
+    NoSuchSavepoint,
+    #[error("Key written by a transaction committed after the snapshot")]
+    WriteConflict,
+    #[error("The database must be reopened after a failure to write its log or apply it")]
+    Poisoned,
 }
 
-// Do the simplest thing that works. Later generalize to being able to contain
-// multiple ARTs, with different key types.
+// A panic cannot leave the state protected by any of the mutexes half-updated
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// How the nodes to evict from the buffer pool are chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[must_use]
+pub enum EvictionPolicy {
+    /// The least recently used node.
+    #[default]
+    Lru,
+    /// The approximation of LRU by a sweep over reference bits.
+    Clock,
+    /// The simplified 2Q, which keeps the nodes touched once by scans from
+    /// evicting the frequently used ones.
+    TwoQueue,
+}
+
+/// How a commit is made durable before it returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[must_use]
+pub enum Durability {
+    /// The log is synced with fsync at every commit.
+    #[default]
+    Fsync,
+    /// The log is synced with fdatasync at every commit, skipping the file
+    /// metadata not needed to read it back.
+    Fdatasync,
+    /// The log is written to the OS at every commit, and synced in the
+    /// background once the interval has passed since the last sync, thus an
+    /// OS crash may lose about the commits made over the last interval. A
+    /// commit made after the interval has passed syncs the log itself.
+    Periodic { interval: Duration },
+    /// The log is written to the OS at every commit, and left for the OS to
+    /// sync, thus an OS crash may lose any commits since the last checkpoint.
+    /// A crash of the process alone loses none.
+    NoSync,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use]
+pub struct DbOptions {
+    /// The memory limit for the nodes cached in memory, in bytes. It may be
+    /// exceeded while all the nodes in memory are in use.
+    pub buffer_pool_size: usize,
+    pub eviction_policy: EvictionPolicy,
+    /// Checkpoint in the background once the log has grown by this many bytes
+    /// since the last checkpoint, bounding the log to redo at open. None
+    /// disables the automatic checkpoints.
+    pub checkpoint_log_size: Option<u64>,
+    /// The size in bytes at which a new log file is started. The log files
+    /// before the last checkpoint are deleted.
+    pub log_segment_size: u64,
+    /// The durability of the commits that do not override it.
+    pub durability: Durability,
+}
+
+impl Default for DbOptions {
+    fn default() -> Self {
+        Self {
+            buffer_pool_size: 64 * 1024 * 1024,
+            eviction_policy: EvictionPolicy::default(),
+            checkpoint_log_size: Some(64 * 1024 * 1024),
+            log_segment_size: 16 * 1024 * 1024,
+            durability: Durability::default(),
+        }
+    }
+}
+
+/// The buffer pool counters since the database was opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[must_use]
+pub struct BufferPoolStats {
+    /// The node accesses served from memory.
+    pub hits: u64,
+    /// The node accesses that read the node from the data file.
+    pub misses: u64,
+    pub evictions: u64,
+    /// The modified nodes written to the data file.
+    pub write_backs: u64,
+}
+
+// A database contains named keyspaces, each of them an ART. Typed keys are
+// mapped onto the ART keys by the key module.
 #[derive(Debug)] // COV_EXCL_LINE
 #[must_use]
 pub struct Db {
     _dir_handle: Dir,
-    transaction_manager: Rc<RefCell<TransactionManager>>,
+    transaction_manager: Arc<TransactionManager>,
+    // The thread of the automatic checkpoints
+    checkpointer: Option<JoinHandle<()>>,
 }
 
 impl Db {
     const VERSION_FILE_NAME: &'static str = "VERSION";
     const LOG_FILE_NAME: &'static str = "LOG";
+    const DATA_FILE_NAME: &'static str = "DATA";
 
     /// # Errors
     /// Will return `DbError` if it encounters any.
     pub fn open(path: &Path) -> Result<Self, DbError> {
+        Self::open_with_options(path, &DbOptions::default())
+    }
+
+    /// # Errors
+    /// Will return `DbError` if it encounters any.
+    pub fn open_with_options(path: &Path, options: &DbOptions) -> Result<Self, DbError> {
         let absolute_path: PathBuf = if path.is_absolute() {
             path.to_path_buf()
         } else {
@@ -88,28 +226,93 @@ impl Db {
                 dir_handle.open_with(Self::VERSION_FILE_NAME, OpenOptions::new().read(true))
             }?;
         }
-        let log = Log::open(&dir_handle, Path::new(Self::LOG_FILE_NAME), is_dir_empty)?;
-        let buffer_manager = BufferManager::new(log.max_logged_node_id().next());
-        let transaction_manager = TransactionManager::new(buffer_manager, log);
+        let checkpoint = Checkpoint::read(&dir_handle)?;
+        let checkpoint_lsn = checkpoint.as_ref().map_or(0, Checkpoint::lsn);
+        let mut log = Log::open(
+            &dir_handle,
+            Self::LOG_FILE_NAME,
+            is_dir_empty,
+            checkpoint_lsn,
+            options.log_segment_size,
+        )?;
+        let recovered_changes = log.take_recovered_changes();
+        let mut first_free_node_id = log.max_logged_node_id().max(node::Id::CATALOG).next();
+        // The transactions before the checkpoint may be gone from the log
+        let mut first_transaction_id = log.next_transaction_id();
+        if let Some(checkpoint) = &checkpoint {
+            first_free_node_id = first_free_node_id.max(checkpoint.next_node_id());
+            first_transaction_id = first_transaction_id.max(checkpoint.next_transaction_id());
+        }
+        let data_file = DataFile::open(&dir_handle, Path::new(Self::DATA_FILE_NAME), is_dir_empty)?;
+        let mut buffer_manager = BufferManager::new(first_free_node_id, data_file, options);
+        buffer_manager.restore(checkpoint.as_ref())?;
+        if checkpoint.is_none() {
+            catalog::bootstrap(&buffer_manager)?;
+        }
+        let transaction_manager = TransactionManager::new(
+            buffer_manager,
+            log,
+            dir_handle.try_clone()?,
+            checkpoint.as_ref(),
+            first_transaction_id,
+            options,
+        );
+        transaction_manager.redo(&recovered_changes)?;
+        let transaction_manager = Arc::new(transaction_manager);
+        let checkpointer = if options.checkpoint_log_size.is_some() {
+            let transaction_manager = Arc::clone(&transaction_manager);
+            Some(
+                thread::Builder::new()
+                    .name("kirunadb-checkpoint".to_owned())
+                    .spawn(move || transaction_manager.checkpoint_when_due())?,
+            )
+        } else {
+            None
+        };
         Ok(Self {
             _dir_handle: dir_handle,
-            transaction_manager: Rc::new(RefCell::new(transaction_manager)),
+            transaction_manager,
+            checkpointer,
         })
     }
 
-    pub fn begin_transaction(&mut self) -> Transaction {
-        let new_transaction_id = self.transaction_manager.borrow_mut().assign_next_id();
-        Transaction::new(&self.transaction_manager, new_transaction_id)
+    pub fn begin_transaction(&self) -> Transaction {
+        let (id, snapshot_ts) = self.transaction_manager.begin_transaction();
+        Transaction::new(&self.transaction_manager, id, snapshot_ts)
+    }
+
+    pub fn buffer_pool_stats(&self) -> BufferPoolStats {
+        self.transaction_manager.buffer_pool_stats()
+    }
+
+    /// Writes all the committed changes to the data file, so that the log
+    /// before them need not be redone at open.
+    /// # Errors
+    /// Will return `DbError` if it encounters any.
+    pub fn checkpoint(&self) -> Result<(), DbError> {
+        self.transaction_manager.checkpoint()
+    }
+}
+
+impl Drop for Db {
+    // Waits for any automatic checkpoint in progress
+    fn drop(&mut self) {
+        self.transaction_manager.close();
+        if let Some(checkpointer) = self.checkpointer.take() {
+            let _result = checkpointer.join();
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::Db;
+    use crate::key::KeySchema;
+    use crate::{node, Db, DbError};
     use kirunadb_test_helpers::get_temp_dir;
     use kirunadb_test_helpers::open_db_err;
     use std::fs;
     use std::path::Path;
+    use std::thread;
 
     fn make_path_read_only(path: &Path) {
         let mut path_permissions = path.metadata().unwrap().permissions();
@@ -194,16 +397,95 @@ mod tests {
         let temp_dir = get_temp_dir();
         let path = temp_dir.path();
         open_db_ok(path);
-        let version_path = path.join("LOG");
+        let version_path = path.join("LOG.00000000000000000000");
         fs::remove_file(version_path).unwrap();
         open_db_err(path);
     }
 
+    #[test]
+    fn try_open_db_missing_data() {
+        let temp_dir = get_temp_dir();
+        let path = temp_dir.path();
+        open_db_ok(path);
+        let data_path = path.join("DATA");
+        fs::remove_file(data_path).unwrap();
+        open_db_err(path);
+    }
+
     #[test]
     fn begin_transaction() {
         let temp_dir = get_temp_dir();
         let path = temp_dir.path();
-        let mut db = Db::open(path).unwrap();
+        let db = Db::open(path).unwrap();
         let _transaction = db.begin_transaction();
     }
+
+    #[test]
+    fn catalog_not_writable() {
+        let temp_dir = get_temp_dir();
+        let path = temp_dir.path();
+        let db = Db::open(path).unwrap();
+        let mut transaction = db.begin_transaction();
+        let key_schema = KeySchema::new(Vec::new());
+        let tree = transaction.create_keyspace("a", key_schema, b"").unwrap();
+        assert_ne!(tree, node::Id::CATALOG);
+        let catalog_value = transaction.get(node::Id::CATALOG, b"a").unwrap();
+        assert!(catalog_value.is_some());
+        assert!(matches!(
+            transaction.upsert(node::Id::CATALOG, b"a", b""),
+            Err(DbError::CatalogWrite)
+        ));
+        assert!(matches!(
+            transaction.insert(node::Id::CATALOG, b"b", b""),
+            Err(DbError::CatalogWrite)
+        ));
+        assert!(matches!(
+            transaction.delete(node::Id::CATALOG, b"a"),
+            Err(DbError::CatalogWrite)
+        ));
+    }
+
+    #[test]
+    fn concurrent_commits_in_one_group() {
+        const COMMITS: u8 = 8;
+        let temp_dir = get_temp_dir();
+        let path = temp_dir.path();
+        let tree;
+        {
+            let db = Db::open(path).unwrap();
+            let mut transaction = db.begin_transaction();
+            tree = transaction
+                .create_keyspace("a", KeySchema::new(Vec::new()), b"")
+                .unwrap();
+            transaction.commit().unwrap();
+            let log = db.transaction_manager.log();
+            let groups = log.commit_groups();
+            // Hold off the commits until all of them are waiting
+            log.hold_commits();
+            thread::scope(|scope| {
+                for key in 0..COMMITS {
+                    let db = &db;
+                    scope.spawn(move || {
+                        let mut transaction = db.begin_transaction();
+                        transaction.insert(tree, &[key], &[key]).unwrap();
+                        transaction.commit().unwrap();
+                    });
+                }
+                while log.waiting_commits() < COMMITS.into() {
+                    thread::yield_now();
+                }
+                log.release_commits();
+            });
+            assert_eq!(log.commit_groups(), groups + 1);
+            let transaction = db.begin_transaction();
+            for key in 0..COMMITS {
+                assert_eq!(transaction.get(tree, &[key]).unwrap().unwrap(), [key]);
+            }
+        }
+        let db = Db::open(path).unwrap();
+        let transaction = db.begin_transaction();
+        for key in 0..COMMITS {
+            assert_eq!(transaction.get(tree, &[key]).unwrap().unwrap(), [key]);
+        }
+    }
 }
@@ -1,17 +1,137 @@
-// Copyright (C) 2022-2023 Laurynas Biveinis
-use crate::{node, transaction_manager::TransactionChange, DbError};
+// Copyright (C) 2022-2024 Laurynas Biveinis
+use crate::{
+    lock, node,
+    transaction_manager::{
+        self, TransactionChange, TransactionChangeKey, TransactionChangeKeyValue,
+        TransactionChangeNewNode, TransactionChangeTree,
+    },
+    DbError, Durability,
+};
 use cap_std::fs::{Dir, File, OpenOptions};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use std::{
-    io::{self, Read, Write},
-    path::Path,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::{Arc, Condvar, Mutex, PoisonError},
+    thread::{self, JoinHandle},
+    time::Instant,
 };
 
+// The log is split into segment files, each named by its first LSN. The LSNs
+// are the byte offsets over all the segments, and no record spans segments.
+// The segments before the last checkpoint are deleted.
+//
+// A record is a header followed by the payload, which starts with the record
+// type. The header holds the record LSN, the transaction ID, the payload
+// length, and the CRC32C of the preceding header fields and the payload. A
+// transaction is logged at its commit as a begin record, a record for each
+// change, and a commit record, and only the transactions with a commit record
+// are redone. The transactions left without one by a crash are closed by abort
+// records at open, so that their records are not taken for those of a later
+// transaction with the same ID. A record that fails the checks and is followed
+// only by zeros until the end of the log has been torn by a crash while being
+// written, and is truncated at open. Any other bad record is corruption.
+//
+// The transaction IDs are reserved in blocks by the records of the ID to start
+// the next block from, so that the IDs of the transactions that logged nothing
+// are not taken again after open either.
+//
+// Once a sync fails, or a failed write cannot be cut off, the log is poisoned
+// and takes no more records: the OS may have dropped the written pages, and
+// the records of the failed commits may be found at open. It is poisoned too
+// once a logged commit fails to apply, as the trees are behind the log then.
+//
+// The records of the periodic durability commits are synced by a background
+// thread once their interval since the last sync has passed, unless a commit
+// syncs them before.
 #[derive(Debug)] // COV_EXCL_LINE
 #[must_use]
 pub struct Log {
-    file: File,
+    writer: Arc<Mutex<LogWriter>>,
+    sync_due: Arc<Condvar>,
+    syncer: Option<JoinHandle<()>>,
+    // The commits are written in groups, each by one of its commits, the
+    // leader, while the rest of them wait
+    commit_queue: Mutex<CommitQueue>,
+    group_committed: Condvar,
     max_logged_node_id: node::Id,
+    next_transaction_id: transaction_manager::Id,
+    recovered_changes: Vec<TransactionChange>,
+}
+
+#[derive(Debug)] // COV_EXCL_LINE
+struct LogWriter {
+    dir_handle: Dir,
+    base_name: String,
+    // The first LSNs of the segments, the last one being appended to
+    segment_starts: VecDeque<u64>,
+    file: File,
+    // A new segment is started once the records of a write would not fit in
+    // this size
+    segment_size: u64,
+    next_lsn: u64,
+    // The records at the end of the log not written to the file yet
+    buffer: Vec<u8>,
+    // The end of the records known to be durable, and when it was last moved
+    synced_lsn: u64,
+    last_sync: Instant,
+    // When the background thread is to sync the records written so far
+    sync_deadline: Option<Instant>,
+    is_closing: bool,
+    poisoned: bool,
+}
+
+#[derive(Debug, Default)] // COV_EXCL_LINE
+struct CommitQueue {
+    waiting: Vec<QueuedCommit>,
+    // The commits are numbered in their queuing order, and the ones below done
+    // have been written
+    next_ticket: u64,
+    done: u64,
+    is_led: bool,
+    // The errors of the written commits that failed, until they are taken
+    failures: HashMap<u64, io::Error>,
+    #[cfg(test)]
+    groups: u64,
+}
+
+#[derive(Debug)] // COV_EXCL_LINE
+struct QueuedCommit {
+    transaction_id: u64,
+    payloads: Vec<Vec<u8>>,
+    durability: Durability,
+}
+
+// The leadership of a commit group, ended when dropped, even if writing the
+// group panics. The group fails then, and the log is poisoned.
+#[derive(Debug)] // COV_EXCL_LINE
+struct GroupLead<'a> {
+    log: &'a Log,
+    group_end: u64,
+    result: Option<Result<(), io::Error>>,
+}
+
+impl Drop for GroupLead<'_> {
+    fn drop(&mut self) {
+        let result = self.result.take().unwrap_or_else(|| {
+            lock(&self.log.writer).poisoned = true;
+            Err(io::Error::other("Failed to write the log"))
+        });
+        let mut queue = lock(&self.log.commit_queue);
+        if let Err(error) = result {
+            for failed in queue.done..self.group_end {
+                let error = io::Error::new(error.kind(), error.to_string());
+                queue.failures.insert(failed, error);
+            }
+        }
+        queue.done = self.group_end;
+        queue.is_led = false;
+        #[cfg(test)]
+        {
+            queue.groups += 1;
+        }
+        self.log.group_committed.notify_all();
+    }
 }
 
 #[derive(IntoPrimitive, TryFromPrimitive)]
@@ -19,6 +139,19 @@ pub struct Log {
 #[must_use]
 enum ChangeId {
     NewNode = 0,
+    Insert = 1,
+    Upsert = 2,
+    Delete = 3,
+    DropTree = 4,
+    // Not a change, but the active transactions and the modified nodes at a
+    // checkpoint
+    Checkpoint = 5,
+    // The transaction boundaries
+    Begin = 6,
+    Commit = 7,
+    Abort = 8,
+    // Not a change, but the transaction IDs handed out below the logged one
+    ReserveTransactionIds = 9,
 }
 
 impl ChangeId {
@@ -26,79 +159,1101 @@ impl ChangeId {
     fn new(transaction_change: &TransactionChange) -> Self {
         match transaction_change {
             TransactionChange::NewNode(_) => Self::NewNode,
+            TransactionChange::Insert(_) => Self::Insert,
+            TransactionChange::Upsert(_) => Self::Upsert,
+            TransactionChange::Delete(_) => Self::Delete,
+            TransactionChange::DropTree(_) => Self::DropTree,
         }
     }
 }
 
-impl Log {
-    pub fn open(dir_handle: &Dir, log_file_name: &Path, create: bool) -> Result<Self, DbError> {
-        let mut file = if create {
-            dir_handle.open_with(
-                log_file_name,
-                OpenOptions::new().read(true).write(true).create_new(true),
-            )
-        } else {
-            dir_handle.open_with(log_file_name, OpenOptions::new().read(true).write(true))
-        }?;
-        let max_logged_node_id = if create {
-            0
-        } else {
-            let mut max_logged_node_id = 0;
-            // We could use MaybeUninit here, but not worth it.
-            let mut one_byte_buf = [0; 1];
-            loop {
-                let n = file.read(&mut one_byte_buf)?;
-                if n == 0 {
+const RECORD_HEADER_SIZE: usize = 24;
+// The log buffer is written out once it grows to this size, in whole chunks
+// aligned in the file, keeping the rest
+const LOG_BUFFER_SIZE: usize = 1024 * 1024;
+const LOG_CHUNK_SIZE: u64 = 4096;
+// The transaction ID of the records not written by a transaction
+const NO_TRANSACTION: u64 = u64::MAX;
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut result = [0; 256];
+    let mut i = 0;
+    while i < result.len() {
+        #[allow(clippy::cast_possible_truncation)]
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            // The reversed Castagnoli polynomial
+            crc = if crc & 1 == 0 {
+                crc >> 1
+            } else {
+                (crc >> 1) ^ 0x82F6_3B78
+            };
+            bit += 1;
+        }
+        result[i] = crc;
+        i += 1;
+    }
+    result
+};
+
+#[must_use]
+fn crc32c(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0;
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        crc = CRC32C_TABLE[((crc ^ u32::from(*byte)) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32, io::Error> {
+    let mut four_byte_buf = [0; 4];
+    reader.read_exact(&mut four_byte_buf)?;
+    Ok(u32::from_le_bytes(four_byte_buf))
+}
+
+// Returns the transaction ID and the payload of the record at the LSN, if it is
+// complete and intact
+fn parse_record(bytes: &[u8], lsn: u64) -> Option<(u64, &[u8])> {
+    let mut header = bytes.get(..RECORD_HEADER_SIZE)?;
+    let record_lsn = read_u64(&mut header).ok()?;
+    let transaction_id = read_u64(&mut header).ok()?;
+    let payload_len = usize::try_from(read_u32(&mut header).ok()?).ok()?;
+    let crc = read_u32(&mut header).ok()?;
+    let payload = bytes.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE.checked_add(payload_len)?)?;
+    let is_intact =
+        record_lsn == lsn && crc == crc32c(&[&bytes[..RECORD_HEADER_SIZE - 4], payload]);
+    is_intact.then_some((transaction_id, payload))
+}
+
+// Whether the bad record at the start of the bytes is the last one, followed
+// only by zeros if anything
+fn is_torn(bytes: &[u8]) -> bool {
+    let record_end = bytes
+        .get(RECORD_HEADER_SIZE - 8..RECORD_HEADER_SIZE - 4)
+        .and_then(|mut payload_len| read_u32(&mut payload_len).ok())
+        .and_then(|payload_len| usize::try_from(payload_len).ok())
+        .and_then(|payload_len| RECORD_HEADER_SIZE.checked_add(payload_len))
+        .map_or(bytes.len(), |record_end| record_end.min(bytes.len()));
+    bytes[record_end..].iter().all(|byte| *byte == 0)
+}
+
+// The integers in all the files are little-endian, so that the files can be
+// moved between hosts
+pub(crate) fn read_u64(reader: &mut impl Read) -> Result<u64, io::Error> {
+    let mut eight_byte_buf = [0; 8];
+    reader.read_exact(&mut eight_byte_buf)?;
+    Ok(u64::from_le_bytes(eight_byte_buf))
+}
+
+pub(crate) fn read_bytes(reader: &mut impl Read) -> Result<Vec<u8>, io::Error> {
+    let len = read_u64(reader)?;
+    let mut result = Vec::new();
+    // Do not trust the length to preallocate, it might be corrupted
+    reader.take(len).read_to_end(&mut result)?;
+    if u64::try_from(result.len()) == Ok(len) {
+        Ok(result)
+    } else {
+        Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+    }
+}
+
+pub(crate) fn write_bytes(writer: &mut impl Write, bytes: &[u8]) -> Result<(), io::Error> {
+    let len = u64::try_from(bytes.len()).map_err(io::Error::other)?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+fn read_ids(reader: &mut impl Read) -> Result<Vec<u64>, io::Error> {
+    let count = read_u64(reader)?;
+    // Do not trust the count to preallocate either
+    let mut result = Vec::new();
+    for _ in 0..count {
+        result.push(read_u64(reader)?);
+    }
+    Ok(result)
+}
+
+fn write_ids(writer: &mut impl Write, ids: &[u64]) -> Result<(), io::Error> {
+    writer.write_all(&(ids.len() as u64).to_le_bytes())?;
+    for id in ids {
+        writer.write_all(&id.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+// TODO(laurynas): this is throwaway code anyway. Use serde (C-SERDE)
+fn encode_change(change: &TransactionChange) -> Result<Vec<u8>, io::Error> {
+    let change_type_id: u8 = ChangeId::new(change).into();
+    let mut result = vec![change_type_id];
+    match change {
+        TransactionChange::NewNode(new_art_descriptor) => {
+            let node_id = new_art_descriptor.node_id();
+            result.write_all(&node_id.to_le_bytes())?;
+        }
+        TransactionChange::Insert(insert) | TransactionChange::Upsert(insert) => {
+            result.write_all(&insert.tree().to_le_bytes())?;
+            write_bytes(&mut result, insert.key())?;
+            write_bytes(&mut result, insert.value())?;
+        }
+        TransactionChange::Delete(delete) => {
+            result.write_all(&delete.tree().to_le_bytes())?;
+            write_bytes(&mut result, delete.key())?;
+        }
+        TransactionChange::DropTree(drop_tree) => {
+            result.write_all(&drop_tree.tree().to_le_bytes())?;
+        }
+    }
+    Ok(result)
+}
+
+fn record_header(
+    lsn: u64,
+    transaction_id: u64,
+    payload: &[u8],
+) -> Result<[u8; RECORD_HEADER_SIZE], io::Error> {
+    let payload_len = u32::try_from(payload.len()).map_err(io::Error::other)?;
+    let mut result = [0; RECORD_HEADER_SIZE];
+    result[..8].copy_from_slice(&lsn.to_le_bytes());
+    result[8..16].copy_from_slice(&transaction_id.to_le_bytes());
+    result[16..20].copy_from_slice(&payload_len.to_le_bytes());
+    let crc = crc32c(&[&result[..RECORD_HEADER_SIZE - 4], payload]);
+    result[RECORD_HEADER_SIZE - 4..].copy_from_slice(&crc.to_le_bytes());
+    Ok(result)
+}
+
+// The durability of a group of commits, which must satisfy each of them
+fn stricter(a: Durability, b: Durability) -> Durability {
+    match (a, b) {
+        (Durability::Fsync, _) | (_, Durability::Fsync) => Durability::Fsync,
+        (Durability::Fdatasync, _) | (_, Durability::Fdatasync) => Durability::Fdatasync,
+        (Durability::Periodic { interval: a }, Durability::Periodic { interval: b }) => {
+            Durability::Periodic { interval: a.min(b) }
+        }
+        (periodic @ Durability::Periodic { .. }, Durability::NoSync)
+        | (Durability::NoSync, periodic) => periodic,
+    }
+}
+
+#[must_use]
+fn segment_name(base_name: &str, start_lsn: u64) -> String {
+    format!("{base_name}.{start_lsn:020}")
+}
+
+// The first LSNs of the segments found in the directory, in order
+fn find_segments(dir_handle: &Dir, base_name: &str) -> Result<Vec<u64>, io::Error> {
+    let prefix = format!("{base_name}.");
+    let mut result = Vec::new();
+    for entry in dir_handle.entries()? {
+        let file_name = entry?.file_name();
+        let start_lsn = file_name
+            .to_str()
+            .and_then(|file_name| file_name.strip_prefix(&prefix))
+            .and_then(|suffix| suffix.parse().ok());
+        if let Some(start_lsn) = start_lsn {
+            result.push(start_lsn);
+        }
+    }
+    result.sort_unstable();
+    Ok(result)
+}
+
+fn create_segment(dir_handle: &Dir, base_name: &str, start_lsn: u64) -> Result<File, io::Error> {
+    dir_handle.open_with(
+        segment_name(base_name, start_lsn),
+        OpenOptions::new().read(true).write(true).create_new(true),
+    )
+}
+
+// Makes the file creations, renames, and deletions in the directory durable.
+// Directories cannot be synced on Windows.
+pub(crate) fn sync_dir(dir_handle: &Dir) -> Result<(), io::Error> {
+    if cfg!(unix) {
+        dir_handle.open(".")?.sync_all()?;
+    }
+    Ok(())
+}
+
+// The log contents read at open
+#[derive(Default)]
+struct Recovery {
+    max_logged_node_id: u64,
+    max_transaction_id: Option<u64>,
+    reserved_transaction_ids: u64,
+    // The logged tree IDs not dropped since, which may not be allocated again
+    live_node_ids: HashSet<node::Id>,
+    // The changes of the transactions not committed yet
+    pending: BTreeMap<u64, Vec<TransactionChange>>,
+    // The changes of the committed transactions, in their commit order
+    changes: Vec<TransactionChange>,
+}
+
+impl Recovery {
+    // Reads the records in the bytes of a segment from the LSN on. Returns the
+    // length of the intact records, which is shorter than the bytes if the
+    // last segment has a torn tail.
+    fn read_segment(&mut self, bytes: &[u8], lsn: u64, is_last: bool) -> Result<usize, DbError> {
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let record_lsn = lsn + offset as u64;
+            let Some((transaction_id, payload)) = parse_record(&bytes[offset..], record_lsn) else {
+                if is_last && is_torn(&bytes[offset..]) {
                     break;
                 }
-                debug_assert!(n == 1);
-                let type_byte = u8::from_ne_bytes(one_byte_buf);
-                let change_type =
-                    ChangeId::try_from(type_byte).map_err(|_foo| DbError::BadLogRecordType {
-                        bad_type: type_byte,
-                    })?;
-                match change_type {
-                    ChangeId::NewNode => {
-                        let mut eight_byte_buf = [0; 8];
-                        file.read_exact(&mut eight_byte_buf)?;
-                        let node_id = u64::from_ne_bytes(eight_byte_buf);
-                        match node_id.cmp(&max_logged_node_id) {
-                            std::cmp::Ordering::Greater => max_logged_node_id = node_id,
-                            std::cmp::Ordering::Equal => {
-                                return Err(DbError::LoggedMultipleNodeIdAllocations {
-                                    node_id: node::Id::from(node_id),
-                                })
-                            }
-                            std::cmp::Ordering::Less => {}
-                        }
-                    }
+                return Err(DbError::BadLogRecord { lsn: record_lsn });
+            };
+            if transaction_id != NO_TRANSACTION {
+                self.add_transaction_id(transaction_id);
+            }
+            self.read_record(record_lsn, transaction_id, payload)?;
+            offset += RECORD_HEADER_SIZE + payload.len();
+        }
+        Ok(offset)
+    }
+
+    fn read_record(
+        &mut self,
+        lsn: u64,
+        transaction_id: u64,
+        payload: &[u8],
+    ) -> Result<(), DbError> {
+        let bad_record = DbError::BadLogRecord { lsn };
+        let Some((&type_byte, mut reader)) = payload.split_first() else {
+            return Err(bad_record);
+        };
+        let change_type =
+            ChangeId::try_from(type_byte).map_err(|_foo| DbError::BadLogRecordType {
+                bad_type: type_byte,
+            })?;
+        let change = match change_type {
+            ChangeId::NewNode => {
+                let node_id = node::Id::from(read_u64(&mut reader)?);
+                TransactionChange::NewNode(TransactionChangeNewNode::new(node_id))
+            }
+            ChangeId::Insert | ChangeId::Upsert => {
+                let tree = node::Id::from(read_u64(&mut reader)?);
+                let key = read_bytes(&mut reader)?;
+                let value = read_bytes(&mut reader)?;
+                let change = TransactionChangeKeyValue::new(tree, key, value);
+                if matches!(change_type, ChangeId::Insert) {
+                    TransactionChange::Insert(change)
+                } else {
+                    TransactionChange::Upsert(change)
+                }
+            }
+            ChangeId::Delete => {
+                let tree = node::Id::from(read_u64(&mut reader)?);
+                let key = read_bytes(&mut reader)?;
+                TransactionChange::Delete(TransactionChangeKey::new(tree, key))
+            }
+            ChangeId::DropTree => {
+                let tree = node::Id::from(read_u64(&mut reader)?);
+                TransactionChange::DropTree(TransactionChangeTree::new(tree))
+            }
+            ChangeId::Checkpoint => {
+                for active_transaction in read_ids(&mut reader)? {
+                    self.add_transaction_id(active_transaction);
+                }
+                // The checkpoints are consistent, thus the modified node IDs
+                // written by the older versions are not needed
+                if !reader.is_empty() {
+                    let _dirty_node_ids = read_ids(&mut reader)?;
+                }
+                return if reader.is_empty() {
+                    Ok(())
+                } else {
+                    Err(bad_record)
+                };
+            }
+            ChangeId::ReserveTransactionIds => {
+                let until = read_u64(&mut reader)?;
+                self.reserved_transaction_ids = self.reserved_transaction_ids.max(until);
+                return if reader.is_empty() {
+                    Ok(())
+                } else {
+                    Err(bad_record)
+                };
+            }
+            ChangeId::Begin | ChangeId::Commit | ChangeId::Abort => {
+                if !reader.is_empty() {
+                    return Err(bad_record);
                 }
+                // A begin record discards whatever an incomplete transaction
+                // with the same ID has left
+                let changes = if matches!(change_type, ChangeId::Begin) {
+                    self.pending.insert(transaction_id, Vec::new())
+                } else {
+                    self.pending.remove(&transaction_id)
+                };
+                if let (ChangeId::Commit, Some(changes)) = (change_type, changes) {
+                    self.commit(changes)?;
+                }
+                return Ok(());
             }
-            max_logged_node_id
         };
-        Ok(Self {
-            file,
-            max_logged_node_id: node::Id::from(max_logged_node_id),
-        })
+        if !reader.is_empty() {
+            return Err(bad_record);
+        }
+        let Some(changes) = self.pending.get_mut(&transaction_id) else {
+            return Err(bad_record);
+        };
+        changes.push(change);
+        Ok(())
+    }
+
+    fn add_transaction_id(&mut self, transaction_id: u64) {
+        self.max_transaction_id = self.max_transaction_id.max(Some(transaction_id));
     }
 
-    pub fn append(&mut self, changes: &Vec<TransactionChange>) -> Result<(), io::Error> {
-        // TODO(laurynas): this is throwaway code anyway. Use serde (C-SERDE)
-        for change in changes {
-            let change_type_id: u8 = ChangeId::new(change).into();
+    fn commit(&mut self, changes: Vec<TransactionChange>) -> Result<(), DbError> {
+        for change in &changes {
             match change {
-                TransactionChange::NewNode(new_art_descriptor) => {
-                    self.file.write_all(&change_type_id.to_ne_bytes())?;
-                    let node_id = new_art_descriptor.node_id();
-                    self.file.write_all(&node_id.to_ne_bytes())?;
+                TransactionChange::NewNode(new_node) => {
+                    let node_id = new_node.node_id();
+                    if !self.live_node_ids.insert(node_id) {
+                        return Err(DbError::LoggedMultipleNodeIdAllocations { node_id });
+                    }
+                    self.max_logged_node_id = self.max_logged_node_id.max(node_id.as_u64());
                 }
+                TransactionChange::DropTree(drop_tree) => {
+                    self.live_node_ids.remove(&drop_tree.tree());
+                }
+                _ => {}
             }
         }
+        self.changes.extend(changes);
         Ok(())
     }
+}
+
+impl Log {
+    // Reads the log from the start LSN on, which must be within the log, and
+    // deletes the segments before it
+    pub fn open(
+        dir_handle: &Dir,
+        base_name: &str,
+        create: bool,
+        start_lsn: u64,
+        segment_size: u64,
+    ) -> Result<Self, DbError> {
+        let dir_handle = dir_handle.try_clone()?;
+        let mut recovery = Recovery::default();
+        let (segment_starts, file, next_lsn) = if create {
+            debug_assert_eq!(start_lsn, 0);
+            let file = create_segment(&dir_handle, base_name, 0)?;
+            (VecDeque::from([0]), file, 0)
+        } else {
+            let segment_starts = find_segments(&dir_handle, base_name)?;
+            // The segment containing the start LSN and the ones after it
+            let first = segment_starts
+                .partition_point(|segment_start| *segment_start <= start_lsn)
+                .checked_sub(1)
+                .ok_or(DbError::MissingLogSegment { lsn: start_lsn })?;
+            let mut segment_end = start_lsn;
+            let mut last_file = None;
+            for (i, segment_start) in segment_starts[first..].iter().enumerate() {
+                if i > 0 && *segment_start != segment_end {
+                    return Err(DbError::MissingLogSegment { lsn: segment_end });
+                }
+                let mut file = dir_handle.open_with(
+                    segment_name(base_name, *segment_start),
+                    OpenOptions::new().read(true).write(true),
+                )?;
+                let offset = segment_end - segment_start;
+                if offset > file.metadata()?.len() {
+                    return Err(DbError::BadCheckpoint);
+                }
+                file.seek(SeekFrom::Start(offset))?;
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes)?;
+                let is_last = *segment_start == segment_starts[segment_starts.len() - 1];
+                let intact_len = recovery.read_segment(&bytes, segment_end, is_last)?;
+                segment_end += intact_len as u64;
+                if intact_len < bytes.len() {
+                    file.set_len(segment_end - segment_start)?;
+                    file.sync_data()?;
+                }
+                last_file = Some(file);
+            }
+            let Some(mut file) = last_file else {
+                unreachable!("The segment containing the start LSN was read");
+            };
+            file.seek(SeekFrom::End(0))?;
+            (VecDeque::from(segment_starts), file, segment_end)
+        };
+        let mut writer = LogWriter {
+            dir_handle,
+            base_name: base_name.to_owned(),
+            segment_starts,
+            file,
+            segment_size,
+            next_lsn,
+            buffer: Vec::with_capacity(LOG_BUFFER_SIZE),
+            synced_lsn: next_lsn,
+            last_sync: Instant::now(),
+            sync_deadline: None,
+            is_closing: false,
+            poisoned: false,
+        };
+        let abort: [u8; 1] = [ChangeId::Abort.into()];
+        let aborts: Vec<_> = recovery
+            .pending
+            .keys()
+            .map(|transaction_id| (*transaction_id, &abort[..]))
+            .collect();
+        if !aborts.is_empty() {
+            let _lsn = writer.write(&aborts)?;
+        }
+        writer.remove_segments_before(start_lsn)?;
+        let writer = Arc::new(Mutex::new(writer));
+        let sync_due = Arc::new(Condvar::new());
+        let syncer = thread::Builder::new()
+            .name("kirunadb-log-sync".to_owned())
+            .spawn({
+                let writer = Arc::clone(&writer);
+                let sync_due = Arc::clone(&sync_due);
+                move || sync_periodically(&writer, &sync_due)
+            })?;
+        Ok(Self {
+            writer,
+            sync_due,
+            syncer: Some(syncer),
+            commit_queue: Mutex::new(CommitQueue::default()),
+            group_committed: Condvar::new(),
+            max_logged_node_id: node::Id::from(recovery.max_logged_node_id),
+            next_transaction_id: transaction_manager::Id::from(
+                recovery
+                    .max_transaction_id
+                    .map_or(0, |id| id + 1)
+                    .max(recovery.reserved_transaction_ids),
+            ),
+            recovered_changes: recovery.changes,
+        })
+    }
+
+    // Queues the records of a committing transaction to be written in the
+    // returned turn, which is the order of the transactions in the log
+    pub fn enqueue(
+        &self,
+        transaction_id: transaction_manager::Id,
+        changes: &[TransactionChange],
+        durability: Durability,
+    ) -> Result<u64, io::Error> {
+        let payloads = changes
+            .iter()
+            .map(encode_change)
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut queue = lock(&self.commit_queue);
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        queue.waiting.push(QueuedCommit {
+            transaction_id: transaction_id.as_u64(),
+            payloads,
+            durability,
+        });
+        Ok(ticket)
+    }
+
+    // Waits until the queued records have been written, and synced as their
+    // durability requires. The commits queued while a group is being written
+    // are written together as the next group, sharing the write and the sync.
+    pub fn wait_written(&self, ticket: u64) -> Result<(), io::Error> {
+        let mut queue = lock(&self.commit_queue);
+        loop {
+            if ticket < queue.done {
+                return queue.failures.remove(&ticket).map_or(Ok(()), Err);
+            }
+            if queue.is_led {
+                queue = self
+                    .group_committed
+                    .wait(queue)
+                    .unwrap_or_else(PoisonError::into_inner);
+                continue;
+            }
+            // Lead the group of all the waiting commits, this one included
+            queue.is_led = true;
+            let group = std::mem::take(&mut queue.waiting);
+            let mut lead = GroupLead {
+                log: self,
+                group_end: queue.next_ticket,
+                result: None,
+            };
+            drop(queue);
+            let mut writer = lock(&self.writer);
+            lead.result = Some(writer.write_commits(&group));
+            if writer.sync_deadline.is_some() {
+                self.sync_due.notify_one();
+            }
+            drop(writer);
+            drop(lead);
+            queue = lock(&self.commit_queue);
+        }
+    }
+
+    // Holds off the commits as if a group were being written
+    #[cfg(test)]
+    pub(crate) fn hold_commits(&self) {
+        lock(&self.commit_queue).is_led = true;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn release_commits(&self) {
+        lock(&self.commit_queue).is_led = false;
+        self.group_committed.notify_all();
+    }
+
+    #[cfg(test)]
+    pub(crate) fn waiting_commits(&self) -> usize {
+        lock(&self.commit_queue).waiting.len()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn commit_groups(&self) -> u64 {
+        lock(&self.commit_queue).groups
+    }
+
+    // The turn of the next queued commit
+    #[inline]
+    pub fn next_ticket(&self) -> u64 {
+        lock(&self.commit_queue).next_ticket
+    }
+
+    // Returns the record LSN, which is where the redo starts from
+    pub fn append_checkpoint(
+        &self,
+        active_transactions: &[transaction_manager::Id],
+    ) -> Result<u64, io::Error> {
+        let mut payload = vec![ChangeId::Checkpoint.into()];
+        let active_transactions: Vec<_> =
+            active_transactions.iter().map(|id| id.as_u64()).collect();
+        write_ids(&mut payload, &active_transactions)?;
+        lock(&self.writer).write(&[(NO_TRANSACTION, &payload)])
+    }
+
+    // Durably records that the transaction IDs below the given one may have
+    // been handed out
+    pub fn reserve_transaction_ids(&self, until: transaction_manager::Id) -> Result<(), io::Error> {
+        let mut payload = vec![ChangeId::ReserveTransactionIds.into()];
+        payload.extend_from_slice(&until.as_u64().to_le_bytes());
+        let mut writer = lock(&self.writer);
+        writer.write(&[(NO_TRANSACTION, &payload)])?;
+        writer.sync()
+    }
+
+    pub fn sync(&self) -> Result<(), io::Error> {
+        lock(&self.writer).sync()
+    }
+
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        lock(&self.writer).poisoned
+    }
+
+    pub fn poison(&self) {
+        lock(&self.writer).poisoned = true;
+    }
+
+    // Deletes the segments which end at or before the LSN
+    pub fn remove_segments_before(&self, lsn: u64) -> Result<(), io::Error> {
+        lock(&self.writer).remove_segments_before(lsn)
+    }
+
+    #[inline]
+    pub fn next_lsn(&self) -> u64 {
+        lock(&self.writer).next_lsn
+    }
 
     #[inline]
     pub fn max_logged_node_id(&self) -> node::Id {
         self.max_logged_node_id
     }
+
+    // Above the IDs of all the transactions found or reserved in the log at
+    // open
+    #[inline]
+    pub fn next_transaction_id(&self) -> transaction_manager::Id {
+        self.next_transaction_id
+    }
+
+    // The changes found in the log at open, to be redone
+    #[inline]
+    pub fn take_recovered_changes(&mut self) -> Vec<TransactionChange> {
+        std::mem::take(&mut self.recovered_changes)
+    }
+}
+
+impl Drop for Log {
+    // Stops the background sync, and syncs the records it has not synced yet
+    fn drop(&mut self) {
+        let mut writer = lock(&self.writer);
+        writer.is_closing = true;
+        if writer.sync_deadline.is_some() {
+            let _result = writer.sync();
+        }
+        drop(writer);
+        self.sync_due.notify_one();
+        if let Some(syncer) = self.syncer.take() {
+            let _result = syncer.join();
+        }
+    }
+}
+
+// The loop of the background thread syncing the log for the periodic
+// durability. A failed sync poisons the log, failing the later commits.
+fn sync_periodically(writer: &Mutex<LogWriter>, sync_due: &Condvar) {
+    let mut writer = lock(writer);
+    while !writer.is_closing {
+        writer = match writer.sync_deadline {
+            None => sync_due
+                .wait(writer)
+                .unwrap_or_else(PoisonError::into_inner),
+            Some(deadline) if deadline > Instant::now() => {
+                let timeout = deadline - Instant::now();
+                sync_due
+                    .wait_timeout(writer, timeout)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0
+            }
+            Some(_) => {
+                let _result = writer.sync();
+                writer.sync_deadline = None;
+                writer
+            }
+        };
+    }
+}
+
+impl LogWriter {
+    // Writes the records of the commits with a single write, and syncs them once
+    // as the strictest of their durabilities requires
+    fn write_commits(&mut self, commits: &[QueuedCommit]) -> Result<(), io::Error> {
+        let begin: [u8; 1] = [ChangeId::Begin.into()];
+        let commit: [u8; 1] = [ChangeId::Commit.into()];
+        let mut records = Vec::new();
+        for queued in commits {
+            records.push((queued.transaction_id, &begin[..]));
+            for payload in &queued.payloads {
+                records.push((queued.transaction_id, &payload[..]));
+            }
+            records.push((queued.transaction_id, &commit[..]));
+        }
+        let lsn = self.write(&records)?;
+        let durability = commits
+            .iter()
+            .map(|queued| queued.durability)
+            .reduce(stricter)
+            .unwrap_or(Durability::NoSync);
+        let result = self.sync_for_commit(durability);
+        if result.is_err() && self.flushed_lsn() <= lsn {
+            self.undo_write(lsn);
+        }
+        result
+    }
+
+    // Adds the records of the transactions with the payloads to the log buffer,
+    // returning the LSN of the first one. Starts a new segment first if the
+    // records do not fit in the current one, unless it is empty.
+    fn write(&mut self, records: &[(u64, &[u8])]) -> Result<u64, io::Error> {
+        self.check_poisoned()?;
+        let records_len: u64 = records
+            .iter()
+            .map(|(_, payload)| (RECORD_HEADER_SIZE + payload.len()) as u64)
+            .sum();
+        let segment_len = self.next_lsn - self.segment_start();
+        if segment_len > 0 && segment_len + records_len > self.segment_size {
+            self.start_segment()?;
+        }
+        let lsn = self.next_lsn;
+        for (transaction_id, payload) in records {
+            let header = record_header(self.next_lsn, *transaction_id, payload)?;
+            self.buffer.extend_from_slice(&header);
+            self.buffer.extend_from_slice(payload);
+            self.next_lsn += (RECORD_HEADER_SIZE + payload.len()) as u64;
+        }
+        if self.buffer.len() >= LOG_BUFFER_SIZE {
+            if let Err(error) = self.flush(true) {
+                self.undo_write(lsn);
+                return Err(error);
+            }
+        }
+        Ok(lsn)
+    }
+
+    // Drops the buffered records from the LSN on
+    fn undo_write(&mut self, lsn: u64) {
+        let Ok(undone_len) = usize::try_from(self.next_lsn - lsn) else {
+            unreachable!("The undone records are in the buffer");
+        };
+        self.buffer.truncate(self.buffer.len() - undone_len);
+        self.next_lsn = lsn;
+    }
+
+    #[inline]
+    fn flushed_lsn(&self) -> u64 {
+        self.next_lsn - self.buffer.len() as u64
+    }
+
+    // Writes out the log buffer, or only its whole chunks
+    fn flush(&mut self, whole_chunks: bool) -> Result<(), io::Error> {
+        self.check_poisoned()?;
+        let file_len = self.flushed_lsn() - self.segment_start();
+        let flush_len = if whole_chunks {
+            let buffer_end = file_len + self.buffer.len() as u64;
+            let chunks_end = buffer_end - buffer_end % LOG_CHUNK_SIZE;
+            usize::try_from(chunks_end.saturating_sub(file_len)).map_err(io::Error::other)?
+        } else {
+            self.buffer.len()
+        };
+        if flush_len == 0 {
+            return Ok(());
+        }
+        if let Err(error) = self.file.write_all(&self.buffer[..flush_len]) {
+            // Cut off any written part, so that it is written again in place
+            let cut_off = self
+                .file
+                .set_len(file_len)
+                .and_then(|()| self.file.seek(SeekFrom::End(0)));
+            self.poisoned = cut_off.is_err();
+            return Err(error);
+        }
+        self.buffer.drain(..flush_len);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), io::Error> {
+        self.flush(false)?;
+        self.sync_file(false)
+    }
+
+    // Makes the records written so far durable as far as the durability of the
+    // commit requires, and otherwise writes them to the OS, so that only an OS
+    // crash may lose them
+    fn sync_for_commit(&mut self, durability: Durability) -> Result<(), io::Error> {
+        if self.synced_lsn == self.next_lsn {
+            return Ok(());
+        }
+        let sync_metadata = match durability {
+            Durability::Fsync => true,
+            Durability::Fdatasync => false,
+            Durability::Periodic { interval } if self.last_sync.elapsed() >= interval => false,
+            Durability::Periodic { interval } => {
+                // Too long an interval never comes
+                if let Some(deadline) = self.last_sync.checked_add(interval) {
+                    self.sync_deadline = Some(match self.sync_deadline {
+                        Some(sync_deadline) => sync_deadline.min(deadline),
+                        None => deadline,
+                    });
+                }
+                return self.flush(false);
+            }
+            Durability::NoSync => return self.flush(false),
+        };
+        self.flush(false)?;
+        self.sync_file(sync_metadata)
+    }
+
+    fn sync_file(&mut self, sync_metadata: bool) -> Result<(), io::Error> {
+        let result = if sync_metadata {
+            self.file.sync_all()
+        } else {
+            self.file.sync_data()
+        };
+        if result.is_err() {
+            self.poisoned = true;
+        }
+        result?;
+        self.synced_lsn = self.next_lsn;
+        self.last_sync = Instant::now();
+        self.sync_deadline = None;
+        Ok(())
+    }
+
+    #[inline]
+    fn check_poisoned(&self) -> Result<(), io::Error> {
+        if self.poisoned {
+            Err(io::Error::other("The log is poisoned"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn remove_segments_before(&mut self, lsn: u64) -> Result<(), io::Error> {
+        while self.segment_starts.len() > 1 && self.segment_starts[1] <= lsn {
+            let Some(segment_start) = self.segment_starts.pop_front() else {
+                unreachable!("Checked the segment count above");
+            };
+            self.dir_handle
+                .remove_file(segment_name(&self.base_name, segment_start))?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn segment_start(&self) -> u64 {
+        let Some(segment_start) = self.segment_starts.back() else {
+            unreachable!("The log has at least one segment");
+        };
+        *segment_start
+    }
+
+    fn start_segment(&mut self) -> Result<(), io::Error> {
+        // The finished segment must be durable before any later one
+        self.sync()?;
+        self.file = create_segment(&self.dir_handle, &self.base_name, self.next_lsn)?;
+        self.segment_starts.push_back(self.next_lsn);
+        sync_dir(&self.dir_handle)
+    }
+}
+
+impl Drop for LogWriter {
+    // The buffered records not written by a commit, such as the aborts at open
+    fn drop(&mut self) {
+        let _result = self.flush(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        crc32c, is_torn, parse_record, stricter, GroupLead, Log, LOG_CHUNK_SIZE, NO_TRANSACTION,
+        RECORD_HEADER_SIZE,
+    };
+    use crate::node;
+    use crate::transaction_manager::{self, TransactionChange, TransactionChangeNewNode};
+    use crate::Durability;
+    use cap_std::fs::Dir;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(&[b"123456789"]), 0xE306_9283);
+        assert_eq!(crc32c(&[b"1234", b"", b"56789"]), 0xE306_9283);
+        assert_eq!(crc32c(&[]), 0);
+    }
+
+    fn record(lsn: u64, payload: &[u8]) -> Vec<u8> {
+        let mut result = Vec::new();
+        result.extend_from_slice(&lsn.to_le_bytes());
+        result.extend_from_slice(&7_u64.to_le_bytes());
+        result.extend_from_slice(&u32::try_from(payload.len()).unwrap().to_le_bytes());
+        let crc = crc32c(&[&result, payload]);
+        result.extend_from_slice(&crc.to_le_bytes());
+        result.extend_from_slice(payload);
+        result
+    }
+
+    #[test]
+    fn records() {
+        let bytes = record(100, b"payload");
+        assert_eq!(parse_record(&bytes, 100), Some((7, &b"payload"[..])));
+        assert!(parse_record(&bytes, 101).is_none());
+        for torn_len in [0, 1, RECORD_HEADER_SIZE, bytes.len() - 1] {
+            assert!(parse_record(&bytes[..torn_len], 100).is_none());
+            assert!(is_torn(&bytes[..torn_len]));
+        }
+        let mut corrupted = bytes.clone();
+        corrupted[RECORD_HEADER_SIZE] ^= 1;
+        assert!(parse_record(&corrupted, 100).is_none());
+        assert!(is_torn(&corrupted));
+        // Followed by zeros from the preallocated file space
+        corrupted.resize(corrupted.len() + 100, 0);
+        assert!(is_torn(&corrupted));
+        // But not by another record
+        corrupted.extend(record(131, b""));
+        assert!(!is_torn(&corrupted));
+        assert!(is_torn(&[0; 100]));
+    }
+
+    fn new_log(dir_handle: &Dir) -> Log {
+        Log::open(dir_handle, "LOG", true, 0, 1024).unwrap()
+    }
+
+    #[test]
+    fn sync_for_commit() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir_handle =
+            Dir::open_ambient_dir(temp_dir.path(), cap_std::ambient_authority()).unwrap();
+        let log = new_log(&dir_handle);
+        let mut writer = log.writer.lock().unwrap();
+        let never = Durability::Periodic {
+            interval: Duration::MAX,
+        };
+        let always = Durability::Periodic {
+            interval: Duration::ZERO,
+        };
+        for (durability, syncs) in [
+            (Durability::NoSync, false),
+            (never, false),
+            (Durability::Fsync, true),
+            (Durability::Fdatasync, true),
+            (always, true),
+        ] {
+            let lsn = writer.write(&[(NO_TRANSACTION, b"payload")]).unwrap();
+            writer.sync_for_commit(durability).unwrap();
+            let expected_lsn = if syncs { writer.next_lsn } else { lsn };
+            assert_eq!(writer.synced_lsn, expected_lsn);
+            writer.sync().unwrap();
+            assert_eq!(writer.synced_lsn, writer.next_lsn);
+        }
+        assert_eq!(stricter(Durability::NoSync, never), never);
+        assert_eq!(stricter(always, never), always);
+        assert_eq!(
+            stricter(never, Durability::Fdatasync),
+            Durability::Fdatasync
+        );
+        assert_eq!(
+            stricter(Durability::Fdatasync, Durability::Fsync),
+            Durability::Fsync
+        );
+    }
+
+    #[test]
+    fn periodic_sync() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir_handle =
+            Dir::open_ambient_dir(temp_dir.path(), cap_std::ambient_authority()).unwrap();
+        let log = new_log(&dir_handle);
+        let durability = Durability::Periodic {
+            interval: Duration::from_millis(50),
+        };
+        let ticket = log
+            .enqueue(transaction_manager::Id::from(1), &[], durability)
+            .unwrap();
+        log.wait_written(ticket).unwrap();
+        // Synced in the background with no later commit
+        let start = Instant::now();
+        loop {
+            let writer = log.writer.lock().unwrap();
+            if writer.synced_lsn == writer.next_lsn {
+                assert!(writer.sync_deadline.is_none());
+                break;
+            }
+            drop(writer);
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn log_buffer() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir_handle =
+            Dir::open_ambient_dir(temp_dir.path(), cap_std::ambient_authority()).unwrap();
+        let log = Log::open(&dir_handle, "LOG", true, 0, u64::MAX).unwrap();
+        let file_len = || {
+            dir_handle
+                .metadata("LOG.00000000000000000000")
+                .unwrap()
+                .len()
+        };
+        let mut writer = log.writer.lock().unwrap();
+        let payload = vec![0xAB; 1000];
+        writer.write(&[(NO_TRANSACTION, &payload)]).unwrap();
+        writer.sync_for_commit(Durability::NoSync).unwrap();
+        // Written to the OS, but not synced
+        assert_eq!(file_len(), writer.next_lsn);
+        assert_eq!(writer.synced_lsn, 0);
+        // The records written without a commit are kept until the buffer fills
+        // up
+        let flushed_lsn = writer.flushed_lsn();
+        while writer.flushed_lsn() == flushed_lsn {
+            writer.write(&[(NO_TRANSACTION, &payload)]).unwrap();
+        }
+        assert_eq!(file_len() % LOG_CHUNK_SIZE, 0);
+        assert_eq!(file_len(), writer.flushed_lsn());
+        assert!(writer.next_lsn - writer.flushed_lsn() < LOG_CHUNK_SIZE);
+        writer.sync_for_commit(Durability::Fdatasync).unwrap();
+        assert_eq!(file_len(), writer.next_lsn);
+        // Flushed at close
+        writer.write(&[(NO_TRANSACTION, &payload)]).unwrap();
+        let next_lsn = writer.next_lsn;
+        drop(writer);
+        drop(log);
+        assert_eq!(file_len(), next_lsn);
+    }
+
+    #[test]
+    fn group_commit() {
+        const COMMITS: usize = 8;
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir_handle =
+            Dir::open_ambient_dir(temp_dir.path(), cap_std::ambient_authority()).unwrap();
+        let log = new_log(&dir_handle);
+        // Hold off the commits until all of them are waiting
+        log.hold_commits();
+        thread::scope(|scope| {
+            for i in 0..COMMITS {
+                let log = &log;
+                scope.spawn(move || {
+                    let i = i as u64;
+                    let new_node = TransactionChangeNewNode::new(node::Id::from(i + 1));
+                    let changes = [TransactionChange::NewNode(new_node)];
+                    let id = transaction_manager::Id::from(i);
+                    let ticket = log.enqueue(id, &changes, Durability::Fsync).unwrap();
+                    log.wait_written(ticket).unwrap();
+                });
+            }
+            while log.waiting_commits() < COMMITS {
+                thread::yield_now();
+            }
+            log.release_commits();
+        });
+        assert_eq!(log.commit_groups(), 1);
+        assert_eq!(log.commit_queue.lock().unwrap().done, COMMITS as u64);
+        let writer = log.writer.lock().unwrap();
+        assert_eq!(writer.synced_lsn, writer.next_lsn);
+        drop(writer);
+        drop(log);
+        let mut log = Log::open(&dir_handle, "LOG", false, 0, 1024).unwrap();
+        assert_eq!(log.take_recovered_changes().len(), COMMITS);
+        assert_eq!(log.max_logged_node_id(), node::Id::from(COMMITS as u64));
+    }
+
+    #[test]
+    fn poisoned_by_failed_write() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir_handle =
+            Dir::open_ambient_dir(temp_dir.path(), cap_std::ambient_authority()).unwrap();
+        let log = new_log(&dir_handle);
+        let mut writer = log.writer.lock().unwrap();
+        writer.write(&[(NO_TRANSACTION, b"payload")]).unwrap();
+        writer.sync_for_commit(Durability::Fsync).unwrap();
+        // Neither written nor cut off through a read-only handle
+        writer.file = dir_handle.open("LOG.00000000000000000000").unwrap();
+        writer.write(&[(NO_TRANSACTION, b"payload")]).unwrap();
+        assert!(writer.sync_for_commit(Durability::Fsync).is_err());
+        assert!(writer.poisoned);
+        assert!(writer.write(&[(NO_TRANSACTION, b"payload")]).is_err());
+        assert!(writer.sync().is_err());
+        drop(writer);
+        assert!(log.is_poisoned());
+    }
+
+    #[test]
+    fn panicked_group_lead_fails_group() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir_handle =
+            Dir::open_ambient_dir(temp_dir.path(), cap_std::ambient_authority()).unwrap();
+        let log = new_log(&dir_handle);
+        let new_node = TransactionChangeNewNode::new(node::Id::from(1));
+        let changes = [TransactionChange::NewNode(new_node)];
+        let id = transaction_manager::Id::from(0);
+        let ticket = log.enqueue(id, &changes, Durability::Fsync).unwrap();
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut queue = log.commit_queue.lock().unwrap();
+            queue.is_led = true;
+            let _lead = GroupLead {
+                log: &log,
+                group_end: queue.next_ticket,
+                result: None,
+            };
+            drop(queue);
+            panic!("Writing the group");
+        }));
+        assert!(panicked.is_err());
+        assert!(!log.commit_queue.lock().unwrap().is_led);
+        assert!(log.wait_written(ticket).is_err());
+        assert!(log.is_poisoned());
+    }
 }
@@ -6,12 +6,14 @@ use std::{
     sync::atomic::{AtomicU64, Ordering},
 };
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[must_use]
 pub struct Id(u64);
 
 impl Id {
-    const NULL: Self = Self(0);
+    pub const NULL: Self = Self(0);
+    // The descriptor of the system catalog tree, never allocated nor logged
+    pub const CATALOG: Self = Self(1);
 
     #[inline]
     pub fn next(self) -> Self {
@@ -27,8 +29,14 @@ impl Id {
 
     #[must_use]
     #[inline]
-    pub fn to_ne_bytes(self) -> [u8; 8] {
-        self.0.to_ne_bytes()
+    pub fn to_le_bytes(self) -> [u8; 8] {
+        self.0.to_le_bytes()
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn is_null(self) -> bool {
+        self == Self::NULL
     }
 }
 
@@ -57,10 +65,1326 @@ impl AtomicId {
     }
 
     #[inline]
-    pub fn get_and_advance(&mut self) -> Id {
+    pub fn get(&self) -> Id {
+        Id(self.0.load(Ordering::Relaxed))
+    }
+
+    #[inline]
+    pub fn get_and_advance(&self) -> Id {
         let result_u64 = self.0.fetch_add(1, Ordering::Relaxed);
         let result = Id(result_u64);
         debug_assert_ne!(result, Id::NULL);
         result
     }
 }
+
+// The adaptive radix tree nodes. All the references between the nodes are by
+// their IDs, resolved through the buffer manager, with the inner node children
+// references also swizzled into the frames of the resident children. Leaves
+// hold full keys and are placed as high in the tree as their key is unique
+// (lazy expansion). Inner nodes compress the single-child paths into a prefix,
+// and consume one more key byte to select a child. A key that ends right after
+// the prefix of an inner node has its leaf in the terminal slot of that node,
+// so that keys may be prefixes of other keys.
+#[derive(Clone, Debug)] // COV_EXCL_LINE
+#[must_use]
+pub enum Node {
+    ArtDescriptor(ArtDescriptor),
+    Leaf(Leaf),
+    Inner(Inner),
+}
+
+impl Node {
+    const ART_DESCRIPTOR_TAG: u8 = 0;
+    const LEAF_TAG: u8 = 1;
+    const INNER_TAG: u8 = 2;
+    const VERSIONED_LEAF_TAG: u8 = 3;
+    const DROPPED_ART_DESCRIPTOR_TAG: u8 = 4;
+    const DELETED_VALUE_LEN: u64 = u64::MAX;
+
+    #[must_use]
+    #[inline]
+    pub fn as_art_descriptor(&self) -> Option<&ArtDescriptor> {
+        match self {
+            Self::ArtDescriptor(descriptor) => Some(descriptor),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn as_art_descriptor_mut(&mut self) -> Option<&mut ArtDescriptor> {
+        match self {
+            Self::ArtDescriptor(descriptor) => Some(descriptor),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn as_leaf(&self) -> Option<&Leaf> {
+        match self {
+            Self::Leaf(leaf) => Some(leaf),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn as_leaf_mut(&mut self) -> Option<&mut Leaf> {
+        match self {
+            Self::Leaf(leaf) => Some(leaf),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn as_inner(&self) -> Option<&Inner> {
+        match self {
+            Self::Inner(inner) => Some(inner),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn as_inner_mut(&mut self) -> Option<&mut Inner> {
+        match self {
+            Self::Inner(inner) => Some(inner),
+            _ => None,
+        }
+    }
+
+    // The type tag, followed by: the root, and the commit timestamp if
+    // dropped, for a descriptor; the key and the
+    // value lengths and bytes for a leaf; the key, the commit timestamp, the
+    // value, and the count and (commit timestamp, value) pairs of the older
+    // versions for a leaf with any, or deleted, where the value length of a
+    // deleted key is u64::MAX; the prefix length and bytes, the terminal leaf,
+    // and the children count and (key byte, child) pairs for an inner node.
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        match self {
+            Self::ArtDescriptor(descriptor) => match descriptor.dropped_ts() {
+                None => {
+                    result.push(Self::ART_DESCRIPTOR_TAG);
+                    result.extend_from_slice(&descriptor.root().to_le_bytes());
+                }
+                Some(dropped_ts) => {
+                    result.push(Self::DROPPED_ART_DESCRIPTOR_TAG);
+                    result.extend_from_slice(&descriptor.root().to_le_bytes());
+                    result.extend_from_slice(&dropped_ts.to_le_bytes());
+                }
+            },
+            Self::Leaf(leaf) => match leaf.value() {
+                Some(value) if leaf.commit_ts() == 0 => {
+                    result.push(Self::LEAF_TAG);
+                    for bytes in [leaf.key(), value] {
+                        result.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+                        result.extend_from_slice(bytes);
+                    }
+                }
+                value => {
+                    result.push(Self::VERSIONED_LEAF_TAG);
+                    result.extend_from_slice(&(leaf.key().len() as u64).to_le_bytes());
+                    result.extend_from_slice(leaf.key());
+                    Self::serialize_version(&mut result, leaf.commit_ts(), value);
+                    result.extend_from_slice(&(leaf.versions.len() as u64).to_le_bytes());
+                    for version in &leaf.versions {
+                        Self::serialize_version(
+                            &mut result,
+                            version.commit_ts,
+                            version.value.as_deref(),
+                        );
+                    }
+                }
+            },
+            Self::Inner(inner) => {
+                result.push(Self::INNER_TAG);
+                result.extend_from_slice(&inner.prefix_len.to_le_bytes());
+                // Only the stored part of the prefix is meaningful
+                let mut prefix = [0; Inner::MAX_STORED_PREFIX_LEN];
+                let stored_prefix = inner.stored_prefix();
+                prefix[..stored_prefix.len()].copy_from_slice(stored_prefix);
+                result.extend_from_slice(&prefix);
+                result.extend_from_slice(&inner.terminal_leaf().to_le_bytes());
+                result.extend_from_slice(
+                    &u16::try_from(inner.len()).unwrap_or(u16::MAX).to_le_bytes(),
+                );
+                let mut child = inner.first_child();
+                while let Some((key_byte, child_id)) = child {
+                    result.push(key_byte);
+                    result.extend_from_slice(&child_id.to_le_bytes());
+                    child = inner.child_after(key_byte);
+                }
+            }
+        }
+        result
+    }
+
+    // Returns None if the bytes are not a serialized node
+    pub fn deserialize(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader(bytes);
+        let result = match reader.u8()? {
+            Self::ART_DESCRIPTOR_TAG => {
+                let mut descriptor = ArtDescriptor::new();
+                descriptor.set_root(reader.id()?);
+                Self::ArtDescriptor(descriptor)
+            }
+            Self::DROPPED_ART_DESCRIPTOR_TAG => {
+                let mut descriptor = ArtDescriptor::new();
+                descriptor.set_root(reader.id()?);
+                descriptor.set_dropped(reader.u64()?);
+                Self::ArtDescriptor(descriptor)
+            }
+            Self::LEAF_TAG => {
+                let key_len = usize::try_from(reader.u64()?).ok()?;
+                let key = reader.bytes(key_len)?;
+                let value_len = usize::try_from(reader.u64()?).ok()?;
+                let value = reader.bytes(value_len)?;
+                Self::Leaf(Leaf::new(key, value))
+            }
+            Self::VERSIONED_LEAF_TAG => {
+                let key_len = usize::try_from(reader.u64()?).ok()?;
+                let key = reader.bytes(key_len)?.to_vec();
+                let Version { commit_ts, value } = Self::deserialize_version(&mut reader)?;
+                let versions_len = reader.u64()?;
+                // Do not trust the count to preallocate
+                let mut versions = Vec::new();
+                for _ in 0..versions_len {
+                    versions.push(Self::deserialize_version(&mut reader)?);
+                }
+                Self::Leaf(Leaf {
+                    key,
+                    value,
+                    commit_ts,
+                    versions,
+                })
+            }
+            Self::INNER_TAG => {
+                let prefix_len = u32::from_le_bytes(*reader.array()?);
+                let prefix = reader.array::<{ Inner::MAX_STORED_PREFIX_LEN }>()?;
+                let mut inner = Inner::new();
+                inner.set_prefix(prefix, usize::try_from(prefix_len).ok()?);
+                inner.set_terminal_leaf(reader.id()?);
+                let children_len = u16::from_le_bytes(*reader.array()?);
+                for _ in 0..children_len {
+                    let key_byte = reader.u8()?;
+                    let child = reader.id()?;
+                    if inner.find_child(key_byte).is_some() {
+                        return None;
+                    }
+                    inner.add_child(key_byte, child);
+                }
+                Self::Inner(inner)
+            }
+            _ => return None,
+        };
+        reader.0.is_empty().then_some(result)
+    }
+
+    fn serialize_version(result: &mut Vec<u8>, commit_ts: u64, value: Option<&[u8]>) {
+        result.extend_from_slice(&commit_ts.to_le_bytes());
+        match value {
+            Some(value) => {
+                result.extend_from_slice(&(value.len() as u64).to_le_bytes());
+                result.extend_from_slice(value);
+            }
+            None => result.extend_from_slice(&Self::DELETED_VALUE_LEN.to_le_bytes()),
+        }
+    }
+
+    fn deserialize_version(reader: &mut Reader) -> Option<Version> {
+        let commit_ts = reader.u64()?;
+        let value = match reader.u64()? {
+            Self::DELETED_VALUE_LEN => None,
+            value_len => Some(reader.bytes(usize::try_from(value_len).ok()?)?.to_vec()),
+        };
+        Some(Version { commit_ts, value })
+    }
+
+    // The approximate memory taken by the node, including its heap data
+    #[must_use]
+    pub fn memory_size(&self) -> usize {
+        let heap_size = match self {
+            Self::ArtDescriptor(_) => 0,
+            Self::Leaf(leaf) => {
+                let value_size = |value: &Option<Vec<u8>>| value.as_ref().map_or(0, Vec::capacity);
+                leaf.key.capacity()
+                    + value_size(&leaf.value)
+                    + leaf.versions.capacity() * size_of::<Version>()
+                    + leaf
+                        .versions
+                        .iter()
+                        .map(|version| value_size(&version.value))
+                        .sum::<usize>()
+            }
+            Self::Inner(inner) => match &inner.children {
+                Children::Node4(_) | Children::Node16(_) => 0,
+                Children::Node48(_) => size_of::<Node48>(),
+                Children::Node256(_) => size_of::<Node256>(),
+            },
+        };
+        size_of::<Self>() + heap_size
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (result, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(result)
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<&'a [u8; N]> {
+        self.bytes(N)?.try_into().ok()
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.array::<1>()?[0])
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(*self.array()?))
+    }
+
+    fn id(&mut self) -> Option<Id> {
+        Some(Id::from(self.u64()?))
+    }
+}
+
+#[derive(Clone, Debug)] // COV_EXCL_LINE
+#[must_use]
+pub struct ArtDescriptor {
+    root: Id,
+    // The commit timestamp of the drop of the tree, which is destroyed once no
+    // snapshot before it is left
+    dropped_ts: Option<u64>,
+}
+
+impl ArtDescriptor {
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            root: Id::NULL,
+            dropped_ts: None,
+        }
+    }
+
+    #[inline]
+    pub fn root(&self) -> Id {
+        self.root
+    }
+
+    #[inline]
+    pub fn set_root(&mut self, root: Id) {
+        self.root = root;
+    }
+
+    #[inline]
+    pub fn dropped_ts(&self) -> Option<u64> {
+        self.dropped_ts
+    }
+
+    #[inline]
+    pub fn set_dropped(&mut self, commit_ts: u64) {
+        self.dropped_ts = Some(commit_ts);
+    }
+
+    // Whether the snapshot sees the tree
+    #[inline]
+    pub fn is_visible_at(&self, snapshot_ts: u64) -> bool {
+        match self.dropped_ts {
+            Some(dropped_ts) => dropped_ts > snapshot_ts,
+            None => true,
+        }
+    }
+}
+
+impl Default for ArtDescriptor {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// An older value of a leaf key, None if the key was deleted or absent
+#[derive(Clone, Debug)] // COV_EXCL_LINE
+struct Version {
+    commit_ts: u64,
+    value: Option<Vec<u8>>,
+}
+
+// A leaf keeps the versions of its value that some snapshot may still read,
+// and a timestamp of zero marks the value seen by all the snapshots. A deleted
+// key stays in the tree until no snapshot sees it. The versions are written
+// out with the leaf, but the commit timestamps only order the commits since
+// open, and the versioned leaves are purged at the next open.
+#[derive(Clone, Debug)] // COV_EXCL_LINE
+#[must_use]
+pub struct Leaf {
+    key: Vec<u8>,
+    value: Option<Vec<u8>>,
+    commit_ts: u64,
+    // Newest first, all older than commit_ts
+    versions: Vec<Version>,
+}
+
+impl Leaf {
+    #[inline]
+    pub fn new(key: &[u8], value: &[u8]) -> Self {
+        Self {
+            key: key.to_vec(),
+            value: Some(value.to_vec()),
+            commit_ts: 0,
+            versions: Vec::new(),
+        }
+    }
+
+    // A key inserted by the commit at the timestamp, absent for the older
+    // snapshots
+    #[inline]
+    pub fn new_at(key: &[u8], value: &[u8], commit_ts: u64) -> Self {
+        let mut result = Self::new(key, value);
+        if commit_ts != 0 {
+            result.commit_ts = commit_ts;
+            result.versions.push(Version {
+                commit_ts: 0,
+                value: None,
+            });
+        }
+        result
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    // The newest value, None if the key has been deleted
+    #[must_use]
+    #[inline]
+    pub fn value(&self) -> Option<&[u8]> {
+        self.value.as_deref()
+    }
+
+    // The value seen by the snapshot at the timestamp
+    #[must_use]
+    pub fn value_at(&self, snapshot_ts: u64) -> Option<&[u8]> {
+        if self.commit_ts <= snapshot_ts {
+            return self.value();
+        }
+        self.versions
+            .iter()
+            .find(|version| version.commit_ts <= snapshot_ts)
+            .and_then(|version| version.value.as_deref())
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn commit_ts(&self) -> u64 {
+        self.commit_ts
+    }
+
+    #[inline]
+    pub fn set_value(&mut self, value: &[u8]) {
+        self.update(Some(value), 0);
+    }
+
+    // Sets the value committed at the timestamp, keeping the current one for
+    // the older snapshots unless the timestamp is zero or the same commit set it
+    pub fn update(&mut self, value: Option<&[u8]>, commit_ts: u64) {
+        let old_value = std::mem::replace(&mut self.value, value.map(<[u8]>::to_vec));
+        if commit_ts == 0 {
+            self.versions.clear();
+        } else if commit_ts != self.commit_ts {
+            debug_assert!(commit_ts > self.commit_ts);
+            self.versions.insert(
+                0,
+                Version {
+                    commit_ts: self.commit_ts,
+                    value: old_value,
+                },
+            );
+        }
+        self.commit_ts = commit_ts;
+    }
+
+    // Drops the versions that no snapshot at or after the timestamp reads.
+    // Returns whether the key is deleted for all such snapshots.
+    pub fn purge(&mut self, oldest_snapshot_ts: u64) -> bool {
+        if self.commit_ts <= oldest_snapshot_ts {
+            self.commit_ts = 0;
+            self.versions.clear();
+            return self.value.is_none();
+        }
+        if let Some(oldest_read) = self
+            .versions
+            .iter()
+            .position(|version| version.commit_ts <= oldest_snapshot_ts)
+        {
+            self.versions.truncate(oldest_read + 1);
+        }
+        false
+    }
+}
+
+// A reference to a child node: its ID and, while the child is in memory and
+// has been reached through this reference, the buffer manager frame holding it
+// (pointer swizzling). It is a tagged word: with the top bit clear it is the
+// node ID, otherwise it packs the frame above the lower ID bits. The frame is
+// set through shared references, and is never persisted.
+#[derive(Debug)] // COV_EXCL_LINE
+#[must_use]
+pub struct Swip(AtomicU64);
+
+impl Clone for Swip {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self(AtomicU64::new(self.0.load(Ordering::Relaxed)))
+    }
+}
+
+impl Swip {
+    const SWIZZLED_TAG: u64 = 1 << 63;
+    const ID_BITS: u32 = 40;
+    const ID_MASK: u64 = (1 << Self::ID_BITS) - 1;
+    const FRAME_MASK: u64 = !Self::SWIZZLED_TAG >> Self::ID_BITS;
+
+    #[inline]
+    pub const fn new(id: Id) -> Self {
+        Self(AtomicU64::new(id.0))
+    }
+
+    #[inline]
+    pub fn id(&self) -> Id {
+        let swip = self.0.load(Ordering::Relaxed);
+        if swip & Self::SWIZZLED_TAG == 0 {
+            Id(swip)
+        } else {
+            Id(swip & Self::ID_MASK)
+        }
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn frame(&self) -> Option<usize> {
+        let swip = self.0.load(Ordering::Relaxed);
+        if swip & Self::SWIZZLED_TAG == 0 {
+            return None;
+        }
+        usize::try_from((swip >> Self::ID_BITS) & Self::FRAME_MASK).ok()
+    }
+
+    // The IDs and the frames beyond the packed range are left unswizzled
+    #[inline]
+    pub fn swizzle(&self, frame: usize) {
+        let id = self.id().0;
+        let Ok(frame) = u64::try_from(frame) else {
+            return;
+        };
+        if id <= Self::ID_MASK && frame <= Self::FRAME_MASK {
+            self.0.store(
+                Self::SWIZZLED_TAG | (frame << Self::ID_BITS) | id,
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    #[inline]
+    pub fn unswizzle(&self) {
+        self.0.store(self.id().0, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Debug)] // COV_EXCL_LINE
+#[must_use]
+pub struct Node4 {
+    len: u8,
+    keys: [u8; 4],
+    children: [Swip; 4],
+}
+
+#[derive(Clone, Debug)] // COV_EXCL_LINE
+#[must_use]
+pub struct Node16 {
+    len: u8,
+    keys: [u8; 16],
+    children: [Swip; 16],
+}
+
+#[derive(Clone, Debug)] // COV_EXCL_LINE
+#[must_use]
+pub struct Node48 {
+    len: u8,
+    child_index: [u8; 256],
+    children: [Swip; 48],
+}
+
+#[derive(Clone, Debug)] // COV_EXCL_LINE
+#[must_use]
+pub struct Node256 {
+    len: u16,
+    children: [Swip; 256],
+}
+
+// Node4 and Node16 keep their keys sorted, thus share the implementation.
+macro_rules! impl_sorted_keys_node {
+    ($node:ident, $capacity:literal) => {
+        impl $node {
+            const CAPACITY: usize = $capacity;
+
+            #[inline]
+            fn new() -> Self {
+                Self {
+                    len: 0,
+                    keys: [0; $capacity],
+                    children: [const { Swip::new(Id::NULL) }; $capacity],
+                }
+            }
+
+            #[inline]
+            fn len(&self) -> usize {
+                usize::from(self.len)
+            }
+
+            #[inline]
+            fn position(&self, key_byte: u8) -> Result<usize, usize> {
+                self.keys[..self.len()].binary_search(&key_byte)
+            }
+
+            #[inline]
+            fn child_swip(&self, key_byte: u8) -> Option<&Swip> {
+                self.position(key_byte).ok().map(|i| &self.children[i])
+            }
+
+            #[inline]
+            fn find_child(&self, key_byte: u8) -> Option<Id> {
+                self.child_swip(key_byte).map(Swip::id)
+            }
+
+            fn add_child(&mut self, key_byte: u8, child: Id) {
+                debug_assert!(self.len() < Self::CAPACITY);
+                let Err(i) = self.position(key_byte) else {
+                    unreachable!("Adding an existing child {key_byte}");
+                };
+                let len = self.len();
+                self.keys.copy_within(i..len, i + 1);
+                self.children[i..=len].rotate_right(1);
+                self.keys[i] = key_byte;
+                self.children[i] = Swip::new(child);
+                self.len += 1;
+            }
+
+            fn remove_child(&mut self, key_byte: u8) -> Id {
+                let Ok(i) = self.position(key_byte) else {
+                    unreachable!("Removing a non-existing child {key_byte}");
+                };
+                let result = self.children[i].id();
+                let len = self.len();
+                self.keys.copy_within(i + 1..len, i);
+                self.children[i..len].rotate_left(1);
+                self.len -= 1;
+                self.children[self.len()] = Swip::new(Id::NULL);
+                result
+            }
+
+            fn replace_child(&mut self, key_byte: u8, child: Id) {
+                let Ok(i) = self.position(key_byte) else {
+                    unreachable!("Replacing a non-existing child {key_byte}");
+                };
+                self.children[i] = Swip::new(child);
+            }
+
+            fn child_at_or_after(&self, key_byte: u8) -> Option<(u8, Id)> {
+                let i = self.position(key_byte).unwrap_or_else(|i| i);
+                (i < self.len()).then(|| (self.keys[i], self.children[i].id()))
+            }
+
+            fn child_at_or_before(&self, key_byte: u8) -> Option<(u8, Id)> {
+                match self.position(key_byte) {
+                    Ok(i) => Some((self.keys[i], self.children[i].id())),
+                    Err(0) => None,
+                    Err(i) => Some((self.keys[i - 1], self.children[i - 1].id())),
+                }
+            }
+        }
+    };
+}
+
+impl_sorted_keys_node!(Node4, 4);
+impl_sorted_keys_node!(Node16, 16);
+
+impl Node4 {
+    fn grow(&self) -> Node16 {
+        let mut result = Node16::new();
+        let len = self.len();
+        result.keys[..len].copy_from_slice(&self.keys[..len]);
+        result.children[..len].clone_from_slice(&self.children[..len]);
+        result.len = self.len;
+        result
+    }
+}
+
+impl Node16 {
+    const SHRINK_THRESHOLD: usize = 3;
+
+    fn grow(&self) -> Node48 {
+        let mut result = Node48::new();
+        for i in 0..self.len() {
+            result.add_child(self.keys[i], self.children[i].id());
+        }
+        result
+    }
+
+    fn shrink(&self) -> Node4 {
+        debug_assert!(self.len() <= Node4::CAPACITY);
+        let mut result = Node4::new();
+        let len = self.len();
+        result.keys[..len].copy_from_slice(&self.keys[..len]);
+        result.children[..len].clone_from_slice(&self.children[..len]);
+        result.len = self.len;
+        result
+    }
+}
+
+impl Node48 {
+    const CAPACITY: usize = 48;
+    const SHRINK_THRESHOLD: usize = 12;
+    const EMPTY: u8 = u8::MAX;
+
+    #[inline]
+    fn new() -> Self {
+        Self {
+            len: 0,
+            child_index: [Self::EMPTY; 256],
+            children: [const { Swip::new(Id::NULL) }; 48],
+        }
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        usize::from(self.len)
+    }
+
+    #[inline]
+    fn child_swip(&self, key_byte: u8) -> Option<&Swip> {
+        let i = self.child_index[usize::from(key_byte)];
+        (i != Self::EMPTY).then(|| &self.children[usize::from(i)])
+    }
+
+    #[inline]
+    fn find_child(&self, key_byte: u8) -> Option<Id> {
+        self.child_swip(key_byte).map(Swip::id)
+    }
+
+    fn add_child(&mut self, key_byte: u8, child: Id) {
+        debug_assert!(self.len() < Self::CAPACITY);
+        debug_assert_eq!(self.child_index[usize::from(key_byte)], Self::EMPTY);
+        // Removals may leave holes in the children array.
+        let free_slot = self.children.iter().position(|c| c.id().is_null());
+        let Some(free_slot) = free_slot else {
+            unreachable!("No free slot in a non-full Node48");
+        };
+        self.children[free_slot] = Swip::new(child);
+        self.child_index[usize::from(key_byte)] = u8::try_from(free_slot).unwrap_or(Self::EMPTY);
+        self.len += 1;
+    }
+
+    fn remove_child(&mut self, key_byte: u8) -> Id {
+        let i = self.child_index[usize::from(key_byte)];
+        debug_assert_ne!(i, Self::EMPTY);
+        let result = self.children[usize::from(i)].id();
+        self.children[usize::from(i)] = Swip::new(Id::NULL);
+        self.child_index[usize::from(key_byte)] = Self::EMPTY;
+        self.len -= 1;
+        result
+    }
+
+    fn replace_child(&mut self, key_byte: u8, child: Id) {
+        let i = self.child_index[usize::from(key_byte)];
+        debug_assert_ne!(i, Self::EMPTY);
+        self.children[usize::from(i)] = Swip::new(child);
+    }
+
+    fn child_at_or_after(&self, key_byte: u8) -> Option<(u8, Id)> {
+        (key_byte..=u8::MAX).find_map(|b| self.find_child(b).map(|c| (b, c)))
+    }
+
+    fn child_at_or_before(&self, key_byte: u8) -> Option<(u8, Id)> {
+        (0..=key_byte)
+            .rev()
+            .find_map(|b| self.find_child(b).map(|c| (b, c)))
+    }
+
+    fn grow(&self) -> Node256 {
+        let mut result = Node256::new();
+        for b in 0..=u8::MAX {
+            if let Some(child) = self.find_child(b) {
+                result.add_child(b, child);
+            }
+        }
+        result
+    }
+
+    fn shrink(&self) -> Node16 {
+        debug_assert!(self.len() <= Node16::CAPACITY);
+        let mut result = Node16::new();
+        for b in 0..=u8::MAX {
+            if let Some(child) = self.find_child(b) {
+                result.add_child(b, child);
+            }
+        }
+        result
+    }
+}
+
+impl Node256 {
+    const SHRINK_THRESHOLD: usize = 37;
+
+    #[inline]
+    fn new() -> Self {
+        Self {
+            len: 0,
+            children: [const { Swip::new(Id::NULL) }; 256],
+        }
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        usize::from(self.len)
+    }
+
+    #[inline]
+    fn child_swip(&self, key_byte: u8) -> Option<&Swip> {
+        let child = &self.children[usize::from(key_byte)];
+        (!child.id().is_null()).then_some(child)
+    }
+
+    #[inline]
+    fn find_child(&self, key_byte: u8) -> Option<Id> {
+        self.child_swip(key_byte).map(Swip::id)
+    }
+
+    fn add_child(&mut self, key_byte: u8, child: Id) {
+        debug_assert!(self.children[usize::from(key_byte)].id().is_null());
+        self.children[usize::from(key_byte)] = Swip::new(child);
+        self.len += 1;
+    }
+
+    fn remove_child(&mut self, key_byte: u8) -> Id {
+        let result = self.children[usize::from(key_byte)].id();
+        debug_assert!(!result.is_null());
+        self.children[usize::from(key_byte)] = Swip::new(Id::NULL);
+        self.len -= 1;
+        result
+    }
+
+    fn replace_child(&mut self, key_byte: u8, child: Id) {
+        debug_assert!(!self.children[usize::from(key_byte)].id().is_null());
+        self.children[usize::from(key_byte)] = Swip::new(child);
+    }
+
+    fn child_at_or_after(&self, key_byte: u8) -> Option<(u8, Id)> {
+        (key_byte..=u8::MAX).find_map(|b| self.find_child(b).map(|c| (b, c)))
+    }
+
+    fn child_at_or_before(&self, key_byte: u8) -> Option<(u8, Id)> {
+        (0..=key_byte)
+            .rev()
+            .find_map(|b| self.find_child(b).map(|c| (b, c)))
+    }
+
+    fn shrink(&self) -> Node48 {
+        debug_assert!(self.len() <= Node48::CAPACITY);
+        let mut result = Node48::new();
+        for b in 0..=u8::MAX {
+            if let Some(child) = self.find_child(b) {
+                result.add_child(b, child);
+            }
+        }
+        result
+    }
+}
+
+#[derive(Clone, Debug)] // COV_EXCL_LINE
+#[must_use]
+pub enum Children {
+    Node4(Node4),
+    Node16(Node16),
+    Node48(Box<Node48>),
+    Node256(Box<Node256>),
+}
+
+#[derive(Clone, Debug)] // COV_EXCL_LINE
+#[must_use]
+pub struct Inner {
+    // The full length of the compressed path, of which only the first
+    // MAX_STORED_PREFIX_LEN bytes are stored. The rest must be taken from any
+    // leaf under this node (the hybrid of pessimistic and optimistic path
+    // compression).
+    prefix_len: u32,
+    prefix: [u8; Inner::MAX_STORED_PREFIX_LEN],
+    terminal_leaf: Id,
+    children: Children,
+}
+
+impl Inner {
+    pub const MAX_STORED_PREFIX_LEN: usize = 8;
+
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            prefix_len: 0,
+            prefix: [0; Self::MAX_STORED_PREFIX_LEN],
+            terminal_leaf: Id::NULL,
+            children: Children::Node4(Node4::new()),
+        }
+    }
+
+    pub fn with_prefix(prefix: &[u8]) -> Self {
+        let mut result = Self::new();
+        result.set_prefix(prefix, prefix.len());
+        result
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn prefix_len(&self) -> usize {
+        self.prefix_len as usize
+    }
+
+    // The stored part of the prefix, which is the whole prefix if it is short
+    // enough.
+    #[must_use]
+    #[inline]
+    pub fn stored_prefix(&self) -> &[u8] {
+        &self.prefix[..self.prefix_len().min(Self::MAX_STORED_PREFIX_LEN)]
+    }
+
+    // Only the bytes that fit are taken from `prefix`, which may be shorter
+    // than `len` if the rest is not known.
+    pub fn set_prefix(&mut self, prefix: &[u8], len: usize) {
+        let stored_len = len.min(Self::MAX_STORED_PREFIX_LEN);
+        debug_assert!(prefix.len() >= stored_len);
+        self.prefix[..stored_len].copy_from_slice(&prefix[..stored_len]);
+        self.prefix_len = u32::try_from(len).unwrap_or(u32::MAX);
+        debug_assert_eq!(self.prefix_len(), len);
+    }
+
+    #[inline]
+    pub fn children(&self) -> &Children {
+        &self.children
+    }
+
+    #[inline]
+    pub fn terminal_leaf(&self) -> Id {
+        self.terminal_leaf
+    }
+
+    #[inline]
+    pub fn set_terminal_leaf(&mut self, leaf: Id) {
+        self.terminal_leaf = leaf;
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn len(&self) -> usize {
+        match &self.children {
+            Children::Node4(n) => n.len(),
+            Children::Node16(n) => n.len(),
+            Children::Node48(n) => n.len(),
+            Children::Node256(n) => n.len(),
+        }
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0 && self.terminal_leaf.is_null()
+    }
+
+    #[inline]
+    pub fn find_child(&self, key_byte: u8) -> Option<Id> {
+        match &self.children {
+            Children::Node4(n) => n.find_child(key_byte),
+            Children::Node16(n) => n.find_child(key_byte),
+            Children::Node48(n) => n.find_child(key_byte),
+            Children::Node256(n) => n.find_child(key_byte),
+        }
+    }
+
+    #[inline]
+    pub fn child_swip(&self, key_byte: u8) -> Option<&Swip> {
+        match &self.children {
+            Children::Node4(n) => n.child_swip(key_byte),
+            Children::Node16(n) => n.child_swip(key_byte),
+            Children::Node48(n) => n.child_swip(key_byte),
+            Children::Node256(n) => n.child_swip(key_byte),
+        }
+    }
+
+    // Grows the node to the next size class first if needed.
+    pub fn add_child(&mut self, key_byte: u8, child: Id) {
+        debug_assert!(!child.is_null());
+        self.grow_if_full();
+        match &mut self.children {
+            Children::Node4(n) => n.add_child(key_byte, child),
+            Children::Node16(n) => n.add_child(key_byte, child),
+            Children::Node48(n) => n.add_child(key_byte, child),
+            Children::Node256(n) => n.add_child(key_byte, child),
+        }
+    }
+
+    // Shrinks the node to the previous size class if it becomes underfull.
+    pub fn remove_child(&mut self, key_byte: u8) -> Id {
+        let result = match &mut self.children {
+            Children::Node4(n) => n.remove_child(key_byte),
+            Children::Node16(n) => n.remove_child(key_byte),
+            Children::Node48(n) => n.remove_child(key_byte),
+            Children::Node256(n) => n.remove_child(key_byte),
+        };
+        self.shrink_if_underfull();
+        result
+    }
+
+    pub fn replace_child(&mut self, key_byte: u8, child: Id) {
+        debug_assert!(!child.is_null());
+        match &mut self.children {
+            Children::Node4(n) => n.replace_child(key_byte, child),
+            Children::Node16(n) => n.replace_child(key_byte, child),
+            Children::Node48(n) => n.replace_child(key_byte, child),
+            Children::Node256(n) => n.replace_child(key_byte, child),
+        }
+    }
+
+    #[must_use]
+    pub fn child_at_or_after(&self, key_byte: u8) -> Option<(u8, Id)> {
+        match &self.children {
+            Children::Node4(n) => n.child_at_or_after(key_byte),
+            Children::Node16(n) => n.child_at_or_after(key_byte),
+            Children::Node48(n) => n.child_at_or_after(key_byte),
+            Children::Node256(n) => n.child_at_or_after(key_byte),
+        }
+    }
+
+    #[must_use]
+    pub fn child_at_or_before(&self, key_byte: u8) -> Option<(u8, Id)> {
+        match &self.children {
+            Children::Node4(n) => n.child_at_or_before(key_byte),
+            Children::Node16(n) => n.child_at_or_before(key_byte),
+            Children::Node48(n) => n.child_at_or_before(key_byte),
+            Children::Node256(n) => n.child_at_or_before(key_byte),
+        }
+    }
+
+    #[inline]
+    pub fn first_child(&self) -> Option<(u8, Id)> {
+        self.child_at_or_after(0)
+    }
+
+    #[inline]
+    pub fn last_child(&self) -> Option<(u8, Id)> {
+        self.child_at_or_before(u8::MAX)
+    }
+
+    pub fn child_after(&self, key_byte: u8) -> Option<(u8, Id)> {
+        key_byte
+            .checked_add(1)
+            .and_then(|b| self.child_at_or_after(b))
+    }
+
+    pub fn child_before(&self, key_byte: u8) -> Option<(u8, Id)> {
+        key_byte
+            .checked_sub(1)
+            .and_then(|b| self.child_at_or_before(b))
+    }
+
+    fn grow_if_full(&mut self) {
+        let grown = match &self.children {
+            Children::Node4(n) if n.len() == Node4::CAPACITY => Children::Node16(n.grow()),
+            Children::Node16(n) if n.len() == Node16::CAPACITY => {
+                Children::Node48(Box::new(n.grow()))
+            }
+            Children::Node48(n) if n.len() == Node48::CAPACITY => {
+                Children::Node256(Box::new(n.grow()))
+            }
+            _ => return,
+        };
+        self.children = grown;
+    }
+
+    fn shrink_if_underfull(&mut self) {
+        let shrunk = match &self.children {
+            Children::Node16(n) if n.len() == Node16::SHRINK_THRESHOLD => {
+                Children::Node4(n.shrink())
+            }
+            Children::Node48(n) if n.len() == Node48::SHRINK_THRESHOLD => {
+                Children::Node16(n.shrink())
+            }
+            Children::Node256(n) if n.len() == Node256::SHRINK_THRESHOLD => {
+                Children::Node48(Box::new(n.shrink()))
+            }
+            _ => return,
+        };
+        self.children = shrunk;
+    }
+}
+
+impl Default for Inner {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ArtDescriptor, Children, Id, Inner, Leaf, Node, Swip};
+
+    fn id(i: usize) -> Id {
+        Id::from(u64::try_from(i).unwrap() + 1)
+    }
+
+    fn key_byte(i: usize) -> u8 {
+        // Spread the keys and insert them out of order
+        u8::try_from((i * 7) % 256).unwrap()
+    }
+
+    fn assert_children_in_order(node: &Inner, expected_len: usize) {
+        assert_eq!(node.len(), expected_len);
+        let mut count = 0;
+        let mut prev = None;
+        let mut next = node.first_child();
+        while let Some((b, child)) = next {
+            assert!(prev.is_none_or(|p| p < b));
+            assert_eq!(node.find_child(b), Some(child));
+            prev = Some(b);
+            count += 1;
+            next = node.child_after(b);
+        }
+        assert_eq!(count, expected_len);
+    }
+
+    #[test]
+    fn grow_through_all_sizes() {
+        let mut node = Inner::new();
+        for i in 0..256 {
+            node.add_child(key_byte(i), id(i));
+            let expected_kind = match i + 1 {
+                0..=4 => matches!(node.children(), Children::Node4(_)),
+                5..=16 => matches!(node.children(), Children::Node16(_)),
+                17..=48 => matches!(node.children(), Children::Node48(_)),
+                _ => matches!(node.children(), Children::Node256(_)),
+            };
+            assert!(expected_kind);
+            assert_children_in_order(&node, i + 1);
+        }
+        for i in 0..256 {
+            assert_eq!(node.find_child(key_byte(i)), Some(id(i)));
+        }
+    }
+
+    #[test]
+    fn shrink_through_all_sizes() {
+        let mut node = Inner::new();
+        for i in 0..256 {
+            node.add_child(key_byte(i), id(i));
+        }
+        for i in 0..256 {
+            assert_eq!(node.remove_child(key_byte(i)), id(i));
+            assert_eq!(node.find_child(key_byte(i)), None);
+            assert_children_in_order(&node, 255 - i);
+        }
+        assert!(matches!(node.children(), Children::Node4(_)));
+        assert!(node.is_empty());
+    }
+
+    #[test]
+    fn node48_reuses_freed_slots() {
+        let mut node = Inner::new();
+        for i in 0..48 {
+            node.add_child(key_byte(i), id(i));
+        }
+        assert_eq!(node.remove_child(key_byte(10)), id(10));
+        node.add_child(key_byte(100), id(100));
+        assert!(matches!(node.children(), Children::Node48(_)));
+        assert_eq!(node.find_child(key_byte(100)), Some(id(100)));
+        assert_children_in_order(&node, 48);
+    }
+
+    #[test]
+    fn prefix() {
+        let mut node = Inner::with_prefix(b"abc");
+        assert_eq!(node.prefix_len(), 3);
+        assert_eq!(node.stored_prefix(), b"abc");
+        node.set_prefix(b"0123456789", 10);
+        assert_eq!(node.prefix_len(), 10);
+        assert_eq!(node.stored_prefix(), b"01234567");
+        node.set_prefix(b"01234567", 12);
+        assert_eq!(node.prefix_len(), 12);
+        assert_eq!(node.stored_prefix(), b"01234567");
+        node.set_prefix(b"", 0);
+        assert!(node.stored_prefix().is_empty());
+    }
+
+    #[test]
+    fn replace_child() {
+        let mut node = Inner::new();
+        node.add_child(1, id(1));
+        node.add_child(2, id(2));
+        node.replace_child(2, id(3));
+        assert_eq!(node.find_child(2), Some(id(3)));
+    }
+
+    #[test]
+    fn neighbor_children() {
+        let mut node = Inner::new();
+        node.add_child(10, id(1));
+        node.add_child(20, id(2));
+        assert_eq!(node.child_at_or_after(11), Some((20, id(2))));
+        assert_eq!(node.child_at_or_after(21), None);
+        assert_eq!(node.child_at_or_before(19), Some((10, id(1))));
+        assert_eq!(node.child_at_or_before(9), None);
+        assert_eq!(node.child_before(10), None);
+        assert_eq!(node.child_after(20), None);
+        assert_eq!(node.last_child(), Some((20, id(2))));
+    }
+
+    fn assert_round_trip(node: &Node) {
+        let bytes = node.serialize();
+        let Some(deserialized) = Node::deserialize(&bytes) else {
+            panic!("Failed to deserialize {node:?}");
+        };
+        assert_eq!(deserialized.serialize(), bytes);
+        assert!(Node::deserialize(&bytes[..bytes.len() - 1]).is_none());
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(Node::deserialize(&trailing).is_none());
+    }
+
+    #[test]
+    fn serialization() {
+        let mut descriptor = ArtDescriptor::new();
+        descriptor.set_root(id(5));
+        assert_round_trip(&Node::ArtDescriptor(descriptor.clone()));
+        descriptor.set_dropped(7);
+        assert_round_trip(&Node::ArtDescriptor(descriptor));
+        assert_round_trip(&Node::Leaf(Leaf::new(b"", b"")));
+        assert_round_trip(&Node::Leaf(Leaf::new(b"key", b"value")));
+        let mut deleted = Leaf::new(b"key", b"value");
+        deleted.update(None, 0);
+        assert_round_trip(&Node::Leaf(deleted.clone()));
+        let Some(Node::Leaf(deserialized)) = Node::deserialize(&Node::Leaf(deleted).serialize())
+        else {
+            panic!("Expected a leaf");
+        };
+        assert_eq!(deserialized.value(), None);
+        let mut versioned = Leaf::new_at(b"key", b"1", 10);
+        versioned.update(Some(b"2"), 20);
+        versioned.update(None, 30);
+        assert_round_trip(&Node::Leaf(versioned));
+        let mut inner = Inner::new();
+        inner.set_prefix(b"0123456789", 10);
+        inner.set_terminal_leaf(id(1000));
+        for i in 0..200 {
+            inner.add_child(key_byte(i), id(i));
+        }
+        let node = Node::Inner(inner);
+        assert_round_trip(&node);
+        let Some(Node::Inner(deserialized)) = Node::deserialize(&node.serialize()) else {
+            panic!("Expected an inner node");
+        };
+        assert_eq!(deserialized.prefix_len(), 10);
+        assert_eq!(deserialized.stored_prefix(), b"01234567");
+        assert_eq!(deserialized.terminal_leaf(), id(1000));
+        assert!(matches!(deserialized.children(), Children::Node256(_)));
+        assert_children_in_order(&deserialized, 200);
+        assert!(Node::deserialize(&[]).is_none());
+        assert!(Node::deserialize(&[3]).is_none());
+    }
+
+    #[test]
+    fn swips() {
+        let swip = Swip::new(Id::from(42));
+        assert_eq!(swip.frame(), None);
+        swip.swizzle(7);
+        assert_eq!(swip.id(), Id::from(42));
+        assert_eq!(swip.frame(), Some(7));
+        swip.unswizzle();
+        assert_eq!(swip.id(), Id::from(42));
+        assert_eq!(swip.frame(), None);
+        let large_id = Swip::new(Id::from(1 << 40));
+        large_id.swizzle(7);
+        assert_eq!(large_id.frame(), None);
+        assert_eq!(large_id.id(), Id::from(1 << 40));
+        let large_frame = Swip::new(Id::from(42));
+        large_frame.swizzle(1 << 23);
+        assert_eq!(large_frame.frame(), None);
+        large_frame.swizzle((1 << 23) - 1);
+        assert_eq!(large_frame.frame(), Some((1 << 23) - 1));
+    }
+
+    #[test]
+    fn swizzled_children_survive_changes() {
+        let mut node = Inner::new();
+        node.add_child(1, id(1));
+        node.add_child(3, id(3));
+        node.child_swip(3).unwrap().swizzle(5);
+        node.add_child(2, id(2));
+        assert_eq!(node.remove_child(1), id(1));
+        // Growing to Node16 keeps the swizzled references
+        for i in 10..13 {
+            node.add_child(key_byte(i), id(i));
+        }
+        assert!(matches!(node.children(), Children::Node16(_)));
+        assert_eq!(node.child_swip(3).unwrap().frame(), Some(5));
+        assert_eq!(node.find_child(3), Some(id(3)));
+        assert_eq!(node.child_swip(2).unwrap().frame(), None);
+        // Replacing a child unswizzles its reference
+        node.replace_child(3, id(4));
+        assert_eq!(node.child_swip(3).unwrap().frame(), None);
+        // Serialization uses the IDs
+        node.child_swip(2).unwrap().swizzle(6);
+        let bytes = Node::Inner(node).serialize();
+        let Some(Node::Inner(node)) = Node::deserialize(&bytes) else {
+            panic!("Expected an inner node");
+        };
+        assert_eq!(node.find_child(2), Some(id(2)));
+        assert_eq!(node.child_swip(2).unwrap().frame(), None);
+    }
+
+    #[test]
+    fn leaf_versions() {
+        let mut leaf = Leaf::new_at(b"key", b"1", 10);
+        assert_eq!(leaf.value_at(9), None);
+        assert_eq!(leaf.value_at(10), Some(&b"1"[..]));
+        leaf.update(Some(b"2"), 20);
+        leaf.update(None, 30);
+        assert_eq!(leaf.value(), None);
+        for (snapshot_ts, value) in [
+            (5, None),
+            (15, Some(&b"1"[..])),
+            (25, Some(b"2")),
+            (35, None),
+        ] {
+            assert_eq!(leaf.value_at(snapshot_ts), value);
+        }
+        // Only the versions of the snapshots at 25 and later are kept
+        assert!(!leaf.purge(25));
+        assert_eq!(leaf.value_at(25), Some(&b"2"[..]));
+        assert!(leaf.purge(30));
+        assert_eq!(leaf.commit_ts(), 0);
+        leaf.update(Some(b"3"), 40);
+        assert!(!leaf.purge(50));
+        assert_eq!(leaf.value_at(0), Some(&b"3"[..]));
+    }
+}
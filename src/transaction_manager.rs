@@ -1,14 +1,25 @@
-// Copyright (C) 2022-2023 Laurynas Biveinis
-use std::cell::RefCell;
+// Copyright (C) 2022-2024 Laurynas Biveinis
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::io;
-use std::rc::Rc;
+use std::ops::Bound;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{
+    Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+
+use crate::art::{self, Entry};
+use cap_std::fs::Dir;
 
 use crate::buffer_manager::BufferManager;
+use crate::catalog::Keyspace;
+use crate::checkpoint::Checkpoint;
+use crate::cursor::{Cursor, Range};
+use crate::key::KeySchema;
 use crate::log::Log;
 use crate::node;
+use crate::{lock, BufferPoolStats, DbError, DbOptions, Durability};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[must_use]
 pub struct Id(u64);
 
@@ -27,6 +38,9 @@ impl From<u64> for Id {
     }
 }
 
+// The transaction IDs are reserved in the log in blocks of this many
+const ID_BLOCK_SIZE: u64 = 1024;
+
 #[derive(Debug)] // COV_EXCL_LINE
 #[must_use]
 struct AtomicId(AtomicU64);
@@ -38,7 +52,12 @@ impl AtomicId {
     }
 
     #[inline]
-    fn get_and_advance(&mut self) -> Id {
+    fn get(&self) -> Id {
+        Id::from(self.0.load(Ordering::Relaxed))
+    }
+
+    #[inline]
+    fn get_and_advance(&self) -> Id {
         let result_u64 = self.0.fetch_add(1, Ordering::Relaxed);
         debug_assert_ne!(result_u64, u64::MAX);
         Id::from(result_u64)
@@ -49,6 +68,10 @@ impl AtomicId {
 #[must_use]
 pub enum TransactionChange {
     NewNode(TransactionChangeNewNode),
+    Insert(TransactionChangeKeyValue),
+    Upsert(TransactionChangeKeyValue),
+    Delete(TransactionChangeKey),
+    DropTree(TransactionChangeTree),
 }
 
 #[derive(Debug)] // COV_EXCL_LINE
@@ -58,7 +81,7 @@ pub struct TransactionChangeNewNode {
 }
 
 impl TransactionChangeNewNode {
-    fn new(node_id: node::Id) -> Self {
+    pub(crate) fn new(node_id: node::Id) -> Self {
         Self { node_id }
     }
 
@@ -68,71 +91,1287 @@ impl TransactionChangeNewNode {
     }
 }
 
+#[derive(Debug)] // COV_EXCL_LINE
+#[must_use]
+pub struct TransactionChangeKeyValue {
+    tree: node::Id,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl TransactionChangeKeyValue {
+    pub(crate) fn new(tree: node::Id, key: Vec<u8>, value: Vec<u8>) -> Self {
+        Self { tree, key, value }
+    }
+
+    #[inline]
+    pub fn tree(&self) -> node::Id {
+        self.tree
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+}
+
+#[derive(Debug)] // COV_EXCL_LINE
+#[must_use]
+pub struct TransactionChangeKey {
+    tree: node::Id,
+    key: Vec<u8>,
+}
+
+impl TransactionChangeKey {
+    pub(crate) fn new(tree: node::Id, key: Vec<u8>) -> Self {
+        Self { tree, key }
+    }
+
+    #[inline]
+    pub fn tree(&self) -> node::Id {
+        self.tree
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+}
+
+#[derive(Debug)] // COV_EXCL_LINE
+#[must_use]
+pub struct TransactionChangeTree {
+    tree: node::Id,
+}
+
+impl TransactionChangeTree {
+    pub(crate) fn new(tree: node::Id) -> Self {
+        Self { tree }
+    }
+
+    #[inline]
+    pub fn tree(&self) -> node::Id {
+        self.tree
+    }
+}
+
+// The keys written by the changes, other than by dropping their trees
+fn changed_keys(changes: &[TransactionChange]) -> impl Iterator<Item = (node::Id, &[u8])> {
+    changes.iter().filter_map(|change| match change {
+        TransactionChange::Insert(insert) | TransactionChange::Upsert(insert) => {
+            Some((insert.tree(), insert.key()))
+        }
+        TransactionChange::Delete(delete) => Some((delete.tree(), delete.key())),
+        TransactionChange::NewNode(_) | TransactionChange::DropTree(_) => None,
+    })
+}
+
+// The trees changed by the changes, other than by creating them
+fn changed_trees(changes: &[TransactionChange]) -> impl Iterator<Item = node::Id> + '_ {
+    changes.iter().filter_map(|change| match change {
+        TransactionChange::Insert(insert) | TransactionChange::Upsert(insert) => {
+            Some(insert.tree())
+        }
+        TransactionChange::Delete(delete) => Some(delete.tree()),
+        TransactionChange::DropTree(drop_tree) => Some(drop_tree.tree()),
+        TransactionChange::NewNode(_) => None,
+    })
+}
+
+// BTreeMap::range panics on these
+fn is_empty_range(lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> bool {
+    match (lower, upper) {
+        (Bound::Included(lower), Bound::Included(upper)) => lower > upper,
+        (Bound::Included(lower) | Bound::Excluded(lower), Bound::Excluded(upper))
+        | (Bound::Excluded(lower), Bound::Included(upper)) => lower >= upper,
+        _ => false,
+    }
+}
+
+// The catalog is only changed through the keyspace operations
+fn check_not_catalog(tree: node::Id) -> Result<(), DbError> {
+    if tree == node::Id::CATALOG {
+        Err(DbError::CatalogWrite)
+    } else {
+        Ok(())
+    }
+}
+
+// The changes are applied to the trees only at commit. Until then the
+// transaction sees its own writes through this overlay, where None marks a
+// deleted key.
+type WriteSet = BTreeMap<node::Id, BTreeMap<Vec<u8>, Option<Vec<u8>>>>;
+
+/// A mark in the changes of a transaction to roll back to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use]
+pub struct Savepoint(u64);
+
+impl Savepoint {
+    #[must_use]
+    #[inline]
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for Savepoint {
+    #[inline]
+    fn from(val: u64) -> Self {
+        Self(val)
+    }
+}
+
+// A transaction reads the snapshot of the commits applied when it began, or
+// when it last committed or rolled back, under its own writes. The older
+// versions of the keys are kept for the snapshots, thus a long read does not
+// hold back the commits, and the commits do not change what it reads. A
+// commit fails if another transaction has written any of the same keys after
+// its snapshot.
 #[derive(Debug)] // COV_EXCL_LINE
 #[must_use]
 pub struct Transaction {
-    manager: Rc<RefCell<TransactionManager>>,
+    manager: Arc<TransactionManager>,
     id: Id,
+    // The commit timestamp of the last commit in the snapshot
+    snapshot_ts: u64,
     changes: Vec<TransactionChange>,
+    writes: WriteSet,
+    new_trees: Vec<node::Id>,
+    dropped_trees: Vec<node::Id>,
+    // The savepoints and the change counts at them, in their order. The
+    // savepoints are numbered over the whole transaction, so that a released
+    // one is not taken for a later one.
+    savepoints: Vec<(Savepoint, usize)>,
+    next_savepoint: u64,
 }
 
 impl Transaction {
-    pub fn new(manager: &Rc<RefCell<TransactionManager>>, id: Id) -> Self {
+    pub fn new(manager: &Arc<TransactionManager>, id: Id, snapshot_ts: u64) -> Self {
         Self {
             manager: manager.clone(),
             id,
+            snapshot_ts,
             changes: Vec::new(),
+            writes: WriteSet::new(),
+            new_trees: Vec::new(),
+            dropped_trees: Vec::new(),
+            savepoints: Vec::new(),
+            next_savepoint: 0,
         }
     }
 
+    /// Commits with the durability of the database options.
     /// # Errors
-    /// Will return `io::Error` if it encounters any.
-    pub fn commit(&mut self) -> Result<(), io::Error> {
-        self.manager.borrow_mut().log_append(&self.changes)
+    /// Will return `DbError::WriteConflict` if a key written by the
+    /// transaction has been written by another one committed after its
+    /// snapshot, or a tree written or dropped by it has been dropped, or
+    /// written after its snapshot, `DbError::KeyExists` if a key inserted by
+    /// it has been inserted by another one, `DbError::Poisoned` once the log
+    /// has failed, or another `DbError` if it encounters any.
+    pub fn commit(&mut self) -> Result<(), DbError> {
+        let durability = self.manager.durability;
+        self.commit_with_durability(durability)
+    }
+
+    /// Commits and takes a new snapshot, which includes this commit.
+    /// # Errors
+    /// Will return `DbError::WriteConflict` if a key written by the
+    /// transaction has been written by another one committed after its
+    /// snapshot, or a tree written or dropped by it has been dropped, or
+    /// written after its snapshot, `DbError::KeyExists` if a key inserted by
+    /// it has been inserted by another one, `DbError::Poisoned` once the log
+    /// has failed, or another `DbError` if it encounters any.
+    pub fn commit_with_durability(&mut self, durability: Durability) -> Result<(), DbError> {
+        self.snapshot_ts =
+            self.manager
+                .commit(self.id, &self.changes, self.snapshot_ts, durability)?;
+        self.clear();
+        Ok(())
+    }
+
+    /// Discards the changes since the last commit, if any, and frees the node
+    /// IDs allocated for them. Releases all the savepoints and takes a new
+    /// snapshot. Dropping the transaction rolls it back too.
+    pub fn rollback(&mut self) {
+        self.manager.free_new_node_ids(&self.changes);
+        self.snapshot_ts = self.manager.new_snapshot(self.id);
+        self.clear();
+    }
+
+    pub fn savepoint(&mut self) -> Savepoint {
+        let savepoint = Savepoint(self.next_savepoint);
+        self.next_savepoint += 1;
+        self.savepoints.push((savepoint, self.changes.len()));
+        savepoint
+    }
+
+    // The index of the savepoint, unless released
+    fn find_savepoint(&self, savepoint: Savepoint) -> Result<usize, DbError> {
+        self.savepoints
+            .binary_search_by_key(&savepoint.0, |(kept, _)| kept.0)
+            .map_err(|_insert_index| DbError::NoSuchSavepoint)
+    }
+
+    /// Discards the changes since the savepoint, and frees the node IDs
+    /// allocated for them. The savepoint is kept, and the later ones are
+    /// released.
+    /// # Errors
+    /// Will return `DbError::NoSuchSavepoint` if the savepoint has been
+    /// released.
+    pub fn rollback_to_savepoint(&mut self, savepoint: Savepoint) -> Result<(), DbError> {
+        let index = self.find_savepoint(savepoint)?;
+        let changes_len = self.savepoints[index].1;
+        self.savepoints.truncate(index + 1);
+        let undone = self.changes.split_off(changes_len);
+        self.manager.free_new_node_ids(&undone);
+        self.replay_changes();
+        Ok(())
+    }
+
+    /// Forgets the savepoint and the later ones, keeping the changes.
+    /// # Errors
+    /// Will return `DbError::NoSuchSavepoint` if the savepoint has been
+    /// released.
+    pub fn release_savepoint(&mut self, savepoint: Savepoint) -> Result<(), DbError> {
+        let index = self.find_savepoint(savepoint)?;
+        self.savepoints.truncate(index);
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        self.changes.clear();
+        self.writes.clear();
+        self.new_trees.clear();
+        self.dropped_trees.clear();
+        self.savepoints.clear();
+    }
+
+    // Rebuilds the overlay of the writes and the created and dropped trees from
+    // the changes
+    fn replay_changes(&mut self) {
+        let changes = std::mem::take(&mut self.changes);
+        self.writes.clear();
+        self.new_trees.clear();
+        self.dropped_trees.clear();
+        for change in &changes {
+            match change {
+                TransactionChange::NewNode(new_node) => self.new_trees.push(new_node.node_id()),
+                TransactionChange::Insert(insert) | TransactionChange::Upsert(insert) => {
+                    self.add_write(insert.tree(), insert.key(), Some(insert.value()));
+                }
+                TransactionChange::Delete(delete) => {
+                    self.add_write(delete.tree(), delete.key(), None);
+                }
+                TransactionChange::DropTree(drop_tree) => {
+                    let tree = drop_tree.tree();
+                    self.writes.remove(&tree);
+                    self.new_trees.retain(|new_tree| *new_tree != tree);
+                    self.dropped_trees.push(tree);
+                }
+            }
+        }
+        self.changes = changes;
     }
 
     pub fn new_art_descriptor_node(&mut self) -> node::Id {
-        let new_node_trx_change = self.manager.borrow_mut().new_art_descriptor_node();
+        let new_node_trx_change = self.manager.new_art_descriptor_node();
         let new_node_id = new_node_trx_change.node_id();
         let trx_change = TransactionChange::NewNode(new_node_trx_change);
         self.changes.push(trx_change);
+        self.new_trees.push(new_node_id);
         new_node_id
     }
 
+    /// Creates a new keyspace backed by a new tree, returning the tree.
+    /// # Errors
+    /// Will return `DbError::KeyspaceExists` if there is a keyspace with the
+    /// same name, or another `DbError` if it encounters any.
+    pub fn create_keyspace(
+        &mut self,
+        name: &str,
+        key_schema: KeySchema,
+        options: &[u8],
+    ) -> Result<node::Id, DbError> {
+        if self.get(node::Id::CATALOG, name.as_bytes())?.is_some() {
+            return Err(DbError::KeyspaceExists {
+                name: name.to_owned(),
+            });
+        }
+        let tree = self.new_art_descriptor_node();
+        let creation_lsn = self.manager.next_lsn();
+        let keyspace = Keyspace::new(
+            name.to_owned(),
+            tree,
+            key_schema,
+            creation_lsn,
+            options.to_vec(),
+        );
+        let value = keyspace.to_value()?;
+        self.add_write(node::Id::CATALOG, name.as_bytes(), Some(&value));
+        let change =
+            TransactionChangeKeyValue::new(node::Id::CATALOG, name.as_bytes().to_vec(), value);
+        self.changes.push(TransactionChange::Insert(change));
+        Ok(tree)
+    }
+
+    /// # Errors
+    /// Will return `DbError::NoSuchKeyspace` if there is no such keyspace, or
+    /// another `DbError` if it encounters any.
+    pub fn open_keyspace(&self, name: &str) -> Result<Keyspace, DbError> {
+        let Some(value) = self.get(node::Id::CATALOG, name.as_bytes())? else {
+            return Err(DbError::NoSuchKeyspace {
+                name: name.to_owned(),
+            });
+        };
+        Keyspace::from_entry(name.as_bytes().to_vec(), &value)
+    }
+
+    /// Drops the keyspace together with its tree.
+    /// # Errors
+    /// Will return `DbError::NoSuchKeyspace` if there is no such keyspace, or
+    /// another `DbError` if it encounters any.
+    pub fn drop_keyspace(&mut self, name: &str) -> Result<(), DbError> {
+        let tree = self.open_keyspace(name)?.tree();
+        self.add_write(node::Id::CATALOG, name.as_bytes(), None);
+        let change = TransactionChangeKey::new(node::Id::CATALOG, name.as_bytes().to_vec());
+        self.changes.push(TransactionChange::Delete(change));
+        self.writes.remove(&tree);
+        self.new_trees.retain(|new_tree| *new_tree != tree);
+        self.dropped_trees.push(tree);
+        let change = TransactionChangeTree::new(tree);
+        self.changes.push(TransactionChange::DropTree(change));
+        Ok(())
+    }
+
+    /// The keyspaces in their name order.
+    /// # Errors
+    /// Will return `DbError` if it encounters any.
+    pub fn list_keyspaces(&self) -> Result<Vec<Keyspace>, DbError> {
+        self.range(node::Id::CATALOG, Bound::Unbounded, Bound::Unbounded)
+            .map(|entry| {
+                let (name, value) = entry?;
+                Keyspace::from_entry(name, &value)
+            })
+            .collect()
+    }
+
+    /// # Errors
+    /// Will return `DbError::NotArtDescriptor` if `tree` is not an ART, or
+    /// another `DbError` if it encounters any.
+    pub fn get(&self, tree: node::Id, key: &[u8]) -> Result<Option<Vec<u8>>, DbError> {
+        self.check_not_dropped(tree)?;
+        if let Some(own_write) = self.writes.get(&tree).and_then(|w| w.get(key)) {
+            return Ok(own_write.clone());
+        }
+        if self.new_trees.contains(&tree) {
+            return Ok(None);
+        }
+        self.manager
+            .read_tree(tree, |nodes| art::get(nodes, tree, key, self.snapshot_ts))
+    }
+
+    /// # Errors
+    /// Will return `DbError::KeyExists` if the key is already present,
+    /// `DbError::NotArtDescriptor` if `tree` is not an ART, or another
+    /// `DbError` if it encounters any.
+    pub fn insert(&mut self, tree: node::Id, key: &[u8], value: &[u8]) -> Result<(), DbError> {
+        check_not_catalog(tree)?;
+        if self.get(tree, key)?.is_some() {
+            return Err(DbError::KeyExists);
+        }
+        self.add_write(tree, key, Some(value));
+        let change = TransactionChangeKeyValue::new(tree, key.to_vec(), value.to_vec());
+        self.changes.push(TransactionChange::Insert(change));
+        Ok(())
+    }
+
+    /// # Errors
+    /// Will return `DbError::NotArtDescriptor` if `tree` is not an ART, or
+    /// another `DbError` if it encounters any.
+    pub fn upsert(&mut self, tree: node::Id, key: &[u8], value: &[u8]) -> Result<(), DbError> {
+        check_not_catalog(tree)?;
+        self.check_tree(tree)?;
+        self.add_write(tree, key, Some(value));
+        let change = TransactionChangeKeyValue::new(tree, key.to_vec(), value.to_vec());
+        self.changes.push(TransactionChange::Upsert(change));
+        Ok(())
+    }
+
+    /// Returns whether the key was present.
+    /// # Errors
+    /// Will return `DbError::NotArtDescriptor` if `tree` is not an ART, or
+    /// another `DbError` if it encounters any.
+    pub fn delete(&mut self, tree: node::Id, key: &[u8]) -> Result<bool, DbError> {
+        check_not_catalog(tree)?;
+        if self.get(tree, key)?.is_none() {
+            return Ok(false);
+        }
+        self.add_write(tree, key, None);
+        let change = TransactionChangeKey::new(tree, key.to_vec());
+        self.changes.push(TransactionChange::Delete(change));
+        Ok(true)
+    }
+
+    /// An unpositioned cursor over `tree`.
+    #[inline]
+    pub fn cursor(&self, tree: node::Id) -> Cursor<'_> {
+        Cursor::new(self, tree)
+    }
+
+    /// An iterator over the entries of `tree` within the bounds, in key order
+    /// from either end.
+    #[inline]
+    pub fn range(&self, tree: node::Id, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Range<'_> {
+        Range::new(self, tree, lower, upper)
+    }
+
+    /// An iterator over the entries of `tree` whose keys start with `prefix`.
+    pub fn scan_prefix(&self, tree: node::Id, prefix: &[u8]) -> Range<'_> {
+        Range::new_prefix(self, tree, prefix)
+    }
+
+    /// The number of the entries of `tree` whose keys start with `prefix`.
+    /// # Errors
+    /// Will return `DbError::NotArtDescriptor` if `tree` is not an ART, or
+    /// another `DbError` if it encounters any.
+    pub fn count_prefix(&self, tree: node::Id, prefix: &[u8]) -> Result<usize, DbError> {
+        self.check_not_dropped(tree)?;
+        let is_new_tree = self.new_trees.contains(&tree);
+        self.manager.read_tree(tree, |nodes| {
+            let mut result = if is_new_tree {
+                0
+            } else {
+                art::count_prefix(nodes, tree, prefix, self.snapshot_ts)?
+            };
+            let Some(own_writes) = self.writes.get(&tree) else {
+                return Ok(result);
+            };
+            let upper = art::prefix_upper_bound(prefix);
+            let own_range = (Bound::Included(prefix), upper.as_ref().map(Vec::as_slice));
+            for (key, value) in own_writes.range::<[u8], _>(own_range) {
+                let is_committed =
+                    !is_new_tree && art::get(nodes, tree, key, self.snapshot_ts)?.is_some();
+                match (is_committed, value.is_some()) {
+                    (false, true) => result += 1,
+                    (true, false) => result -= 1,
+                    _ => {}
+                }
+            }
+            Ok(result)
+        })
+    }
+
+    pub(crate) fn first_in_range(
+        &self,
+        tree: node::Id,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<Option<Entry>, DbError> {
+        self.seek_in_range(tree, lower, upper, true, None)
+    }
+
+    pub(crate) fn last_in_range(
+        &self,
+        tree: node::Id,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<Option<Entry>, DbError> {
+        self.seek_in_range(tree, lower, upper, false, None)
+    }
+
+    // Merges the committed tree contents with the own writes of the
+    // transaction, skipping over the own deletes. The committed contents are
+    // sought under the prefix subtree if given, which the bounds must be
+    // within.
+    pub(crate) fn seek_in_range(
+        &self,
+        tree: node::Id,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        forward: bool,
+        mut prefix_subtree: Option<&mut PrefixSubtree>,
+    ) -> Result<Option<Entry>, DbError> {
+        self.check_not_dropped(tree)?;
+        let own_writes = self.writes.get(&tree);
+        let is_new_tree = self.new_trees.contains(&tree);
+        let mut lower = lower.map(<[u8]>::to_vec);
+        let mut upper = upper.map(<[u8]>::to_vec);
+        loop {
+            let lower_ref = lower.as_ref().map(Vec::as_slice);
+            let upper_ref = upper.as_ref().map(Vec::as_slice);
+            if is_empty_range(lower_ref, upper_ref) {
+                return Ok(None);
+            }
+            let committed = if is_new_tree {
+                None
+            } else {
+                let snapshot_ts = self.snapshot_ts;
+                self.manager
+                    .read_tree_counted(tree, |nodes, change_count| {
+                        let Some(prefix_subtree) = prefix_subtree.as_deref_mut() else {
+                            return if forward {
+                                art::first_in_range(nodes, tree, lower_ref, upper_ref, snapshot_ts)
+                            } else {
+                                art::last_in_range(nodes, tree, lower_ref, upper_ref, snapshot_ts)
+                            };
+                        };
+                        let Some(subtree) =
+                            prefix_subtree.get(nodes, tree, change_count, snapshot_ts)?
+                        else {
+                            return Ok(None);
+                        };
+                        if forward {
+                            art::first_in_subtree(nodes, subtree, lower_ref, upper_ref, snapshot_ts)
+                        } else {
+                            art::last_in_subtree(nodes, subtree, lower_ref, upper_ref, snapshot_ts)
+                        }
+                    })?
+            };
+            let own = own_writes.and_then(|own_writes| {
+                let mut range = own_writes.range::<[u8], _>((lower_ref, upper_ref));
+                if forward {
+                    range.next()
+                } else {
+                    range.next_back()
+                }
+            });
+            let Some((own_key, own_value)) = own else {
+                return Ok(committed);
+            };
+            if let Some(committed) = committed {
+                let committed_first = if forward {
+                    committed.0 < *own_key
+                } else {
+                    committed.0 > *own_key
+                };
+                if committed_first {
+                    return Ok(Some(committed));
+                }
+            }
+            if let Some(own_value) = own_value {
+                return Ok(Some((own_key.clone(), own_value.clone())));
+            }
+            if forward {
+                lower = Bound::Excluded(own_key.clone());
+            } else {
+                upper = Bound::Excluded(own_key.clone());
+            }
+        }
+    }
+
     #[inline]
     pub fn id(&self) -> Id {
         self.id
     }
+
+    fn check_tree(&self, tree: node::Id) -> Result<(), DbError> {
+        self.check_not_dropped(tree)?;
+        let exists = || {
+            self.manager
+                .read_tree(tree, |nodes| art::exists(nodes, tree, self.snapshot_ts))
+        };
+        if self.new_trees.contains(&tree) || exists() {
+            Ok(())
+        } else {
+            Err(DbError::NotArtDescriptor { node_id: tree })
+        }
+    }
+
+    // The trees dropped by the transaction stay in the buffer manager until
+    // its commit
+    fn check_not_dropped(&self, tree: node::Id) -> Result<(), DbError> {
+        if self.dropped_trees.contains(&tree) {
+            Err(DbError::NotArtDescriptor { node_id: tree })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn add_write(&mut self, tree: node::Id, key: &[u8], value: Option<&[u8]>) {
+        self.writes
+            .entry(tree)
+            .or_default()
+            .insert(key.to_vec(), value.map(<[u8]>::to_vec));
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.rollback();
+        self.manager.end(self.id);
+    }
+}
+
+// What is left to purge once no snapshot reads it
+#[derive(Debug)] // COV_EXCL_LINE
+enum Unpurged {
+    // The older versions of the key
+    Key(node::Id, Vec<u8>),
+    // The nodes of the dropped tree
+    DroppedTree(node::Id),
+}
+
+// The subtree of the keys starting with a prefix, found once for the steps of
+// a prefix scan, and found again only if the tree may have been changed since
+#[derive(Debug)] // COV_EXCL_LINE
+pub(crate) struct PrefixSubtree {
+    prefix: Vec<u8>,
+    // The change count of the tree lock stripe when the subtree was found,
+    // and the subtree, if any
+    found: Option<(u64, Option<art::Subtree>)>,
+}
+
+impl PrefixSubtree {
+    pub(crate) fn new(prefix: &[u8]) -> Self {
+        Self {
+            prefix: prefix.to_vec(),
+            found: None,
+        }
+    }
+
+    fn get(
+        &mut self,
+        nodes: &BufferManager,
+        tree: node::Id,
+        change_count: u64,
+        snapshot_ts: u64,
+    ) -> Result<Option<&art::Subtree>, DbError> {
+        let is_current =
+            matches!(self.found, Some((found_count, _)) if found_count == change_count);
+        if !is_current {
+            let subtree = art::prefix_subtree(nodes, tree, &self.prefix, snapshot_ts)?;
+            self.found = Some((change_count, subtree));
+        }
+        Ok(self
+            .found
+            .as_ref()
+            .and_then(|(_, subtree)| subtree.as_ref()))
+    }
+}
+
+const TREE_LOCK_STRIPES: usize = 64;
+
+// A read of a tree takes its stripe shared, and a change of it exclusive, thus
+// the reads of the trees not being changed go on meanwhile. Each stripe counts
+// the changes under it, so that the node IDs found by a read may be used by a
+// later one as long as the count stays the same.
+#[derive(Debug)] // COV_EXCL_LINE
+struct TreeLocks([RwLock<u64>; TREE_LOCK_STRIPES]);
+
+impl TreeLocks {
+    fn new() -> Self {
+        Self(std::array::from_fn(|_| RwLock::new(0)))
+    }
+
+    #[inline]
+    fn stripe(tree: node::Id) -> usize {
+        usize::from(tree.as_u64().to_le_bytes()[0]) % TREE_LOCK_STRIPES
+    }
+
+    fn read(&self, tree: node::Id) -> RwLockReadGuard<'_, u64> {
+        self.0[Self::stripe(tree)]
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Takes the stripes in their order
+    fn write(&self, trees: impl IntoIterator<Item = node::Id>) -> Vec<RwLockWriteGuard<'_, u64>> {
+        let stripes: BTreeSet<_> = trees.into_iter().map(Self::stripe).collect();
+        stripes
+            .into_iter()
+            .map(|stripe| {
+                let mut change_count = self.0[stripe]
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner);
+                *change_count += 1;
+                change_count
+            })
+            .collect()
+    }
+}
+
+// The state shared by all the transactions, accessed under the lock
+#[derive(Debug)] // COV_EXCL_LINE
+struct ManagerState {
+    // The active transactions and the timestamps of their snapshots
+    active: BTreeMap<Id, u64>,
+    checkpoint_lsn: u64,
+    // The log turn of the next commit to apply. The commits are applied in
+    // their log order, so that redoing the log reproduces the same trees.
+    next_to_apply: u64,
+    // The timestamp of the last applied commit. The commits are timestamped
+    // in their apply order, starting from one at open, while zero stands for
+    // the versions seen by all the snapshots.
+    last_commit_ts: u64,
+    // The keys written by the commits queued but not applied yet, and whether
+    // they are present after them
+    pending_writes: BTreeMap<(node::Id, Vec<u8>), bool>,
+    // The trees dropped by the commits queued but not applied yet
+    pending_drops: BTreeSet<node::Id>,
+    // The timestamps of the last commits writing the trees, as long as some
+    // snapshot is older
+    tree_commit_ts: BTreeMap<node::Id, u64>,
+    // What to purge once no snapshot reads it, in the order of the commit
+    // timestamps
+    unpurged: VecDeque<(u64, Unpurged)>,
+    // A checkpoint is writing back the nodes, which are not changed meanwhile
+    // by the commits nor the purges
+    is_checkpointing: bool,
+    // The log has grown enough for the background thread to checkpoint
+    is_checkpoint_due: bool,
+    is_closing: bool,
 }
 
 #[derive(Debug)] // COV_EXCL_LINE
 #[must_use]
 pub struct TransactionManager {
+    state: Mutex<ManagerState>,
+    // The trees, read under their lock stripes without the state lock, and
+    // changed under both, so that the changes are serialized
     buffer_manager: BufferManager,
+    tree_locks: TreeLocks,
+    // Notified whenever a commit has been applied or a checkpoint has ended
+    applied: Condvar,
+    checkpoint_due: Condvar,
     log: Log,
     next_id: AtomicId,
+    // The IDs below this one have been reserved in the log
+    reserved_ids: Mutex<Id>,
+    // For writing the checkpoints
+    dir_handle: Dir,
+    checkpoint_log_size: Option<u64>,
+    durability: Durability,
 }
 
 impl TransactionManager {
-    pub fn new(buffer_manager: BufferManager, log: Log) -> Self {
+    // The unpurged keys and the dropped trees of the checkpoint are purged at
+    // the redo
+    pub fn new(
+        buffer_manager: BufferManager,
+        log: Log,
+        dir_handle: Dir,
+        checkpoint: Option<&Checkpoint>,
+        first_id: Id,
+        options: &DbOptions,
+    ) -> Self {
+        let next_to_apply = log.next_ticket();
+        let checkpoint_lsn = checkpoint.map_or(0, Checkpoint::lsn);
+        let unpurged = checkpoint
+            .into_iter()
+            .flat_map(|checkpoint| {
+                let keys = checkpoint
+                    .unpurged_keys()
+                    .iter()
+                    .map(|(tree, key)| Unpurged::Key(*tree, key.clone()));
+                let dropped_trees = checkpoint
+                    .dropped_trees()
+                    .iter()
+                    .map(|tree| Unpurged::DroppedTree(*tree));
+                keys.chain(dropped_trees)
+            })
+            .map(|unpurged| (0, unpurged))
+            .collect();
         Self {
+            state: Mutex::new(ManagerState {
+                active: BTreeMap::new(),
+                checkpoint_lsn,
+                next_to_apply,
+                last_commit_ts: 0,
+                pending_writes: BTreeMap::new(),
+                pending_drops: BTreeSet::new(),
+                tree_commit_ts: BTreeMap::new(),
+                unpurged,
+                is_checkpointing: false,
+                is_checkpoint_due: false,
+                is_closing: false,
+            }),
             buffer_manager,
+            tree_locks: TreeLocks::new(),
+            applied: Condvar::new(),
+            checkpoint_due: Condvar::new(),
             log,
-            next_id: AtomicId::new(Id::from(0)),
+            next_id: AtomicId::new(first_id),
+            reserved_ids: Mutex::new(first_id),
+            dir_handle,
+            checkpoint_log_size: options.checkpoint_log_size,
+            durability: options.durability,
         }
     }
 
+    // Returns the new transaction ID and the timestamp of its snapshot
     #[inline]
-    pub fn assign_next_id(&mut self) -> Id {
-        self.next_id.get_and_advance()
+    pub fn begin_transaction(&self) -> (Id, u64) {
+        let id = self.next_id.get_and_advance();
+        // A failure is retried by the next transaction, or at the commit
+        let _result = self.reserve_id(id);
+        (id, self.new_snapshot(id))
     }
 
-    fn new_art_descriptor_node(&mut self) -> TransactionChangeNewNode {
+    // Reserves the next block of the transaction IDs in the log if the ID is
+    // not reserved yet
+    fn reserve_id(&self, id: Id) -> Result<(), DbError> {
+        let mut reserved_ids = lock(&self.reserved_ids);
+        if id < *reserved_ids {
+            return Ok(());
+        }
+        let until = Id::from(id.as_u64() + ID_BLOCK_SIZE);
+        self.log
+            .reserve_transaction_ids(until)
+            .map_err(|error| self.log_error(error))?;
+        *reserved_ids = until;
+        Ok(())
+    }
+
+    /// Writes back all the modified nodes and records them as the data file
+    /// image to redo the log from at open. The image is consistent as of the
+    /// checkpoint log record: the commits wait until it is written, while the
+    /// reads and the new transactions go on.
+    /// # Errors
+    /// Will return `DbError` if it encounters any.
+    pub fn checkpoint(&self) -> Result<(), DbError> {
+        let mut state = self.lock();
+        // The commits logged before the checkpoint record must be in the image.
+        // The next ones cannot be queued while the lock is held.
+        while state.is_checkpointing || state.next_to_apply != self.log.next_ticket() {
+            state = self.wait_applied(state);
+        }
+        state.is_checkpoint_due = false;
+        // The uncommitted transactions have not changed any nodes yet
+        let active: Vec<_> = state.active.keys().copied().collect();
+        let lsn = self
+            .log
+            .append_checkpoint(&active)
+            .map_err(|error| self.log_error(error))?;
+        self.log.sync().map_err(|error| self.log_error(error))?;
+        let mut unpurged_keys = Vec::new();
+        let mut dropped_trees = Vec::new();
+        for (_, unpurged) in &state.unpurged {
+            match unpurged {
+                Unpurged::Key(tree, key) => unpurged_keys.push((*tree, key.clone())),
+                Unpurged::DroppedTree(tree) => dropped_trees.push(*tree),
+            }
+        }
+        let next_transaction_id = self.next_id.get().max(*lock(&self.reserved_ids));
+        state.is_checkpointing = true;
+        drop(state);
+        let result = self
+            .buffer_manager
+            .checkpoint(lsn, next_transaction_id, |checkpoint| {
+                checkpoint
+                    .with_unpurged_keys(unpurged_keys)
+                    .with_dropped_trees(dropped_trees)
+                    .write(&self.dir_handle)
+            });
+        let mut state = self.lock();
+        state.is_checkpointing = false;
+        self.applied.notify_all();
+        result?;
+        state.checkpoint_lsn = lsn;
+        drop(state);
+        self.log.remove_segments_before(lsn)?;
+        Ok(())
+    }
+
+    // The loop of the background thread checkpointing once the log has grown
+    // enough since the last checkpoint, until closed
+    pub fn checkpoint_when_due(&self) {
+        let mut state = self.lock();
+        while !state.is_closing {
+            if state.is_checkpoint_due {
+                drop(state);
+                // A failure is retried once a commit finds the log grown
+                // enough again
+                let _result = self.checkpoint();
+                state = self.lock();
+            } else {
+                state = self
+                    .checkpoint_due
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+        }
+    }
+
+    // Ends the loop of the background checkpointing thread
+    pub fn close(&self) {
+        self.lock().is_closing = true;
+        self.checkpoint_due.notify_all();
+    }
+
+    fn end(&self, id: Id) {
+        let mut state = self.lock();
+        state.active.remove(&id);
+        // A failed purge is retried by the next one
+        let _result = self.purge(&mut state);
+    }
+
+    // Starts a snapshot of all the applied commits for the transaction
+    fn new_snapshot(&self, id: Id) -> u64 {
+        let mut state = self.lock();
+        let snapshot_ts = state.last_commit_ts;
+        state.active.insert(id, snapshot_ts);
+        let _result = self.purge(&mut state);
+        snapshot_ts
+    }
+
+    // Nothing has been applied before the commit, thus only the node IDs are
+    // to be returned on a rollback
+    fn free_new_node_ids(&self, changes: &[TransactionChange]) {
+        for change in changes {
+            if let TransactionChange::NewNode(new_node) = change {
+                self.buffer_manager.free_node_id(new_node.node_id());
+            }
+        }
+    }
+
+    /// Re-apply the changes of the committed transactions found in the log,
+    /// and free the node IDs the uncommitted ones took.
+    /// # Errors
+    /// Will return `DbError` if it encounters any.
+    pub fn redo(&self, changes: &[TransactionChange]) -> Result<(), DbError> {
+        let mut state = self.lock();
+        let nodes = &self.buffer_manager;
+        // The logged tree IDs may have been freed and allocated again. The
+        // nodes created while redoing must not take them in between, and the
+        // nodes that had them must be gone before, thus the checkpoint is
+        // purged first.
+        nodes.reserve_node_ids(changes.iter().filter_map(|change| match change {
+            TransactionChange::NewNode(new_node) => Some(new_node.node_id()),
+            _ => None,
+        }));
+        // No snapshot needs the older versions
+        let result = self
+            .purge(&mut state)
+            .and_then(|()| state.apply(nodes, changes, 0));
+        nodes.release_reserved_node_ids();
+        result?;
+        nodes.reclaim_unused_node_ids();
+        Ok(())
+    }
+
+    fn new_art_descriptor_node(&self) -> TransactionChangeNewNode {
         let new_node_id = self.buffer_manager.allocate_new_node_id();
         TransactionChangeNewNode::new(new_node_id)
     }
 
-    fn log_append(&mut self, changes: &Vec<TransactionChange>) -> Result<(), io::Error> {
-        self.log.append(changes)
+    pub(crate) fn buffer_pool_stats(&self) -> BufferPoolStats {
+        self.buffer_manager.stats()
+    }
+
+    fn next_lsn(&self) -> u64 {
+        self.log.next_lsn()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn log(&self) -> &Log {
+        &self.log
+    }
+
+    // A failure of the poisoned log is final until reopen
+    fn log_error(&self, error: io::Error) -> DbError {
+        if self.log.is_poisoned() {
+            DbError::Poisoned
+        } else {
+            DbError::Io(error)
+        }
+    }
+
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, ManagerState> {
+        lock(&self.state)
+    }
+
+    // Reads the tree, which is not changed meanwhile
+    fn read_tree<T>(&self, tree: node::Id, read: impl FnOnce(&BufferManager) -> T) -> T {
+        let _stripe = self.tree_locks.read(tree);
+        read(&self.buffer_manager)
+    }
+
+    // Reads the tree as above, given the change count of its lock stripe
+    fn read_tree_counted<T>(
+        &self,
+        tree: node::Id,
+        read: impl FnOnce(&BufferManager, u64) -> T,
+    ) -> T {
+        let stripe = self.tree_locks.read(tree);
+        read(&self.buffer_manager, *stripe)
+    }
+
+    // Drops the versions older than what the oldest snapshot reads, or all
+    // but the newest ones without any snapshot, and destroys the dropped trees
+    // that no snapshot reads
+    fn purge(&self, state: &mut ManagerState) -> Result<(), DbError> {
+        if state.is_checkpointing {
+            return Ok(());
+        }
+        let oldest_snapshot_ts = state.oldest_snapshot_ts();
+        state
+            .tree_commit_ts
+            .retain(|_, commit_ts| *commit_ts > oldest_snapshot_ts);
+        let nodes = &self.buffer_manager;
+        while let Some((commit_ts, unpurged)) = state.unpurged.front() {
+            if *commit_ts > oldest_snapshot_ts {
+                break;
+            }
+            match unpurged {
+                Unpurged::Key(tree, key) => {
+                    let _stripes = self.tree_locks.write([*tree]);
+                    // The dropped trees take their keys with them
+                    if art::exists(nodes, *tree, u64::MAX) {
+                        art::purge(nodes, *tree, key, oldest_snapshot_ts)?;
+                    }
+                }
+                Unpurged::DroppedTree(tree) => {
+                    let _stripes = self.tree_locks.write([*tree]);
+                    art::destroy(nodes, *tree)?;
+                }
+            }
+            state.unpurged.pop_front();
+        }
+        Ok(())
+    }
+
+    fn wait_applied<'a>(
+        &self,
+        state: MutexGuard<'a, ManagerState>,
+    ) -> MutexGuard<'a, ManagerState> {
+        self.applied
+            .wait(state)
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // The log records of the concurrent commits are written together, without
+    // holding the lock, and then the changes are applied in the log order.
+    // Returns the timestamp of the new snapshot of the transaction.
+    fn commit(
+        &self,
+        id: Id,
+        changes: &[TransactionChange],
+        snapshot_ts: u64,
+        durability: Durability,
+    ) -> Result<u64, DbError> {
+        if changes.is_empty() {
+            return Ok(self.new_snapshot(id));
+        }
+        self.reserve_id(id)?;
+        let ticket = {
+            // Not to be queued past a checkpoint in progress
+            let mut state = self.lock();
+            while state.is_checkpointing {
+                state = self.wait_applied(state);
+            }
+            state.check_write_conflicts(&self.buffer_manager, changes, snapshot_ts)?;
+            let ticket = self.log.enqueue(id, changes, durability)?;
+            for change in changes {
+                let (tree, key, is_present) = match change {
+                    TransactionChange::Insert(insert) | TransactionChange::Upsert(insert) => {
+                        (insert.tree(), insert.key(), true)
+                    }
+                    TransactionChange::Delete(delete) => (delete.tree(), delete.key(), false),
+                    TransactionChange::DropTree(drop_tree) => {
+                        state.pending_drops.insert(drop_tree.tree());
+                        continue;
+                    }
+                    TransactionChange::NewNode(_) => continue,
+                };
+                state
+                    .pending_writes
+                    .insert((tree, key.to_vec()), is_present);
+            }
+            ticket
+        };
+        let written = self
+            .log
+            .wait_written(ticket)
+            .map_err(|error| self.log_error(error));
+        let mut state = self.lock();
+        while state.next_to_apply != ticket {
+            state = self.wait_applied(state);
+        }
+        let commit_ts = state.last_commit_ts + 1;
+        // The log has the commit, thus a failure to apply it leaves the trees
+        // behind the log until redone
+        let result = written.and_then(|()| {
+            let _stripes = self.tree_locks.write(changed_trees(changes));
+            state
+                .apply(&self.buffer_manager, changes, commit_ts)
+                .map_err(|_apply_error| {
+                    self.log.poison();
+                    DbError::Poisoned
+                })
+        });
+        state.last_commit_ts = commit_ts;
+        for (tree, key) in changed_keys(changes) {
+            state.pending_writes.remove(&(tree, key.to_vec()));
+        }
+        for change in changes {
+            if let TransactionChange::DropTree(drop_tree) = change {
+                state.pending_drops.remove(&drop_tree.tree());
+            }
+        }
+        state.next_to_apply += 1;
+        self.applied.notify_all();
+        result?;
+        state.active.insert(id, commit_ts);
+        self.purge(&mut state)?;
+        let log_size = self.log.next_lsn() - state.checkpoint_lsn;
+        if self
+            .checkpoint_log_size
+            .is_some_and(|checkpoint_log_size| log_size >= checkpoint_log_size)
+        {
+            state.is_checkpoint_due = true;
+            self.checkpoint_due.notify_one();
+        }
+        Ok(commit_ts)
+    }
+}
+
+impl ManagerState {
+    // The first committer wins. The keys inserted must not be present after
+    // the commits before, unless the transaction has written them before the
+    // insert. The trees created by the changes have no committed keys. A tree
+    // dropped conflicts with any write to it.
+    fn check_write_conflicts(
+        &self,
+        buffer_manager: &BufferManager,
+        changes: &[TransactionChange],
+        snapshot_ts: u64,
+    ) -> Result<(), DbError> {
+        let new_trees: Vec<_> = changes
+            .iter()
+            .filter_map(|change| match change {
+                TransactionChange::NewNode(new_node) => Some(new_node.node_id()),
+                _ => None,
+            })
+            .collect();
+        let mut checked = BTreeSet::new();
+        for change in changes {
+            let (tree, key) = match change {
+                TransactionChange::Insert(insert) | TransactionChange::Upsert(insert) => {
+                    (insert.tree(), insert.key())
+                }
+                TransactionChange::Delete(delete) => (delete.tree(), delete.key()),
+                TransactionChange::DropTree(drop_tree) => {
+                    let tree = drop_tree.tree();
+                    if !new_trees.contains(&tree) && self.is_tree_written(tree, snapshot_ts) {
+                        return Err(DbError::WriteConflict);
+                    }
+                    continue;
+                }
+                TransactionChange::NewNode(_) => continue,
+            };
+            if new_trees.contains(&tree) || !checked.insert((tree, key)) {
+                continue;
+            }
+            if self.pending_drops.contains(&tree) || !art::exists(buffer_manager, tree, u64::MAX) {
+                return Err(DbError::WriteConflict);
+            }
+            let pending = self.pending_writes.get(&(tree, key.to_vec())).copied();
+            if matches!(change, TransactionChange::Insert(_)) {
+                let is_present = match pending {
+                    Some(is_present) => is_present,
+                    None => art::get(buffer_manager, tree, key, u64::MAX)?.is_some(),
+                };
+                if is_present {
+                    return Err(DbError::KeyExists);
+                }
+            }
+            let is_conflict = pending.is_some()
+                || art::commit_ts(buffer_manager, tree, key)?
+                    .is_some_and(|commit_ts| commit_ts > snapshot_ts);
+            if is_conflict {
+                return Err(DbError::WriteConflict);
+            }
+        }
+        Ok(())
+    }
+
+    // Whether the tree has been dropped or written by a commit after the
+    // snapshot, or is about to be
+    fn is_tree_written(&self, tree: node::Id, snapshot_ts: u64) -> bool {
+        let is_write_pending = self
+            .pending_writes
+            .range((tree, Vec::new())..)
+            .next()
+            .is_some_and(|((pending_tree, _), _)| *pending_tree == tree);
+        is_write_pending
+            || self.pending_drops.contains(&tree)
+            || self
+                .tree_commit_ts
+                .get(&tree)
+                .is_some_and(|commit_ts| *commit_ts > snapshot_ts)
+    }
+
+    // The changes of a commit at the timestamp, or of a redo at zero. The
+    // trees dropped by a commit stay for the older snapshots until purged.
+    fn apply(
+        &mut self,
+        buffer_manager: &BufferManager,
+        changes: &[TransactionChange],
+        commit_ts: u64,
+    ) -> Result<(), DbError> {
+        for change in changes {
+            match change {
+                TransactionChange::NewNode(new_node) => {
+                    art::create(buffer_manager, new_node.node_id())?;
+                }
+                TransactionChange::Insert(insert) => {
+                    art::insert(
+                        buffer_manager,
+                        insert.tree(),
+                        insert.key(),
+                        insert.value(),
+                        commit_ts,
+                    )?;
+                }
+                TransactionChange::Upsert(upsert) => {
+                    art::upsert(
+                        buffer_manager,
+                        upsert.tree(),
+                        upsert.key(),
+                        upsert.value(),
+                        commit_ts,
+                    )?;
+                }
+                TransactionChange::Delete(delete) => {
+                    art::delete(buffer_manager, delete.tree(), delete.key(), commit_ts)?;
+                }
+                TransactionChange::DropTree(drop_tree) if commit_ts == 0 => {
+                    art::destroy(buffer_manager, drop_tree.tree())?;
+                }
+                TransactionChange::DropTree(drop_tree) => {
+                    art::mark_dropped(buffer_manager, drop_tree.tree(), commit_ts)?;
+                }
+            }
+        }
+        if commit_ts != 0 {
+            for tree in changed_trees(changes) {
+                self.tree_commit_ts.insert(tree, commit_ts);
+            }
+            for (tree, key) in changed_keys(changes) {
+                let unpurged = Unpurged::Key(tree, key.to_vec());
+                self.unpurged.push_back((commit_ts, unpurged));
+            }
+            for change in changes {
+                if let TransactionChange::DropTree(drop_tree) = change {
+                    let unpurged = Unpurged::DroppedTree(drop_tree.tree());
+                    self.unpurged.push_back((commit_ts, unpurged));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn oldest_snapshot_ts(&self) -> u64 {
+        self.active.values().min().copied().unwrap_or(u64::MAX)
     }
 }
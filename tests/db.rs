@@ -2,14 +2,20 @@
 #![deny(clippy::pedantic)]
 #![allow(clippy::unwrap_used)]
 
+use kirunadb::catalog::Keyspace;
+use kirunadb::cursor::DetachedCursor;
+use kirunadb::key::{Collation, Column, ColumnType, KeySchema, Order};
 use kirunadb::transaction_manager::Transaction;
-use kirunadb::Db;
+use kirunadb::{Db, DbError, DbOptions, Durability, EvictionPolicy};
 use kirunadb_test_helpers::get_temp_dir;
 use kirunadb_test_helpers::open_db_err;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
 use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
 
 fn commit_ok(mut t: Transaction) {
     let commit_result = t.commit();
@@ -18,7 +24,7 @@ fn commit_ok(mut t: Transaction) {
 
 #[must_use]
 fn open_log_for_corruption(db_path: &Path) -> File {
-    let log_path = db_path.join("LOG");
+    let log_path = db_path.join("LOG.00000000000000000000");
     OpenOptions::new()
         .read(true)
         .write(true)
@@ -31,31 +37,68 @@ fn expect_u64(file: &mut File, offset: u64, value: u64) {
     file.seek(SeekFrom::Start(offset)).unwrap();
     let mut u64_buf = [0; 8];
     file.read_exact(&mut u64_buf).unwrap();
-    let existing = u64::from_ne_bytes(u64_buf);
+    let existing = u64::from_le_bytes(u64_buf);
     assert_eq!(existing, value);
 }
 
 fn replace_u64(file: &mut File, offset: u64, expected: u64, new: u64) {
     expect_u64(file, offset, expected);
     file.seek(SeekFrom::Start(offset)).unwrap();
-    file.write_all(&new.to_ne_bytes()).unwrap();
+    file.write_all(&new.to_le_bytes()).unwrap();
+}
+
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 0 {
+                crc >> 1
+            } else {
+                (crc >> 1) ^ 0x82F6_3B78
+            };
+        }
+    }
+    !crc
+}
+
+const LOG_RECORD_HEADER_SIZE: u64 = 24;
+
+// A transaction begin or commit record has the type byte only
+const LOG_BOUNDARY_RECORD_SIZE: u64 = LOG_RECORD_HEADER_SIZE + 1;
+
+// The log starts with the reservation of the first transaction IDs
+const FIRST_TRANSACTION_LSN: u64 = LOG_RECORD_HEADER_SIZE + 9;
+
+// Recomputes the checksum of the corrupted log record, so that the corruption
+// is found in its contents
+fn fix_log_record_checksum(file: &mut File, lsn: u64) {
+    let mut header = [0; 24];
+    file.seek(SeekFrom::Start(lsn)).unwrap();
+    file.read_exact(&mut header).unwrap();
+    let payload_len = u32::from_le_bytes(header[16..20].try_into().unwrap());
+    let mut record = header[..20].to_vec();
+    record.resize(20 + usize::try_from(payload_len).unwrap(), 0);
+    file.read_exact(&mut record[20..]).unwrap();
+    file.seek(SeekFrom::Start(lsn + 20)).unwrap();
+    file.write_all(&crc32c(&record).to_le_bytes()).unwrap();
 }
 
 fn replace_u8(file: &mut File, offset: u64, expected: u8, new: u8) {
     file.seek(SeekFrom::Start(offset)).unwrap();
     let mut u8_buf = [0; 1];
     file.read_exact(&mut u8_buf).unwrap();
-    let existing = u8::from_ne_bytes(u8_buf);
+    let existing = u8::from_le_bytes(u8_buf);
     assert_eq!(existing, expected);
     file.seek(SeekFrom::Start(offset)).unwrap();
-    file.write_all(&new.to_ne_bytes()).unwrap();
+    file.write_all(&new.to_le_bytes()).unwrap();
 }
 
 #[test]
 fn sequential_transaction_ids() {
     let temp_dir = get_temp_dir();
     let path = temp_dir.path();
-    let mut db = Db::open(path).unwrap();
+    let db = Db::open(path).unwrap();
     let t1 = db.begin_transaction();
     let t1_id = t1.id();
     commit_ok(t1);
@@ -69,7 +112,7 @@ fn sequential_transaction_ids() {
 fn interleaved_transaction_ids() {
     let temp_dir = get_temp_dir();
     let path = temp_dir.path();
-    let mut db = Db::open(path).unwrap();
+    let db = Db::open(path).unwrap();
     let t1 = db.begin_transaction();
     let t1_id = t1.id();
     let t2 = db.begin_transaction();
@@ -83,7 +126,7 @@ fn interleaved_transaction_ids() {
 fn transaction_new_node() {
     let temp_dir = get_temp_dir();
     let path = temp_dir.path();
-    let mut db = Db::open(path).unwrap();
+    let db = Db::open(path).unwrap();
     let mut transaction = db.begin_transaction();
     let _new_node_id = transaction.new_art_descriptor_node();
     commit_ok(transaction);
@@ -93,7 +136,7 @@ fn transaction_new_node() {
 fn transaction_two_new_nodes() {
     let temp_dir = get_temp_dir();
     let path = temp_dir.path();
-    let mut db = Db::open(path).unwrap();
+    let db = Db::open(path).unwrap();
     let mut t1 = db.begin_transaction();
     let t1_new_node_id = t1.new_art_descriptor_node();
     commit_ok(t1);
@@ -109,13 +152,13 @@ fn node_id_assignment_consistent_on_reopen() {
     let path = temp_dir.path();
     let n1_id;
     {
-        let mut created_db = Db::open(path).unwrap();
+        let created_db = Db::open(path).unwrap();
         let mut transaction = created_db.begin_transaction();
         n1_id = transaction.new_art_descriptor_node();
         commit_ok(transaction);
     }
     {
-        let mut opened_db = Db::open(path).unwrap();
+        let opened_db = Db::open(path).unwrap();
         let mut transaction = opened_db.begin_transaction();
         let n2_id = transaction.new_art_descriptor_node();
         commit_ok(transaction);
@@ -130,7 +173,7 @@ fn node_id_assignment_consistent_on_reopen_two_ids() {
     let n1_id;
     let n2_id;
     {
-        let mut created_db = Db::open(path).unwrap();
+        let created_db = Db::open(path).unwrap();
         let mut t1 = created_db.begin_transaction();
         n1_id = t1.new_art_descriptor_node();
         commit_ok(t1);
@@ -139,7 +182,7 @@ fn node_id_assignment_consistent_on_reopen_two_ids() {
         commit_ok(t2);
     }
     {
-        let mut opened_db = Db::open(path).unwrap();
+        let opened_db = Db::open(path).unwrap();
         let mut transaction = opened_db.begin_transaction();
         let n3_id = transaction.new_art_descriptor_node();
         commit_ok(transaction);
@@ -155,7 +198,7 @@ fn node_id_assignment_consistent_on_reopen_two_ids_lower_id_committed_later() {
     let n1_id;
     let n2_id;
     {
-        let mut created_db = Db::open(path).unwrap();
+        let created_db = Db::open(path).unwrap();
         let mut t1 = created_db.begin_transaction();
         n1_id = t1.new_art_descriptor_node();
         let mut t2 = created_db.begin_transaction();
@@ -164,7 +207,7 @@ fn node_id_assignment_consistent_on_reopen_two_ids_lower_id_committed_later() {
         commit_ok(t1);
     }
     {
-        let mut opened_db = Db::open(path).unwrap();
+        let opened_db = Db::open(path).unwrap();
         let mut transaction = opened_db.begin_transaction();
         let n3_id = transaction.new_art_descriptor_node();
         commit_ok(transaction);
@@ -180,7 +223,7 @@ fn node_id_assignment_corruption_repeated_id() {
     let n1_id;
     let n2_id;
     {
-        let mut created_db = Db::open(path).unwrap();
+        let created_db = Db::open(path).unwrap();
         let mut t1 = created_db.begin_transaction();
         n1_id = t1.new_art_descriptor_node();
         commit_ok(t1);
@@ -190,8 +233,21 @@ fn node_id_assignment_corruption_repeated_id() {
     }
     {
         let mut log_file = open_log_for_corruption(path);
-        expect_u64(&mut log_file, 1, n1_id.as_u64());
-        replace_u64(&mut log_file, 10, n2_id.as_u64(), n1_id.as_u64());
+        // Each transaction is framed by its begin and commit records
+        let n1_lsn = FIRST_TRANSACTION_LSN + LOG_BOUNDARY_RECORD_SIZE;
+        expect_u64(
+            &mut log_file,
+            n1_lsn + LOG_RECORD_HEADER_SIZE + 1,
+            n1_id.as_u64(),
+        );
+        let n2_lsn = n1_lsn + LOG_RECORD_HEADER_SIZE + 9 + 2 * LOG_BOUNDARY_RECORD_SIZE;
+        replace_u64(
+            &mut log_file,
+            n2_lsn + LOG_RECORD_HEADER_SIZE + 1,
+            n2_id.as_u64(),
+            n1_id.as_u64(),
+        );
+        fix_log_record_checksum(&mut log_file, n2_lsn);
     }
     open_db_err(path);
 }
@@ -201,14 +257,1403 @@ fn log_corruption_unknown_type() {
     let temp_dir = get_temp_dir();
     let path = temp_dir.path();
     {
-        let mut created_db = Db::open(path).unwrap();
+        let created_db = Db::open(path).unwrap();
         let mut transaction = created_db.begin_transaction();
         let _n = transaction.new_art_descriptor_node();
         commit_ok(transaction);
     }
     {
         let mut log_file = open_log_for_corruption(path);
-        replace_u8(&mut log_file, 0, 0, 0xBD);
+        let lsn = FIRST_TRANSACTION_LSN + LOG_BOUNDARY_RECORD_SIZE;
+        replace_u8(&mut log_file, lsn + LOG_RECORD_HEADER_SIZE, 0, 0xBD);
+        fix_log_record_checksum(&mut log_file, lsn);
     }
-    open_db_err(path);
+    assert!(matches!(
+        Db::open(path),
+        Err(DbError::BadLogRecordType { bad_type: 0xBD })
+    ));
+}
+
+#[test]
+fn insert_get_delete() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let db = Db::open(path).unwrap();
+    let mut t1 = db.begin_transaction();
+    let tree = t1.new_art_descriptor_node();
+    t1.insert(tree, b"key", b"value").unwrap();
+    assert_eq!(t1.get(tree, b"key").unwrap().unwrap(), b"value");
+    commit_ok(t1);
+    let mut t2 = db.begin_transaction();
+    assert_eq!(t2.get(tree, b"key").unwrap().unwrap(), b"value");
+    assert_eq!(t2.get(tree, b"ke").unwrap(), None);
+    assert!(t2.delete(tree, b"key").unwrap());
+    assert!(!t2.delete(tree, b"key").unwrap());
+    assert_eq!(t2.get(tree, b"key").unwrap(), None);
+    commit_ok(t2);
+    let t3 = db.begin_transaction();
+    assert_eq!(t3.get(tree, b"key").unwrap(), None);
+}
+
+#[test]
+fn insert_existing_key() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let db = Db::open(path).unwrap();
+    let mut transaction = db.begin_transaction();
+    let tree = transaction.new_art_descriptor_node();
+    transaction.insert(tree, b"key", b"1").unwrap();
+    assert!(matches!(
+        transaction.insert(tree, b"key", b"2"),
+        Err(DbError::KeyExists)
+    ));
+    transaction.upsert(tree, b"key", b"3").unwrap();
+    assert_eq!(transaction.get(tree, b"key").unwrap().unwrap(), b"3");
+    commit_ok(transaction);
+}
+
+#[test]
+fn uncommitted_changes_not_visible() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let db = Db::open(path).unwrap();
+    let mut t1 = db.begin_transaction();
+    let tree = t1.new_art_descriptor_node();
+    commit_ok(t1);
+    let mut t2 = db.begin_transaction();
+    t2.upsert(tree, b"key", b"value").unwrap();
+    let t3 = db.begin_transaction();
+    assert_eq!(t3.get(tree, b"key").unwrap(), None);
+    drop(t2);
+    let t4 = db.begin_transaction();
+    assert_eq!(t4.get(tree, b"key").unwrap(), None);
+}
+
+#[test]
+fn rollback() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let tree;
+    {
+        let db = Db::open(path).unwrap();
+        let mut t1 = db.begin_transaction();
+        tree = t1.create_keyspace("k", untyped(), b"").unwrap();
+        t1.insert(tree, b"a", b"a").unwrap();
+        commit_ok(t1);
+        let mut t2 = db.begin_transaction();
+        t2.upsert(tree, b"a", b"b").unwrap();
+        t2.insert(tree, b"c", b"c").unwrap();
+        let rolled_back_tree = t2.create_keyspace("l", untyped(), b"").unwrap();
+        t2.rollback();
+        assert_eq!(t2.get(tree, b"a").unwrap().unwrap(), b"a");
+        assert!(t2.get(tree, b"c").unwrap().is_none());
+        assert!(matches!(
+            t2.open_keyspace("l"),
+            Err(DbError::NoSuchKeyspace { .. })
+        ));
+        // Usable after the rollback, reusing the freed node ID
+        assert_eq!(
+            t2.create_keyspace("m", untyped(), b"").unwrap(),
+            rolled_back_tree
+        );
+        t2.insert(tree, b"d", b"d").unwrap();
+        commit_ok(t2);
+        // Rolled back by dropping
+        let mut t3 = db.begin_transaction();
+        let dropped_tree = t3.new_art_descriptor_node();
+        t3.delete(tree, b"a").unwrap();
+        drop(t3);
+        let mut t4 = db.begin_transaction();
+        assert_eq!(t4.get(tree, b"a").unwrap().unwrap(), b"a");
+        assert_eq!(t4.new_art_descriptor_node(), dropped_tree);
+    }
+    let db = Db::open(path).unwrap();
+    let transaction = db.begin_transaction();
+    assert_eq!(transaction.get(tree, b"a").unwrap().unwrap(), b"a");
+    assert!(transaction.get(tree, b"c").unwrap().is_none());
+    assert_eq!(transaction.get(tree, b"d").unwrap().unwrap(), b"d");
+    assert!(transaction.open_keyspace("l").is_err());
+    assert!(transaction.open_keyspace("m").is_ok());
+}
+
+#[test]
+fn savepoints() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let tree;
+    {
+        let db = Db::open(path).unwrap();
+        let mut t1 = db.begin_transaction();
+        tree = t1.create_keyspace("k", untyped(), b"").unwrap();
+        t1.insert(tree, b"a", b"a").unwrap();
+        let s1 = t1.savepoint();
+        t1.upsert(tree, b"a", b"b").unwrap();
+        let rolled_back_tree = t1.create_keyspace("l", untyped(), b"").unwrap();
+        let s2 = t1.savepoint();
+        t1.drop_keyspace("k").unwrap();
+        t1.rollback_to_savepoint(s2).unwrap();
+        assert_eq!(t1.get(tree, b"a").unwrap().unwrap(), b"b");
+        // Rolled back again to the kept savepoint
+        t1.delete(tree, b"a").unwrap();
+        t1.rollback_to_savepoint(s2).unwrap();
+        assert_eq!(t1.get(tree, b"a").unwrap().unwrap(), b"b");
+        t1.rollback_to_savepoint(s1).unwrap();
+        assert_eq!(t1.get(tree, b"a").unwrap().unwrap(), b"a");
+        assert!(t1.open_keyspace("l").is_err());
+        assert!(matches!(
+            t1.rollback_to_savepoint(s2),
+            Err(DbError::NoSuchSavepoint)
+        ));
+        // The node ID of the rolled back tree is reused
+        assert_eq!(t1.new_art_descriptor_node(), rolled_back_tree);
+        let s3 = t1.savepoint();
+        t1.insert(tree, b"c", b"c").unwrap();
+        t1.release_savepoint(s3).unwrap();
+        assert!(matches!(
+            t1.rollback_to_savepoint(s3),
+            Err(DbError::NoSuchSavepoint)
+        ));
+        t1.release_savepoint(s1).unwrap();
+        assert!(matches!(
+            t1.release_savepoint(s1),
+            Err(DbError::NoSuchSavepoint)
+        ));
+        // A released savepoint is not taken for a later one
+        let s4 = t1.savepoint();
+        assert_ne!(s4, s1);
+        t1.upsert(tree, b"a", b"d").unwrap();
+        assert!(matches!(
+            t1.rollback_to_savepoint(s1),
+            Err(DbError::NoSuchSavepoint)
+        ));
+        t1.rollback_to_savepoint(s4).unwrap();
+        commit_ok(t1);
+    }
+    let db = Db::open(path).unwrap();
+    let transaction = db.begin_transaction();
+    assert_eq!(transaction.get(tree, b"a").unwrap().unwrap(), b"a");
+    assert_eq!(transaction.get(tree, b"c").unwrap().unwrap(), b"c");
+    assert!(transaction.open_keyspace("l").is_err());
+}
+
+#[test]
+fn operations_on_non_tree() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let db = Db::open(path).unwrap();
+    let mut t1 = db.begin_transaction();
+    let tree = t1.new_art_descriptor_node();
+    commit_ok(t1);
+    let mut t2 = db.begin_transaction();
+    let not_tree = tree.next();
+    assert!(matches!(
+        t2.get(not_tree, b"key"),
+        Err(DbError::NotArtDescriptor { .. })
+    ));
+    assert!(t2.insert(not_tree, b"key", b"value").is_err());
+    assert!(t2.upsert(not_tree, b"key", b"value").is_err());
+    assert!(t2.delete(not_tree, b"key").is_err());
+}
+
+#[test]
+fn data_persists_on_reopen() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let tree;
+    {
+        let db = Db::open(path).unwrap();
+        let mut t1 = db.begin_transaction();
+        tree = t1.new_art_descriptor_node();
+        t1.insert(tree, b"a", b"1").unwrap();
+        t1.insert(tree, b"ab", b"2").unwrap();
+        t1.insert(tree, b"b", b"3").unwrap();
+        commit_ok(t1);
+        let mut t2 = db.begin_transaction();
+        t2.upsert(tree, b"a", b"4").unwrap();
+        assert!(t2.delete(tree, b"b").unwrap());
+        commit_ok(t2);
+        let mut t3 = db.begin_transaction();
+        t3.upsert(tree, b"uncommitted", b"5").unwrap();
+    }
+    {
+        let db = Db::open(path).unwrap();
+        let mut transaction = db.begin_transaction();
+        assert_eq!(transaction.get(tree, b"a").unwrap().unwrap(), b"4");
+        assert_eq!(transaction.get(tree, b"ab").unwrap().unwrap(), b"2");
+        assert_eq!(transaction.get(tree, b"b").unwrap(), None);
+        assert_eq!(transaction.get(tree, b"uncommitted").unwrap(), None);
+        let new_tree = transaction.new_art_descriptor_node();
+        assert_ne!(new_tree, tree);
+        commit_ok(transaction);
+    }
+}
+
+#[test]
+fn concurrent_transactions() {
+    const THREADS: u8 = 8;
+    const KEYS_PER_THREAD: u8 = 16;
+    fn assert_send_sync<T: Send + Sync>() {}
+    fn assert_send<T: Send>() {}
+    assert_send_sync::<Db>();
+    assert_send::<Transaction>();
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let tree;
+    {
+        let db = Db::open(path).unwrap();
+        let mut transaction = db.begin_transaction();
+        tree = transaction.new_art_descriptor_node();
+        commit_ok(transaction);
+        std::thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let db = &db;
+                scope.spawn(move || {
+                    for key in 0..KEYS_PER_THREAD {
+                        let mut transaction = db.begin_transaction();
+                        transaction.insert(tree, &[thread, key], &[key]).unwrap();
+                        commit_ok(transaction);
+                    }
+                });
+            }
+        });
+    }
+    let db = Db::open(path).unwrap();
+    let transaction = db.begin_transaction();
+    assert_eq!(
+        transaction.count_prefix(tree, b"").unwrap(),
+        usize::from(THREADS) * usize::from(KEYS_PER_THREAD)
+    );
+    for thread in 0..THREADS {
+        for key in 0..KEYS_PER_THREAD {
+            assert_eq!(
+                transaction.get(tree, &[thread, key]).unwrap().unwrap(),
+                [key]
+            );
+        }
+    }
+}
+
+#[test]
+fn reads_during_commits() {
+    const KEYS: u8 = 64;
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let db = Db::open(path).unwrap();
+    let mut transaction = db.begin_transaction();
+    let read_tree = transaction.new_art_descriptor_node();
+    let written_tree = transaction.new_art_descriptor_node();
+    for key in 0..KEYS {
+        transaction.insert(read_tree, &[key], &[key]).unwrap();
+    }
+    commit_ok(transaction);
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            let db = &db;
+            scope.spawn(move || {
+                let transaction = db.begin_transaction();
+                for _ in 0..8 {
+                    assert_eq!(
+                        transaction.count_prefix(read_tree, b"").unwrap(),
+                        usize::from(KEYS)
+                    );
+                    for key in 0..KEYS {
+                        assert_eq!(transaction.get(read_tree, &[key]).unwrap().unwrap(), [key]);
+                    }
+                }
+            });
+        }
+        for key in 0..KEYS {
+            let mut transaction = db.begin_transaction();
+            transaction.insert(written_tree, &[key], &[key]).unwrap();
+            transaction.upsert(read_tree, &[key], &[key]).unwrap();
+            commit_ok(transaction);
+        }
+    });
+    let transaction = db.begin_transaction();
+    assert_eq!(
+        transaction.count_prefix(written_tree, b"").unwrap(),
+        usize::from(KEYS)
+    );
+}
+
+#[test]
+fn snapshot_isolation() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let db = Db::open(path).unwrap();
+    let mut t1 = db.begin_transaction();
+    let tree = t1.new_art_descriptor_node();
+    for key in [b"a", b"b", b"c"] {
+        t1.insert(tree, key, b"1").unwrap();
+    }
+    commit_ok(t1);
+    let mut reader = db.begin_transaction();
+    let mut scan = reader.range(tree, Bound::Unbounded, Bound::Unbounded);
+    assert_eq!(
+        scan.next().unwrap().unwrap(),
+        (b"a".to_vec(), b"1".to_vec())
+    );
+    // Committed in the middle of the scan
+    let mut t2 = db.begin_transaction();
+    t2.upsert(tree, b"b", b"2").unwrap();
+    assert!(t2.delete(tree, b"c").unwrap());
+    t2.insert(tree, b"d", b"2").unwrap();
+    commit_ok(t2);
+    let rest: Vec<_> = scan.map(Result::unwrap).collect();
+    assert_eq!(
+        rest,
+        [
+            (b"b".to_vec(), b"1".to_vec()),
+            (b"c".to_vec(), b"1".to_vec())
+        ]
+    );
+    assert_eq!(reader.get(tree, b"d").unwrap(), None);
+    assert_eq!(reader.count_prefix(tree, b"").unwrap(), 3);
+    let t3 = db.begin_transaction();
+    assert_eq!(keys(t3.scan_prefix(tree, b"")), [b"a", b"b", b"d"]);
+    assert_eq!(t3.get(tree, b"b").unwrap().unwrap(), b"2");
+    // A new snapshot sees the commit
+    reader.rollback();
+    assert_eq!(reader.count_prefix(tree, b"").unwrap(), 3);
+    assert_eq!(reader.get(tree, b"c").unwrap(), None);
+    assert_eq!(reader.get(tree, b"d").unwrap().unwrap(), b"2");
+}
+
+#[test]
+fn write_conflict() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let db = Db::open(path).unwrap();
+    let mut t1 = db.begin_transaction();
+    let tree = t1.new_art_descriptor_node();
+    t1.insert(tree, b"key", b"1").unwrap();
+    commit_ok(t1);
+    let mut t2 = db.begin_transaction();
+    let mut t3 = db.begin_transaction();
+    let mut t4 = db.begin_transaction();
+    t2.upsert(tree, b"key", b"2").unwrap();
+    t3.upsert(tree, b"key", b"3").unwrap();
+    t4.upsert(tree, b"other", b"4").unwrap();
+    commit_ok(t2);
+    assert!(matches!(t3.commit(), Err(DbError::WriteConflict)));
+    // Other keys do not conflict
+    commit_ok(t4);
+    t3.rollback();
+    assert_eq!(t3.get(tree, b"key").unwrap().unwrap(), b"2");
+    t3.upsert(tree, b"key", b"3").unwrap();
+    t3.commit().unwrap();
+    // An insert of a key inserted concurrently is a duplicate
+    let mut t5 = db.begin_transaction();
+    let mut t6 = db.begin_transaction();
+    t5.insert(tree, b"new", b"5").unwrap();
+    t6.insert(tree, b"new", b"6").unwrap();
+    commit_ok(t5);
+    assert!(matches!(t6.commit(), Err(DbError::KeyExists)));
+    drop(t6);
+    let t7 = db.begin_transaction();
+    assert_eq!(t7.get(tree, b"key").unwrap().unwrap(), b"3");
+    assert_eq!(t7.get(tree, b"new").unwrap().unwrap(), b"5");
+}
+
+#[test]
+fn snapshot_across_evictions_and_checkpoints() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let options = DbOptions {
+        buffer_pool_size: 16 * 1024,
+        checkpoint_log_size: None,
+        ..DbOptions::default()
+    };
+    let keys: Vec<_> = (0..500_u32).map(u32::to_be_bytes).collect();
+    let tree;
+    {
+        let db = Db::open_with_options(path, &options).unwrap();
+        let mut t1 = db.begin_transaction();
+        tree = t1.create_keyspace("k", untyped(), b"").unwrap();
+        for key in &keys {
+            t1.insert(tree, key, b"old").unwrap();
+        }
+        commit_ok(t1);
+        let reader = db.begin_transaction();
+        let mut t2 = db.begin_transaction();
+        for (i, key) in keys.iter().enumerate() {
+            if i % 2 == 0 {
+                assert!(t2.delete(tree, key).unwrap());
+            } else {
+                t2.upsert(tree, key, b"new").unwrap();
+            }
+        }
+        commit_ok(t2);
+        // The deleted keys are still in the checkpoint image for the reader
+        db.checkpoint().unwrap();
+        assert!(db.buffer_pool_stats().evictions > 0);
+        assert_eq!(reader.count_prefix(tree, b"").unwrap(), keys.len());
+        for key in &keys {
+            assert_eq!(reader.get(tree, key).unwrap().unwrap(), b"old");
+        }
+        let t3 = db.begin_transaction();
+        assert_eq!(t3.count_prefix(tree, b"").unwrap(), keys.len() / 2);
+    }
+    let db = Db::open_with_options(path, &options).unwrap();
+    let transaction = db.begin_transaction();
+    assert_eq!(transaction.count_prefix(tree, b"").unwrap(), keys.len() / 2);
+    for (i, key) in keys.iter().enumerate() {
+        let expected = (i % 2 == 1).then(|| b"new".to_vec());
+        assert_eq!(transaction.get(tree, key).unwrap(), expected);
+    }
+}
+
+fn keys(entries: impl Iterator<Item = Result<(Vec<u8>, Vec<u8>), DbError>>) -> Vec<Vec<u8>> {
+    entries.map(|entry| entry.unwrap().0).collect()
+}
+
+#[test]
+fn cursor_seek_and_move() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let db = Db::open(path).unwrap();
+    let mut t1 = db.begin_transaction();
+    let tree = t1.new_art_descriptor_node();
+    for key in [&b"b"[..], b"d", b"da", b"f"] {
+        t1.insert(tree, key, key).unwrap();
+    }
+    commit_ok(t1);
+    let t2 = db.begin_transaction();
+    let mut cursor = t2.cursor(tree);
+    assert!(!cursor.is_valid());
+    assert!(cursor.seek(b"c").unwrap());
+    assert_eq!(cursor.key().unwrap(), b"d");
+    assert_eq!(cursor.value().unwrap(), b"d");
+    assert!(cursor.move_next().unwrap());
+    assert_eq!(cursor.key().unwrap(), b"da");
+    assert!(cursor.move_prev().unwrap());
+    assert!(cursor.move_prev().unwrap());
+    assert_eq!(cursor.key().unwrap(), b"b");
+    assert!(!cursor.move_prev().unwrap());
+    assert!(!cursor.is_valid());
+    assert!(cursor.seek_for_prev(b"e").unwrap());
+    assert_eq!(cursor.key().unwrap(), b"da");
+    assert!(!cursor.seek(b"g").unwrap());
+    assert!(!cursor.seek_for_prev(b"a").unwrap());
+    assert!(cursor.seek_to_last().unwrap());
+    assert_eq!(cursor.key().unwrap(), b"f");
+    assert!(!cursor.move_next().unwrap());
+    assert!(cursor.seek_to_first().unwrap());
+    assert_eq!(cursor.key().unwrap(), b"b");
+}
+
+#[test]
+fn cursor_iterator() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let db = Db::open(path).unwrap();
+    let mut transaction = db.begin_transaction();
+    let tree = transaction.new_art_descriptor_node();
+    for key in [&b"c"[..], b"a", b"b"] {
+        transaction.insert(tree, key, key).unwrap();
+    }
+    assert_eq!(
+        keys(transaction.cursor(tree)),
+        vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]
+    );
+    let mut cursor = transaction.cursor(tree);
+    assert!(cursor.seek(b"b").unwrap());
+    assert_eq!(keys(cursor), vec![b"b".to_vec(), b"c".to_vec()]);
+}
+
+#[test]
+fn detached_cursor_across_writes() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let db = Db::open(path).unwrap();
+    let mut transaction = db.begin_transaction();
+    let tree = transaction.new_art_descriptor_node();
+    for key in [&b"a"[..], b"c", b"e"] {
+        transaction.insert(tree, key, key).unwrap();
+    }
+    let mut cursor = DetachedCursor::new(tree);
+    assert!(!cursor.move_next(&transaction).unwrap());
+    assert!(cursor.seek(&transaction, b"b").unwrap());
+    assert_eq!(cursor.key().unwrap(), b"c");
+    // Updating the current key and adding the next one, as an index scan
+    // updating the rows does
+    transaction.upsert(tree, b"c", b"updated").unwrap();
+    transaction.insert(tree, b"d", b"d").unwrap();
+    assert!(transaction.delete(tree, b"e").unwrap());
+    assert_eq!(cursor.value().unwrap(), b"c");
+    assert!(cursor.move_next(&transaction).unwrap());
+    assert_eq!(cursor.key().unwrap(), b"d");
+    assert!(!cursor.move_next(&transaction).unwrap());
+    assert!(cursor.seek_for_prev(&transaction, b"c").unwrap());
+    assert_eq!(cursor.value().unwrap(), b"updated");
+    assert!(cursor.move_prev(&transaction).unwrap());
+    assert_eq!(cursor.key().unwrap(), b"a");
+    assert!(cursor.seek_to_last(&transaction).unwrap());
+    assert_eq!(cursor.key().unwrap(), b"d");
+    assert!(cursor.seek_to_first(&transaction).unwrap());
+    assert_eq!(cursor.key().unwrap(), b"a");
+}
+
+#[test]
+fn range_merges_own_writes() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let db = Db::open(path).unwrap();
+    let mut t1 = db.begin_transaction();
+    let tree = t1.new_art_descriptor_node();
+    for key in [&b"a"[..], b"b", b"c", b"d", b"e"] {
+        t1.insert(tree, key, b"committed").unwrap();
+    }
+    commit_ok(t1);
+    let mut t2 = db.begin_transaction();
+    assert!(t2.delete(tree, b"b").unwrap());
+    t2.insert(tree, b"bb", b"own").unwrap();
+    t2.upsert(tree, b"c", b"own").unwrap();
+    assert!(t2.delete(tree, b"e").unwrap());
+    let all: Vec<_> = t2
+        .range(tree, Bound::Unbounded, Bound::Unbounded)
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(
+        all,
+        vec![
+            (b"a".to_vec(), b"committed".to_vec()),
+            (b"bb".to_vec(), b"own".to_vec()),
+            (b"c".to_vec(), b"own".to_vec()),
+            (b"d".to_vec(), b"committed".to_vec()),
+        ]
+    );
+    let lower = Bound::Excluded(&b"a"[..]);
+    let upper = Bound::Included(&b"d"[..]);
+    assert_eq!(
+        keys(t2.range(tree, lower, upper).rev()),
+        vec![b"d".to_vec(), b"c".to_vec(), b"bb".to_vec()]
+    );
+    let mut range = t2.range(tree, lower, upper);
+    assert_eq!(range.next().unwrap().unwrap().0, b"bb");
+    assert_eq!(range.next_back().unwrap().unwrap().0, b"d");
+    assert_eq!(range.next().unwrap().unwrap().0, b"c");
+    assert!(range.next_back().is_none());
+    assert!(range.next().is_none());
+    assert!(t2
+        .range(tree, Bound::Excluded(b"c"), Bound::Excluded(b"c"))
+        .next()
+        .is_none());
+}
+
+#[test]
+fn scan_and_count_prefix() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let db = Db::open(path).unwrap();
+    let mut t1 = db.begin_transaction();
+    let tree = t1.new_art_descriptor_node();
+    for key in [
+        &b"t1|a|1"[..],
+        b"t1|a|2",
+        b"t1|b|1",
+        b"t2|a|1",
+        b"t1",
+        &[b't', 0xFF, 1],
+        &[b't', 0xFF, 0xFF],
+        b"u",
+    ] {
+        t1.insert(tree, key, key).unwrap();
+    }
+    assert_eq!(t1.count_prefix(tree, b"t1|").unwrap(), 3);
+    commit_ok(t1);
+    let mut t2 = db.begin_transaction();
+    assert_eq!(t2.count_prefix(tree, b"t1|").unwrap(), 3);
+    assert_eq!(
+        keys(t2.scan_prefix(tree, b"t1|a|")),
+        vec![b"t1|a|1".to_vec(), b"t1|a|2".to_vec()]
+    );
+    assert!(t2.delete(tree, b"t1|a|1").unwrap());
+    t2.insert(tree, b"t1|c|1", b"").unwrap();
+    t2.upsert(tree, b"t1|b|1", b"").unwrap();
+    t2.upsert(tree, b"t3", b"").unwrap();
+    assert_eq!(t2.count_prefix(tree, b"t1|").unwrap(), 3);
+    assert_eq!(
+        keys(t2.scan_prefix(tree, b"t1|")),
+        vec![b"t1|a|2".to_vec(), b"t1|b|1".to_vec(), b"t1|c|1".to_vec()]
+    );
+    assert_eq!(
+        keys(t2.scan_prefix(tree, &[b't', 0xFF]).rev()),
+        vec![vec![b't', 0xFF, 0xFF], vec![b't', 0xFF, 1]]
+    );
+    assert_eq!(t2.count_prefix(tree, b"").unwrap(), 9);
+    assert_eq!(t2.count_prefix(tree, b"v").unwrap(), 0);
+    assert_eq!(t2.scan_prefix(tree, b"v").count(), 0);
+}
+
+#[test]
+fn scan_prefix_across_commits() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let db = Db::open(path).unwrap();
+    let mut t1 = db.begin_transaction();
+    let tree = t1.new_art_descriptor_node();
+    for key in [&b"p|1"[..], b"p|3", b"p|5", b"q|1"] {
+        t1.insert(tree, key, b"").unwrap();
+    }
+    commit_ok(t1);
+    let t2 = db.begin_transaction();
+    let mut scan = t2.scan_prefix(tree, b"p|");
+    assert_eq!(scan.next().unwrap().unwrap().0, b"p|1");
+    // The keys are split under new nodes, while the snapshot of the scan does
+    // not see them
+    let mut t3 = db.begin_transaction();
+    for key in [&b"p|2"[..], b"p|4", b"p", b"p|"] {
+        t3.insert(tree, key, b"").unwrap();
+    }
+    assert!(t3.delete(tree, b"p|3").unwrap());
+    commit_ok(t3);
+    assert_eq!(keys(scan.by_ref()), [b"p|3", b"p|5"]);
+    assert!(scan.next().is_none());
+    let t4 = db.begin_transaction();
+    assert_eq!(
+        keys(t4.scan_prefix(tree, b"p|")),
+        [&b"p|"[..], b"p|1", b"p|2", b"p|4", b"p|5"]
+    );
+}
+
+fn names(keyspaces: &[Keyspace]) -> Vec<&str> {
+    keyspaces.iter().map(Keyspace::name).collect()
+}
+
+fn untyped() -> KeySchema {
+    KeySchema::new(Vec::new())
+}
+
+#[test]
+fn keyspaces_create_open_drop() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let db = Db::open(path).unwrap();
+    let mut t1 = db.begin_transaction();
+    let table = t1.create_keyspace("db/t", untyped(), b"").unwrap();
+    let index = t1.create_keyspace("db/t#idx", untyped(), b"").unwrap();
+    assert_ne!(table, index);
+    assert!(matches!(
+        t1.create_keyspace("db/t", untyped(), b""),
+        Err(DbError::KeyspaceExists { name }) if name == "db/t"
+    ));
+    assert_eq!(t1.open_keyspace("db/t").unwrap().tree(), table);
+    t1.insert(table, b"a", b"1").unwrap();
+    t1.insert(index, b"1", b"a").unwrap();
+    let mut t2 = db.begin_transaction();
+    assert!(t2.list_keyspaces().unwrap().is_empty());
+    assert!(matches!(
+        t2.open_keyspace("db/t"),
+        Err(DbError::NoSuchKeyspace { .. })
+    ));
+    commit_ok(t1);
+    // Not in the snapshot of t2 until it takes a new one
+    assert!(t2.list_keyspaces().unwrap().is_empty());
+    t2.rollback();
+    assert_eq!(names(&t2.list_keyspaces().unwrap()), ["db/t", "db/t#idx"]);
+    let mut t3 = db.begin_transaction();
+    t3.drop_keyspace("db/t#idx").unwrap();
+    assert_eq!(names(&t3.list_keyspaces().unwrap()), ["db/t"]);
+    assert!(matches!(
+        t3.get(index, b"1"),
+        Err(DbError::NotArtDescriptor { .. })
+    ));
+    assert!(t3.drop_keyspace("db/t#idx").is_err());
+    let recreated = t3.create_keyspace("db/t#idx", untyped(), b"").unwrap();
+    assert_ne!(recreated, index);
+    assert_eq!(t3.get(recreated, b"1").unwrap(), None);
+    assert_eq!(t2.get(index, b"1").unwrap().unwrap(), b"a");
+    commit_ok(t3);
+    // The dropped trees stay for the older snapshots
+    assert_eq!(t2.get(index, b"1").unwrap().unwrap(), b"a");
+    t2.rollback();
+    assert!(t2.get(index, b"1").is_err());
+    assert_eq!(t2.get(table, b"a").unwrap().unwrap(), b"1");
+}
+
+#[test]
+fn dropped_keyspace_read_by_older_snapshot() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let db = Db::open(path).unwrap();
+    let mut t1 = db.begin_transaction();
+    let dropped = t1.create_keyspace("dropped", untyped(), b"").unwrap();
+    for i in 0..100_u32 {
+        t1.insert(dropped, &i.to_be_bytes(), &i.to_le_bytes())
+            .unwrap();
+    }
+    commit_ok(t1);
+    let reader = db.begin_transaction();
+    let mut t2 = db.begin_transaction();
+    t2.drop_keyspace("dropped").unwrap();
+    commit_ok(t2);
+    // The new tree does not take the nodes of the dropped one while the reader
+    // may read them
+    let mut t3 = db.begin_transaction();
+    let created = t3.create_keyspace("created", untyped(), b"").unwrap();
+    assert_ne!(created, dropped);
+    for i in 0..100_u32 {
+        t3.insert(created, &i.to_le_bytes(), b"new").unwrap();
+    }
+    commit_ok(t3);
+    for i in 0..100_u32 {
+        assert_eq!(
+            reader.get(dropped, &i.to_be_bytes()).unwrap().unwrap(),
+            i.to_le_bytes()
+        );
+    }
+    assert_eq!(reader.count_prefix(dropped, b"").unwrap(), 100);
+    assert!(reader.open_keyspace("created").is_err());
+    drop(reader);
+    let t4 = db.begin_transaction();
+    assert!(matches!(
+        t4.get(dropped, b""),
+        Err(DbError::NotArtDescriptor { .. })
+    ));
+    assert_eq!(t4.count_prefix(created, b"").unwrap(), 100);
+    drop(t4);
+    // Destroyed before the reopen too
+    db.checkpoint().unwrap();
+    drop(db);
+    let db = Db::open(path).unwrap();
+    let t5 = db.begin_transaction();
+    assert_eq!(names(&t5.list_keyspaces().unwrap()), ["created"]);
+    assert!(t5.get(dropped, b"").is_err());
+    assert_eq!(t5.count_prefix(created, b"").unwrap(), 100);
+}
+
+#[test]
+fn dropped_keyspace_kept_over_checkpoint() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let dropped;
+    {
+        let db = Db::open(path).unwrap();
+        let mut t1 = db.begin_transaction();
+        dropped = t1.create_keyspace("dropped", untyped(), b"").unwrap();
+        t1.insert(dropped, b"key", b"value").unwrap();
+        commit_ok(t1);
+        let reader = db.begin_transaction();
+        let mut t2 = db.begin_transaction();
+        t2.drop_keyspace("dropped").unwrap();
+        commit_ok(t2);
+        db.checkpoint().unwrap();
+        assert_eq!(reader.get(dropped, b"key").unwrap().unwrap(), b"value");
+    }
+    let db = Db::open(path).unwrap();
+    let mut t3 = db.begin_transaction();
+    assert!(t3.list_keyspaces().unwrap().is_empty());
+    assert!(t3.get(dropped, b"key").is_err());
+    let created = t3.create_keyspace("created", untyped(), b"").unwrap();
+    t3.insert(created, b"key", b"new").unwrap();
+    commit_ok(t3);
+    let t4 = db.begin_transaction();
+    assert_eq!(t4.get(created, b"key").unwrap().unwrap(), b"new");
+}
+
+#[test]
+fn drop_and_write_conflict() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let db = Db::open(path).unwrap();
+    let mut t1 = db.begin_transaction();
+    let tree = t1.create_keyspace("t", untyped(), b"").unwrap();
+    commit_ok(t1);
+    // A write committed after the snapshot of the drop
+    let mut dropper = db.begin_transaction();
+    let mut writer = db.begin_transaction();
+    writer.upsert(tree, b"a", b"1").unwrap();
+    dropper.drop_keyspace("t").unwrap();
+    commit_ok(writer);
+    assert!(matches!(dropper.commit(), Err(DbError::WriteConflict)));
+    dropper.rollback();
+    // A drop committed before the write
+    let mut writer = db.begin_transaction();
+    writer.upsert(tree, b"b", b"2").unwrap();
+    dropper.drop_keyspace("t").unwrap();
+    commit_ok(dropper);
+    assert!(matches!(writer.commit(), Err(DbError::WriteConflict)));
+    writer.rollback();
+    assert!(writer.get(tree, b"a").is_err());
+}
+
+#[test]
+fn keyspaces_persist_on_reopen() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let schema = KeySchema::new(vec![
+        Column::new(ColumnType::I32, Order::Ascending, false),
+        Column::new(
+            ColumnType::String(Collation::CaseInsensitive),
+            Order::Descending,
+            true,
+        ),
+    ]);
+    let a;
+    {
+        let db = Db::open(path).unwrap();
+        let mut t1 = db.begin_transaction();
+        a = t1.create_keyspace("a", schema.clone(), b"options").unwrap();
+        let b = t1.create_keyspace("b", untyped(), b"").unwrap();
+        let c = t1.create_keyspace("c", untyped(), b"").unwrap();
+        t1.insert(a, b"key", b"value").unwrap();
+        t1.insert(b, b"key", b"value").unwrap();
+        t1.insert(c, b"key", b"value").unwrap();
+        commit_ok(t1);
+        let mut t2 = db.begin_transaction();
+        t2.drop_keyspace("c").unwrap();
+        commit_ok(t2);
+        let mut t3 = db.begin_transaction();
+        let _uncommitted = t3.create_keyspace("uncommitted", untyped(), b"").unwrap();
+    }
+    {
+        let db = Db::open(path).unwrap();
+        let transaction = db.begin_transaction();
+        let keyspaces = transaction.list_keyspaces().unwrap();
+        assert_eq!(names(&keyspaces), ["a", "b"]);
+        let keyspace = transaction.open_keyspace("a").unwrap();
+        assert_eq!(keyspace, keyspaces[0]);
+        assert_eq!(keyspace.tree(), a);
+        assert_eq!(keyspace.key_schema(), &schema);
+        assert_eq!(keyspace.options(), b"options");
+        assert!(keyspace.creation_lsn() <= keyspaces[1].creation_lsn());
+        assert_eq!(transaction.get(a, b"key").unwrap().unwrap(), b"value");
+    }
+}
+
+#[test]
+fn bounded_buffer_pool() {
+    for eviction_policy in [
+        EvictionPolicy::Lru,
+        EvictionPolicy::Clock,
+        EvictionPolicy::TwoQueue,
+    ] {
+        let temp_dir = get_temp_dir();
+        let path = temp_dir.path();
+        let options = DbOptions {
+            buffer_pool_size: 16 * 1024,
+            eviction_policy,
+            ..DbOptions::default()
+        };
+        let keys: Vec<_> = (0..1000_u32).map(u32::to_be_bytes).collect();
+        for reopen in [false, true] {
+            let db = Db::open_with_options(path, &options).unwrap();
+            let mut transaction = db.begin_transaction();
+            let tree = if reopen {
+                transaction.open_keyspace("k").unwrap().tree()
+            } else {
+                let tree = transaction.create_keyspace("k", untyped(), b"").unwrap();
+                for key in &keys {
+                    transaction.insert(tree, key, key).unwrap();
+                }
+                commit_ok(transaction);
+                transaction = db.begin_transaction();
+                tree
+            };
+            for key in &keys {
+                assert_eq!(transaction.get(tree, key).unwrap().unwrap(), key);
+            }
+            let stats = db.buffer_pool_stats();
+            assert!(stats.evictions > 0);
+            assert!(stats.misses > 0);
+        }
+    }
+}
+
+#[test]
+fn dropped_keyspace_tree_reused() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let tree;
+    {
+        let db = Db::open(path).unwrap();
+        let mut t1 = db.begin_transaction();
+        tree = t1.create_keyspace("a", untyped(), b"").unwrap();
+        t1.insert(tree, b"key", b"a").unwrap();
+        commit_ok(t1);
+        let mut t2 = db.begin_transaction();
+        t2.drop_keyspace("a").unwrap();
+        commit_ok(t2);
+        let mut t3 = db.begin_transaction();
+        assert_eq!(t3.create_keyspace("b", untyped(), b"").unwrap(), tree);
+        t3.insert(tree, b"key", b"b").unwrap();
+        commit_ok(t3);
+    }
+    let db = Db::open(path).unwrap();
+    let transaction = db.begin_transaction();
+    assert_eq!(names(&transaction.list_keyspaces().unwrap()), ["b"]);
+    assert_eq!(transaction.open_keyspace("b").unwrap().tree(), tree);
+    assert_eq!(transaction.get(tree, b"key").unwrap().unwrap(), b"b");
+}
+
+#[test]
+fn checkpoint_bounds_recovery() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let (a, b);
+    {
+        let db = Db::open(path).unwrap();
+        let mut t1 = db.begin_transaction();
+        a = t1.create_keyspace("a", untyped(), b"").unwrap();
+        t1.insert(a, b"key1", b"value1").unwrap();
+        commit_ok(t1);
+        let mut t2 = db.begin_transaction();
+        t2.insert(a, b"key2", b"value2").unwrap();
+        db.checkpoint().unwrap();
+        commit_ok(t2);
+        let mut t3 = db.begin_transaction();
+        b = t3.create_keyspace("b", untyped(), b"").unwrap();
+        t3.insert(b, b"key", b"value").unwrap();
+        t3.delete(a, b"key1").unwrap();
+        commit_ok(t3);
+    }
+    // The log before the checkpoint is not read anymore
+    let mut log_file = open_log_for_corruption(path);
+    replace_u8(&mut log_file, 0, 0, 0xFF);
+    let db = Db::open(path).unwrap();
+    let mut transaction = db.begin_transaction();
+    assert_eq!(names(&transaction.list_keyspaces().unwrap()), ["a", "b"]);
+    assert!(transaction.get(a, b"key1").unwrap().is_none());
+    assert_eq!(transaction.get(a, b"key2").unwrap().unwrap(), b"value2");
+    assert_eq!(transaction.get(b, b"key").unwrap().unwrap(), b"value");
+    let c = transaction.create_keyspace("c", untyped(), b"").unwrap();
+    assert_ne!(c, a);
+    assert_ne!(c, b);
+}
+
+#[test]
+fn uncommitted_node_ids_reused_after_checkpoint() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let tree;
+    {
+        let db = Db::open(path).unwrap();
+        let mut transaction = db.begin_transaction();
+        tree = transaction.create_keyspace("a", untyped(), b"").unwrap();
+        db.checkpoint().unwrap();
+    }
+    // The descriptor ID taken by the transaction active at the checkpoint is
+    // free again
+    let db = Db::open(path).unwrap();
+    let mut transaction = db.begin_transaction();
+    assert_eq!(
+        transaction.create_keyspace("b", untyped(), b"").unwrap(),
+        tree
+    );
+}
+
+#[test]
+fn automatic_checkpoints() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    // Checkpoint after every commit, and write back nodes in between
+    let options = DbOptions {
+        buffer_pool_size: 16 * 1024,
+        checkpoint_log_size: Some(1),
+        ..DbOptions::default()
+    };
+    let keys: Vec<_> = (0..1000_u32).map(u32::to_be_bytes).collect();
+    let (checkpointed_keys, new_keys) = keys.split_at(500);
+    let tree;
+    {
+        let db = Db::open_with_options(path, &options).unwrap();
+        let mut transaction = db.begin_transaction();
+        tree = transaction.create_keyspace("k", untyped(), b"").unwrap();
+        commit_ok(transaction);
+        // In the background
+        let start = Instant::now();
+        while !path.join("CHECKPOINT").exists() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+        for chunk in checkpointed_keys.chunks(100) {
+            let mut transaction = db.begin_transaction();
+            for key in chunk {
+                transaction.insert(tree, key, key).unwrap();
+            }
+            commit_ok(transaction);
+        }
+    }
+    // Redo the changes after the last checkpoint on top of its image, even
+    // though the changed nodes have been written back in between
+    let options_without_checkpoints = DbOptions {
+        checkpoint_log_size: None,
+        ..options
+    };
+    for chunk in new_keys.chunks(250) {
+        let db = Db::open_with_options(path, &options_without_checkpoints).unwrap();
+        let mut transaction = db.begin_transaction();
+        for key in chunk {
+            transaction.insert(tree, key, key).unwrap();
+        }
+        commit_ok(transaction);
+        assert!(db.buffer_pool_stats().write_backs > 0);
+    }
+    let db = Db::open_with_options(path, &options).unwrap();
+    let transaction = db.begin_transaction();
+    for key in &keys {
+        assert_eq!(transaction.get(tree, key).unwrap().unwrap(), key);
+    }
+}
+
+#[test]
+fn checkpoint_corruption() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    {
+        let db = Db::open(path).unwrap();
+        db.checkpoint().unwrap();
+    }
+    let checkpoint_path = path.join("CHECKPOINT");
+    let checkpoint_len = checkpoint_path.metadata().unwrap().len();
+    OpenOptions::new()
+        .write(true)
+        .open(&checkpoint_path)
+        .unwrap()
+        .set_len(checkpoint_len - 1)
+        .unwrap();
+    assert!(matches!(Db::open(path), Err(DbError::BadCheckpoint)));
+}
+
+fn log_segments(db_path: &Path) -> Vec<String> {
+    let mut result: Vec<_> = std::fs::read_dir(db_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with("LOG."))
+        .collect();
+    result.sort();
+    result
+}
+
+#[test]
+fn log_segments_removed_at_checkpoint() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let options = DbOptions {
+        checkpoint_log_size: None,
+        log_segment_size: 256,
+        ..DbOptions::default()
+    };
+    let keys: Vec<_> = (0..50_u32).map(u32::to_be_bytes).collect();
+    let tree;
+    {
+        let db = Db::open_with_options(path, &options).unwrap();
+        let mut transaction = db.begin_transaction();
+        tree = transaction.create_keyspace("k", untyped(), b"").unwrap();
+        commit_ok(transaction);
+        for key in &keys {
+            let mut transaction = db.begin_transaction();
+            transaction.insert(tree, key, &[0; 100]).unwrap();
+            commit_ok(transaction);
+        }
+    }
+    let segments = log_segments(path);
+    assert!(segments.len() > 10);
+    assert_eq!(segments[0], "LOG.00000000000000000000");
+    let check_keys = |db: &mut Db| {
+        let transaction = db.begin_transaction();
+        for key in &keys {
+            assert_eq!(transaction.get(tree, key).unwrap().unwrap(), [0; 100]);
+        }
+    };
+    let mut db = Db::open_with_options(path, &options).unwrap();
+    check_keys(&mut db);
+    db.checkpoint().unwrap();
+    // Only the segment holding the checkpoint record is left
+    assert_eq!(log_segments(path).len(), 1);
+    drop(db);
+    let mut db = Db::open_with_options(path, &options).unwrap();
+    check_keys(&mut db);
+}
+
+#[test]
+fn log_segment_missing() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let options = DbOptions {
+        log_segment_size: 64,
+        ..DbOptions::default()
+    };
+    {
+        let db = Db::open_with_options(path, &options).unwrap();
+        for name in ["a", "b", "c"] {
+            let mut transaction = db.begin_transaction();
+            let _tree = transaction.create_keyspace(name, untyped(), b"").unwrap();
+            commit_ok(transaction);
+        }
+    }
+    let segments = log_segments(path);
+    assert!(segments.len() >= 3);
+    std::fs::remove_file(path.join(&segments[1])).unwrap();
+    let missing_lsn: u64 = segments[1]["LOG.".len()..].parse().unwrap();
+    assert!(matches!(
+        Db::open_with_options(path, &options),
+        Err(DbError::MissingLogSegment { lsn }) if lsn == missing_lsn
+    ));
+}
+
+#[test]
+fn commit_durability() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let modes = [
+        Durability::Fsync,
+        Durability::Fdatasync,
+        Durability::Periodic {
+            interval: Duration::from_millis(10),
+        },
+        Durability::NoSync,
+    ];
+    let tree;
+    {
+        let db = Db::open(path).unwrap();
+        let mut transaction = db.begin_transaction();
+        tree = transaction.create_keyspace("k", untyped(), b"").unwrap();
+        commit_ok(transaction);
+    }
+    for (i, durability) in modes.into_iter().enumerate() {
+        let options = DbOptions {
+            durability,
+            ..DbOptions::default()
+        };
+        let db = Db::open_with_options(path, &options).unwrap();
+        let key = [u8::try_from(i).unwrap()];
+        let mut transaction = db.begin_transaction();
+        transaction.insert(tree, &key, b"default").unwrap();
+        commit_ok(transaction);
+        // Overriding the default
+        let mut transaction = db.begin_transaction();
+        transaction.upsert(tree, &key, b"override").unwrap();
+        transaction
+            .commit_with_durability(modes[(i + 1) % modes.len()])
+            .unwrap();
+    }
+    let db = Db::open(path).unwrap();
+    let transaction = db.begin_transaction();
+    for i in 0..modes.len() {
+        let key = [u8::try_from(i).unwrap()];
+        assert_eq!(transaction.get(tree, &key).unwrap().unwrap(), b"override");
+    }
+}
+
+#[test]
+fn transaction_ids_unique_across_opens() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let options = DbOptions {
+        checkpoint_log_size: None,
+        log_segment_size: 64,
+        ..DbOptions::default()
+    };
+    let mut last_id;
+    {
+        let db = Db::open_with_options(path, &options).unwrap();
+        let mut transaction = db.begin_transaction();
+        let _tree = transaction.new_art_descriptor_node();
+        last_id = transaction.id();
+        commit_ok(transaction);
+    }
+    // Recovered from the log
+    {
+        let db = Db::open_with_options(path, &options).unwrap();
+        let mut transaction = db.begin_transaction();
+        assert!(transaction.id() > last_id);
+        let _tree = transaction.new_art_descriptor_node();
+        commit_ok(transaction);
+        // Not logged, but before the checkpoint
+        for _ in 0..3 {
+            last_id = db.begin_transaction().id();
+        }
+        db.checkpoint().unwrap();
+    }
+    assert_eq!(log_segments(path).len(), 1);
+    // Recovered from the checkpoint
+    {
+        let db = Db::open_with_options(path, &options).unwrap();
+        assert!(db.begin_transaction().id() > last_id);
+        // Neither logged nor before a checkpoint, over several reserved blocks
+        for _ in 0..3000 {
+            let _transaction = db.begin_transaction();
+        }
+        let mut rolled_back = db.begin_transaction();
+        let _tree = rolled_back.new_art_descriptor_node();
+        last_id = rolled_back.id();
+    }
+    // Recovered from the reserved IDs in the log
+    let db = Db::open_with_options(path, &options).unwrap();
+    assert!(db.begin_transaction().id() > last_id);
+}
+
+// Commits a key to the keyspace "k" in each of the two transactions, returning
+// the LSN of the begin record of the second one
+fn commit_two_keys(path: &Path) -> u64 {
+    let options = DbOptions {
+        checkpoint_log_size: None,
+        ..DbOptions::default()
+    };
+    let db = Db::open_with_options(path, &options).unwrap();
+    let mut t1 = db.begin_transaction();
+    let tree = t1.create_keyspace("k", untyped(), b"").unwrap();
+    t1.insert(tree, b"a", b"a").unwrap();
+    commit_ok(t1);
+    let last_lsn = path
+        .join("LOG.00000000000000000000")
+        .metadata()
+        .unwrap()
+        .len();
+    let mut t2 = db.begin_transaction();
+    t2.insert(tree, b"b", b"b").unwrap();
+    commit_ok(t2);
+    last_lsn
+}
+
+#[test]
+fn torn_log_tail_truncated() {
+    let tears: [fn(&mut File, u64); 4] = [
+        |log_file, last_lsn| log_file.set_len(last_lsn + 30).unwrap(),
+        // The commit record of the last transaction
+        |log_file, _| {
+            let len = log_file.metadata().unwrap().len();
+            replace_u8(log_file, len - 1, 7, 0xFF);
+        },
+        // Cut just before the commit record
+        |log_file, _| {
+            let len = log_file.metadata().unwrap().len();
+            log_file.set_len(len - LOG_BOUNDARY_RECORD_SIZE).unwrap();
+        },
+        // Followed by the zeros of the file space allocated before the crash
+        |log_file, last_lsn| {
+            log_file.set_len(last_lsn + 10).unwrap();
+            log_file.set_len(last_lsn + 1000).unwrap();
+        },
+    ];
+    for tear in tears {
+        let temp_dir = get_temp_dir();
+        let path = temp_dir.path();
+        let last_lsn = commit_two_keys(path);
+        tear(&mut open_log_for_corruption(path), last_lsn);
+        let tree;
+        {
+            let db = Db::open(path).unwrap();
+            let mut transaction = db.begin_transaction();
+            tree = transaction.open_keyspace("k").unwrap().tree();
+            assert_eq!(transaction.get(tree, b"a").unwrap().unwrap(), b"a");
+            assert!(transaction.get(tree, b"b").unwrap().is_none());
+            transaction.insert(tree, b"c", b"c").unwrap();
+            commit_ok(transaction);
+        }
+        // Appended after the truncated tail
+        let db = Db::open(path).unwrap();
+        let transaction = db.begin_transaction();
+        assert!(transaction.get(tree, b"b").unwrap().is_none());
+        assert_eq!(transaction.get(tree, b"c").unwrap().unwrap(), b"c");
+    }
+}
+
+// A transaction whose changes reached the log without its commit record is
+// discarded, also after its ID is reused
+#[test]
+fn incomplete_transaction_ignored() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    commit_two_keys(path);
+    {
+        let log_file = open_log_for_corruption(path);
+        let len = log_file.metadata().unwrap().len();
+        log_file.set_len(len - LOG_BOUNDARY_RECORD_SIZE).unwrap();
+    }
+    let tree;
+    {
+        let db = Db::open(path).unwrap();
+        let t1 = db.begin_transaction();
+        tree = t1.open_keyspace("k").unwrap().tree();
+        assert!(t1.get(tree, b"b").unwrap().is_none());
+        let mut t2 = db.begin_transaction();
+        t2.insert(tree, b"c", b"c").unwrap();
+        commit_ok(t2);
+        drop(t1);
+    }
+    let db = Db::open(path).unwrap();
+    let transaction = db.begin_transaction();
+    assert_eq!(transaction.get(tree, b"a").unwrap().unwrap(), b"a");
+    assert!(transaction.get(tree, b"b").unwrap().is_none());
+    assert_eq!(transaction.get(tree, b"c").unwrap().unwrap(), b"c");
+}
+
+#[test]
+fn log_corruption_before_tail() {
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    let last_lsn = commit_two_keys(path);
+    let mut log_file = open_log_for_corruption(path);
+    // The commit record of the first transaction
+    replace_u8(&mut log_file, last_lsn - 1, 7, 0xFF);
+    assert!(matches!(
+        Db::open(path),
+        Err(DbError::BadLogRecord { lsn }) if lsn == last_lsn - LOG_BOUNDARY_RECORD_SIZE
+    ));
+}
+
+// The files of a database built by a fixed sequence of operations, with a
+// checkpoint in the middle
+const GOLDEN_FILES: [&str; 4] = ["VERSION", "LOG.00000000000000000000", "DATA", "CHECKPOINT"];
+
+fn build_golden_db(path: &Path) {
+    let db = Db::open(path).unwrap();
+    let mut t1 = db.begin_transaction();
+    let a = t1.create_keyspace("a", untyped(), b"options").unwrap();
+    for key in [&b"key1"[..], b"key2", b"key3"] {
+        t1.insert(a, key, b"value").unwrap();
+    }
+    commit_ok(t1);
+    db.checkpoint().unwrap();
+    let mut t2 = db.begin_transaction();
+    let b = t2.create_keyspace("b", untyped(), b"").unwrap();
+    t2.insert(b, b"key", b"value").unwrap();
+    t2.upsert(a, b"key1", b"new value").unwrap();
+    assert!(t2.delete(a, b"key2").unwrap());
+    commit_ok(t2);
+}
+
+// Any change to the file formats must be deliberate. After one, regenerate the
+// golden files by running this test with KIRUNADB_UPDATE_GOLDEN set.
+#[test]
+fn golden_files() {
+    let golden_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    build_golden_db(path);
+    for name in GOLDEN_FILES {
+        let bytes = std::fs::read(path.join(name)).unwrap();
+        if std::env::var_os("KIRUNADB_UPDATE_GOLDEN").is_some() {
+            std::fs::write(golden_path.join(name), &bytes).unwrap();
+        }
+        assert_eq!(
+            bytes,
+            std::fs::read(golden_path.join(name)).unwrap(),
+            "{name} differs from the golden file"
+        );
+    }
+    // The golden database reads back on any host
+    let temp_dir = get_temp_dir();
+    let path = temp_dir.path();
+    for name in GOLDEN_FILES {
+        std::fs::copy(golden_path.join(name), path.join(name)).unwrap();
+    }
+    let db = Db::open(path).unwrap();
+    let transaction = db.begin_transaction();
+    assert_eq!(names(&transaction.list_keyspaces().unwrap()), ["a", "b"]);
+    let a = transaction.open_keyspace("a").unwrap();
+    assert_eq!(a.options(), b"options");
+    assert_eq!(
+        keys(transaction.range(a.tree(), Bound::Unbounded, Bound::Unbounded)),
+        [b"key1", b"key3"]
+    );
+    assert_eq!(
+        transaction.get(a.tree(), b"key1").unwrap().unwrap(),
+        b"new value"
+    );
+    let b = transaction.open_keyspace("b").unwrap().tree();
+    assert_eq!(transaction.get(b, b"key").unwrap().unwrap(), b"value");
 }